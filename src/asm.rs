@@ -0,0 +1,1994 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::Instruction;
+use crate::disasm::{self, Operand};
+use crate::memory::MemoryAccess;
+use crate::rel::{write_rel, Address, AddressType, RelItem};
+
+// Two-pass assembler for Intel syntax 8080 source, as accepted by Intel's and
+// Digital Research's ASM. The opcode for each mnemonic and operand list is
+// looked up among the Instruction values through the disassembler's decoding,
+// so both always agree on the encoding.
+//
+// Macros follow MAC and M80: MACRO/ENDM with parameters, LOCAL labels, `&`
+// concatenation, `<...>` and `%expr` arguments, EXITM, REPT, IRP, IRPC and
+// INCLUDE of other source files.
+//
+// Code is absolute until CSEG or DSEG selects the relocatable code or data
+// segment, each with its own location counter; ASEG goes back. With PUBLIC
+// and EXTRN the program can be written out as a Microsoft REL module for the
+// linker. Relocatable values may only be added to or subtracted from
+// absolute ones, or subtracted from another in the same segment.
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum AsmErrorKind {
+    Syntax(String),
+    UnknownMnemonic(String),
+    InvalidOperands(String),
+    UndefinedSymbol(String),
+    DuplicateSymbol(String),
+    // a label ended up at a different address in the second pass
+    PhaseError(String),
+    ValueOutOfRange(u16),
+    // code or data past the top of memory
+    LocationOverflow,
+    DivisionByZero,
+    UnterminatedString,
+    ElseWithoutIf,
+    EndifWithoutIf,
+    MissingEndif,
+    // MACRO, REPT, IRP or IRPC without ENDM
+    MissingEndm,
+    EndmWithoutMacro,
+    // macro expansions and included files nested too deeply
+    NestingTooDeep,
+    Include(String),
+    // a relocatable or external value where only an absolute one can go
+    Relocation(String)
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmErrorKind::Syntax(message) => write!(f, "{message}"),
+            AsmErrorKind::UnknownMnemonic(mnemonic) => write!(f, "unknown mnemonic {mnemonic}"),
+            AsmErrorKind::InvalidOperands(mnemonic) => write!(f, "invalid operands for {mnemonic}"),
+            AsmErrorKind::UndefinedSymbol(name) => write!(f, "undefined symbol {name}"),
+            AsmErrorKind::DuplicateSymbol(name) => write!(f, "symbol {name} is already defined"),
+            AsmErrorKind::PhaseError(name) => write!(f, "label {name} moved between passes"),
+            AsmErrorKind::ValueOutOfRange(val) => write!(f, "value {val:04X}h out of range"),
+            AsmErrorKind::LocationOverflow => write!(f, "location counter passes FFFFh"),
+            AsmErrorKind::DivisionByZero => write!(f, "division by zero"),
+            AsmErrorKind::UnterminatedString => write!(f, "unterminated string"),
+            AsmErrorKind::ElseWithoutIf => write!(f, "ELSE without IF"),
+            AsmErrorKind::EndifWithoutIf => write!(f, "ENDIF without IF"),
+            AsmErrorKind::MissingEndif => write!(f, "IF without ENDIF"),
+            AsmErrorKind::MissingEndm => write!(f, "block without ENDM"),
+            AsmErrorKind::EndmWithoutMacro => write!(f, "ENDM without MACRO"),
+            AsmErrorKind::NestingTooDeep => write!(f, "macros or includes nested too deeply"),
+            AsmErrorKind::Include(message) => write!(f, "cannot include {message}"),
+            AsmErrorKind::Relocation(message) => write!(f, "{message}")
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct AsmError {
+    // included file the error is in, None for the top level source
+    pub file: Option<PathBuf>,
+    // 1-based source line; for errors inside a macro expansion, the line
+    // that invoked the macro
+    pub line: usize,
+    pub kind: AsmErrorKind
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}: {}", file.display(), self.line, self.kind),
+            None => write!(f, "line {}: {}", self.line, self.kind)
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// Contiguous run of assembled bytes
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Segment {
+    // ASEG, CSEG or DSEG; `address` is an offset into the latter two
+    pub kind: AddressType,
+    pub address: u16,
+    pub bytes: Vec<u8>
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum RelocationTarget {
+    // the word is an offset into the segment
+    Segment(AddressType),
+    // the word is added to the value of the external symbol
+    External(String)
+}
+
+// Word the linker has to fix up
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Relocation {
+    pub location: Address,
+    pub target: RelocationTarget
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Program {
+    pub segments: Vec<Segment>,
+    // every label, EQU and SET symbol, upper case; relocatable ones hold
+    // their offset into their segment
+    pub symbols: BTreeMap<String, u16>,
+    // operand of END
+    pub entry: Option<u16>,
+    pub entry_segment: AddressType,
+    // highest offset reached in CSEG and DSEG
+    pub program_size: u16,
+    pub data_size: u16,
+    pub publics: BTreeMap<String, Address>,
+    pub relocations: Vec<Relocation>
+}
+
+impl Program {
+    // Relocatable segments are loaded at their offsets, as if linked at 0
+    pub fn load_into(&self, memory: &mut impl MemoryAccess) {
+        for segment in &self.segments {
+            for (offset, byte) in segment.bytes.iter().enumerate() {
+                memory.write_byte(segment.address.wrapping_add(offset as u16), *byte);
+            }
+        }
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<u16> {
+        self.symbols.get(&name.to_ascii_uppercase()).copied()
+    }
+
+    // The program as a REL module. References to each external are chained
+    // through the words that use it, with any offset given by an external
+    // plus offset item
+    pub fn rel_items(&self, name: &str) -> Vec<RelItem> {
+        let mut items = vec![RelItem::ProgramName(name.to_ascii_uppercase())];
+        items.extend(self.publics.keys().map(|name| RelItem::EntrySymbol(name.clone())));
+        if self.data_size > 0 {
+            items.push(RelItem::DataSize(Address::absolute(self.data_size)));
+        }
+        items.push(RelItem::ProgramSize(Address::program(self.program_size)));
+
+        // last reference to each external, the head of its chain
+        let mut chains: BTreeMap<&str, Address> = BTreeMap::new();
+        for segment in &self.segments {
+            items.push(RelItem::SetLocation(Address { kind: segment.kind, value: segment.address }));
+
+            let mut offset = 0;
+            while offset < segment.bytes.len() {
+                let location = Address { kind: segment.kind, value: segment.address.wrapping_add(offset as u16) };
+                let target = self.relocations.iter()
+                    .find(|relocation| relocation.location == location)
+                    .map(|relocation| &relocation.target);
+                let word = match segment.bytes.get(offset..offset + 2) {
+                    Some(&[low, high]) if target.is_some() => u16::from_le_bytes([low, high]),
+                    _ => {
+                        items.push(RelItem::Byte(segment.bytes[offset]));
+                        offset += 1;
+                        continue;
+                    }
+                };
+
+                match target {
+                    Some(RelocationTarget::Segment(kind)) => items.push(RelItem::Word(*kind, word)),
+                    Some(RelocationTarget::External(name)) => {
+                        if word != 0 {
+                            items.push(RelItem::ExternalPlusOffset(Address::absolute(word)));
+                        }
+                        let link = chains.insert(name, location).unwrap_or(Address::absolute(0));
+                        push_word(&mut items, link);
+                    },
+                    None => unreachable!()
+                }
+                offset += 2;
+            }
+        }
+
+        for (name, head) in chains {
+            items.push(RelItem::ChainExternal(head, name.to_string()));
+        }
+        for (name, address) in &self.publics {
+            items.push(RelItem::EntryPoint(*address, name.clone()));
+        }
+        let start = match self.entry {
+            Some(value) => Address { kind: self.entry_segment, value },
+            None => Address::absolute(0)
+        };
+        items.push(RelItem::EndModule(start));
+        items.push(RelItem::EndFile);
+        items
+    }
+
+    pub fn to_rel(&self, name: &str) -> Vec<u8> {
+        write_rel(&self.rel_items(name))
+    }
+}
+
+// A word item, or two bytes for an absolute value, which has no word item
+fn push_word(items: &mut Vec<RelItem>, address: Address) {
+    match address.kind {
+        AddressType::Absolute => {
+            let [low, high] = address.value.to_le_bytes();
+            items.push(RelItem::Byte(low));
+            items.push(RelItem::Byte(high));
+        },
+        kind => items.push(RelItem::Word(kind, address.value))
+    }
+}
+
+// INCLUDE resolves relative to the current directory
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    Assembler::new().assemble(source)
+}
+
+pub fn assemble_file(path: impl AsRef<Path>) -> Result<Program, AsmError> {
+    Assembler::new().assemble_file(path)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum SymbolKind {
+    Label,
+    Equ,
+    Set,
+    External
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Symbol {
+    value: u16,
+    segment: AddressType,
+    kind: SymbolKind,
+    // pass the symbol was last defined in
+    pass: u8
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Pattern {
+    // register, register pair or condition name that selects the opcode
+    Fixed(&'static str),
+    Byte,
+    Word,
+    Restart
+}
+
+struct Encoding {
+    mnemonic: &'static str,
+    patterns: Vec<Pattern>,
+    opcode: u8
+}
+
+// Operand patterns of every documented opcode, derived from its decoding
+fn encodings() -> Vec<Encoding> {
+    (0..=0xffu8)
+        .filter(|opcode| Instruction::from(*opcode) as u8 == *opcode)
+        .map(|opcode| {
+            let (mnemonic, operands) = disasm::decode(Instruction::from(opcode), [0, 0]);
+            let patterns = operands.iter().filter_map(|operand| match operand {
+                Operand::Register(register) => Some(Pattern::Fixed(register.name())),
+                Operand::RegisterPair(pair) => Some(Pattern::Fixed(pair.name())),
+                Operand::Condition(_) => None,
+                Operand::Byte(_) | Operand::Port(_) => Some(Pattern::Byte),
+                Operand::Word(_) | Operand::Address(_) => Some(Pattern::Word),
+                Operand::Restart(_) => Some(Pattern::Restart)
+            }).collect();
+
+            Encoding { mnemonic, patterns, opcode }
+        })
+        .collect()
+}
+
+const DIRECTIVES: [&str; 24] = [
+    "ORG", "DB", "DW", "DS", "EQU", "SET", "IF", "ELSE", "ENDIF", "END",
+    "MACRO", "ENDM", "LOCAL", "EXITM", "REPT", "IRP", "IRPC", "INCLUDE",
+    "ASEG", "CSEG", "DSEG", "PUBLIC", "EXTRN", "EXT"
+];
+
+// Location counters and sizes are kept per segment in this order
+fn segment_index(kind: AddressType) -> usize {
+    match kind {
+        AddressType::Program => 1,
+        AddressType::Data => 2,
+        _ => 0
+    }
+}
+
+// Limit on macro expansions and included files active at once
+const MAX_NESTING: usize = 64;
+
+#[derive(Clone, Debug)]
+struct Location {
+    file: Option<PathBuf>,
+    line: usize
+}
+
+#[derive(Clone, Debug)]
+struct Conditional {
+    // the IF, for MissingEndif
+    location: Location,
+    enclosing_active: bool,
+    condition: bool,
+    in_else: bool
+}
+
+impl Conditional {
+    fn active(&self) -> bool {
+        self.enclosing_active && (self.condition != self.in_else)
+    }
+}
+
+struct Statement<'a> {
+    label: Option<&'a str>,
+    operation: String,
+    operands: &'a str
+}
+
+#[derive(Clone, Debug)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>
+}
+
+#[derive(Clone, Debug)]
+enum Block {
+    Macro { name: String, params: Vec<String> },
+    // REPT has no parameter, IRP and IRPC substitute one item per repetition
+    Repeat { param: Option<String>, items: Vec<String> }
+}
+
+// Body of a block being collected up to its ENDM
+#[derive(Clone, Debug)]
+struct Recording {
+    block: Block,
+    depth: usize,
+    body: Vec<String>,
+    location: Location,
+    // input the block started in
+    input: usize
+}
+
+#[derive(Clone, Debug)]
+enum InputKind {
+    Source(Option<PathBuf>),
+    Expansion
+}
+
+#[derive(Clone, Debug)]
+struct Input {
+    kind: InputKind,
+    lines: Vec<String>,
+    next: usize,
+    // conditionals open when the input started, restored by EXITM
+    conditionals: usize
+}
+
+pub struct Assembler {
+    encodings: Vec<Encoding>,
+    include_dirs: Vec<PathBuf>,
+    symbols: HashMap<String, Symbol>,
+    macros: HashMap<String, Macro>,
+    pass: u8,
+    pc: u16,
+    // the location counter ran up to 0x10000 and wrapped to 0
+    past_top: bool,
+    segment: AddressType,
+    // location counters of the other segments, and the highest of each
+    locations: [u16; 3],
+    sizes: [u16; 3],
+    publics: Vec<String>,
+    relocations: Vec<Relocation>,
+    inputs: Vec<Input>,
+    recording: Option<Recording>,
+    local_count: usize,
+    conditionals: Vec<Conditional>,
+    segments: Vec<Segment>,
+    entry: Option<Address>,
+    // radix of numbers without a suffix
+    radix: u32
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self {
+            encodings: encodings(),
+            include_dirs: Vec::new(),
+            symbols: HashMap::new(),
+            macros: HashMap::new(),
+            pass: 1,
+            pc: 0,
+            past_top: false,
+            segment: AddressType::Absolute,
+            locations: [0; 3],
+            sizes: [0; 3],
+            publics: Vec::new(),
+            relocations: Vec::new(),
+            inputs: Vec::new(),
+            recording: None,
+            local_count: 0,
+            conditionals: Vec::new(),
+            segments: Vec::new(),
+            entry: None,
+            radix: 10
+        }
+    }
+
+    // Read numbers without a suffix in `radix`, 10 unless set. In hex, B and
+    // D are digits rather than suffixes
+    pub fn set_radix(&mut self, radix: u32) {
+        self.radix = radix;
+    }
+
+    // Searched for INCLUDE files after the directory of the including file
+    pub fn add_include_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.include_dirs.push(dir.into());
+    }
+
+    pub fn assemble(&mut self, source: &str) -> Result<Program, AsmError> {
+        self.assemble_source(source, None)
+    }
+
+    pub fn assemble_file(&mut self, path: impl AsRef<Path>) -> Result<Program, AsmError> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|err| AsmError {
+            file: None,
+            line: 0,
+            kind: AsmErrorKind::Include(format!("{}: {err}", path.display()))
+        })?;
+
+        self.assemble_source(&source, Some(path.to_path_buf()))
+    }
+
+    fn assemble_source(&mut self, source: &str, file: Option<PathBuf>) -> Result<Program, AsmError> {
+        self.symbols.clear();
+        self.run_pass(source, &file, 1)?;
+        self.run_pass(source, &file, 2)?;
+
+        self.select_segment(AddressType::Absolute);
+        let publics = self.publics.iter()
+            .map(|name| (name.clone(), Address { kind: self.symbols[name].segment, value: self.symbols[name].value }))
+            .collect();
+        Ok(Program {
+            segments: std::mem::take(&mut self.segments),
+            symbols: self.symbols.iter()
+                .filter(|(_, symbol)| symbol.kind != SymbolKind::External)
+                .map(|(name, symbol)| (name.clone(), symbol.value))
+                .collect(),
+            entry: self.entry.map(|entry| entry.value),
+            entry_segment: self.entry.map_or(AddressType::Absolute, |entry| entry.kind),
+            program_size: self.sizes[segment_index(AddressType::Program)],
+            data_size: self.sizes[segment_index(AddressType::Data)],
+            publics,
+            relocations: std::mem::take(&mut self.relocations)
+        })
+    }
+
+    fn run_pass(&mut self, source: &str, file: &Option<PathBuf>, pass: u8) -> Result<(), AsmError> {
+        self.pass = pass;
+        self.pc = 0;
+        self.past_top = false;
+        self.segment = AddressType::Absolute;
+        self.locations = [0; 3];
+        self.sizes = [0; 3];
+        self.publics.clear();
+        self.relocations.clear();
+        self.macros.clear();
+        self.inputs.clear();
+        self.recording = None;
+        self.local_count = 0;
+        self.conditionals.clear();
+        self.segments.clear();
+        self.entry = None;
+
+        self.inputs.push(Input {
+            kind: InputKind::Source(file.clone()),
+            lines: source.lines().map(String::from).collect(),
+            next: 0,
+            conditionals: 0
+        });
+
+        while let Some(text) = self.next_line()? {
+            match self.statement(&text) {
+                Ok(true) => break,
+                Ok(false) => {},
+                Err(kind) => return Err(self.error(kind))
+            }
+        }
+
+        if let Some(recording) = &self.recording {
+            return Err(error_at(&recording.location, AsmErrorKind::MissingEndm));
+        }
+        if let Some(conditional) = self.conditionals.last() {
+            return Err(error_at(&conditional.location, AsmErrorKind::MissingEndif));
+        }
+
+        Ok(())
+    }
+
+    fn next_line(&mut self) -> Result<Option<String>, AsmError> {
+        loop {
+            let depth = self.inputs.len();
+            let input = match self.inputs.last_mut() {
+                Some(input) => input,
+                None => return Ok(None)
+            };
+
+            if input.next < input.lines.len() {
+                input.next += 1;
+                return Ok(Some(input.lines[input.next - 1].clone()));
+            }
+
+            // a block has to end in the file or expansion it started in
+            if let Some(recording) = &self.recording {
+                if recording.input == depth {
+                    return Err(error_at(&recording.location, AsmErrorKind::MissingEndm));
+                }
+            }
+            self.inputs.pop();
+        }
+    }
+
+    // Line of the innermost source file being read
+    fn location(&self) -> Location {
+        self.inputs.iter().rev()
+            .find_map(|input| match &input.kind {
+                InputKind::Source(file) => Some(Location { file: file.clone(), line: input.next }),
+                InputKind::Expansion => None
+            })
+            .unwrap_or(Location { file: None, line: 0 })
+    }
+
+    fn error(&self, kind: AsmErrorKind) -> AsmError {
+        error_at(&self.location(), kind)
+    }
+
+    fn is_operation(&self, word: &str) -> bool {
+        DIRECTIVES.contains(&word) || self.macros.contains_key(word) ||
+            self.encodings.iter().any(|encoding| encoding.mnemonic == word)
+    }
+
+    fn active(&self) -> bool {
+        self.conditionals.last().is_none_or(|conditional| conditional.active())
+    }
+
+    fn parse_statement<'a>(&self, text: &'a str) -> Statement<'a> {
+        let text = strip_comment(text).trim_end();
+        let in_column_one = !text.starts_with(char::is_whitespace);
+        let (word, rest) = split_symbol(text.trim_start());
+
+        let mut label = None;
+        let mut rest = rest;
+        if !word.is_empty() {
+            if let Some(after) = rest.strip_prefix(':') {
+                label = Some(word);
+                rest = after.strip_prefix(':').unwrap_or(after);
+            }
+            else {
+                let upper = word.to_ascii_uppercase();
+                let (next, _) = split_symbol(rest.trim_start());
+                let next = next.to_ascii_uppercase();
+                if (in_column_one && !self.is_operation(&upper)) || matches!(next.as_str(), "EQU" | "SET" | "MACRO") {
+                    label = Some(word);
+                }
+            }
+        }
+
+        let (operation, operands) = match label {
+            Some(_) => split_symbol(rest.trim_start()),
+            None => (word, rest)
+        };
+
+        Statement {
+            label,
+            operation: operation.to_ascii_uppercase(),
+            operands: operands.trim()
+        }
+    }
+
+    // Returns true at END
+    fn statement(&mut self, text: &str) -> Result<bool, AsmErrorKind> {
+        let statement = self.parse_statement(text);
+        if self.recording.is_some() {
+            return self.record(text, &statement.operation);
+        }
+
+        if text.starts_with('*') {
+            return Ok(false);
+        }
+
+        match statement.operation.as_str() {
+            "IF" => {
+                let enclosing_active = self.active();
+                let condition = enclosing_active && self.evaluate(statement.operands)? != 0;
+                self.conditionals.push(Conditional {
+                    location: self.location(),
+                    enclosing_active,
+                    condition,
+                    in_else: false
+                });
+                return Ok(false);
+            },
+            "ELSE" => {
+                match self.conditionals.last_mut() {
+                    Some(conditional) if !conditional.in_else => conditional.in_else = true,
+                    _ => return Err(AsmErrorKind::ElseWithoutIf)
+                }
+                return Ok(false);
+            },
+            "ENDIF" => {
+                self.conditionals.pop().ok_or(AsmErrorKind::EndifWithoutIf)?;
+                return Ok(false);
+            },
+            _ => {}
+        }
+
+        if !self.active() {
+            return Ok(false);
+        }
+
+        let blank = statement.operation.is_empty() && statement.operands.is_empty();
+        if !blank && !statement.operation.starts_with(is_symbol_start) {
+            return Err(AsmErrorKind::Syntax(format!("unexpected '{}'", strip_comment(text).trim())));
+        }
+
+        let operands = statement.operands;
+        match statement.operation.as_str() {
+            "EQU" | "SET" => {
+                let name = statement.label.ok_or_else(|| {
+                    AsmErrorKind::Syntax(format!("{} without a name", statement.operation))
+                })?;
+                let kind = if statement.operation == "EQU" { SymbolKind::Equ } else { SymbolKind::Set };
+                if let Some(value) = self.evaluate_forward(operands)? {
+                    let value = value.relocatable()?;
+                    self.define(name, value.value, value.segment, kind)?;
+                }
+            },
+            "MACRO" => {
+                let name = statement.label
+                    .ok_or_else(|| AsmErrorKind::Syntax("MACRO without a name".to_string()))?
+                    .to_ascii_uppercase();
+                if DIRECTIVES.contains(&name.as_str()) {
+                    return Err(AsmErrorKind::Syntax(format!("invalid macro name {name}")));
+                }
+                let params = split_macro_args(operands).iter().map(|param| param.to_ascii_uppercase()).collect();
+                self.start_recording(Block::Macro { name, params });
+            },
+            "ENDM" => return Err(AsmErrorKind::EndmWithoutMacro),
+            "LOCAL" => return Err(AsmErrorKind::Syntax("LOCAL outside a macro".to_string())),
+            "EXITM" => self.exit_macro()?,
+            "INCLUDE" => self.include(operands)?,
+            "ORG" => {
+                let value = self.evaluate_value(operands)?.relocatable()?;
+                if value.segment != AddressType::Absolute && value.segment != self.segment {
+                    return Err(AsmErrorKind::Relocation("ORG into another segment".to_string()));
+                }
+                self.pc = value.value;
+                self.past_top = false;
+                self.define_label(statement.label)?;
+            },
+            "END" => {
+                self.define_label(statement.label)?;
+                if !operands.is_empty() {
+                    if let Some(value) = self.evaluate_forward(operands)? {
+                        let value = value.relocatable()?;
+                        self.entry = Some(Address { kind: value.segment, value: value.value });
+                    }
+                }
+                return Ok(true);
+            },
+            operation => {
+                self.define_label(statement.label)?;
+                match operation {
+                    "" => {},
+                    "DS" => {
+                        let size = self.evaluate(operands)?;
+                        self.advance(size)?;
+                    },
+                    "ASEG" => self.select_segment(AddressType::Absolute),
+                    "CSEG" => self.select_segment(AddressType::Program),
+                    "DSEG" => self.select_segment(AddressType::Data),
+                    "PUBLIC" => {
+                        for name in split_operands(operands)? {
+                            self.public(name)?;
+                        }
+                    },
+                    "EXTRN" | "EXT" => {
+                        for name in split_operands(operands)? {
+                            self.define(name, 0, AddressType::Absolute, SymbolKind::External)?;
+                        }
+                    },
+                    "DB" => self.define_bytes(operands)?,
+                    "DW" => self.define_words(operands)?,
+                    "REPT" => {
+                        let count = self.evaluate(operands)?;
+                        self.start_recording(Block::Repeat { param: None, items: vec![String::new(); count as usize] });
+                    },
+                    "IRP" | "IRPC" => {
+                        let (param, list) = operands.split_once(',')
+                            .ok_or_else(|| AsmErrorKind::Syntax(format!("{operation} needs a parameter and a list")))?;
+                        let list = list.trim();
+                        let list = list.strip_prefix('<').and_then(|list| list.strip_suffix('>')).unwrap_or(list);
+                        let items = if operation == "IRP" {
+                            self.macro_arguments(list)?
+                        }
+                        else {
+                            list.chars().map(String::from).collect()
+                        };
+                        self.start_recording(Block::Repeat { param: Some(param.trim().to_ascii_uppercase()), items });
+                    },
+                    _ if self.macros.contains_key(operation) => self.invoke(operation, operands)?,
+                    _ => self.instruction(operation, operands)?
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn start_recording(&mut self, block: Block) {
+        self.recording = Some(Recording {
+            block,
+            depth: 1,
+            body: Vec::new(),
+            location: self.location(),
+            input: self.inputs.len()
+        });
+    }
+
+    // Collect a line of a block body. At the matching ENDM a macro is
+    // defined, a repetition is expanded
+    fn record(&mut self, text: &str, operation: &str) -> Result<bool, AsmErrorKind> {
+        let recording = self.recording.as_mut().unwrap();
+        match operation {
+            "MACRO" | "REPT" | "IRP" | "IRPC" => recording.depth += 1,
+            "ENDM" => recording.depth -= 1,
+            _ => {}
+        }
+
+        if recording.depth > 0 {
+            recording.body.push(text.to_string());
+            return Ok(false);
+        }
+
+        let recording = self.recording.take().unwrap();
+        match recording.block {
+            Block::Macro { name, params } => {
+                self.macros.insert(name, Macro { params, body: recording.body });
+            },
+            Block::Repeat { param, items } => {
+                let mut lines = Vec::new();
+                for item in items {
+                    let names = param.iter().map(|param| (param.clone(), item.clone())).collect();
+                    lines.extend(self.expand(&recording.body, names));
+                }
+                self.push_expansion(lines)?;
+            }
+        }
+
+        Ok(false)
+    }
+
+    // Body lines with parameters and LOCAL labels substituted
+    fn expand(&mut self, body: &[String], mut names: HashMap<String, String>) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut depth = 0;
+
+        for line in body {
+            let statement = self.parse_statement(line);
+            match statement.operation.as_str() {
+                "MACRO" | "REPT" | "IRP" | "IRPC" => depth += 1,
+                "ENDM" => depth -= 1,
+                // LOCALs of nested blocks are left for their own expansion
+                "LOCAL" if depth == 0 => {
+                    for name in split_macro_args(statement.operands) {
+                        self.local_count += 1;
+                        names.insert(name.to_ascii_uppercase(), format!("??{:04}", self.local_count));
+                    }
+                    continue;
+                },
+                _ => {}
+            }
+
+            lines.push(substitute(line, &names));
+        }
+
+        lines
+    }
+
+    fn push_expansion(&mut self, lines: Vec<String>) -> Result<(), AsmErrorKind> {
+        if self.inputs.len() >= MAX_NESTING {
+            return Err(AsmErrorKind::NestingTooDeep);
+        }
+
+        self.inputs.push(Input {
+            kind: InputKind::Expansion,
+            lines,
+            next: 0,
+            conditionals: self.conditionals.len()
+        });
+        Ok(())
+    }
+
+    fn invoke(&mut self, name: &str, operands: &str) -> Result<(), AsmErrorKind> {
+        let definition = self.macros[name].clone();
+        let args = self.macro_arguments(operands)?;
+        let names = definition.params.iter().enumerate()
+            .map(|(index, param)| (param.clone(), args.get(index).cloned().unwrap_or_default()))
+            .collect();
+
+        let lines = self.expand(&definition.body, names);
+        self.push_expansion(lines)
+    }
+
+    // Macro arguments with `<...>` brackets removed and `%expr` replaced by
+    // the decimal value of the expression
+    fn macro_arguments(&self, text: &str) -> Result<Vec<String>, AsmErrorKind> {
+        split_macro_args(text).iter()
+            .map(|arg| {
+                if let Some(inner) = arg.strip_prefix('<').and_then(|arg| arg.strip_suffix('>')) {
+                    Ok(inner.to_string())
+                }
+                else if let Some(expr) = arg.strip_prefix('%') {
+                    Ok(self.evaluate(expr.trim())?.to_string())
+                }
+                else {
+                    Ok(arg.to_string())
+                }
+            })
+            .collect()
+    }
+
+    fn exit_macro(&mut self) -> Result<(), AsmErrorKind> {
+        let index = self.inputs.iter()
+            .rposition(|input| matches!(input.kind, InputKind::Expansion))
+            .ok_or_else(|| AsmErrorKind::Syntax("EXITM outside a macro".to_string()))?;
+
+        self.conditionals.truncate(self.inputs[index].conditionals);
+        self.inputs.truncate(index);
+        Ok(())
+    }
+
+    fn include(&mut self, operands: &str) -> Result<(), AsmErrorKind> {
+        let name = match string_literal(operands)? {
+            Some(name) => String::from_utf8_lossy(&name).into_owned(),
+            None => operands.to_string()
+        };
+        if name.is_empty() {
+            return Err(AsmErrorKind::Syntax("INCLUDE without a file name".to_string()));
+        }
+        if self.inputs.len() >= MAX_NESTING {
+            return Err(AsmErrorKind::NestingTooDeep);
+        }
+
+        let path = self.resolve_include(&name)
+            .ok_or_else(|| AsmErrorKind::Include(format!("{name}: file not found")))?;
+        let source = fs::read_to_string(&path)
+            .map_err(|err| AsmErrorKind::Include(format!("{}: {err}", path.display())))?;
+
+        self.inputs.push(Input {
+            kind: InputKind::Source(Some(path)),
+            lines: source.lines().map(String::from).collect(),
+            next: 0,
+            conditionals: self.conditionals.len()
+        });
+        Ok(())
+    }
+
+    fn resolve_include(&self, name: &str) -> Option<PathBuf> {
+        let including_dir = match self.location().file {
+            Some(file) => file.parent().map(Path::to_path_buf).unwrap_or_default(),
+            None => PathBuf::new()
+        };
+
+        std::iter::once(including_dir)
+            .chain(self.include_dirs.iter().cloned())
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+    }
+
+    fn define_label(&mut self, label: Option<&str>) -> Result<(), AsmErrorKind> {
+        match label {
+            Some(name) => self.define(name, self.pc, self.segment, SymbolKind::Label),
+            None => Ok(())
+        }
+    }
+
+    fn define(&mut self, name: &str, value: u16, segment: AddressType, kind: SymbolKind) -> Result<(), AsmErrorKind> {
+        let name = name.to_ascii_uppercase();
+        if !name.starts_with(is_symbol_start) || self.is_operation(&name) {
+            return Err(AsmErrorKind::Syntax(format!("invalid symbol name {name}")));
+        }
+
+        if let Some(symbol) = self.symbols.get(&name) {
+            let redefinable = symbol.kind == SymbolKind::Set && kind == SymbolKind::Set;
+            if !redefinable && (symbol.pass == self.pass || symbol.kind != kind) {
+                return Err(AsmErrorKind::DuplicateSymbol(name));
+            }
+            if kind == SymbolKind::Label && symbol.value != value {
+                return Err(AsmErrorKind::PhaseError(name));
+            }
+        }
+
+        self.symbols.insert(name, Symbol { value, segment, kind, pass: self.pass });
+        Ok(())
+    }
+
+    // Symbols named by PUBLIC have to be defined in the module, by the end
+    // of the first pass
+    fn public(&mut self, name: &str) -> Result<(), AsmErrorKind> {
+        let name = name.to_ascii_uppercase();
+        if self.pass == 2 {
+            match self.symbols.get(&name) {
+                None => return Err(AsmErrorKind::UndefinedSymbol(name)),
+                Some(symbol) if symbol.kind == SymbolKind::External => {
+                    return Err(AsmErrorKind::Relocation(format!("external {name} cannot be PUBLIC")));
+                },
+                Some(_) => {}
+            }
+        }
+
+        if !self.publics.contains(&name) {
+            self.publics.push(name);
+        }
+        Ok(())
+    }
+
+    fn select_segment(&mut self, kind: AddressType) {
+        self.locations[segment_index(self.segment)] = self.pc;
+        self.segment = kind;
+        self.pc = self.locations[segment_index(kind)];
+        self.past_top = false;
+    }
+
+    // Move the location counter on. Running up to the top of memory is fine,
+    // going past it would overlap what is at the bottom
+    fn advance(&mut self, len: u16) -> Result<(), AsmErrorKind> {
+        let end = self.pc as u32 + len as u32;
+        if len > 0 && (self.past_top || end > 0x10000) {
+            return Err(AsmErrorKind::LocationOverflow);
+        }
+
+        self.pc = end as u16;
+        self.past_top |= end == 0x10000;
+        let size = &mut self.sizes[segment_index(self.segment)];
+        *size = (*size).max(self.pc);
+        Ok(())
+    }
+
+    // Value of an expression that has to be absolute
+    fn evaluate(&self, expr: &str) -> Result<u16, AsmErrorKind> {
+        self.evaluate_value(expr)?.absolute()
+    }
+
+    fn evaluate_value(&self, expr: &str) -> Result<Value, AsmErrorKind> {
+        if expr.is_empty() {
+            return Err(AsmErrorKind::Syntax("missing operand".to_string()));
+        }
+
+        Expression::new(expr, self)?.evaluate()
+    }
+
+    // Like evaluate_value, but a symbol defined further down the source is
+    // not an error during the first pass
+    fn evaluate_forward(&self, expr: &str) -> Result<Option<Value>, AsmErrorKind> {
+        match self.evaluate_value(expr) {
+            Err(AsmErrorKind::UndefinedSymbol(_)) if self.pass == 1 => Ok(None),
+            result => result.map(Some)
+        }
+    }
+
+    fn evaluate_byte(&self, expr: &str) -> Result<u8, AsmErrorKind> {
+        let val = match self.evaluate_forward(expr)? {
+            Some(value) => value.absolute()?,
+            None => 0
+        };
+        // 0 to 255, or -128 to -1
+        if val > 0xff && val < 0xff80 {
+            return Err(AsmErrorKind::ValueOutOfRange(val));
+        }
+
+        Ok(val as u8)
+    }
+
+    // Bytes of a word `offset` bytes on from the location counter, noting
+    // the relocation it needs
+    fn evaluate_word(&mut self, expr: &str, offset: u16) -> Result<[u8; 2], AsmErrorKind> {
+        let Some(value) = self.evaluate_forward(expr)? else {
+            return Ok([0, 0]);
+        };
+
+        let target = match value.external {
+            Some(name) => Some(RelocationTarget::External(name)),
+            None if value.segment != AddressType::Absolute => Some(RelocationTarget::Segment(value.segment)),
+            None => None
+        };
+        if let (Some(target), 2) = (target, self.pass) {
+            let location = Address { kind: self.segment, value: self.pc.wrapping_add(offset) };
+            self.relocations.push(Relocation { location, target });
+        }
+
+        Ok(value.value.to_le_bytes())
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), AsmErrorKind> {
+        if self.pass == 2 {
+            match self.segments.last_mut() {
+                Some(segment) if segment.kind == self.segment &&
+                    segment.address.wrapping_add(segment.bytes.len() as u16) == self.pc => {
+                    segment.bytes.extend_from_slice(bytes);
+                },
+                _ => self.segments.push(Segment { kind: self.segment, address: self.pc, bytes: bytes.to_vec() })
+            }
+        }
+
+        self.advance(bytes.len() as u16)
+    }
+
+    fn define_bytes(&mut self, operands: &str) -> Result<(), AsmErrorKind> {
+        let mut bytes = Vec::new();
+        for item in split_operands(operands)? {
+            match string_literal(item)? {
+                Some(text) => bytes.extend_from_slice(&text),
+                None => bytes.push(self.evaluate_byte(item)?)
+            }
+        }
+
+        self.emit(&bytes)
+    }
+
+    fn define_words(&mut self, operands: &str) -> Result<(), AsmErrorKind> {
+        let mut bytes = Vec::new();
+        for item in split_operands(operands)? {
+            let word = self.evaluate_word(item, bytes.len() as u16)?;
+            bytes.extend_from_slice(&word);
+        }
+
+        self.emit(&bytes)
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operands: &str) -> Result<(), AsmErrorKind> {
+        if !self.encodings.iter().any(|encoding| encoding.mnemonic == mnemonic) {
+            return Err(AsmErrorKind::UnknownMnemonic(mnemonic.to_string()));
+        }
+
+        let operands = split_operands(operands)?;
+        let encoding = self.encodings.iter()
+            .filter(|encoding| encoding.mnemonic == mnemonic && encoding.patterns.len() == operands.len())
+            .find(|encoding| encoding.patterns.iter().zip(&operands).all(|(pattern, operand)| match pattern {
+                Pattern::Fixed(name) => operand.eq_ignore_ascii_case(name),
+                _ => true
+            }))
+            .ok_or_else(|| AsmErrorKind::InvalidOperands(mnemonic.to_string()))?;
+
+        let mut bytes = vec![encoding.opcode];
+        let patterns = encoding.patterns.clone();
+        for (pattern, operand) in patterns.iter().zip(&operands) {
+            match pattern {
+                Pattern::Fixed(_) => {},
+                Pattern::Byte => bytes.push(self.evaluate_byte(operand)?),
+                Pattern::Word => {
+                    let word = self.evaluate_word(operand, bytes.len() as u16)?;
+                    bytes.extend_from_slice(&word);
+                },
+                Pattern::Restart => {
+                    let n = self.evaluate(operand)?;
+                    if n > 7 {
+                        return Err(AsmErrorKind::ValueOutOfRange(n));
+                    }
+                    bytes[0] |= (n as u8) << 3;
+                }
+            }
+        }
+
+        self.emit(&bytes)
+    }
+}
+
+fn error_at(location: &Location, kind: AsmErrorKind) -> AsmError {
+    AsmError {
+        file: location.file.clone(),
+        line: location.line,
+        kind
+    }
+}
+
+fn is_symbol_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || matches!(c, '_' | '?' | '@' | '.')
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '?' | '@' | '.')
+}
+
+// Leading symbol or number of `text` and the rest after it
+fn split_symbol(text: &str) -> (&str, &str) {
+    let end = text.find(|c: char| !is_symbol_char(c)).unwrap_or(text.len());
+    text.split_at(end)
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    for (index, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {},
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == ';' => return &text[..index],
+            None => {}
+        }
+    }
+
+    text
+}
+
+// Split on commas outside of strings and parentheses
+fn split_operands(text: &str) -> Result<Vec<&str>, AsmErrorKind> {
+    let mut operands = Vec::new();
+    if text.trim().is_empty() {
+        return Ok(operands);
+    }
+
+    let mut quote = None;
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {},
+            None => match c {
+                '\'' | '"' => quote = Some(c),
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    operands.push(text[start..index].trim());
+                    start = index + 1;
+                },
+                _ => {}
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err(AsmErrorKind::UnterminatedString);
+    }
+
+    operands.push(text[start..].trim());
+    if operands.iter().any(|operand| operand.is_empty()) {
+        return Err(AsmErrorKind::Syntax("empty operand".to_string()));
+    }
+
+    Ok(operands)
+}
+
+// Split macro arguments on commas outside of strings, parentheses and `<...>`
+fn split_macro_args(text: &str) -> Vec<&str> {
+    let mut args = Vec::new();
+    if text.trim().is_empty() {
+        return args;
+    }
+
+    let mut quote = None;
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {},
+            None => match c {
+                '\'' | '"' => quote = Some(c),
+                '(' | '<' => depth += 1,
+                ')' | '>' => depth -= 1,
+                ',' if depth == 0 => {
+                    args.push(text[start..index].trim());
+                    start = index + 1;
+                },
+                _ => {}
+            }
+        }
+    }
+
+    args.push(text[start..].trim());
+    args
+}
+
+// Replace the symbols in `names` by their values. Outside strings `&` only
+// separates a parameter from the text around it and is dropped; inside
+// strings parameters are replaced only next to a `&`. `;;` comments are not
+// copied
+fn substitute(line: &str, names: &HashMap<String, String>) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut result = String::new();
+    let mut quote = None;
+    let mut index = 0;
+
+    while index < chars.len() {
+        let c = chars[index];
+        if is_symbol_char(c) {
+            let start = index;
+            while index < chars.len() && is_symbol_char(chars[index]) {
+                index += 1;
+            }
+
+            let word: String = chars[start..index].iter().collect();
+            let before = start > 0 && chars[start - 1] == '&';
+            let after = chars.get(index) == Some(&'&');
+            match names.get(&word.to_ascii_uppercase()) {
+                Some(value) if quote.is_none() || before || after => {
+                    if quote.is_some() && before {
+                        result.pop();
+                    }
+                    result.push_str(value);
+                    if quote.is_some() && after {
+                        index += 1;
+                    }
+                },
+                _ => result.push_str(&word)
+            }
+            continue;
+        }
+
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {},
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == ';' => {
+                if chars.get(index + 1) != Some(&';') {
+                    result.extend(&chars[index..]);
+                }
+                break;
+            },
+            None => {}
+        }
+
+        if c != '&' || quote.is_some() {
+            result.push(c);
+        }
+        index += 1;
+    }
+
+    result
+}
+
+// Contents of `text` if it is a single quoted string, with doubled quotes
+// standing for one
+fn string_literal(text: &str) -> Result<Option<Vec<u8>>, AsmErrorKind> {
+    let quote = match text.chars().next() {
+        Some(c @ ('\'' | '"')) => c,
+        _ => return Ok(None)
+    };
+
+    let mut bytes = Vec::new();
+    let mut chars = text[1..].chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == quote {
+            if chars.peek() == Some(&quote) {
+                chars.next();
+            }
+            else if chars.next().is_none() {
+                return Ok(Some(bytes));
+            }
+            else {
+                return Ok(None);
+            }
+        }
+        bytes.push(c as u8);
+    }
+
+    Err(AsmErrorKind::UnterminatedString)
+}
+
+fn parse_number(text: &str, default_radix: u32) -> Result<u16, AsmErrorKind> {
+    let upper = text.to_ascii_uppercase();
+    let (digits, radix) = match upper.as_bytes()[upper.len() - 1] {
+        b'H' => (&upper[..upper.len() - 1], 16),
+        b'B' if default_radix != 16 => (&upper[..upper.len() - 1], 2),
+        b'O' | b'Q' => (&upper[..upper.len() - 1], 8),
+        b'D' if default_radix != 16 => (&upper[..upper.len() - 1], 10),
+        _ => (&upper[..], default_radix)
+    };
+
+    let val = u32::from_str_radix(digits, radix)
+        .map_err(|_| AsmErrorKind::Syntax(format!("invalid number {text}")))?;
+    if val > 0xffff {
+        return Err(AsmErrorKind::Syntax(format!("number {text} does not fit in 16 bits")));
+    }
+
+    Ok(val as u16)
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Token {
+    Number(u16),
+    Text(Vec<u8>),
+    Symbol(String),
+    Dollar,
+    Operator(&'static str),
+    Open,
+    Close
+}
+
+const WORD_OPERATORS: [&str; 15] = [
+    "MOD", "SHL", "SHR", "NOT", "AND", "OR", "XOR", "EQ", "NE", "LT", "LE", "GT", "GE", "HIGH", "LOW"
+];
+
+fn tokenize(text: &str, radix: u32) -> Result<Vec<Token>, AsmErrorKind> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        let mut len = c.len_utf8();
+        let token = match c {
+            '\'' | '"' => {
+                let end = rest[1..].find(c).ok_or(AsmErrorKind::UnterminatedString)? + 2;
+                len = end;
+                // doubled quotes continue the string
+                while rest[len..].starts_with(c) {
+                    len += 1 + rest[len + 1..].find(c).ok_or(AsmErrorKind::UnterminatedString)? + 1;
+                }
+                Token::Text(string_literal(&rest[..len])?.unwrap_or_default())
+            },
+            '$' => Token::Dollar,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '+' => Token::Operator("+"),
+            '-' => Token::Operator("-"),
+            '*' => Token::Operator("*"),
+            '/' => Token::Operator("/"),
+            '=' => Token::Operator("EQ"),
+            '<' | '>' => {
+                let (operator, two) = match (c, rest[1..].chars().next()) {
+                    ('<', Some('>')) => ("NE", true),
+                    ('<', Some('=')) => ("LE", true),
+                    ('>', Some('=')) => ("GE", true),
+                    ('<', _) => ("LT", false),
+                    _ => ("GT", false)
+                };
+                if two {
+                    len = 2;
+                }
+                Token::Operator(operator)
+            },
+            c if is_symbol_char(c) => {
+                let (word, _) = split_symbol(rest);
+                len = word.len();
+                if c.is_ascii_digit() {
+                    Token::Number(parse_number(word, radix)?)
+                }
+                else {
+                    let upper = word.to_ascii_uppercase();
+                    match WORD_OPERATORS.iter().find(|operator| **operator == upper) {
+                        Some(&operator) => Token::Operator(operator),
+                        None => Token::Symbol(upper)
+                    }
+                }
+            },
+            _ => return Err(AsmErrorKind::Syntax(format!("unexpected '{c}' in expression")))
+        };
+
+        tokens.push(token);
+        rest = rest[len..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+// Result of an expression: a number, an offset into a relocatable segment,
+// or an offset from an external symbol
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Value {
+    value: u16,
+    segment: AddressType,
+    external: Option<String>
+}
+
+impl Value {
+    fn constant(value: u16) -> Self {
+        Self { value, segment: AddressType::Absolute, external: None }
+    }
+
+    fn is_absolute(&self) -> bool {
+        self.segment == AddressType::Absolute && self.external.is_none()
+    }
+
+    fn absolute(self) -> Result<u16, AsmErrorKind> {
+        match &self.external {
+            Some(name) => Err(AsmErrorKind::Relocation(format!("external {name} not allowed here"))),
+            None if self.segment != AddressType::Absolute => {
+                Err(AsmErrorKind::Relocation("relocatable value not allowed here".to_string()))
+            },
+            None => Ok(self.value)
+        }
+    }
+
+    // Absolute or relocatable, but not external
+    fn relocatable(self) -> Result<Self, AsmErrorKind> {
+        match &self.external {
+            Some(name) => Err(AsmErrorKind::Relocation(format!("external {name} not allowed here"))),
+            None => Ok(self)
+        }
+    }
+
+    fn add(self, rhs: Value) -> Result<Self, AsmErrorKind> {
+        let (mut val, offset) = match (self.is_absolute(), rhs.is_absolute()) {
+            (_, true) => (self, rhs.value),
+            (true, false) => (rhs, self.value),
+            _ => return Err(AsmErrorKind::Relocation("relocatable values cannot be added".to_string()))
+        };
+        val.value = val.value.wrapping_add(offset);
+        Ok(val)
+    }
+
+    fn subtract(mut self, rhs: Value) -> Result<Self, AsmErrorKind> {
+        if rhs.is_absolute() {
+            self.value = self.value.wrapping_sub(rhs.value);
+            return Ok(self);
+        }
+        if self.same_segment(&rhs) {
+            return Ok(Value::constant(self.value.wrapping_sub(rhs.value)));
+        }
+        Err(AsmErrorKind::Relocation("cannot subtract a value in another segment".to_string()))
+    }
+
+    fn same_segment(&self, other: &Value) -> bool {
+        self.external.is_none() && other.external.is_none() && self.segment == other.segment
+    }
+}
+
+// Recursive descent evaluation with Intel's operator precedence, from lowest:
+// OR XOR, AND, NOT, relational, + -, * / MOD SHL SHR, unary - + HIGH LOW
+struct Expression<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    assembler: &'a Assembler
+}
+
+impl<'a> Expression<'a> {
+    fn new(text: &str, assembler: &'a Assembler) -> Result<Self, AsmErrorKind> {
+        Ok(Self {
+            tokens: tokenize(text, assembler.radix)?,
+            pos: 0,
+            assembler
+        })
+    }
+
+    fn evaluate(&mut self) -> Result<Value, AsmErrorKind> {
+        let val = self.or()?;
+        match self.tokens.get(self.pos) {
+            None => Ok(val),
+            Some(token) => Err(AsmErrorKind::Syntax(format!("unexpected {token:?} in expression")))
+        }
+    }
+
+    fn accept(&mut self, operators: &[&str]) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Operator(operator)) if operators.contains(operator) => {
+                self.pos += 1;
+                Some(operator)
+            },
+            _ => None
+        }
+    }
+
+    fn or(&mut self) -> Result<Value, AsmErrorKind> {
+        let mut val = self.and()?;
+        while let Some(operator) = self.accept(&["OR", "XOR"]) {
+            let (lhs, rhs) = (val.absolute()?, self.and()?.absolute()?);
+            val = Value::constant(if operator == "OR" { lhs | rhs } else { lhs ^ rhs });
+        }
+        Ok(val)
+    }
+
+    fn and(&mut self) -> Result<Value, AsmErrorKind> {
+        let mut val = self.not()?;
+        while self.accept(&["AND"]).is_some() {
+            val = Value::constant(val.absolute()? & self.not()?.absolute()?);
+        }
+        Ok(val)
+    }
+
+    fn not(&mut self) -> Result<Value, AsmErrorKind> {
+        if self.accept(&["NOT"]).is_some() {
+            return Ok(Value::constant(!self.not()?.absolute()?));
+        }
+        self.relational()
+    }
+
+    // Relocatable values compare within their segment
+    fn relational(&mut self) -> Result<Value, AsmErrorKind> {
+        let lhs = self.additive()?;
+        let operator = match self.accept(&["EQ", "NE", "LT", "LE", "GT", "GE"]) {
+            Some(operator) => operator,
+            None => return Ok(lhs)
+        };
+
+        let rhs = self.additive()?;
+        if !lhs.same_segment(&rhs) {
+            return Err(AsmErrorKind::Relocation("cannot compare values in different segments".to_string()));
+        }
+        let (lhs, rhs) = (lhs.value, rhs.value);
+        let result = match operator {
+            "EQ" => lhs == rhs,
+            "NE" => lhs != rhs,
+            "LT" => lhs < rhs,
+            "LE" => lhs <= rhs,
+            "GT" => lhs > rhs,
+            _ => lhs >= rhs
+        };
+        Ok(Value::constant(if result { 0xffff } else { 0 }))
+    }
+
+    fn additive(&mut self) -> Result<Value, AsmErrorKind> {
+        let mut val = self.multiplicative()?;
+        while let Some(operator) = self.accept(&["+", "-"]) {
+            let rhs = self.multiplicative()?;
+            val = if operator == "+" { val.add(rhs)? } else { val.subtract(rhs)? };
+        }
+        Ok(val)
+    }
+
+    fn multiplicative(&mut self) -> Result<Value, AsmErrorKind> {
+        let mut val = self.unary()?;
+        while let Some(operator) = self.accept(&["*", "/", "MOD", "SHL", "SHR"]) {
+            let (lhs, rhs) = (val.absolute()?, self.unary()?.absolute()?);
+            val = Value::constant(match operator {
+                "*" => lhs.wrapping_mul(rhs),
+                "/" => lhs.checked_div(rhs).ok_or(AsmErrorKind::DivisionByZero)?,
+                "MOD" => lhs.checked_rem(rhs).ok_or(AsmErrorKind::DivisionByZero)?,
+                "SHL" => lhs.checked_shl(rhs as u32).unwrap_or(0),
+                _ => lhs.checked_shr(rhs as u32).unwrap_or(0)
+            });
+        }
+        Ok(val)
+    }
+
+    fn unary(&mut self) -> Result<Value, AsmErrorKind> {
+        match self.accept(&["-", "+", "HIGH", "LOW"]) {
+            Some("-") => Ok(Value::constant(self.unary()?.absolute()?.wrapping_neg())),
+            Some("HIGH") => Ok(Value::constant(self.unary()?.absolute()? >> 8)),
+            Some("LOW") => Ok(Value::constant(self.unary()?.absolute()? & 0xff)),
+            Some(_) => self.unary(),
+            None => self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Value, AsmErrorKind> {
+        let token = self.tokens.get(self.pos).cloned()
+            .ok_or_else(|| AsmErrorKind::Syntax("missing operand".to_string()))?;
+        self.pos += 1;
+
+        match token {
+            Token::Number(val) => Ok(Value::constant(val)),
+            Token::Dollar => Ok(Value { value: self.assembler.pc, segment: self.assembler.segment, external: None }),
+            Token::Symbol(name) => match self.assembler.symbols.get(&name) {
+                Some(symbol) if symbol.kind == SymbolKind::External => {
+                    Ok(Value { value: 0, segment: AddressType::Absolute, external: Some(name) })
+                },
+                Some(symbol) => Ok(Value { value: symbol.value, segment: symbol.segment, external: None }),
+                None => Err(AsmErrorKind::UndefinedSymbol(name))
+            },
+            Token::Text(text) => match text.as_slice() {
+                [c] => Ok(Value::constant(*c as u16)),
+                [hi, lo] => Ok(Value::constant(u16::from_be_bytes([*hi, *lo]))),
+                _ => Err(AsmErrorKind::Syntax("string constant must be 1 or 2 characters".to_string()))
+            },
+            Token::Open => {
+                let val = self.or()?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Close) => {
+                        self.pos += 1;
+                        Ok(val)
+                    },
+                    _ => Err(AsmErrorKind::Syntax("missing ')'".to_string()))
+                }
+            },
+            token => Err(AsmErrorKind::Syntax(format!("unexpected {token:?} in expression")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Intel8080;
+    use crate::disasm::{disassemble, Syntax};
+    use crate::memory::Memory;
+    use crate::testing::temp_dir;
+
+    fn bytes(source: &str) -> Vec<u8> {
+        let program = assemble(source).unwrap();
+        assert_eq!(program.segments.len(), 1);
+        program.segments[0].bytes.clone()
+    }
+
+    fn value(expr: &str) -> u16 {
+        let program = assemble(&format!("X EQU {expr}")).unwrap();
+        program.symbol("X").unwrap()
+    }
+
+    fn error(source: &str) -> AsmError {
+        assemble(source).unwrap_err()
+    }
+
+    #[test]
+    fn test_instructions() {
+        assert_eq!(bytes("LXI H,1A2BH"), vec![Instruction::LXI_H as u8, 0x2b, 0x1a]);
+        assert_eq!(bytes("mvi m,0ffh"), vec![Instruction::MVI_M as u8, 0xff]);
+        assert_eq!(bytes(" MOV A, M ; load"), vec![Instruction::MOV_A_M as u8]);
+        assert_eq!(bytes("PUSH PSW\nPOP B"), vec![Instruction::PUSH_PSW as u8, Instruction::POP_B as u8]);
+        assert_eq!(bytes("RST 7\nRST 0"), vec![Instruction::RST_8 as u8, Instruction::RST_1 as u8]);
+        assert_eq!(bytes("MVI A,-1"), vec![Instruction::MVI_A as u8, 0xff]);
+        assert_eq!(bytes("DB -128,255"), vec![0x80, 0xff]);
+        assert_eq!(bytes("CPI 'A'"), vec![Instruction::CPI as u8, 0x41]);
+    }
+
+    #[test]
+    fn test_round_trips_disassembler() {
+        for opcode in 0..=0xffu8 {
+            if Instruction::from(opcode) as u8 != opcode {
+                continue;
+            }
+
+            let mut memory: Memory<0x10> = Memory::new();
+            memory.write_bytes(0, &[opcode, 0x34, 0x12]);
+            let disassembly = disassemble(&memory, 0, Syntax::Intel);
+
+            assert_eq!(bytes(&disassembly.text), disassembly.bytes(), "{}", disassembly.text);
+        }
+    }
+
+    #[test]
+    fn test_labels_and_directives() {
+        let source = "\
+COUNT   EQU     3
+        ORG     100H
+START:  LXI     H,TABLE     ; forward reference
+        MVI     B,COUNT
+LOOP    DCR     B
+        JNZ     LOOP
+        JMP     $
+TABLE:  DB      'HI',0,COUNT*2
+        DW      START,TABLE-START
+        DS      2
+TAIL:   DB      'IT''S'
+        END     START
+        DB      0FFH
+";
+        let program = assemble(source).unwrap();
+
+        assert_eq!(program.symbol("start"), Some(0x100));
+        assert_eq!(program.symbol("LOOP"), Some(0x105));
+        assert_eq!(program.symbol("TABLE"), Some(0x10c));
+        assert_eq!(program.symbol("TAIL"), Some(0x116));
+        assert_eq!(program.symbol("COUNT"), Some(3));
+        assert_eq!(program.entry, Some(0x100));
+
+        assert_eq!(program.segments, vec![
+            Segment { kind: AddressType::Absolute, address: 0x100, bytes: vec![
+                0x21, 0x0c, 0x01,
+                0x06, 0x03,
+                0x05,
+                0xc2, 0x05, 0x01,
+                0xc3, 0x09, 0x01,
+                b'H', b'I', 0x00, 0x06,
+                0x00, 0x01, 0x0c, 0x00
+            ]},
+            Segment { kind: AddressType::Absolute, address: 0x116, bytes: b"IT'S".to_vec() }
+        ]);
+    }
+
+    #[test]
+    fn test_expressions() {
+        assert_eq!(value("2+3*4"), 14);
+        assert_eq!(value("(2+3)*4"), 20);
+        assert_eq!(value("17 MOD 5 + 1 SHL 4"), 18);
+        assert_eq!(value("HIGH 1234H + LOW 1234H"), 0x46);
+        assert_eq!(value("NOT 0 AND 0F0H OR 1"), 0xf1);
+        assert_eq!(value("101B XOR 17Q XOR 10D"), 0b101 ^ 0o17 ^ 10);
+        assert_eq!(value("'AB'"), 0x4142);
+        assert_eq!(value("-1"), 0xffff);
+        assert_eq!(value("3 GT 2"), 0xffff);
+        assert_eq!(value("3 <> 3"), 0);
+        assert_eq!(value("2 LE 2 AND 1 EQ 1"), 0xffff);
+        assert_eq!(value("0FFFFH/10H"), 0xfff);
+        assert_eq!(value("$+2"), 2);
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let source = "\
+DEBUG   EQU     0
+LEVEL   SET     1
+        IF      DEBUG
+        DB      1
+        IF      LEVEL
+        DB      2
+        ENDIF
+        ELSE
+        DB      3
+        IF      LEVEL GT 1
+        DB      4
+        ELSE
+        DB      5
+        ENDIF
+        ENDIF
+LEVEL   SET     LEVEL+1
+        IF      LEVEL EQ 2
+        DB      LEVEL
+        ENDIF
+";
+        assert_eq!(bytes(source), vec![3, 5, 2]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error("NOP\nMOV A,Q\n"), AsmError { file: None, line: 2, kind: AsmErrorKind::InvalidOperands("MOV".to_string()) });
+        assert_eq!(error(" JMP NOWHERE").kind, AsmErrorKind::UndefinedSymbol("NOWHERE".to_string()));
+        assert_eq!(error("A1: NOP\nA1: NOP").line, 2);
+        assert_eq!(error("A1: NOP\nA1: NOP").kind, AsmErrorKind::DuplicateSymbol("A1".to_string()));
+        assert_eq!(error("X EQU 1\nX SET 2").kind, AsmErrorKind::DuplicateSymbol("X".to_string()));
+        assert_eq!(error(" FOO A").kind, AsmErrorKind::UnknownMnemonic("FOO".to_string()));
+        assert_eq!(error(" MVI A,100H").kind, AsmErrorKind::ValueOutOfRange(0x100));
+        assert_eq!(error(" RST 8").kind, AsmErrorKind::ValueOutOfRange(8));
+        assert_eq!(error(" DB -129").kind, AsmErrorKind::ValueOutOfRange(0xff7f));
+        assert_eq!(error(" MVI A,-200").kind, AsmErrorKind::ValueOutOfRange(0xff38));
+        assert_eq!(error(" ORG 0FFFEH\n DW 1\n NOP").kind, AsmErrorKind::LocationOverflow);
+        assert_eq!(error(" ORG 0FFFEH\n LXI H,0").line, 2);
+        assert_eq!(error(" ORG 0FFF0H\n DS 20H").kind, AsmErrorKind::LocationOverflow);
+        // code may run right up to the top of memory
+        assert_eq!(assemble(" ORG 0FFFEH\n DW 1234H").unwrap().segments[0].bytes, vec![0x34, 0x12]);
+        assert_eq!(error(" DB 1/0").kind, AsmErrorKind::DivisionByZero);
+        assert_eq!(error(" DB 'abc").kind, AsmErrorKind::UnterminatedString);
+        assert_eq!(error(" NOP\n IF 1\n NOP").kind, AsmErrorKind::MissingEndif);
+        assert_eq!(error(" NOP\n IF 1\n NOP").line, 2);
+        assert_eq!(error(" ENDIF").kind, AsmErrorKind::EndifWithoutIf);
+        assert_eq!(error(" +5").kind, AsmErrorKind::Syntax("unexpected '+5'".to_string()));
+        assert_eq!(error(" DS LATER\nLATER EQU 1").kind, AsmErrorKind::UndefinedSymbol("LATER".to_string()));
+
+        assert_eq!(error("\n\n LDA").to_string(), "line 3: invalid operands for LDA");
+    }
+
+    #[test]
+    fn test_runs_on_cpu() {
+        // sum 1..10 into A
+        let source = "\
+        ORG     0
+        MVI     B,10
+        XRA     A
+NEXT:   ADD     B
+        DCR     B
+        JNZ     NEXT
+        STA     RESULT
+        HLT
+RESULT: DS      1
+";
+        let program = assemble(source).unwrap();
+        let mut memory: Memory<0x100> = Memory::new();
+        program.load_into(&mut memory);
+
+        let mut cpu = Intel8080::new();
+        while !cpu.halted() {
+            cpu.step(&mut memory, &mut ());
+        }
+
+        assert_eq!(memory.read_byte(program.symbol("RESULT").unwrap()), 55);
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = "\
+DELAY   MACRO   COUNT
+        LOCAL   LOOP
+        MVI     B,COUNT
+LOOP:   DCR     B
+        JNZ     LOOP
+        ENDM
+
+LOAD    MACRO   REG,VAL
+        MVI     REG,VAL     ;; not copied
+NAME&REG: DB    '&REG',VAL+1
+        ENDM
+
+        DELAY   3
+        DELAY   <2+3>
+        LOAD    A,10H
+";
+        let program = assemble(source).unwrap();
+
+        assert_eq!(program.segments[0].bytes, vec![
+            0x06, 0x03, 0x05, 0xc2, 0x02, 0x00,
+            0x06, 0x05, 0x05, 0xc2, 0x08, 0x00,
+            0x3e, 0x10, b'A', 0x11
+        ]);
+        assert_eq!(program.symbol("??0001"), Some(0x02));
+        assert_eq!(program.symbol("??0002"), Some(0x08));
+        assert_eq!(program.symbol("NAMEA"), Some(0x0e));
+    }
+
+    #[test]
+    fn test_recursive_macro_with_exitm() {
+        let source = "\
+COUNTDOWN MACRO N
+        IF      N EQ 0
+        EXITM
+        ENDIF
+        DB      N
+        COUNTDOWN %N-1
+        ENDM
+        COUNTDOWN 3
+        DB      0FFH
+";
+        assert_eq!(bytes(source), vec![3, 2, 1, 0xff]);
+    }
+
+    #[test]
+    fn test_repetition() {
+        let source = "\
+        REPT    3
+        DB      $
+        ENDM
+        IRP     R,<B,C,D>
+        INR     R
+        ENDM
+        IRPC    C,XY
+        DB      '&C'
+        ENDM
+        IRPC    N,123
+        REPT    N
+        DB      N
+        ENDM
+        ENDM
+";
+        assert_eq!(bytes(source), vec![
+            0, 1, 2,
+            Instruction::INR_B as u8, Instruction::INR_C as u8, Instruction::INR_D as u8,
+            b'X', b'Y',
+            1, 2, 2, 3, 3, 3
+        ]);
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = temp_dir("asm", "include");
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("main.asm"), "        INCLUDE DEFS.INC\n        PUT2    VALUE\n").unwrap();
+        fs::write(dir.join("DEFS.INC"), "VALUE   EQU     42\n        INCLUDE 'MACROS.LIB'\n").unwrap();
+        fs::write(dir.join("lib").join("MACROS.LIB"), "PUT2    MACRO   X\n        DB      X,X\n        ENDM\n").unwrap();
+        fs::write(dir.join("bad.asm"), "        NOP\n        INCLUDE BROKEN.INC\n").unwrap();
+        fs::write(dir.join("BROKEN.INC"), "        NOP\n        MOV     A,Z\n").unwrap();
+
+        let mut assembler = Assembler::new();
+        assembler.add_include_dir(dir.join("lib"));
+        let program = assembler.assemble_file(dir.join("main.asm")).unwrap();
+        assert_eq!(program.segments[0].bytes, vec![42, 42]);
+
+        let err = assemble_file(dir.join("bad.asm")).unwrap_err();
+        assert_eq!(err.file, Some(dir.join("BROKEN.INC")));
+        assert_eq!(err.line, 2);
+
+        let err = assemble_file(dir.join("main.asm")).unwrap_err();
+        assert_eq!(err.kind, AsmErrorKind::Include("MACROS.LIB: file not found".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_relocatable_modules() {
+        let main = assemble("\
+        EXTRN   PRINT
+        PUBLIC  START,COUNT
+        CSEG
+START:  LXI     SP,STACK+10H
+        LXI     H,MSG
+        CALL    PRINT
+        CALL    PRINT
+        LXI     B,PRINT+2
+        LDA     COUNT
+        HLT
+        DSEG
+COUNT:  DB      0
+MSG:    DB      'HI',0
+STACK:  DS      10H
+        END     START
+").unwrap();
+        let print = assemble("\
+        PUBLIC  PRINT
+        EXTRN   COUNT
+        CSEG
+PRINT:  LDA     COUNT
+        INR     A
+        STA     COUNT
+        RET
+        END
+").unwrap();
+
+        assert_eq!((main.program_size, main.data_size), (19, 0x14));
+        assert_eq!(main.symbol("MSG"), Some(1));
+        assert_eq!(main.symbol("PRINT"), None);
+        assert_eq!(main.publics.get("COUNT"), Some(&Address::data(0)));
+        assert_eq!((main.entry, main.entry_segment), (Some(0), AddressType::Program));
+        assert_eq!(main.relocations[0], Relocation {
+            location: Address::program(1),
+            target: RelocationTarget::Segment(AddressType::Data)
+        });
+        assert_eq!(main.relocations[2], Relocation {
+            location: Address::program(7),
+            target: RelocationTarget::External("PRINT".to_string())
+        });
+        let items = main.rel_items("main");
+        assert_eq!(crate::rel::read_rel(&main.to_rel("main")).unwrap(), items);
+        assert!(items.contains(&RelItem::ChainExternal(Address::program(13), "PRINT".to_string())));
+
+        let mut linker = crate::link::Linker::new();
+        linker.add_rel(&main.to_rel("MAIN")).unwrap();
+        linker.add_rel(&print.to_rel("PRINT")).unwrap();
+        let image = linker.link().unwrap();
+        assert_eq!(image.entry, Some(0x100));
+        assert_eq!(image.symbols.get("PRINT"), Some(&0x113));
+        assert_eq!(image.symbols.get("COUNT"), Some(&0x11b));
+
+        let mut memory: Memory<0x200> = Memory::new();
+        image.load_into(&mut memory);
+        let mut cpu = Intel8080::new();
+        cpu.registers_mut().set_pc(image.entry.unwrap());
+        while !cpu.halted() {
+            cpu.step(&mut memory, &mut ());
+        }
+        assert_eq!(cpu.registers().accumulator(), 2);
+        assert_eq!(cpu.registers().pair_b(), 0x115);
+        assert_eq!(cpu.registers().pair_h(), 0x11c);
+        assert_eq!(cpu.registers().sp(), 0x12f);
+    }
+
+    #[test]
+    fn test_relocation_errors() {
+        let relocation = |source: &str| match error(source).kind {
+            AsmErrorKind::Relocation(message) => message,
+            kind => panic!("{kind:?}")
+        };
+
+        assert_eq!(relocation(" CSEG\nL: MVI A,L"), "relocatable value not allowed here");
+        assert_eq!(relocation(" CSEG\nL: DW L+L"), "relocatable values cannot be added");
+        assert_eq!(relocation(" CSEG\nL: DSEG\nM: DW M-L"), "cannot subtract a value in another segment");
+        assert_eq!(relocation(" EXTRN X\n DB X"), "external X not allowed here");
+        assert_eq!(relocation(" EXTRN X\n PUBLIC X"), "external X cannot be PUBLIC");
+        assert_eq!(error(" PUBLIC Y").kind, AsmErrorKind::UndefinedSymbol("Y".to_string()));
+
+        // the difference of two labels in a segment is absolute
+        let program = assemble(" CSEG\nL: DS 3\nM: DB M-L\n ASEG\n ORG 10H\nA: DW M").unwrap();
+        assert_eq!(program.segments[0].bytes, vec![3]);
+        assert_eq!(program.segments[1], Segment { kind: AddressType::Absolute, address: 0x10, bytes: vec![3, 0] });
+        assert_eq!(program.relocations, vec![Relocation {
+            location: Address::absolute(0x10),
+            target: RelocationTarget::Segment(AddressType::Program)
+        }]);
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        assert_eq!(error(" NOP\nM MACRO\n NOP\n").kind, AsmErrorKind::MissingEndm);
+        assert_eq!(error(" NOP\nM MACRO\n NOP\n").line, 2);
+        assert_eq!(error(" ENDM").kind, AsmErrorKind::EndmWithoutMacro);
+        assert_eq!(error(" EXITM").kind, AsmErrorKind::Syntax("EXITM outside a macro".to_string()));
+        assert_eq!(error("LOOP MACRO\n LOOP\n ENDM\n LOOP").kind, AsmErrorKind::NestingTooDeep);
+
+        // errors inside an expansion point at the invocation
+        let err = error("BAD MACRO\n MOV A,Q\n ENDM\n NOP\n BAD");
+        assert_eq!((err.line, err.kind), (5, AsmErrorKind::InvalidOperands("MOV".to_string())));
+    }
+}
@@ -0,0 +1,913 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::console::{Console, StdConsole, EOF};
+use crate::cpm::{ret, write};
+use crate::cpu::Intel8080;
+use crate::io::IoBus;
+use crate::memory::{Memory, MemoryAccess};
+
+// Host side CP/M 2.2 BDOS for running .COM programs. Calls to 0x0005 are
+// trapped before they execute and answered from Rust, and the files a program
+// opens live in one host directory whatever drive it names.
+//
+// Memory map:
+//   0000  JMP to the BIOS warm boot entry, trapped to end the program
+//   0005  JMP to the BDOS, trapped
+//   005C  default FCB, from the first argument
+//   006C  second FCB, from the second argument
+//   0080  command tail, and the default DMA buffer
+//   0100  TPA, where the program is loaded
+//   FE06  BDOS entry, the top of the TPA
+//   FF00  BIOS jump table; CONST, CONIN and CONOUT are trapped too
+
+pub const BDOS_ENTRY: u16 = 0x0005;
+pub const DEFAULT_FCB: u16 = 0x005c;
+pub const DEFAULT_DMA: u16 = 0x0080;
+pub const TPA: u16 = 0x0100;
+pub const BDOS_BASE: u16 = 0xfe06;
+pub const BIOS_BASE: u16 = 0xff00;
+
+const BIOS_ENTRIES: u16 = 17;
+const RECORD_LEN: usize = 128;
+const RECORDS_PER_EXTENT: usize = 128;
+const FCB_LEN: usize = 36;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum BdosError {
+    Io(String),
+    // program does not fit between the TPA and the BDOS
+    ProgramTooLarge(usize)
+}
+
+impl fmt::Display for BdosError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BdosError::Io(message) => write!(f, "{message}"),
+            BdosError::ProgramTooLarge(len) => write!(f, "program of {len} bytes does not fit in the TPA")
+        }
+    }
+}
+
+impl std::error::Error for BdosError {}
+
+// Why a program stopped
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Exit {
+    // jump to 0x0000 or the BIOS warm boot entry, or a return from the program
+    WarmBoot,
+    // BDOS function 0
+    SystemReset,
+    // HLT with interrupts disabled
+    Halted
+}
+
+pub struct Bdos<C: Console> {
+    directory: PathBuf,
+    console: C,
+    dma: u16,
+    drive: u8,
+    user: u8,
+    // host names left for search next, last first
+    search: Vec<PathBuf>,
+    exit: Option<Exit>
+}
+
+impl<C: Console> Bdos<C> {
+    pub fn new(directory: impl Into<PathBuf>, console: C) -> Self {
+        Self {
+            directory: directory.into(),
+            console,
+            dma: DEFAULT_DMA,
+            drive: 0,
+            user: 0,
+            search: Vec::new(),
+            exit: None
+        }
+    }
+
+    pub fn console(&self) -> &C {
+        &self.console
+    }
+
+    pub fn console_mut(&mut self) -> &mut C {
+        &mut self.console
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    // Load a .COM image at the TPA and boot it with `args` as its command line
+    pub fn load_com(&mut self,
+        cpu: &mut Intel8080,
+        memory: &mut impl MemoryAccess,
+        program: &[u8],
+        args: &str) -> Result<(), BdosError> {
+
+        if program.len() > (BDOS_BASE & 0xff00) as usize - TPA as usize {
+            return Err(BdosError::ProgramTooLarge(program.len()));
+        }
+
+        for (offset, byte) in program.iter().enumerate() {
+            memory.write_byte(TPA + offset as u16, *byte);
+        }
+        self.boot(cpu, memory, args);
+        Ok(())
+    }
+
+    // Set up the zero page, FCBs and command tail the way the CCP leaves them
+    // and point the CPU at the TPA, with a return to 0x0000 on the stack
+    pub fn boot(&mut self, cpu: &mut Intel8080, memory: &mut impl MemoryAccess, args: &str) {
+        let [wboot_low, wboot_high] = (BIOS_BASE + 3).to_le_bytes();
+        let [bdos_low, bdos_high] = BDOS_BASE.to_le_bytes();
+        write(memory, 0x0000, &[0xc3, wboot_low, wboot_high, 0x00, 0x00, 0xc3, bdos_low, bdos_high]);
+
+        let args = args.trim().to_ascii_uppercase();
+        let mut words = args.split_whitespace();
+        write(memory, DEFAULT_FCB, &parse_fcb(words.next().unwrap_or("")));
+        write(memory, DEFAULT_FCB + 16, &parse_fcb(words.next().unwrap_or("")));
+        write(memory, DEFAULT_FCB + 32, &[0; 4]);
+
+        let mut tail = Vec::new();
+        if !args.is_empty() {
+            tail.push(b' ');
+            tail.extend_from_slice(args.as_bytes());
+        }
+        tail.truncate(RECORD_LEN - 2);
+        memory.write_byte(DEFAULT_DMA, tail.len() as u8);
+        write(memory, DEFAULT_DMA + 1, &tail);
+        memory.write_byte(DEFAULT_DMA + 1 + tail.len() as u16, 0);
+
+        // never executed, but a RET keeps a stray jump from running off
+        memory.write_byte(BDOS_BASE, 0xc9);
+        for entry in 0..BIOS_ENTRIES {
+            memory.write_byte(BIOS_BASE + entry * 3, 0xc9);
+        }
+
+        let sp = (BDOS_BASE & 0xff00) - 2;
+        write(memory, sp, &[0x00, 0x00]);
+        let registers = cpu.registers_mut();
+        registers.set_sp(sp);
+        registers.set_pc(TPA);
+
+        self.dma = DEFAULT_DMA;
+        self.drive = 0;
+        self.user = 0;
+        self.search.clear();
+        self.exit = None;
+    }
+
+    // Run until the program exits
+    pub fn run(&mut self, cpu: &mut Intel8080, memory: &mut impl MemoryAccess, io: &mut impl IoBus) -> Exit {
+        loop {
+            if let Some(exit) = self.trap(cpu, memory) {
+                return exit;
+            }
+            if cpu.halted() && !cpu.interrupts_enabled() {
+                return Exit::Halted;
+            }
+            cpu.step(memory, io);
+        }
+    }
+
+    // Answer a call to the BDOS or the trapped BIOS entries if PC is on one
+    // and return to the caller. Returns the reason if the program has ended
+    pub fn trap(&mut self, cpu: &mut Intel8080, memory: &mut impl MemoryAccess) -> Option<Exit> {
+        let pc = cpu.registers().pc();
+        match pc {
+            0x0000 => return Some(Exit::WarmBoot),
+            BDOS_ENTRY | BDOS_BASE => {
+                let result = self.call(cpu, memory);
+                if let Some(exit) = self.exit {
+                    return Some(exit);
+                }
+                set_result(cpu, result);
+            },
+            _ if pc == BIOS_BASE + 3 => return Some(Exit::WarmBoot),
+            _ if pc == BIOS_BASE + 6 => {
+                let status = if self.console.status() { 0xff } else { 0x00 };
+                cpu.registers_mut().set_accumulator(status);
+            },
+            _ if pc == BIOS_BASE + 9 => {
+                let byte = self.console_in();
+                cpu.registers_mut().set_accumulator(byte);
+            },
+            _ if pc == BIOS_BASE + 12 => {
+                let byte = cpu.registers().c();
+                self.console.write(byte);
+            },
+            _ => return None
+        }
+
+        ret(cpu, memory);
+        None
+    }
+
+    fn call(&mut self, cpu: &mut Intel8080, memory: &mut impl MemoryAccess) -> u16 {
+        let function = cpu.registers().c();
+        let de = cpu.registers().pair_d();
+        let e = cpu.registers().e();
+
+        match function {
+            0 => {
+                self.exit = Some(Exit::SystemReset);
+                0
+            },
+            1 => {
+                let byte = self.console_in();
+                if byte >= b' ' || matches!(byte, b'\r' | b'\n' | b'\t' | 0x08) {
+                    self.console.write(byte);
+                }
+                byte as u16
+            },
+            2 => {
+                self.console.write(e);
+                0
+            },
+            // reader
+            3 => EOF as u16,
+            // punch and list
+            4 | 5 => 0,
+            6 => match e {
+                0xff if self.console.status() => self.console_in() as u16,
+                0xff => 0,
+                0xfe => if self.console.status() { 0xff } else { 0x00 },
+                _ => {
+                    self.console.write(e);
+                    0
+                }
+            },
+            7 => memory.read_byte(0x0003) as u16,
+            8 => {
+                memory.write_byte(0x0003, e);
+                0
+            },
+            9 => {
+                let mut addr = de;
+                loop {
+                    let byte = memory.read_byte(addr);
+                    if byte == b'$' {
+                        break;
+                    }
+                    self.console.write(byte);
+                    addr = addr.wrapping_add(1);
+                    if addr == de {
+                        break;
+                    }
+                }
+                0
+            },
+            10 => {
+                self.read_buffer(memory, de);
+                0
+            },
+            11 => if self.console.status() { 0xff } else { 0x00 },
+            // CP/M 2.2
+            12 => 0x0022,
+            13 => {
+                self.dma = DEFAULT_DMA;
+                self.drive = 0;
+                0
+            },
+            14 => {
+                self.drive = e & 0x0f;
+                0
+            },
+            15 => self.open(memory, de),
+            16 => if self.find(&read_fcb(memory, de)).is_some() { 0 } else { 0xff },
+            17 => self.search_first(memory, de),
+            18 => self.search_next(memory),
+            19 => self.delete(memory, de),
+            20 => self.read_sequential(memory, de),
+            21 => self.write_sequential(memory, de),
+            22 => self.make(memory, de),
+            23 => self.rename(memory, de),
+            24 => 1 << self.drive,
+            25 => self.drive as u16,
+            26 => {
+                self.dma = de;
+                0
+            },
+            // write protect disk, set file attributes
+            28 | 30 => 0,
+            // read only vector
+            29 => 0,
+            32 => {
+                if e == 0xff {
+                    self.user as u16
+                }
+                else {
+                    self.user = e & 0x1f;
+                    0
+                }
+            },
+            33 => self.read_random(memory, de),
+            34 | 40 => self.write_random(memory, de),
+            35 => self.compute_file_size(memory, de),
+            36 => {
+                let mut fcb = read_fcb(memory, de);
+                let [r0, r1, r2, _] = (fcb_record(&fcb) as u32).to_le_bytes();
+                fcb[33..36].copy_from_slice(&[r0, r1, r2]);
+                write(memory, de, &fcb);
+                0
+            },
+            _ => {
+                warn!("unsupported BDOS function {function}");
+                0
+            }
+        }
+    }
+
+    fn console_in(&mut self) -> u8 {
+        self.console.read().unwrap_or(EOF)
+    }
+
+    // Function 10: line input into the buffer at `addr`, whose first byte is
+    // its size. The count read goes into the second byte
+    fn read_buffer(&mut self, memory: &mut impl MemoryAccess, addr: u16) {
+        let max = memory.read_byte(addr);
+        let mut count = 0;
+
+        while count < max {
+            let Some(byte) = self.console.read() else {
+                break;
+            };
+
+            match byte {
+                b'\r' | b'\n' => break,
+                0x08 | 0x7f => {
+                    if count > 0 {
+                        count -= 1;
+                        for echo in [0x08, b' ', 0x08] {
+                            self.console.write(echo);
+                        }
+                    }
+                },
+                _ => {
+                    memory.write_byte(addr.wrapping_add(2 + count as u16), byte);
+                    self.console.write(byte);
+                    count += 1;
+                }
+            }
+        }
+
+        memory.write_byte(addr.wrapping_add(1), count);
+        self.console.write(b'\r');
+    }
+
+    // Host files whose CP/M names match the FCB, in name order
+    fn matching(&self, pattern: &[u8; 11]) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(&self.directory) else {
+            return Vec::new();
+        };
+
+        let mut files: Vec<([u8; 11], PathBuf)> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_file()))
+            .filter_map(|entry| {
+                let name = cpm_name(entry.file_name().to_str()?)?;
+                Some((name, entry.path()))
+            })
+            .filter(|(name, _)| pattern.iter().zip(name).all(|(p, n)| *p == b'?' || p == n))
+            .collect();
+
+        files.sort();
+        files.into_iter().map(|(_, path)| path).collect()
+    }
+
+    fn find(&self, fcb: &[u8; FCB_LEN]) -> Option<PathBuf> {
+        self.matching(&fcb_name(fcb)).into_iter().next()
+    }
+
+    fn open(&mut self, memory: &mut impl MemoryAccess, addr: u16) -> u16 {
+        let mut fcb = read_fcb(memory, addr);
+        let Some(path) = self.find(&fcb) else {
+            return 0xff;
+        };
+
+        let records = file_records(&path);
+        let record = fcb_record(&fcb) - fcb[32] as usize;
+        if record > 0 && record >= records {
+            return 0xff;
+        }
+
+        fcb[15] = extent_records(records, record);
+        write(memory, addr, &fcb);
+        0
+    }
+
+    fn make(&mut self, memory: &mut impl MemoryAccess, addr: u16) -> u16 {
+        let mut fcb = read_fcb(memory, addr);
+        let name = fcb_name(&fcb);
+        if name.contains(&b'?') {
+            return 0xff;
+        }
+
+        // an existing file is left alone, there is only one entry per name
+        if self.find(&fcb).is_some() {
+            return 0xff;
+        }
+        let path = self.directory.join(host_name(&name));
+        if OpenOptions::new().write(true).create_new(true).open(path).is_err() {
+            return 0xff;
+        }
+
+        fcb[15] = 0;
+        write(memory, addr, &fcb);
+        0
+    }
+
+    fn delete(&mut self, memory: &mut impl MemoryAccess, addr: u16) -> u16 {
+        let fcb = read_fcb(memory, addr);
+        let mut deleted = false;
+        for path in self.matching(&fcb_name(&fcb)) {
+            deleted |= fs::remove_file(path).is_ok();
+        }
+
+        if deleted { 0 } else { 0xff }
+    }
+
+    fn rename(&mut self, memory: &mut impl MemoryAccess, addr: u16) -> u16 {
+        let fcb = read_fcb(memory, addr);
+        let Some(path) = self.find(&fcb) else {
+            return 0xff;
+        };
+
+        let mut new_name = [0; 11];
+        new_name.copy_from_slice(&fcb[17..28]);
+        for byte in new_name.iter_mut() {
+            *byte = (*byte & 0x7f).to_ascii_uppercase();
+        }
+        if fs::rename(path, self.directory.join(host_name(&new_name))).is_err() {
+            return 0xff;
+        }
+
+        0
+    }
+
+    fn search_first(&mut self, memory: &mut impl MemoryAccess, addr: u16) -> u16 {
+        let fcb = read_fcb(memory, addr);
+        let pattern = if fcb[0] == b'?' { [b'?'; 11] } else { fcb_name(&fcb) };
+
+        self.search = self.matching(&pattern);
+        self.search.reverse();
+        self.search_next(memory)
+    }
+
+    // Directory entries go into the first slot of the DMA buffer, one per file
+    fn search_next(&mut self, memory: &mut impl MemoryAccess) -> u16 {
+        let Some(path) = self.search.pop() else {
+            return 0xff;
+        };
+        let Some(name) = path.file_name().and_then(|name| name.to_str()).and_then(cpm_name) else {
+            return 0xff;
+        };
+
+        let records = file_records(&path);
+        let extent = records.saturating_sub(1) / RECORDS_PER_EXTENT;
+        let mut entry = [0xe5; RECORD_LEN];
+        entry[..32].fill(0);
+        entry[0] = self.user;
+        entry[1..12].copy_from_slice(&name);
+        entry[12] = (extent % 32) as u8;
+        entry[14] = (extent / 32) as u8;
+        entry[15] = extent_records(records, extent * RECORDS_PER_EXTENT);
+        write(memory, self.dma, &entry);
+        0
+    }
+
+    fn read_sequential(&mut self, memory: &mut impl MemoryAccess, addr: u16) -> u16 {
+        let mut fcb = read_fcb(memory, addr);
+        let Some(path) = self.find(&fcb) else {
+            return 1;
+        };
+
+        let record = fcb_record(&fcb);
+        match read_record(&path, record) {
+            Ok(Some(data)) => {
+                write(memory, self.dma, &data);
+                set_fcb_record(&mut fcb, record + 1, file_records(&path));
+                write(memory, addr, &fcb);
+                0
+            },
+            _ => 1
+        }
+    }
+
+    fn write_sequential(&mut self, memory: &mut impl MemoryAccess, addr: u16) -> u16 {
+        let mut fcb = read_fcb(memory, addr);
+        let Some(path) = self.find(&fcb) else {
+            return 2;
+        };
+
+        let record = fcb_record(&fcb);
+        if write_record(&path, record, &self.dma_record(memory)).is_err() {
+            return 2;
+        }
+        set_fcb_record(&mut fcb, record + 1, file_records(&path));
+        write(memory, addr, &fcb);
+        0
+    }
+
+    fn read_random(&mut self, memory: &mut impl MemoryAccess, addr: u16) -> u16 {
+        let mut fcb = read_fcb(memory, addr);
+        if fcb[35] != 0 {
+            return 6;
+        }
+        let Some(path) = self.find(&fcb) else {
+            return 4;
+        };
+
+        let record = u16::from_le_bytes([fcb[33], fcb[34]]) as usize;
+        match read_record(&path, record) {
+            Ok(Some(data)) => {
+                write(memory, self.dma, &data);
+                set_fcb_record(&mut fcb, record, file_records(&path));
+                write(memory, addr, &fcb);
+                0
+            },
+            _ => 1
+        }
+    }
+
+    fn write_random(&mut self, memory: &mut impl MemoryAccess, addr: u16) -> u16 {
+        let mut fcb = read_fcb(memory, addr);
+        if fcb[35] != 0 {
+            return 6;
+        }
+        let Some(path) = self.find(&fcb) else {
+            return 5;
+        };
+
+        let record = u16::from_le_bytes([fcb[33], fcb[34]]) as usize;
+        if write_record(&path, record, &self.dma_record(memory)).is_err() {
+            return 2;
+        }
+        set_fcb_record(&mut fcb, record, file_records(&path));
+        write(memory, addr, &fcb);
+        0
+    }
+
+    fn compute_file_size(&mut self, memory: &mut impl MemoryAccess, addr: u16) -> u16 {
+        let mut fcb = read_fcb(memory, addr);
+        let Some(path) = self.find(&fcb) else {
+            return 0xff;
+        };
+
+        let [r0, r1, r2, _] = (file_records(&path) as u32).to_le_bytes();
+        fcb[33..36].copy_from_slice(&[r0, r1, r2]);
+        write(memory, addr, &fcb);
+        0
+    }
+
+    fn dma_record(&self, memory: &impl MemoryAccess) -> [u8; RECORD_LEN] {
+        let mut data = [0; RECORD_LEN];
+        for (offset, byte) in data.iter_mut().enumerate() {
+            *byte = memory.read_byte(self.dma.wrapping_add(offset as u16));
+        }
+        data
+    }
+}
+
+// Run a .COM file on the host console, with its directory as the disk
+pub fn run_com(path: impl AsRef<Path>, args: &str) -> Result<Exit, BdosError> {
+    let path = path.as_ref();
+    let program = fs::read(path).map_err(|err| BdosError::Io(format!("{}: {err}", path.display())))?;
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new(".")
+    };
+
+    let mut bdos = Bdos::new(directory, StdConsole::new());
+    let mut cpu = Intel8080::new();
+    let mut memory: Memory<0x10000> = Memory::new();
+    bdos.load_com(&mut cpu, &mut memory, &program, args)?;
+
+    let exit = bdos.run(&mut cpu, &mut memory, &mut ());
+    let _ = io::stdout().flush();
+    Ok(exit)
+}
+
+// BDOS results go in HL, with A = L and B = H
+fn set_result(cpu: &mut Intel8080, result: u16) {
+    let registers = cpu.registers_mut();
+    registers.set_pair_h(result);
+    registers.set_accumulator(result as u8);
+    registers.set_b((result >> 8) as u8);
+}
+
+fn read_fcb(memory: &impl MemoryAccess, addr: u16) -> [u8; FCB_LEN] {
+    let mut fcb = [0; FCB_LEN];
+    for (offset, byte) in fcb.iter_mut().enumerate() {
+        *byte = memory.read_byte(addr.wrapping_add(offset as u16));
+    }
+    fcb
+}
+
+// Drive, name and type of a command line argument, as the CCP parses it
+fn parse_fcb(word: &str) -> [u8; 16] {
+    let mut fcb = [0; 16];
+    fcb[1..12].fill(b' ');
+
+    let mut word = word.as_bytes();
+    if word.len() >= 2 && word[1] == b':' {
+        fcb[0] = word[0].to_ascii_uppercase().wrapping_sub(b'A') + 1;
+        word = &word[2..];
+    }
+
+    let (name, ext) = match word.iter().position(|byte| *byte == b'.') {
+        Some(dot) => (&word[..dot], &word[dot + 1..]),
+        None => (word, &word[word.len()..])
+    };
+    let (name_field, ext_field) = fcb[1..12].split_at_mut(8);
+    for (field, text) in [(name_field, name), (ext_field, ext)] {
+        for (i, byte) in text.iter().take(field.len()).enumerate() {
+            if *byte == b'*' {
+                field[i..].fill(b'?');
+                break;
+            }
+            field[i] = byte.to_ascii_uppercase();
+        }
+    }
+
+    fcb
+}
+
+// Name and type with the attribute bits cleared
+fn fcb_name(fcb: &[u8; FCB_LEN]) -> [u8; 11] {
+    let mut name = [0; 11];
+    for (byte, fcb_byte) in name.iter_mut().zip(&fcb[1..12]) {
+        *byte = (fcb_byte & 0x7f).to_ascii_uppercase();
+    }
+    name
+}
+
+// CP/M name of a host file, None if it has no 8.3 equivalent
+fn cpm_name(host: &str) -> Option<[u8; 11]> {
+    let (name, ext) = host.split_once('.').unwrap_or((host, ""));
+    let valid = |part: &str, max| {
+        part.len() <= max && part.bytes().all(|byte| byte.is_ascii_graphic() && !b"<>.,;:=?*[]".contains(&byte))
+    };
+    if name.is_empty() || !valid(name, 8) || !valid(ext, 3) {
+        return None;
+    }
+
+    let mut cpm = [b' '; 11];
+    for (byte, host_byte) in cpm.iter_mut().zip(name.bytes()) {
+        *byte = host_byte.to_ascii_uppercase();
+    }
+    for (byte, host_byte) in cpm[8..].iter_mut().zip(ext.bytes()) {
+        *byte = host_byte.to_ascii_uppercase();
+    }
+    Some(cpm)
+}
+
+fn host_name(name: &[u8; 11]) -> String {
+    let base = String::from_utf8_lossy(&name[..8]).trim_end().to_string();
+    let ext = String::from_utf8_lossy(&name[8..]).trim_end().to_string();
+    if ext.is_empty() { base } else { format!("{base}.{ext}") }
+}
+
+// Sequential position from the current record, extent and S2 fields
+fn fcb_record(fcb: &[u8; FCB_LEN]) -> usize {
+    let extent = (fcb[14] as usize & 0x3f) * 32 + (fcb[12] as usize & 0x1f);
+    extent * RECORDS_PER_EXTENT + fcb[32] as usize
+}
+
+fn set_fcb_record(fcb: &mut [u8; FCB_LEN], record: usize, records: usize) {
+    let extent = record / RECORDS_PER_EXTENT;
+    fcb[32] = (record % RECORDS_PER_EXTENT) as u8;
+    fcb[12] = (extent % 32) as u8;
+    fcb[14] = (extent / 32) as u8;
+    fcb[15] = extent_records(records, extent * RECORDS_PER_EXTENT);
+}
+
+// Record count of the extent starting at `first`
+fn extent_records(records: usize, first: usize) -> u8 {
+    records.saturating_sub(first).min(RECORDS_PER_EXTENT) as u8
+}
+
+fn file_records(path: &Path) -> usize {
+    let len = fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0) as usize;
+    len.div_ceil(RECORD_LEN)
+}
+
+// A record padded with ^Z, None past the end of the file
+fn read_record(path: &Path, record: usize) -> io::Result<Option<[u8; RECORD_LEN]>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start((record * RECORD_LEN) as u64))?;
+
+    let mut data = [EOF; RECORD_LEN];
+    let mut len = 0;
+    while len < RECORD_LEN {
+        match file.read(&mut data[len..])? {
+            0 => break,
+            read => len += read
+        }
+    }
+
+    Ok(if len == 0 { None } else { Some(data) })
+}
+
+fn write_record(path: &Path, record: usize, data: &[u8; RECORD_LEN]) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start((record * RECORD_LEN) as u64))?;
+    file.write_all(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::BufferConsole;
+    use crate::testing::{call, temp_dir};
+
+    struct Machine {
+        bdos: Bdos<BufferConsole>,
+        cpu: Intel8080,
+        memory: Memory<0x10000>
+    }
+
+    impl Machine {
+        fn new(directory: &Path, input: &[u8]) -> Self {
+            let mut machine = Self {
+                bdos: Bdos::new(directory, BufferConsole::new(input)),
+                cpu: Intel8080::new(),
+                memory: Memory::new()
+            };
+            machine.bdos.boot(&mut machine.cpu, &mut machine.memory, "");
+            machine
+        }
+
+        // Call a BDOS function as a program would and return A
+        fn call(&mut self, function: u8, de: u16) -> u8 {
+            self.cpu.registers_mut().set_c(function);
+            self.cpu.registers_mut().set_pair_d(de);
+            let bdos = &mut self.bdos;
+            call(&mut self.cpu, &mut self.memory, BDOS_ENTRY, |cpu, memory| bdos.trap(cpu, memory));
+            self.cpu.registers().accumulator()
+        }
+
+        fn set_fcb(&mut self, name: &str) {
+            self.memory.write_bytes(DEFAULT_FCB, &parse_fcb(name));
+            self.memory.write_bytes(DEFAULT_FCB + 12, &[0; 24]);
+        }
+    }
+
+    #[test]
+    fn test_boot() {
+        let mut bdos = Bdos::new(".", BufferConsole::default());
+        let mut cpu = Intel8080::new();
+        let mut memory: Memory<0x10000> = Memory::new();
+        bdos.load_com(&mut cpu, &mut memory, &[0xc9], "b:foo.c* bar").unwrap();
+
+        assert_eq!(memory.get_bytes(0, 8), &[0xc3, 0x03, 0xff, 0x00, 0x00, 0xc3, 0x06, 0xfe]);
+        assert_eq!(memory.get_bytes(0x5c, 0x68), b"\x02FOO     C??");
+        assert_eq!(memory.get_bytes(0x6c, 0x78), b"\x00BAR        ");
+        assert_eq!(memory.get_bytes(0x80, 0x8f), b"\x0d B:FOO.C* BAR\x00");
+        assert_eq!(memory.read_byte(0x100), 0xc9);
+        assert_eq!(cpu.registers().pc(), 0x100);
+
+        // RET from the program warm boots
+        assert_eq!(bdos.run(&mut cpu, &mut memory, &mut ()), Exit::WarmBoot);
+
+        let too_large = vec![0; 0xff00];
+        assert_eq!(bdos.load_com(&mut cpu, &mut memory, &too_large, ""), Err(BdosError::ProgramTooLarge(0xff00)));
+    }
+
+    #[test]
+    fn test_console() {
+        let program = assemble("
+BDOS    EQU     5
+        ORG     100H
+        MVI     C,9
+        LXI     D,PROMPT
+        CALL    BDOS
+        MVI     C,10
+        LXI     D,BUFFER
+        CALL    BDOS
+        MVI     C,2
+        LDA     BUFFER+1
+        ADI     '0'
+        MOV     E,A
+        CALL    BDOS
+        MVI     C,1
+        CALL    BDOS
+        STA     CHAR
+        MVI     C,0
+        CALL    BDOS
+PROMPT: DB      'Name? $'
+CHAR:   DB      0
+BUFFER: DB      8,0
+        DS      8
+").unwrap();
+
+        let dir = temp_dir("bdos", "console");
+        let mut machine = Machine::new(&dir, b"JOX\x08E\rQ");
+        program.load_into(&mut machine.memory);
+        let exit = machine.bdos.run(&mut machine.cpu, &mut machine.memory, &mut ());
+
+        assert_eq!(exit, Exit::SystemReset);
+        assert_eq!(machine.bdos.console().output_string(), "Name? JOX\x08 \x08E\r3Q");
+        let buffer = program.symbol("BUFFER").unwrap();
+        assert_eq!(machine.memory.get_bytes(buffer, buffer + 5), b"\x08\x03JOE");
+        assert_eq!(machine.memory.read_byte(program.symbol("CHAR").unwrap()), b'Q');
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = temp_dir("bdos", "files");
+        let mut machine = Machine::new(&dir, b"");
+
+        machine.set_fcb("test.dat");
+        assert_eq!(machine.call(15, DEFAULT_FCB), 0xff);
+        assert_eq!(machine.call(22, DEFAULT_FCB), 0);
+        for fill in [0x11, 0x22, 0x33] {
+            machine.memory.write_bytes(DEFAULT_DMA, &[fill; RECORD_LEN]);
+            assert_eq!(machine.call(21, DEFAULT_FCB), 0);
+        }
+        assert_eq!(machine.memory.read_byte(DEFAULT_FCB + 32), 3);
+        assert_eq!(machine.call(16, DEFAULT_FCB), 0);
+        assert_eq!(fs::read(dir.join("TEST.DAT")).unwrap().len(), 3 * RECORD_LEN);
+
+        // making a file that exists fails and keeps its data
+        machine.set_fcb("test.dat");
+        assert_eq!(machine.call(22, DEFAULT_FCB), 0xff);
+        assert_eq!(fs::read(dir.join("TEST.DAT")).unwrap().len(), 3 * RECORD_LEN);
+
+        // sequential read from the start, until the end of file
+        machine.set_fcb("TEST.DAT");
+        assert_eq!(machine.call(15, DEFAULT_FCB), 0);
+        assert_eq!(machine.memory.read_byte(DEFAULT_FCB + 15), 3);
+        machine.call(26, 0x2000);
+        for fill in [0x11, 0x22, 0x33] {
+            assert_eq!(machine.call(20, DEFAULT_FCB), 0);
+            assert_eq!(machine.memory.get_bytes(0x2000, 0x2080), &[fill; RECORD_LEN]);
+        }
+        assert_eq!(machine.call(20, DEFAULT_FCB), 1);
+
+        // random access
+        machine.memory.write_bytes(DEFAULT_FCB + 33, &[1, 0, 0]);
+        assert_eq!(machine.call(33, DEFAULT_FCB), 0);
+        assert_eq!(machine.memory.read_byte(0x2000), 0x22);
+        machine.memory.write_bytes(0x2000, &[0x44; RECORD_LEN]);
+        machine.memory.write_bytes(DEFAULT_FCB + 33, &[4, 0, 0]);
+        assert_eq!(machine.call(34, DEFAULT_FCB), 0);
+        assert_eq!(machine.call(35, DEFAULT_FCB), 0);
+        assert_eq!(machine.memory.get_bytes(DEFAULT_FCB + 33, DEFAULT_FCB + 36), &[5, 0, 0]);
+        assert_eq!(machine.memory.read_byte(DEFAULT_FCB + 32), 4);
+        // the gap left by the random write reads as zeros
+        machine.memory.write_bytes(DEFAULT_FCB + 33, &[3, 0, 0]);
+        assert_eq!(machine.call(33, DEFAULT_FCB), 0);
+        assert_eq!(machine.memory.read_byte(0x2000), 0);
+        machine.memory.write_bytes(DEFAULT_FCB + 33, &[9, 0, 0]);
+        assert_eq!(machine.call(33, DEFAULT_FCB), 1);
+
+        // a short last record is padded with ^Z
+        fs::write(dir.join("short.txt"), b"hi").unwrap();
+        machine.set_fcb("SHORT.TXT");
+        assert_eq!(machine.call(15, DEFAULT_FCB), 0);
+        assert_eq!(machine.call(20, DEFAULT_FCB), 0);
+        assert_eq!(machine.memory.get_bytes(0x2000, 0x2003), b"hi\x1a");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_directory() {
+        let dir = temp_dir("bdos", "directory");
+        fs::write(dir.join("ONE.COM"), [0; 300]).unwrap();
+        fs::write(dir.join("two.com"), []).unwrap();
+        fs::write(dir.join("THREE.TXT"), []).unwrap();
+        fs::write(dir.join("not.an.83.name"), []).unwrap();
+        let mut machine = Machine::new(&dir, b"");
+
+        machine.set_fcb("*.COM");
+        assert_eq!(machine.call(17, DEFAULT_FCB), 0);
+        assert_eq!(machine.memory.get_bytes(0x80, 0x90), b"\x00ONE     COM\x00\x00\x00\x03");
+        assert_eq!(machine.call(18, DEFAULT_FCB), 0);
+        assert_eq!(machine.memory.get_bytes(0x81, 0x8c), b"TWO     COM");
+        assert_eq!(machine.call(18, DEFAULT_FCB), 0xff);
+
+        machine.set_fcb("THREE.TXT");
+        machine.memory.write_bytes(DEFAULT_FCB + 16, &parse_fcb("FOUR.TXT"));
+        assert_eq!(machine.call(23, DEFAULT_FCB), 0);
+        assert!(dir.join("FOUR.TXT").exists());
+        assert_eq!(machine.call(23, DEFAULT_FCB), 0xff);
+
+        machine.set_fcb("*.*");
+        assert_eq!(machine.call(19, DEFAULT_FCB), 0);
+        assert!(!dir.join("ONE.COM").exists());
+        assert!(dir.join("not.an.83.name").exists());
+        assert_eq!(machine.call(17, DEFAULT_FCB), 0xff);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,623 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::console::{Console, EOF};
+use crate::cpm::{ret, write};
+use crate::cpu::Intel8080;
+use crate::io::IoBus;
+use crate::memory::MemoryAccess;
+
+// CP/M 2.2 BIOS for booting a genuine CCP and BDOS from a disk image. The
+// jump table is written to memory with every entry jumping to itself, and a
+// step that would execute an entry is answered from Rust instead.
+//
+// Memory from the BIOS base:
+//   +0000  jump table, 17 entries
+//   +0033  directory buffer
+//   +00B3  DPH, DPB, sector translate table, CSV and ALV of each drive
+//
+// The CCP and BDOS are loaded from the system tracks, starting at the second
+// sector of track 0, into the 0x1600 bytes below the BIOS.
+
+// BIOS base of a 64K CP/M 2.2
+pub const DEFAULT_BIOS_BASE: u16 = 0xfa00;
+pub const SYSTEM_LEN: u16 = 0x1600;
+pub const SECTOR_LEN: usize = 128;
+pub const MAX_DRIVES: usize = 16;
+
+const BDOS_OFFSET: u16 = 0x0806;
+const DIRBUF_OFFSET: u16 = 17 * 3;
+const DPH_LEN: u16 = 16;
+const DPB_LEN: u16 = 15;
+
+// Jump table entries
+const BOOT: u16 = 0;
+const WBOOT: u16 = 1;
+const CONST: u16 = 2;
+const CONIN: u16 = 3;
+const CONOUT: u16 = 4;
+const LIST: u16 = 5;
+const PUNCH: u16 = 6;
+const READER: u16 = 7;
+const HOME: u16 = 8;
+const SELDSK: u16 = 9;
+const SETTRK: u16 = 10;
+const SETSEC: u16 = 11;
+const SETDMA: u16 = 12;
+const READ: u16 = 13;
+const WRITE: u16 = 14;
+const LISTST: u16 = 15;
+const SECTRAN: u16 = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DiskParameterBlock {
+    // 128 byte records per track
+    pub spt: u16,
+    // block shift and mask
+    pub bsh: u8,
+    pub blm: u8,
+    // extent mask
+    pub exm: u8,
+    // highest block number
+    pub dsm: u16,
+    // highest directory entry number
+    pub drm: u16,
+    // blocks reserved for the directory
+    pub al0: u8,
+    pub al1: u8,
+    // size of the directory check vector
+    pub cks: u16,
+    // reserved system tracks
+    pub off: u16
+}
+
+impl DiskParameterBlock {
+    pub fn to_bytes(&self) -> [u8; DPB_LEN as usize] {
+        let [spt_low, spt_high] = self.spt.to_le_bytes();
+        let [dsm_low, dsm_high] = self.dsm.to_le_bytes();
+        let [drm_low, drm_high] = self.drm.to_le_bytes();
+        let [cks_low, cks_high] = self.cks.to_le_bytes();
+        let [off_low, off_high] = self.off.to_le_bytes();
+        [
+            spt_low, spt_high, self.bsh, self.blm, self.exm,
+            dsm_low, dsm_high, drm_low, drm_high, self.al0, self.al1,
+            cks_low, cks_high, off_low, off_high
+        ]
+    }
+
+    // Bytes in the allocation vector, a bit per block
+    pub fn alv_len(&self) -> u16 {
+        self.dsm / 8 + 1
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DiskDefinition {
+    pub dpb: DiskParameterBlock,
+    pub tracks: u16,
+    // number of the first physical sector on a track
+    pub first_sector: u8,
+    // physical sector for each logical sector, empty if they are the same
+    pub translate: Vec<u8>
+}
+
+impl DiskDefinition {
+    // Standard 8" single sided single density: 77 tracks of 26 sectors,
+    // skewed by 6, with two system tracks
+    pub fn ibm_8_sssd() -> Self {
+        Self {
+            dpb: DiskParameterBlock {
+                spt: 26,
+                bsh: 3,
+                blm: 7,
+                exm: 0,
+                dsm: 242,
+                drm: 63,
+                al0: 0xc0,
+                al1: 0x00,
+                cks: 16,
+                off: 2
+            },
+            tracks: 77,
+            first_sector: 1,
+            translate: vec![
+                1, 7, 13, 19, 25, 5, 11, 17, 23, 3, 9, 15, 21,
+                2, 8, 14, 20, 26, 6, 12, 18, 24, 4, 10, 16, 22
+            ]
+        }
+    }
+
+    pub fn image_len(&self) -> usize {
+        self.tracks as usize * self.dpb.spt as usize * SECTOR_LEN
+    }
+
+    // Offset of a physical sector in the image
+    fn offset(&self, track: u16, sector: u16) -> Option<usize> {
+        let index = sector.checked_sub(self.first_sector as u16)?;
+        if track >= self.tracks || index >= self.dpb.spt {
+            return None;
+        }
+
+        Some((track as usize * self.dpb.spt as usize + index as usize) * SECTOR_LEN)
+    }
+}
+
+// Raw disk image, sectors in physical order track by track
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Disk {
+    definition: DiskDefinition,
+    image: Vec<u8>
+}
+
+impl Disk {
+    // Freshly formatted, every byte 0xE5
+    pub fn new(definition: DiskDefinition) -> Self {
+        let image = vec![0xe5; definition.image_len()];
+        Self {
+            definition,
+            image
+        }
+    }
+
+    // Short images are padded as if formatted
+    pub fn from_bytes(definition: DiskDefinition, bytes: &[u8]) -> Self {
+        let mut disk = Self::new(definition);
+        let len = bytes.len().min(disk.image.len());
+        disk.image[..len].copy_from_slice(&bytes[..len]);
+        disk
+    }
+
+    pub fn open(path: impl AsRef<Path>, definition: DiskDefinition) -> io::Result<Self> {
+        Ok(Self::from_bytes(definition, &fs::read(path)?))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, &self.image)
+    }
+
+    pub fn definition(&self) -> &DiskDefinition {
+        &self.definition
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    pub fn image_mut(&mut self) -> &mut [u8] {
+        &mut self.image
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum BiosError {
+    // no disk in drive A to boot from
+    NoBootDisk,
+    // the disk tables do not fit between the BIOS base and 0xFFFF
+    TablesOverflow,
+    // a drive number of MAX_DRIVES or more
+    NoSuchDrive(usize)
+}
+
+impl fmt::Display for BiosError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BiosError::NoBootDisk => write!(f, "no disk in drive A"),
+            BiosError::TablesOverflow => write!(f, "disk tables do not fit above the BIOS"),
+            BiosError::NoSuchDrive(drive) => write!(f, "no drive {drive}, there are {MAX_DRIVES}")
+        }
+    }
+}
+
+impl std::error::Error for BiosError {}
+
+// Why the BIOS stopped running the system
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BiosExit {
+    // CONIN was called with no input left
+    EndOfInput,
+    // HLT with interrupts disabled
+    Halted
+}
+
+pub struct Bios<C: Console> {
+    base: u16,
+    console: C,
+    disks: Vec<Option<Disk>>,
+    // DPH address of each drive, 0 if it has no disk
+    dph: [u16; MAX_DRIVES],
+    disk: u8,
+    track: u16,
+    sector: u16,
+    dma: u16
+}
+
+impl<C: Console> Bios<C> {
+    pub fn new(base: u16, console: C) -> Self {
+        Self {
+            base,
+            console,
+            disks: vec![None; MAX_DRIVES],
+            dph: [0; MAX_DRIVES],
+            disk: 0,
+            track: 0,
+            sector: 0,
+            dma: 0x80
+        }
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    pub fn ccp_base(&self) -> u16 {
+        self.base.wrapping_sub(SYSTEM_LEN)
+    }
+
+    pub fn bdos_entry(&self) -> u16 {
+        self.ccp_base().wrapping_add(BDOS_OFFSET)
+    }
+
+    pub fn console(&self) -> &C {
+        &self.console
+    }
+
+    pub fn console_mut(&mut self) -> &mut C {
+        &mut self.console
+    }
+
+    // Disks take effect at the next cold boot, which builds their tables
+    pub fn insert_disk(&mut self, drive: usize, disk: Disk) -> Result<(), BiosError> {
+        let slot = self.disks.get_mut(drive).ok_or(BiosError::NoSuchDrive(drive))?;
+        *slot = Some(disk);
+        Ok(())
+    }
+
+    pub fn eject_disk(&mut self, drive: usize) -> Option<Disk> {
+        self.disks.get_mut(drive)?.take()
+    }
+
+    pub fn disk(&self, drive: usize) -> Option<&Disk> {
+        self.disks.get(drive)?.as_ref()
+    }
+
+    // Cold boot: build the jump table and disk tables, load the CCP and BDOS
+    // from drive A and enter the CCP
+    pub fn boot(&mut self, cpu: &mut Intel8080, memory: &mut impl MemoryAccess) -> Result<(), BiosError> {
+        if self.disks[0].is_none() {
+            return Err(BiosError::NoBootDisk);
+        }
+
+        self.install(memory)?;
+        memory.write_byte(0x0003, 0);
+        memory.write_byte(0x0004, 0);
+        self.warm_boot(cpu, memory);
+        Ok(())
+    }
+
+    fn install(&mut self, memory: &mut impl MemoryAccess) -> Result<(), BiosError> {
+        for entry in 0..=SECTRAN {
+            let addr = self.entry(entry);
+            let [low, high] = addr.to_le_bytes();
+            write(memory, addr, &[0xc3, low, high]);
+        }
+
+        let dirbuf = self.base as u32 + DIRBUF_OFFSET as u32;
+        let mut next = dirbuf + SECTOR_LEN as u32;
+        for (drive, disk) in self.disks.iter().enumerate() {
+            let Some(disk) = disk else {
+                self.dph[drive] = 0;
+                continue;
+            };
+
+            let definition = &disk.definition;
+            let dph = next;
+            let dpb = dph + DPH_LEN as u32;
+            let xlt = dpb + DPB_LEN as u32;
+            let csv = xlt + definition.translate.len() as u32;
+            let alv = csv + definition.dpb.cks as u32;
+            next = alv + definition.dpb.alv_len() as u32;
+            if next > 0x10000 {
+                return Err(BiosError::TablesOverflow);
+            }
+
+            let xlt_addr = if definition.translate.is_empty() { 0 } else { xlt as u16 };
+            let mut header = Vec::new();
+            for word in [xlt_addr, 0, 0, 0, dirbuf as u16, dpb as u16, csv as u16, alv as u16] {
+                header.extend_from_slice(&word.to_le_bytes());
+            }
+            write(memory, dph as u16, &header);
+            write(memory, dpb as u16, &definition.dpb.to_bytes());
+            write(memory, xlt as u16, &definition.translate);
+
+            self.dph[drive] = dph as u16;
+        }
+
+        Ok(())
+    }
+
+    // Reload the CCP and BDOS, set up the zero page and enter the CCP with
+    // the current drive in C
+    fn warm_boot(&mut self, cpu: &mut Intel8080, memory: &mut impl MemoryAccess) {
+        if let Some(disk) = &self.disks[0] {
+            let start = SECTOR_LEN;
+            let end = (start + SYSTEM_LEN as usize).min(disk.image.len());
+            write(memory, self.ccp_base(), &disk.image[start..end]);
+        }
+
+        let [wboot_low, wboot_high] = self.entry(WBOOT).to_le_bytes();
+        let [bdos_low, bdos_high] = self.bdos_entry().to_le_bytes();
+        write(memory, 0x0000, &[0xc3, wboot_low, wboot_high]);
+        write(memory, 0x0005, &[0xc3, bdos_low, bdos_high]);
+
+        self.dma = 0x80;
+        let registers = cpu.registers_mut();
+        registers.set_c(memory.read_byte(0x0004));
+        registers.set_sp(0x0100);
+        registers.set_pc(self.ccp_base());
+    }
+
+    fn entry(&self, entry: u16) -> u16 {
+        self.base.wrapping_add(entry * 3)
+    }
+
+    // Run the system until it halts or asks for input there is none of
+    pub fn run(&mut self, cpu: &mut Intel8080, memory: &mut impl MemoryAccess, io: &mut impl IoBus) -> BiosExit {
+        loop {
+            if let Some(exit) = self.trap(cpu, memory) {
+                return exit;
+            }
+            if cpu.halted() && !cpu.interrupts_enabled() {
+                return BiosExit::Halted;
+            }
+            cpu.step(memory, io);
+        }
+    }
+
+    // Answer a call to a jump table entry if PC is on one and return to the
+    // caller. CONIN with no input left is not answered, so the call can be
+    // resumed once there is some
+    pub fn trap(&mut self, cpu: &mut Intel8080, memory: &mut impl MemoryAccess) -> Option<BiosExit> {
+        let offset = cpu.registers().pc().wrapping_sub(self.base);
+        if offset > SECTRAN * 3 || !offset.is_multiple_of(3) {
+            return None;
+        }
+
+        let bc = cpu.registers().pair_b();
+        let registers = cpu.registers_mut();
+        match offset / 3 {
+            BOOT => {
+                let _ = self.boot(cpu, memory);
+                return None;
+            },
+            WBOOT => {
+                self.warm_boot(cpu, memory);
+                return None;
+            },
+            CONST => registers.set_accumulator(if self.console.status() { 0xff } else { 0x00 }),
+            CONIN => match self.console.read() {
+                Some(byte) => registers.set_accumulator(byte & 0x7f),
+                None => return Some(BiosExit::EndOfInput)
+            },
+            CONOUT => self.console.write(registers.c() & 0x7f),
+            LIST | PUNCH => {},
+            READER => registers.set_accumulator(EOF),
+            HOME => self.track = 0,
+            SELDSK => {
+                let drive = registers.c() as usize;
+                let dph = if drive < MAX_DRIVES { self.dph[drive] } else { 0 };
+                if dph != 0 {
+                    self.disk = drive as u8;
+                }
+                registers.set_pair_h(dph);
+            },
+            SETTRK => self.track = bc,
+            SETSEC => self.sector = bc,
+            SETDMA => self.dma = bc,
+            READ => {
+                let result = self.transfer(memory, false);
+                cpu.registers_mut().set_accumulator(result);
+            },
+            WRITE => {
+                let result = self.transfer(memory, true);
+                cpu.registers_mut().set_accumulator(result);
+            },
+            LISTST => registers.set_accumulator(0xff),
+            _ => {
+                let table = registers.pair_d();
+                let sector = if table == 0 {
+                    let first = self.disks[self.disk as usize].as_ref().map_or(0, |disk| disk.definition.first_sector);
+                    bc.wrapping_add(first as u16)
+                }
+                else {
+                    memory.read_byte(table.wrapping_add(bc)) as u16
+                };
+                cpu.registers_mut().set_pair_h(sector);
+            }
+        }
+
+        ret(cpu, memory);
+        None
+    }
+
+    // READ or WRITE the selected sector through the DMA address, 0 on success
+    // and 1 if there is no such sector
+    fn transfer(&mut self, memory: &mut impl MemoryAccess, write_sector: bool) -> u8 {
+        let Some(disk) = self.disks[self.disk as usize].as_mut() else {
+            return 1;
+        };
+        let Some(offset) = disk.definition.offset(self.track, self.sector) else {
+            return 1;
+        };
+
+        let sector = &mut disk.image[offset..offset + SECTOR_LEN];
+        for (i, byte) in sector.iter_mut().enumerate() {
+            let addr = self.dma.wrapping_add(i as u16);
+            if write_sector {
+                *byte = memory.read_byte(addr);
+            }
+            else {
+                memory.write_byte(addr, *byte);
+            }
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::BufferConsole;
+    use crate::memory::Memory;
+    use crate::testing::call;
+
+    struct Machine {
+        bios: Bios<BufferConsole>,
+        cpu: Intel8080,
+        memory: Memory<0x10000>
+    }
+
+    impl Machine {
+        fn new(system: &[u8], input: &[u8]) -> Self {
+            let mut image = vec![0; SECTOR_LEN];
+            image.extend_from_slice(system);
+
+            let mut machine = Self {
+                bios: Bios::new(DEFAULT_BIOS_BASE, BufferConsole::new(input)),
+                cpu: Intel8080::new(),
+                memory: Memory::new()
+            };
+            machine.bios.insert_disk(0, Disk::from_bytes(DiskDefinition::ibm_8_sssd(), &image)).unwrap();
+            machine.bios.insert_disk(2, Disk::new(DiskDefinition::ibm_8_sssd())).unwrap();
+            machine.bios.boot(&mut machine.cpu, &mut machine.memory).unwrap();
+            machine
+        }
+
+        // Call a jump table entry as the BDOS would and return HL
+        fn call(&mut self, entry: u16, bc: u16, de: u16) -> u16 {
+            self.cpu.registers_mut().set_pair_b(bc);
+            self.cpu.registers_mut().set_pair_d(de);
+            let bios = &mut self.bios;
+            call(&mut self.cpu, &mut self.memory, DEFAULT_BIOS_BASE + entry * 3, |cpu, memory| bios.trap(cpu, memory));
+            self.cpu.registers().pair_h()
+        }
+    }
+
+    fn word(memory: &Memory<0x10000>, addr: u16) -> u16 {
+        u16::from_le_bytes([memory.read_byte(addr), memory.read_byte(addr + 1)])
+    }
+
+    #[test]
+    fn test_disk_tables() {
+        let mut machine = Machine::new(&[], b"");
+
+        assert_eq!(machine.memory.get_bytes(0xfa00, 0xfa06), &[0xc3, 0x00, 0xfa, 0xc3, 0x03, 0xfa]);
+
+        let dph = machine.call(SELDSK, 0, 0);
+        assert_ne!(dph, 0);
+        let xlt = word(&machine.memory, dph);
+        let dpb = word(&machine.memory, dph + 10);
+        assert_eq!(word(&machine.memory, dph + 8), DEFAULT_BIOS_BASE + DIRBUF_OFFSET);
+        assert_eq!(machine.memory.get_bytes(xlt, xlt + 26), DiskDefinition::ibm_8_sssd().translate.as_slice());
+        assert_eq!(machine.memory.get_bytes(dpb, dpb + 15), &[26, 0, 3, 7, 0, 242, 0, 63, 0, 0xc0, 0, 16, 0, 2, 0]);
+
+        // drive C has its own tables, drive B has none
+        assert_ne!(machine.call(SELDSK, 2, 0), dph);
+        assert_eq!(machine.call(SELDSK, 1, 0), 0);
+
+        assert_eq!(machine.call(SECTRAN, 0, xlt), 1);
+        assert_eq!(machine.call(SECTRAN, 1, xlt), 7);
+        assert_eq!(machine.call(SECTRAN, 25, xlt), 22);
+        assert_eq!(machine.call(SECTRAN, 4, 0), 5);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let mut machine = Machine::new(&[], b"");
+
+        machine.call(SELDSK, 2, 0);
+        machine.call(SETTRK, 2, 0);
+        machine.call(SETSEC, 7, 0);
+        machine.call(SETDMA, 0x2000, 0);
+        machine.memory.write_bytes(0x2000, &[0x42; SECTOR_LEN]);
+        machine.call(WRITE, 0, 0);
+        assert_eq!(machine.cpu.registers().accumulator(), 0);
+
+        let offset = (2 * 26 + 6) * SECTOR_LEN;
+        let image = machine.bios.disk(2).unwrap().image();
+        assert_eq!(&image[offset..offset + SECTOR_LEN], &[0x42; SECTOR_LEN]);
+        assert_eq!(image[offset - 1], 0xe5);
+
+        machine.call(SETDMA, 0x3000, 0);
+        machine.call(READ, 0, 0);
+        assert_eq!(machine.cpu.registers().accumulator(), 0);
+        assert_eq!(machine.memory.get_bytes(0x3000, 0x3080), &[0x42; SECTOR_LEN]);
+
+        // sector 0 and track 77 do not exist on an 8" disk
+        machine.call(SETSEC, 0, 0);
+        machine.call(READ, 0, 0);
+        assert_eq!(machine.cpu.registers().accumulator(), 1);
+        machine.call(SETSEC, 1, 0);
+        machine.call(SETTRK, 77, 0);
+        machine.call(READ, 0, 0);
+        assert_eq!(machine.cpu.registers().accumulator(), 1);
+    }
+
+    #[test]
+    fn test_boot() {
+        // stands in for the CCP: echoes input through the BIOS until it runs
+        // out, warm booting on a '!'
+        let ccp = assemble("
+BIOS    EQU     0FA00H
+        ORG     0E400H
+        MVI     C,'>'
+        CALL    BIOS+12
+LOOP:   CALL    BIOS+9
+        CPI     '!'
+        JZ      0
+        MOV     C,A
+        CALL    BIOS+12
+        JMP     LOOP
+").unwrap();
+        assert_eq!(ccp.segments[0].address, 0xe400);
+
+        let mut machine = Machine::new(&ccp.segments[0].bytes, b"ab!c");
+        assert_eq!(machine.cpu.registers().pc(), 0xe400);
+        assert_eq!(machine.memory.get_bytes(0, 8), &[0xc3, 0x03, 0xfa, 0x00, 0x00, 0xc3, 0x06, 0xec]);
+
+        let exit = machine.bios.run(&mut machine.cpu, &mut machine.memory, &mut ());
+        assert_eq!(exit, BiosExit::EndOfInput);
+        assert_eq!(machine.bios.console().output_string(), ">ab>c");
+
+        // more input resumes the waiting CONIN
+        machine.bios.console_mut().input.extend(b"d");
+        let exit = machine.bios.run(&mut machine.cpu, &mut machine.memory, &mut ());
+        assert_eq!(exit, BiosExit::EndOfInput);
+        assert_eq!(machine.bios.console().output_string(), ">ab>cd");
+    }
+
+    #[test]
+    fn test_no_boot_disk() {
+        let mut bios = Bios::new(DEFAULT_BIOS_BASE, BufferConsole::default());
+        let mut memory: Memory<0x10000> = Memory::new();
+        assert_eq!(bios.boot(&mut Intel8080::new(), &mut memory), Err(BiosError::NoBootDisk));
+
+        let mut bios = Bios::new(0xff80, BufferConsole::default());
+        bios.insert_disk(0, Disk::new(DiskDefinition::ibm_8_sssd())).unwrap();
+        assert_eq!(bios.boot(&mut Intel8080::new(), &mut memory), Err(BiosError::TablesOverflow));
+    }
+
+    #[test]
+    fn test_no_such_drive() {
+        let mut bios = Bios::new(DEFAULT_BIOS_BASE, BufferConsole::default());
+        assert_eq!(bios.insert_disk(MAX_DRIVES, Disk::new(DiskDefinition::ibm_8_sssd())),
+            Err(BiosError::NoSuchDrive(16)));
+        assert!(bios.eject_disk(16).is_none());
+        assert!(bios.disk(16).is_none());
+
+        bios.insert_disk(15, Disk::new(DiskDefinition::ibm_8_sssd())).unwrap();
+        assert!(bios.eject_disk(15).is_some());
+    }
+}
@@ -0,0 +1,115 @@
+// Machine cycles as seen on the 8080 system bus, for hosts that step the
+// CPU with Intel8080::step_cycles.
+
+// 8228 status byte, latched from the data bus during SYNC in T1
+pub const STATUS_INTA: u8  = 0b0000_0001;
+pub const STATUS_WO: u8    = 0b0000_0010;
+pub const STATUS_STACK: u8 = 0b0000_0100;
+pub const STATUS_HLTA: u8  = 0b0000_1000;
+pub const STATUS_OUT: u8   = 0b0001_0000;
+pub const STATUS_M1: u8    = 0b0010_0000;
+pub const STATUS_INP: u8   = 0b0100_0000;
+pub const STATUS_MEMR: u8  = 0b1000_0000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MachineCycleKind {
+    InstructionFetch,
+    MemoryRead,
+    MemoryWrite,
+    StackRead,
+    StackWrite,
+    InputRead,
+    OutputWrite,
+    // M1 of an interrupt: the opcode comes from the interrupting device
+    InterruptAcknowledge,
+    // operand bytes of an interrupt instruction, turned into INTA by the 8228
+    InterruptOperand,
+    HaltAcknowledge,
+    InterruptAcknowledgeWhileHalt,
+    // internal cycles of DAD and time spent halted, no status is strobed
+    BusIdle,
+    // bus granted to a DMA device through HOLD/HLDA
+    Hold
+}
+
+impl MachineCycleKind {
+    pub fn status(&self) -> u8 {
+        match self {
+            MachineCycleKind::InstructionFetch => STATUS_MEMR | STATUS_M1 | STATUS_WO,
+            MachineCycleKind::MemoryRead => STATUS_MEMR | STATUS_WO,
+            MachineCycleKind::MemoryWrite => 0,
+            MachineCycleKind::StackRead => STATUS_MEMR | STATUS_STACK | STATUS_WO,
+            MachineCycleKind::StackWrite => STATUS_STACK,
+            MachineCycleKind::InputRead => STATUS_INP | STATUS_WO,
+            MachineCycleKind::OutputWrite => STATUS_OUT,
+            MachineCycleKind::InterruptAcknowledge => STATUS_M1 | STATUS_WO | STATUS_INTA,
+            MachineCycleKind::InterruptOperand => STATUS_MEMR | STATUS_WO,
+            MachineCycleKind::HaltAcknowledge => STATUS_MEMR | STATUS_HLTA | STATUS_WO,
+            MachineCycleKind::InterruptAcknowledgeWhileHalt => {
+                STATUS_M1 | STATUS_HLTA | STATUS_WO | STATUS_INTA
+            },
+            MachineCycleKind::BusIdle | MachineCycleKind::Hold => 0
+        }
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(self,
+            MachineCycleKind::MemoryWrite | MachineCycleKind::StackWrite | MachineCycleKind::OutputWrite)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MachineCycle {
+    pub kind: MachineCycleKind,
+    // address bus; I/O cycles carry the port on both halves
+    pub address: u16,
+    // byte read or written
+    pub data: u8,
+    pub status: u8,
+    // T-state this cycle starts at, counted from the start of the instruction
+    pub start: u64,
+    // T-states of the cycle without wait states
+    pub states: u64,
+    // wait states inserted between T2 and T3
+    pub wait_states: u64
+}
+
+impl MachineCycle {
+    pub(crate) fn new(kind: MachineCycleKind, address: u16, data: u8, states: u64) -> Self {
+        Self {
+            kind,
+            address,
+            data,
+            status: kind.status(),
+            start: 0,
+            states,
+            wait_states: 0
+        }
+    }
+}
+
+pub trait BusMonitor {
+    // Called for every machine cycle in order, as it runs. Returns the number
+    // of wait states to insert, as a device holding READY low would
+    fn machine_cycle(&mut self, cycle: &MachineCycle) -> u64;
+
+    // HOLD, sampled at the end of every machine cycle but a hold. Returns the
+    // T-states a DMA device keeps the bus for before the CPU runs the next
+    // cycle; they are counted as stolen cycles. The device sees no memory
+    // here, so the host makes its transfers once the step returns
+    fn hold(&mut self, _cycle: &MachineCycle) -> u64 {
+        0
+    }
+}
+
+impl BusMonitor for () {
+    fn machine_cycle(&mut self, _cycle: &MachineCycle) -> u64 {
+        0
+    }
+}
+
+impl<F: FnMut(&MachineCycle) -> u64> BusMonitor for F {
+    fn machine_cycle(&mut self, cycle: &MachineCycle) -> u64 {
+        self(cycle)
+    }
+}
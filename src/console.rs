@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+// Terminal used by the CP/M BDOS and BIOS
+
+// End of input, as CP/M marks it
+pub const EOF: u8 = 0x1a;
+
+pub trait Console {
+    // Whether a character is waiting
+    fn status(&mut self) -> bool;
+
+    // Wait for a character, None once the input has ended
+    fn read(&mut self) -> Option<u8>;
+
+    fn write(&mut self, byte: u8);
+}
+
+// Host stdin and stdout. Line feeds are read as carriage returns. std cannot
+// poll stdin, so status always reports no character waiting
+#[derive(Default)]
+pub struct StdConsole;
+
+impl StdConsole {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Console for StdConsole {
+    fn status(&mut self) -> bool {
+        false
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        let _ = io::stdout().flush();
+
+        let mut byte = [0];
+        match io::stdin().read(&mut byte) {
+            Ok(1) if byte[0] == b'\n' => Some(b'\r'),
+            Ok(1) => Some(byte[0]),
+            _ => None
+        }
+    }
+
+    fn write(&mut self, byte: u8) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&[byte]);
+        if byte == b'\n' {
+            let _ = stdout.flush();
+        }
+    }
+}
+
+// Console fed from a buffer that collects its output, for tests and scripted
+// runs
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct BufferConsole {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>
+}
+
+impl BufferConsole {
+    pub fn new(input: &[u8]) -> Self {
+        Self {
+            input: input.iter().copied().collect(),
+            output: Vec::new()
+        }
+    }
+
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+}
+
+impl Console for BufferConsole {
+    fn status(&mut self) -> bool {
+        !self.input.is_empty()
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.output.push(byte);
+    }
+}
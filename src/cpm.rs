@@ -0,0 +1,20 @@
+use crate::cpu::Intel8080;
+use crate::memory::MemoryAccess;
+
+// Pieces shared by the host side BDOS and BIOS
+
+// Write `bytes` from `addr` on, wrapping at the top of memory
+pub(crate) fn write(memory: &mut impl MemoryAccess, addr: u16, bytes: &[u8]) {
+    for (offset, byte) in bytes.iter().enumerate() {
+        memory.write_byte(addr.wrapping_add(offset as u16), *byte);
+    }
+}
+
+// Leave a trapped entry as its RET would, popping the return address into PC
+pub(crate) fn ret(cpu: &mut Intel8080, memory: &impl MemoryAccess) {
+    let registers = cpu.registers_mut();
+    let sp = registers.sp();
+    let ret = u16::from_le_bytes([memory.read_byte(sp), memory.read_byte(sp.wrapping_add(1))]);
+    registers.set_pc(ret);
+    registers.set_sp(sp.wrapping_add(2));
+}
@@ -1,13 +1,23 @@
+// Returns are written out, and instructions and registers keep their mnemonics
+#![allow(clippy::needless_return, clippy::upper_case_acronyms, clippy::enum_variant_names)]
+
 use std::fmt;
 
-use log::{debug, warn, error};
+use log::{debug, warn};
 
+use crate::bus::{BusMonitor, MachineCycle, MachineCycleKind};
+use crate::io::IoBus as IoBus;
 use crate::memory::MemoryAccess as MemoryAccess;
+use crate::snapshot::CpuState;
+use crate::trace::{TraceRecord, Tracer};
 
 // 2 MHz
 pub const CYCLE_TIME_SECS: f64 = 0.000_000_005;
 pub const CYCLE_TIME_NANO_SECS: u64 = 500;
 
+// T-states a step takes while halted, as long as the shortest instruction
+pub const HALT_STATES: u64 = 4;
+
 #[allow(non_camel_case_types)]
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -503,6 +513,43 @@ pub enum Instruction {
     HLT = 0x76,
 }
 
+impl Instruction {
+    // Number of bytes occupied by the opcode and its operands
+    pub fn length(&self) -> u16 {
+        return match self {
+            Instruction::LXI_B | Instruction::LXI_D | Instruction::LXI_H | Instruction::LXI_SP |
+            Instruction::STA | Instruction::LDA | Instruction::SHLD | Instruction::LHLD |
+            Instruction::JMP | Instruction::JC | Instruction::JNC | Instruction::JZ |
+            Instruction::JNZ | Instruction::JM | Instruction::JP | Instruction::JPE |
+            Instruction::JPO | Instruction::CALL | Instruction::CC | Instruction::CNC |
+            Instruction::CZ | Instruction::CNZ | Instruction::CM | Instruction::CP |
+            Instruction::CPE | Instruction::CPO => 3,
+            Instruction::MVI_B | Instruction::MVI_C | Instruction::MVI_D | Instruction::MVI_E |
+            Instruction::MVI_H | Instruction::MVI_L | Instruction::MVI_M | Instruction::MVI_A |
+            Instruction::ADI | Instruction::ACI | Instruction::SUI | Instruction::SBI |
+            Instruction::ANI | Instruction::XRI | Instruction::ORI | Instruction::CPI |
+            Instruction::IN | Instruction::OUT => 2,
+            _ => 1
+        }
+    }
+
+    // CALL, the conditional calls and RST, which push PC
+    pub fn is_call(&self) -> bool {
+        matches!(self,
+            Instruction::CALL | Instruction::CC | Instruction::CNC | Instruction::CZ |
+            Instruction::CNZ | Instruction::CM | Instruction::CP | Instruction::CPE | Instruction::CPO |
+            Instruction::RST_1 | Instruction::RST_2 | Instruction::RST_3 | Instruction::RST_4 |
+            Instruction::RST_5 | Instruction::RST_6 | Instruction::RST_7 | Instruction::RST_8)
+    }
+
+    // RET and the conditional returns, which pop PC
+    pub fn is_return(&self) -> bool {
+        matches!(self,
+            Instruction::RET | Instruction::RC | Instruction::RNC | Instruction::RZ |
+            Instruction::RNZ | Instruction::RM | Instruction::RP | Instruction::RPE | Instruction::RPO)
+    }
+}
+
 impl From<u8> for Instruction {
     fn from(orig: u8) -> Self {
         return match orig {
@@ -764,6 +811,124 @@ enum StatusFlags {
     CarryBit    = 0b0000_0001
 }
 
+// Typed view over the status byte
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const SIGN: Flags = Flags(StatusFlags::SignBit as u8);
+    pub const ZERO: Flags = Flags(StatusFlags::ZeroBit as u8);
+    pub const AUX_CARRY: Flags = Flags(StatusFlags::AuxCarryBit as u8);
+    pub const PARITY: Flags = Flags(StatusFlags::ParityBit as u8);
+    pub const CARRY: Flags = Flags(StatusFlags::CarryBit as u8);
+
+    pub const fn empty() -> Self {
+        Flags(0)
+    }
+
+    pub const fn all() -> Self {
+        Flags(Self::SIGN.0 | Self::ZERO.0 | Self::AUX_CARRY.0 | Self::PARITY.0 | Self::CARRY.0)
+    }
+
+    // Unused bits of the status byte are dropped
+    pub const fn from_bits(bits: u8) -> Self {
+        Flags(bits & Self::all().0)
+    }
+
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(&self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Flags) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Flags) {
+        self.0 &= !other.0;
+    }
+
+    pub fn toggle(&mut self, other: Flags) {
+        self.0 ^= other.0;
+    }
+
+    pub fn set(&mut self, other: Flags, value: bool) {
+        if value {
+            self.insert(other);
+        }
+        else {
+            self.remove(other);
+        }
+    }
+}
+
+impl std::ops::BitOr for Flags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Flags(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitOrAssign for Flags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl std::ops::BitAnd for Flags {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Flags(self.0 & rhs.0)
+    }
+}
+
+impl std::ops::BitAndAssign for Flags {
+    fn bitand_assign(&mut self, rhs: Self) {
+        self.0 &= rhs.0;
+    }
+}
+
+impl std::ops::BitXor for Flags {
+    type Output = Self;
+
+    fn bitxor(self, rhs: Self) -> Self {
+        Flags(self.0 ^ rhs.0)
+    }
+}
+
+impl std::ops::Not for Flags {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Flags(!self.0 & Self::all().0)
+    }
+}
+
+// Set flags as letters, clear flags as '-', e.g. "SZ-A-P-C"
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bit = |flag: Flags, c: char| if self.contains(flag) { c } else { '-' };
+        write!(f, "{}{}-{}-{}-{}",
+            bit(Flags::SIGN, 'S'), bit(Flags::ZERO, 'Z'), bit(Flags::AUX_CARRY, 'A'),
+            bit(Flags::PARITY, 'P'), bit(Flags::CARRY, 'C'))
+    }
+}
+
+impl fmt::Debug for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Flags({self})")
+    }
+}
+
 union RegisterPair {
     pair: u16,
     regs: (u8, u8)
@@ -779,8 +944,14 @@ pub struct Registers {
     wz: RegisterPair
 }
 
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Registers {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             pc: 0,
             sp: 0,
@@ -796,7 +967,7 @@ impl Registers {
         self.pc
     }
 
-    fn set_pc(&mut self, val: u16) {
+    pub fn set_pc(&mut self, val: u16) {
         self.pc = val
     }
 
@@ -805,7 +976,7 @@ impl Registers {
         self.sp
     }
 
-    fn set_sp(&mut self, val: u16) {
+    pub fn set_sp(&mut self, val: u16) {
         self.sp = val
     }
 
@@ -814,7 +985,7 @@ impl Registers {
         unsafe { self.bc.pair } 
     }
 
-    fn set_pair_b(&mut self, val: u16) {
+    pub fn set_pair_b(&mut self, val: u16) {
         self.bc.pair = val
     }
 
@@ -822,7 +993,7 @@ impl Registers {
         unsafe { self.bc.regs.1 }
     }
 
-    fn set_b(&mut self, val: u8) {
+    pub fn set_b(&mut self, val: u8) {
         self.bc.regs.1 = val;
     }
 
@@ -830,7 +1001,7 @@ impl Registers {
         unsafe { self.bc.regs.0 }
     }
 
-    fn set_c(&mut self, val: u8) {
+    pub fn set_c(&mut self, val: u8) {
         self.bc.regs.0 = val;
     }
 
@@ -839,7 +1010,7 @@ impl Registers {
         unsafe { self.de.pair }
     }
 
-    fn set_pair_d(&mut self, val: u16) {
+    pub fn set_pair_d(&mut self, val: u16) {
         self.de.pair = val;
     }
 
@@ -847,7 +1018,7 @@ impl Registers {
         unsafe { self.de.regs.1 }
     }
 
-    fn set_d(&mut self, val: u8) {
+    pub fn set_d(&mut self, val: u8) {
         self.de.regs.1 = val;
     }
 
@@ -855,7 +1026,7 @@ impl Registers {
         unsafe { self.de.regs.0 }
     }
 
-    fn set_e(&mut self, val: u8) {
+    pub fn set_e(&mut self, val: u8) {
         self.de.regs.0 = val;
     }
 
@@ -864,7 +1035,7 @@ impl Registers {
         unsafe { self.hl.pair }
     }
 
-    fn set_pair_h(&mut self, val: u16) {
+    pub fn set_pair_h(&mut self, val: u16) {
         self.hl.pair = val;
     } 
 
@@ -872,7 +1043,7 @@ impl Registers {
         unsafe { self.hl.regs.1 }
     }
 
-    fn set_h(&mut self, val: u8) {
+    pub fn set_h(&mut self, val: u8) {
         self.hl.regs.1 = val
     }
 
@@ -880,7 +1051,7 @@ impl Registers {
         unsafe { self.hl.regs.0 }
     }
 
-    fn set_l(&mut self, val: u8) {
+    pub fn set_l(&mut self, val: u8) {
         self.hl.regs.0 = val;
     }
 
@@ -889,9 +1060,9 @@ impl Registers {
         unsafe { self.psw.pair }
     }
 
-    fn set_psw(&mut self, val: u16) {
+    pub fn set_psw(&mut self, val: u16) {
         let bytes = val.to_le_bytes();
-        self.psw.regs.1 = bytes[1] as u8;
+        self.psw.regs.1 = bytes[1];
         self.set_status_byte(bytes[0]);
     }
 
@@ -899,7 +1070,7 @@ impl Registers {
         unsafe { self.psw.regs.1 }
     }
 
-    fn set_accumulator(&mut self, val: u8) {
+    pub fn set_accumulator(&mut self, val: u8) {
         self.psw.regs.1 = val;
     }
 
@@ -907,12 +1078,12 @@ impl Registers {
         unsafe { self.psw.regs.0 }
     }
 
-    fn set_status_byte(&mut self, val: u8) {
+    pub fn set_status_byte(&mut self, val: u8) {
         // bit 1 is always 1 and bits 3 and 5 are always 0
         self.psw.regs.0 = 0b0000_0010 | (val & 0b1101_0111);
     }
 
-    fn set_status_all(&mut self, 
+    pub fn set_status_all(&mut self, 
         carry: Option<bool>,
         aux_carry: Option<bool>,
         zero: Option<bool>,
@@ -965,11 +1136,19 @@ impl Registers {
         }
     }
 
+    pub fn flags(&self) -> Flags {
+        Flags::from_bits(self.status())
+    }
+
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.set_status_byte(flags.bits());
+    }
+
     pub fn status_carry(&self) -> bool {
         unsafe { (self.psw.regs.0 & (StatusFlags::CarryBit as u8)) != 0 }
     }
 
-    fn set_status_carry(&mut self, carry: bool) {
+    pub fn set_status_carry(&mut self, carry: bool) {
         if carry {
             unsafe { self.psw.regs.0 |= StatusFlags::CarryBit as u8 };
         }
@@ -982,7 +1161,7 @@ impl Registers {
         unsafe { (self.psw.regs.0 & (StatusFlags::AuxCarryBit as u8)) != 0 }
     }
 
-    fn set_status_aux_carry(&mut self, aux_carry: bool) {
+    pub fn set_status_aux_carry(&mut self, aux_carry: bool) {
         if aux_carry {
             unsafe { self.psw.regs.0 |= StatusFlags::AuxCarryBit as u8; }
         }
@@ -995,7 +1174,7 @@ impl Registers {
         unsafe { (self.psw.regs.0 & StatusFlags::ZeroBit as u8) != 0 }
     }
 
-    fn set_status_zero(&mut self, zero: bool) {
+    pub fn set_status_zero(&mut self, zero: bool) {
         if zero {
             unsafe { self.psw.regs.0 |= StatusFlags::ZeroBit as u8; }
         }
@@ -1008,7 +1187,7 @@ impl Registers {
         unsafe { (self.psw.regs.0 & (StatusFlags::ParityBit as u8)) != 0 }
     }
 
-    fn set_status_parity(&mut self, parity: bool) {
+    pub fn set_status_parity(&mut self, parity: bool) {
         if parity {
             unsafe { self.psw.regs.0 |= StatusFlags::ParityBit as u8; }
         }
@@ -1021,7 +1200,7 @@ impl Registers {
         unsafe { (self.psw.regs.0 & (StatusFlags::SignBit as u8)) != 0 }
     }
 
-    fn set_status_sign(&mut self, sign: bool) {
+    pub fn set_status_sign(&mut self, sign: bool) {
         if sign {
             unsafe { self.psw.regs.0 |= StatusFlags::SignBit as u8; }
         }
@@ -1034,7 +1213,7 @@ impl Registers {
        unsafe { self.wz.regs.1 }
     }
 
-    fn set_w(&mut self, val: u8) {
+    pub fn set_w(&mut self, val: u8) {
         self.wz.regs.1 = val;
     }
     
@@ -1042,7 +1221,7 @@ impl Registers {
        unsafe { self.wz.regs.0 }
     }
 
-    fn set_z(&mut self, val: u8) {
+    pub fn set_z(&mut self, val: u8) {
         self.wz.regs.0 = val
     }
 
@@ -1050,7 +1229,7 @@ impl Registers {
         unsafe { self.wz.pair }
     }
 
-    fn set_pair_w(&mut self, val: u16) {
+    pub fn set_pair_w(&mut self, val: u16) {
         self.wz.pair = val;
     }
 }
@@ -1131,77 +1310,271 @@ enum ConditionCode {
     Unconditional = 8
 }
 
+impl From<u8> for ConditionCode {
+    fn from(orig: u8) -> Self {
+        match orig {
+            0 => ConditionCode::NotZero,
+            1 => ConditionCode::Zero,
+            2 => ConditionCode::NoCarry,
+            3 => ConditionCode::Carry,
+            4 => ConditionCode::ParityOdd,
+            5 => ConditionCode::ParityEven,
+            6 => ConditionCode::Postive,
+            7 => ConditionCode::Minus,
+            _ => ConditionCode::Unconditional
+        }
+    }
+}
+
+// Operand bytes supplied by the interrupting device during INTA
+#[derive(Debug)]
+struct InterruptData {
+    bytes: [u8; 2],
+    index: usize
+}
+
+impl InterruptData {
+    fn next(&mut self) -> u8 {
+        let val = self.bytes[self.index];
+        self.index += 1;
+        val
+    }
+}
+
 #[derive(Debug)]
 pub struct Intel8080 {
     registers: Registers,
-    interrupt_instruction: Option<Instruction>,
+    int_line: bool,
+    interrupt_data: Option<InterruptData>,
     io_port: u8,
     stopped: bool,
-    output_ready: bool,
-    awaiting_input: bool,
-    inte: bool
+    inte: bool,
+    // set by EI so the following instruction runs before an interrupt is taken
+    ei_delay: bool,
+    // wait states requested by memory, I/O and the bus monitor during the
+    // current instruction, and any time it gave up the bus to DMA
+    wait_states: u64,
+    hold_line: bool,
+    hlda: bool,
+    // bus time used by DMA since the last step, and in total
+    dma_cycles: u64,
+    stolen_cycles: u64,
+    record_cycles: bool,
+    machine_cycles: Vec<MachineCycle>,
+    // every cycle returned by step since the CPU was created
+    cycles: u64
+}
+
+impl Default for Intel8080 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Intel8080 {
     pub fn new() -> Self {
         Self {
             registers: Registers::new(),
-            interrupt_instruction: None,
+            int_line: false,
+            interrupt_data: None,
             io_port: 0,
             stopped: false,
-            output_ready: false,
-            awaiting_input: false,
             inte: false,
+            ei_delay: false,
+            wait_states: 0,
+            hold_line: false,
+            hlda: false,
+            dma_cycles: 0,
+            stolen_cycles: 0,
+            record_cycles: false,
+            machine_cycles: Vec::new(),
+            cycles: 0
         }
     }
     
+    // Port addressed by the most recent IN or OUT
     pub fn active_io_port(&self) -> u8 {
         self.io_port
     }
 
-    pub fn awaiting_input(&self) -> bool {
-        self.awaiting_input
+    pub fn step(&mut self, memory: &mut impl MemoryAccess, io: &mut impl IoBus) -> u64 {
+        self.step_monitored(memory, io, &mut ())
+    }
+
+    fn step_monitored(&mut self,
+        memory: &mut impl MemoryAccess,
+        io: &mut impl IoBus,
+        monitor: &mut impl BusMonitor) -> u64 {
+
+        let cycles = self.execute(&mut Monitored { memory, monitor }, io);
+        self.cycles += cycles;
+        cycles
     }
 
-    pub fn write_input(&mut self, data: u8) {
-        if self.awaiting_input {
-            self.registers.set_accumulator(data);
+    fn execute(&mut self, memory: &mut impl Bus, io: &mut impl IoBus) -> u64 {
+        self.wait_states = 0;
+        if self.hold_line {
+            return self.bus_hold(memory);
         }
+        self.hlda = false;
+
+        let accept_interrupt = self.int_line && self.inte && !self.ei_delay;
+        self.ei_delay = false;
+
+        let instruction = if accept_interrupt {
+            let halted = self.stopped;
+            self.inte = false;
+            self.stopped = false;
+            self.acknowledge_interrupt(memory, io, halted)
+        }
+        else if !self.stopped {
+            self.fetch_instruction(memory)
+        }
+        else {
+            // the clock runs on in the halt state
+            self.record_cycle(memory, MachineCycleKind::BusIdle, self.registers.pc(), 0, HALT_STATES, 0);
+            return HALT_STATES + self.wait_states
+        };
+
+        let cycles = self.do_instruction(instruction, memory, io);
+        self.interrupt_data = None;
+        return cycles + self.wait_states
+    }
+
+    // Execute one instruction, breaking it into its machine cycles. Each cycle
+    // is passed to `monitor` with its T-state timing and 8228 status byte as
+    // it runs, before the memory or I/O access of the next cycle; wait states
+    // it requests are inserted into that cycle. HOLD is sampled through the
+    // monitor at the end of every cycle, as the 8080 does
+    pub fn step_cycles(&mut self,
+        memory: &mut impl MemoryAccess,
+        io: &mut impl IoBus,
+        monitor: &mut impl BusMonitor) -> u64 {
+
+        self.machine_cycles.clear();
+        self.record_cycles = true;
+        let cycles = self.step_monitored(memory, io, monitor);
+        self.record_cycles = false;
+
+        debug_assert_eq!(self.machine_cycles.iter().map(|cycle| cycle.states + cycle.wait_states).sum::<u64>(), cycles);
+        cycles
+    }
+
+    // Execute one instruction and pass `tracer` a record of it, with the
+    // registers as they were before it ran. Nothing is traced while halted
+    // or in the hold state
+    pub fn step_traced(&mut self,
+        memory: &mut impl MemoryAccess,
+        io: &mut impl IoBus,
+        tracer: &mut impl Tracer) -> u64 {
+
+        let pc = self.registers.pc();
+        let mut record = TraceRecord {
+            pc,
+            instruction: Instruction::NOP,
+            bytes: [0; 3],
+            length: 1,
+            interrupt: false,
+            memory: std::array::from_fn(|i| memory.read_byte(pc.wrapping_add(i as u16))),
+            af: self.registers.psw(),
+            bc: self.registers.pair_b(),
+            de: self.registers.pair_d(),
+            hl: self.registers.pair_h(),
+            sp: self.registers.sp(),
+            inte: self.inte,
+            cycles: self.cycles,
+            instruction_cycles: 0
+        };
+
+        self.machine_cycles.clear();
+        self.record_cycles = true;
+        let cycles = self.step(memory, io);
+        self.record_cycles = false;
+
+        let mut machine_cycles = self.machine_cycles.iter();
+        let Some(fetch) = machine_cycles.find(|cycle| matches!(cycle.kind,
+            MachineCycleKind::InstructionFetch |
+            MachineCycleKind::InterruptAcknowledge |
+            MachineCycleKind::InterruptAcknowledgeWhileHalt)) else {
+            return cycles;
+        };
+
+        record.instruction = Instruction::from(fetch.data);
+        record.length = record.instruction.length();
+        record.interrupt = fetch.kind != MachineCycleKind::InstructionFetch;
+        record.bytes[0] = fetch.data;
+        let operands = machine_cycles
+            .filter(|cycle| matches!(cycle.kind, MachineCycleKind::MemoryRead | MachineCycleKind::InterruptOperand))
+            .take(record.length as usize - 1);
+        for (byte, cycle) in record.bytes[1..].iter_mut().zip(operands) {
+            *byte = cycle.data;
+        }
+        record.instruction_cycles = cycles;
+
+        tracer.trace(&record);
+        cycles
     }
 
-    pub fn output_ready(&self) -> bool {
-        self.output_ready
+    // Cycles returned by every step so far, wait states included
+    pub fn cycles(&self) -> u64 {
+        self.cycles
     }
 
-    pub fn read_output(&self) -> u8 {
-        match self.output_ready {
-            true => self.registers.accumulator(),
-            false => 0
-        }
+    // Machine cycles of the instruction executed by the last step_cycles
+    pub fn machine_cycles(&self) -> &[MachineCycle] {
+        &self.machine_cycles
     }
 
-    pub fn step(&mut self, memory: &mut impl MemoryAccess) -> u64 {
-        self.awaiting_input = false;
-        self.output_ready = false;
-        
-        if !self.stopped {
-            let instruction = match self.interrupt_instruction.take() {
-                Some(instruction) => instruction,
-                None => self.fetch_instruction(memory)
-            };
-            return self.do_instruction(instruction, memory)
+    // Drive the INT pin. The request is level triggered: it is sampled before
+    // every instruction and taken once interrupts are enabled, at which point
+    // the instruction is read from the device through IoBus::interrupt_ack
+    pub fn set_int(&mut self, level: bool) {
+        self.int_line = level;
+    }
+
+    pub fn int(&self) -> bool {
+        self.int_line
+    }
+
+    // Drive the HOLD pin between steps. The request is honoured before the
+    // next instruction (or while halted); from then on HLDA is high and step
+    // executes nothing until HOLD is released. A request raised while an
+    // instruction runs is made through BusMonitor::hold in step_cycles, which
+    // grants it at the end of the current machine cycle
+    pub fn set_hold(&mut self, level: bool) {
+        self.hold_line = level;
+    }
+
+    pub fn hold(&self) -> bool {
+        self.hold_line
+    }
+
+    pub fn hlda(&self) -> bool {
+        self.hlda
+    }
+
+    // Account for bus time used by a DMA device while HLDA is high. The cycles
+    // are returned by the next step, so the host's clock keeps running
+    pub fn dma_cycles(&mut self, cycles: u64) {
+        if self.hlda {
+            self.dma_cycles += cycles;
         }
         else {
-            return 0
+            warn!("DMA cycles reported without HLDA, ignoring");
         }
     }
 
-    pub fn interrupt(&mut self, instruction: Instruction) {
-        if self.inte {
-            debug!("Interrupt {instruction:?}");
-            self.interrupt_instruction = Some(instruction);
-            self.stopped = false;
-        }
+    // Total cycles the CPU has spent in the hold state
+    pub fn stolen_cycles(&self) -> u64 {
+        self.stolen_cycles
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.inte
+    }
+
+    pub fn halted(&self) -> bool {
+        self.stopped
     }
 
     pub fn reset(&mut self) {
@@ -1212,23 +1585,186 @@ impl Intel8080 {
         &self.registers
     }
 
-    fn fetch_instruction(&mut self, memory: &impl MemoryAccess) -> Instruction {
-        self.registers.set_pc(self.registers.pc().wrapping_add(1));
-        Instruction::from(memory.read_byte(self.registers.pc().wrapping_sub(1)))
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    // Capture everything needed to resume execution at the next step
+    pub fn save_state(&self) -> CpuState {
+        CpuState {
+            pc: self.registers.pc(),
+            sp: self.registers.sp(),
+            bc: self.registers.pair_b(),
+            de: self.registers.pair_d(),
+            hl: self.registers.pair_h(),
+            psw: self.registers.psw(),
+            wz: self.registers.pair_w(),
+            io_port: self.io_port,
+            inte: self.inte,
+            ei_delay: self.ei_delay,
+            halted: self.stopped,
+            int_line: self.int_line,
+            hold_line: self.hold_line,
+            hlda: self.hlda,
+            dma_cycles: self.dma_cycles,
+            stolen_cycles: self.stolen_cycles,
+            cycles: self.cycles
+        }
+    }
+
+    pub fn restore_state(&mut self, state: &CpuState) {
+        self.registers.set_pc(state.pc);
+        self.registers.set_sp(state.sp);
+        self.registers.set_pair_b(state.bc);
+        self.registers.set_pair_d(state.de);
+        self.registers.set_pair_h(state.hl);
+        self.registers.set_psw(state.psw);
+        self.registers.set_pair_w(state.wz);
+        self.io_port = state.io_port;
+        self.inte = state.inte;
+        self.ei_delay = state.ei_delay;
+        self.stopped = state.halted;
+        self.int_line = state.int_line;
+        self.hold_line = state.hold_line;
+        self.hlda = state.hlda;
+        self.dma_cycles = state.dma_cycles;
+        self.stolen_cycles = state.stolen_cycles;
+        self.cycles = state.cycles;
+        self.interrupt_data = None;
+    }
+
+    fn bus_hold(&mut self, memory: &mut impl Bus) -> u64 {
+        if !self.hlda {
+            debug!("HOLD acknowledged");
+            self.hlda = true;
+        }
+
+        let cycles = std::mem::take(&mut self.dma_cycles);
+        self.stolen_cycles += cycles;
+        if cycles > 0 {
+            self.record_cycle(memory, MachineCycleKind::Hold, 0, 0, cycles, 0);
+        }
+        cycles + self.wait_states
+    }
+
+    // Run the INTA cycles: one for the opcode and one for each operand byte. The
+    // PC is not incremented, so a CALL or RST pushes the interrupted address
+    fn acknowledge_interrupt(&mut self, memory: &mut impl Bus, io: &mut impl IoBus, halted: bool) -> Instruction {
+        let opcode = io.interrupt_ack();
+        let instruction = Instruction::from(opcode);
+        let kind = match halted {
+            true => MachineCycleKind::InterruptAcknowledgeWhileHalt,
+            false => MachineCycleKind::InterruptAcknowledge
+        };
+        self.record_cycle(memory, kind, self.registers.pc(), opcode, fetch_states(instruction), 0);
+
+        let mut data = InterruptData { bytes: [0; 2], index: 0 };
+        for i in 1..instruction.length() as usize {
+            data.bytes[i - 1] = io.interrupt_ack();
+        }
+        self.interrupt_data = Some(data);
+
+        debug!("Interrupt acknowledged with {instruction:?}");
+        instruction
+    }
+
+    fn fetch_instruction(&mut self, memory: &mut impl Bus) -> Instruction {
+        let pc = self.registers.pc();
+        self.registers.set_pc(pc.wrapping_add(1));
+
+        let opcode = memory.read_byte(pc);
+        let instruction = Instruction::from(opcode);
+        let wait_states = memory.wait_states(pc, MachineCycleKind::InstructionFetch);
+        self.record_cycle(memory, MachineCycleKind::InstructionFetch, pc, opcode, fetch_states(instruction), wait_states);
+        instruction
+    }
+
+    fn fetch_immediate(&mut self, memory: &mut impl Bus) -> u8 {
+        let pc = self.registers.pc();
+        if let Some(data) = &mut self.interrupt_data {
+            let val = data.next();
+            self.record_cycle(memory, MachineCycleKind::InterruptOperand, pc, val, 3, 0);
+            return val;
+        }
+
+        self.registers.set_pc(pc.wrapping_add(1));
+        self.read_memory(memory, pc, MachineCycleKind::MemoryRead)
+    }
+
+    fn fetch_immediate16(&mut self, memory: &mut impl Bus) -> [u8; 2] {
+        [self.fetch_immediate(memory), self.fetch_immediate(memory)]
+    }
+
+    // Account for a machine cycle and the wait states its memory or I/O
+    // device asked for, as if READY was held low. When cycles are recorded the
+    // monitor sees it, adding wait states of its own, and is asked whether a
+    // DMA device takes the bus before the next cycle
+    #[inline(always)]
+    fn record_cycle(&mut self,
+        memory: &mut impl Bus,
+        kind: MachineCycleKind,
+        address: u16,
+        data: u8,
+        states: u64,
+        wait_states: u64) {
+
+        self.wait_states += wait_states;
+        if !self.record_cycles {
+            return;
+        }
+
+        let mut cycle = MachineCycle::new(kind, address, data, states);
+        cycle.start = self.machine_cycles.last()
+            .map_or(0, |last| last.start + last.states + last.wait_states);
+        cycle.wait_states = wait_states;
+        let monitor_wait_states = memory.machine_cycle(&cycle);
+        cycle.wait_states += monitor_wait_states;
+        self.wait_states += monitor_wait_states;
+        self.machine_cycles.push(cycle);
+
+        if kind != MachineCycleKind::Hold {
+            let held = memory.hold(&cycle);
+            if held > 0 {
+                debug!("HOLD acknowledged for {held} states after {kind:?}");
+                self.stolen_cycles += held;
+                self.wait_states += held;
+                self.record_cycle(memory, MachineCycleKind::Hold, 0, 0, held, 0);
+            }
+        }
+    }
+
+    fn read_memory(&mut self, memory: &mut impl Bus, addr: u16, kind: MachineCycleKind) -> u8 {
+        let val = memory.read_byte(addr);
+        let wait_states = memory.wait_states(addr, kind);
+        self.record_cycle(memory, kind, addr, val, 3, wait_states);
+        val
+    }
+
+    fn write_memory(&mut self, memory: &mut impl Bus, addr: u16, val: u8, kind: MachineCycleKind) {
+        memory.write_byte(addr, val);
+        let wait_states = memory.wait_states(addr, kind);
+        self.record_cycle(memory, kind, addr, val, 3, wait_states);
     }
 
-    fn fetch_immediate(&mut self, memory: &impl MemoryAccess) -> u8 {
-        self.registers.set_pc(self.registers.pc().wrapping_add(1));
-        memory.read_byte(self.registers.pc().wrapping_sub(1))
+    // Push high byte then low byte, as the 8080 does
+    fn push_stack(&mut self, memory: &mut impl Bus, val: u16) {
+        let [lo, hi] = val.to_le_bytes();
+        let sp = self.registers.sp();
+        self.write_memory(memory, sp.wrapping_sub(1), hi, MachineCycleKind::StackWrite);
+        self.write_memory(memory, sp.wrapping_sub(2), lo, MachineCycleKind::StackWrite);
+        self.registers.set_sp(sp.wrapping_sub(2));
     }
 
-    fn fetch_immediate16(&mut self, memory: &impl MemoryAccess) -> [u8; 2] {
-        self.registers.set_pc(self.registers.pc().wrapping_add(2));
-        memory.read_bytes::<2>(self.registers.pc().wrapping_sub(2))
+    fn pop_stack(&mut self, memory: &mut impl Bus) -> u16 {
+        let sp = self.registers.sp();
+        let lo = self.read_memory(memory, sp, MachineCycleKind::StackRead);
+        let hi = self.read_memory(memory, sp.wrapping_add(1), MachineCycleKind::StackRead);
+        self.registers.set_sp(sp.wrapping_add(2));
+        u16::from_le_bytes([lo, hi])
     }
 
 
-    fn do_instruction(&mut self, instruction: Instruction, memory: &mut impl MemoryAccess) -> u64 {
+    fn do_instruction(&mut self, instruction: Instruction, memory: &mut impl Bus, io: &mut impl IoBus) -> u64 {
         debug!("Performing {instruction:?}");
         return match instruction {
             Instruction::STC => { self.stc() },
@@ -1251,6 +1787,7 @@ impl Intel8080 {
             Instruction::DCR_A => { self.dcr(Operand8::RegA, memory) },
             Instruction::CMA => { self.cma() },
             Instruction::DAA => { self.daa() },
+            Instruction::MOV_B_B => { self.mov(Operand8::RegB, Operand8::RegB, memory) },
             Instruction::MOV_B_C => { self.mov(Operand8::RegB, Operand8::RegC, memory) },
             Instruction::MOV_B_D => { self.mov(Operand8::RegB, Operand8::RegD, memory) },
             Instruction::MOV_B_E => { self.mov(Operand8::RegB, Operand8::RegE, memory) },
@@ -1259,6 +1796,7 @@ impl Intel8080 {
             Instruction::MOV_B_M => { self.mov(Operand8::RegB, Operand8::Memory, memory) },
             Instruction::MOV_B_A => { self.mov(Operand8::RegB, Operand8::RegA, memory) },
             Instruction::MOV_C_B => { self.mov(Operand8::RegC, Operand8::RegB, memory) },
+            Instruction::MOV_C_C => { self.mov(Operand8::RegC, Operand8::RegC, memory) },
             Instruction::MOV_C_D => { self.mov(Operand8::RegC, Operand8::RegD, memory) },
             Instruction::MOV_C_E => { self.mov(Operand8::RegC, Operand8::RegE, memory) },
             Instruction::MOV_C_H => { self.mov(Operand8::RegC, Operand8::RegH, memory) },
@@ -1267,6 +1805,7 @@ impl Intel8080 {
             Instruction::MOV_C_A => { self.mov(Operand8::RegC, Operand8::RegA, memory) },
             Instruction::MOV_D_B => { self.mov(Operand8::RegD, Operand8::RegB, memory) },
             Instruction::MOV_D_C => { self.mov(Operand8::RegD, Operand8::RegC, memory) },
+            Instruction::MOV_D_D => { self.mov(Operand8::RegD, Operand8::RegD, memory) },
             Instruction::MOV_D_E => { self.mov(Operand8::RegD, Operand8::RegE, memory) },
             Instruction::MOV_D_H => { self.mov(Operand8::RegD, Operand8::RegH, memory) },
             Instruction::MOV_D_L => { self.mov(Operand8::RegD, Operand8::RegL, memory) },
@@ -1275,6 +1814,7 @@ impl Intel8080 {
             Instruction::MOV_E_B => { self.mov(Operand8::RegE, Operand8::RegB, memory) },
             Instruction::MOV_E_C => { self.mov(Operand8::RegE, Operand8::RegC, memory) },
             Instruction::MOV_E_D => { self.mov(Operand8::RegE, Operand8::RegD, memory) },
+            Instruction::MOV_E_E => { self.mov(Operand8::RegE, Operand8::RegE, memory) },
             Instruction::MOV_E_H => { self.mov(Operand8::RegE, Operand8::RegH, memory) },
             Instruction::MOV_E_L => { self.mov(Operand8::RegE, Operand8::RegL, memory) },
             Instruction::MOV_E_M => { self.mov(Operand8::RegE, Operand8::Memory, memory) },
@@ -1282,7 +1822,8 @@ impl Intel8080 {
             Instruction::MOV_H_B => { self.mov(Operand8::RegH, Operand8::RegB, memory) },
             Instruction::MOV_H_C => { self.mov(Operand8::RegH, Operand8::RegC, memory) },
             Instruction::MOV_H_D => { self.mov(Operand8::RegH, Operand8::RegD, memory) },
-            Instruction::MOV_H_E => { self.mov(Operand8::RegE, Operand8::RegE, memory) },
+            Instruction::MOV_H_E => { self.mov(Operand8::RegH, Operand8::RegE, memory) },
+            Instruction::MOV_H_H => { self.mov(Operand8::RegH, Operand8::RegH, memory) },
             Instruction::MOV_H_L => { self.mov(Operand8::RegH, Operand8::RegL, memory) },
             Instruction::MOV_H_M => { self.mov(Operand8::RegH, Operand8::Memory, memory) },
             Instruction::MOV_H_A => { self.mov(Operand8::RegH, Operand8::RegA, memory) },
@@ -1291,6 +1832,7 @@ impl Intel8080 {
             Instruction::MOV_L_D => { self.mov(Operand8::RegL, Operand8::RegD, memory) },
             Instruction::MOV_L_E => { self.mov(Operand8::RegL, Operand8::RegE, memory) },
             Instruction::MOV_L_H => { self.mov(Operand8::RegL, Operand8::RegH, memory) },
+            Instruction::MOV_L_L => { self.mov(Operand8::RegL, Operand8::RegL, memory) },
             Instruction::MOV_L_M => { self.mov(Operand8::RegL, Operand8::Memory, memory) },
             Instruction::MOV_L_A => { self.mov(Operand8::RegL, Operand8::RegA, memory) },
             Instruction::MOV_M_B => { self.mov(Operand8::Memory, Operand8::RegB, memory) },
@@ -1306,6 +1848,7 @@ impl Intel8080 {
             Instruction::MOV_A_E => { self.mov(Operand8::RegA, Operand8::RegE, memory) },
             Instruction::MOV_A_H => { self.mov(Operand8::RegA, Operand8::RegH, memory) },
             Instruction::MOV_A_L => { self.mov(Operand8::RegA, Operand8::RegL, memory) },
+            Instruction::MOV_A_A => { self.mov(Operand8::RegA, Operand8::RegA, memory) },
             Instruction::MOV_A_M => { self.mov(Operand8::RegA, Operand8::Memory, memory) },
             Instruction::STAX_B => { self.stax(Operand16::RegPairB, memory) },
             Instruction::STAX_D => { self.stax(Operand16::RegPairD, memory) },
@@ -1387,10 +1930,10 @@ impl Intel8080 {
             Instruction::POP_D => { self.pop(Operand16::RegPairD, memory) },
             Instruction::POP_H => { self.pop(Operand16::RegPairH, memory) },
             Instruction::POP_PSW => { self.pop(Operand16::PSW, memory) },
-            Instruction::DAD_B => { self.dad(Operand16::RegPairB) },
-            Instruction::DAD_D => { self.dad(Operand16::RegPairD) },
-            Instruction::DAD_H => { self.dad(Operand16::RegPairH) },
-            Instruction::DAD_SP => { self.dad(Operand16::SP) },
+            Instruction::DAD_B => { self.dad(Operand16::RegPairB, memory) },
+            Instruction::DAD_D => { self.dad(Operand16::RegPairD, memory) },
+            Instruction::DAD_H => { self.dad(Operand16::RegPairH, memory) },
+            Instruction::DAD_SP => { self.dad(Operand16::SP, memory) },
             Instruction::INX_B => { self.inx(Operand16::RegPairB) },
             Instruction::INX_D => { self.inx(Operand16::RegPairD) },
             Instruction::INX_H => { self.inx(Operand16::RegPairH) },
@@ -1464,29 +2007,29 @@ impl Intel8080 {
             Instruction::RST_8 => { self.rst(7, memory) },
             Instruction::EI => { self.ei() },
             Instruction::DI => { self.di() },
-            Instruction::IN => { self.input(memory) },
-            Instruction::OUT => { self.output(memory) },
-            Instruction::HLT => { self.hlt() },
+            Instruction::IN => { self.input(memory, io) },
+            Instruction::OUT => { self.output(memory, io) },
+            Instruction::HLT => { self.hlt(memory) },
             _ => {4}
         }
     }
 
     #[inline(always)]
-    fn load_imm(&mut self, memory: &impl MemoryAccess) -> u8 {
-        let val = self.fetch_immediate(memory) as u8;
+    fn load_imm(&mut self, memory: &mut impl Bus) -> u8 {
+        let val = self.fetch_immediate(memory);
         self.registers.set_z(val);
         val
     }
     
     #[inline(always)]
-    fn load_imm16(&mut self, memory: &impl MemoryAccess) -> u16 {
+    fn load_imm16(&mut self, memory: &mut impl Bus) -> u16 {
         let bytes = self.fetch_immediate16(memory);
-        let val = u16::from_le_bytes(unsafe{ bytes.try_into().unwrap_unchecked() });
+        let val = u16::from_le_bytes(bytes);
         self.registers.set_pair_w(val);
         val
     }
 
-    fn get_src(&mut self, src: Operand8, memory: &impl MemoryAccess) -> u8 {
+    fn get_src(&mut self, src: Operand8, memory: &mut impl Bus) -> u8 {
         match src {
             Operand8::RegB => { self.registers.b() },
             Operand8::RegC => { self.registers.c() },
@@ -1494,13 +2037,15 @@ impl Intel8080 {
             Operand8::RegE => { self.registers.e() },
             Operand8::RegH => { self.registers.h() },
             Operand8::RegL => { self.registers.l() },
-            Operand8::Memory => { memory.read_byte(self.registers.pair_h()) }
+            Operand8::Memory => {
+                self.read_memory(memory, self.registers.pair_h(), MachineCycleKind::MemoryRead)
+            },
             Operand8::RegA => { self.registers.accumulator() },
             Operand8::Immediate => { self.load_imm(memory) }
         }
     }
 
-    fn write_dst(&mut self, dst: Operand8, val: u8, memory: &mut impl MemoryAccess) {
+    fn write_dst(&mut self, dst: Operand8, val: u8, memory: &mut impl Bus) {
         match dst {
             Operand8::RegB => { self.registers.set_b(val) },
             Operand8::RegC => { self.registers.set_c(val) },
@@ -1508,7 +2053,9 @@ impl Intel8080 {
             Operand8::RegE => { self.registers.set_e(val) },
             Operand8::RegH => { self.registers.set_h(val) },
             Operand8::RegL => { self.registers.set_l(val) },
-            Operand8::Memory => { memory.write_byte(self.registers.pair_h(), val); },
+            Operand8::Memory => {
+                self.write_memory(memory, self.registers.pair_h(), val, MachineCycleKind::MemoryWrite)
+            },
             Operand8::RegA => { self.registers.set_accumulator(val) },
             Operand8::Immediate => { panic!("Can't write to immediate!") } 
         }
@@ -1558,11 +2105,11 @@ impl Intel8080 {
     }
 
     // Increment Register or Memory
-    fn inr(&mut self, reg: Operand8, memory: &mut impl MemoryAccess) -> u64 {
+    fn inr(&mut self, reg: Operand8, memory: &mut impl Bus) -> u64 {
         let val = self.get_src(reg, memory);
         let val = val.wrapping_add(1);
         self.write_dst(reg, val, memory);
-        self.set_condition(val, None, Some(val & 0x0F == 0x0F));
+        self.set_condition(val, None, Some(val & 0x0F == 0));
 
         if reg == Operand8::Memory {
             10
@@ -1573,7 +2120,7 @@ impl Intel8080 {
     }
 
     // Decrement Register or Memory
-    fn dcr(&mut self, reg: Operand8, memory: &mut impl MemoryAccess) -> u64 {
+    fn dcr(&mut self, reg: Operand8, memory: &mut impl Bus) -> u64 {
         let orig_val = self.get_src(reg, memory);
         let new_val = orig_val.wrapping_sub(0x1);
         self.write_dst(reg, new_val, memory);
@@ -1595,78 +2142,74 @@ impl Intel8080 {
 
     // Decimal Adjust Accumulator
     fn daa(&mut self) -> u64 {
-        let mut val: u8 = self.registers.accumulator();
-        
-        let aux_carry = val & 0x0F > 0x09;
+        let val: u8 = self.registers.accumulator();
+        let low = val & 0x0F;
+        let high = val >> 4;
+
+        // both corrections are decided from A before either is added
+        let aux_carry = low > 0x09;
+        let carry = high > 0x09 || self.registers.status_carry() || (high >= 0x09 && low > 0x09);
+        let mut correction = 0;
         if aux_carry || self.registers.status_aux_carry() {
-            val = val.wrapping_add(0x6);
+            correction |= 0x06;
         }
-
-        let carry = val & 0xF0 > 0x90;
-        if carry || self.registers.status_carry() {
-            val = val.wrapping_add(0x60);
+        if carry {
+            correction |= 0x60;
         }
 
+        let val = val.wrapping_add(correction);
         self.registers.set_accumulator(val);
         self.set_condition(val, Some(carry), Some(aux_carry));
         4
     }
 
     // Move
-    fn mov(&mut self, dst: Operand8, src: Operand8, memory: &mut impl MemoryAccess) -> u64 {
+    fn mov(&mut self, dst: Operand8, src: Operand8, memory: &mut impl Bus) -> u64 {
         let val = self.get_src(src, memory);
         self.write_dst(dst, val, memory);
 
-        match dst {
-            Operand8::Memory | Operand8::Immediate => 7,
-            _ => {
-                match src {
-                    Operand8::Immediate => 7,
-                    _ => 5
-                }
-            }
+        match (dst, src) {
+            (Operand8::Memory, Operand8::Immediate) => 10,
+            (Operand8::Memory, _) | (_, Operand8::Memory) | (_, Operand8::Immediate) => 7,
+            _ => 5
         }
     }
 
     // Store Accumulator
-    fn stax(&mut self, dst: Operand16, memory: &mut impl MemoryAccess) -> u64 {
-        match dst {
-            Operand16::RegPairB => {
-                memory.write_byte(self.registers.pair_b(), self.registers.accumulator());
-            },
-            Operand16::RegPairD => {
-                memory.write_byte(self.registers.pair_d(), self.registers.accumulator());
-            },
+    fn stax(&mut self, dst: Operand16, memory: &mut impl Bus) -> u64 {
+        let addr = match dst {
+            Operand16::RegPairB => { self.registers.pair_b() },
+            Operand16::RegPairD => { self.registers.pair_d() },
             _ => { panic!("Invalid dst passed to STAX: {:?}!", dst) }
         };
+        self.write_memory(memory, addr, self.registers.accumulator(), MachineCycleKind::MemoryWrite);
 
         7
     }
 
     // Load Accumulator
-    fn ldax(&mut self, dst: Operand16, memory: &mut impl MemoryAccess) -> u64 {
-        match dst {
-            Operand16::RegPairB => {
-                self.registers.set_accumulator(memory.read_byte(self.registers.pair_b()));
-            },
-            Operand16::RegPairD => {
-                self.registers.set_accumulator(memory.read_byte(self.registers.pair_d()));
-            },
+    fn ldax(&mut self, dst: Operand16, memory: &mut impl Bus) -> u64 {
+        let addr = match dst {
+            Operand16::RegPairB => { self.registers.pair_b() },
+            Operand16::RegPairD => { self.registers.pair_d() },
             _ => { panic!("Invalid dst passed to LDAX: {:?}!", dst) }
         };
+        let val = self.read_memory(memory, addr, MachineCycleKind::MemoryRead);
+        self.registers.set_accumulator(val);
 
         7
     }
 
     // ADD Register or Memory to Accumulator
-    fn add(&mut self, src: Operand8, memory: &impl MemoryAccess) -> u64 {
+    fn add(&mut self, src: Operand8, memory: &mut impl Bus) -> u64 {
         let old_val: u8 = self.registers.accumulator();
-        let (new_val, carry) = old_val.overflowing_add(self.get_src(src, memory)); 
+        let val = self.get_src(src, memory);
+        let (new_val, carry) = old_val.overflowing_add(val);
         
-        let aux_carry: bool = check_aux_carry(old_val, new_val);
+        let aux_carry: bool = aux_carry(old_val, val, false);
 
-        self.registers.set_accumulator(new_val as u8);
-        self.set_condition(new_val as u8, Some(carry), Some(aux_carry));
+        self.registers.set_accumulator(new_val);
+        self.set_condition(new_val, Some(carry), Some(aux_carry));
         
         match src {
             Operand8::Memory | Operand8::Immediate => { 7 }
@@ -1675,16 +2218,17 @@ impl Intel8080 {
     }
 
     // ADD Register or Memory to Accumulator With Carry
-    fn adc(&mut self, src: Operand8, memory: &impl MemoryAccess) -> u64 {
+    fn adc(&mut self, src: Operand8, memory: &mut impl Bus) -> u64 {
         let old_val: u8 = self.registers.accumulator();
-        let (new_val, carry) = old_val.overflowing_add(self.get_src(src, memory));
+        let val = self.get_src(src, memory);
+        let (new_val, carry) = old_val.overflowing_add(val);
         let (new_val, carry_carry) = new_val.overflowing_add(self.registers.status_carry() as u8);
 
         let carry = carry || carry_carry;
-        let aux_carry: bool = check_aux_carry(old_val, new_val);
+        let aux_carry: bool = aux_carry(old_val, val, self.registers.status_carry());
 
         self.registers.set_accumulator(new_val);
-        self.set_condition(new_val as u8, Some(carry), Some(aux_carry));
+        self.set_condition(new_val, Some(carry), Some(aux_carry));
         
         match src {
             Operand8::Memory | Operand8::Immediate => { 7 }
@@ -1693,14 +2237,15 @@ impl Intel8080 {
     }
 
     // Subtract Register or Memory From Accumulator
-    fn sub(&mut self, src: Operand8, memory: &impl MemoryAccess) -> u64 {
+    fn sub(&mut self, src: Operand8, memory: &mut impl Bus) -> u64 {
         let old_val = self.registers.accumulator();
-        let (new_val, carry) = old_val.overflowing_sub(self.get_src(src, memory));
+        let val = self.get_src(src, memory);
+        let (new_val, carry) = old_val.overflowing_sub(val);
 
-        let aux_carry: bool = check_aux_carry(old_val, new_val);
+        let aux_carry: bool = aux_carry(old_val, !val, true);
 
         self.registers.set_accumulator(new_val);
-        self.set_condition(new_val as u8, Some(carry), Some(aux_carry));
+        self.set_condition(new_val, Some(carry), Some(aux_carry));
         
         match src {
             Operand8::Memory | Operand8::Immediate => { 7 }
@@ -1709,13 +2254,14 @@ impl Intel8080 {
     } 
 
     // Subtract Register or Memory From Accumulator With Borrow
-    fn sbb(&mut self, src: Operand8, memory: &impl MemoryAccess) -> u64 {
+    fn sbb(&mut self, src: Operand8, memory: &mut impl Bus) -> u64 {
         let old_val = self.registers.accumulator();
-        let (new_val, carry) = old_val.overflowing_sub(self.get_src(src, memory));
+        let val = self.get_src(src, memory);
+        let (new_val, carry) = old_val.overflowing_sub(val);
         let (new_val, carry_carry) = new_val.overflowing_sub(self.registers.status_carry() as u8);
 
         let carry: bool = carry || carry_carry;
-        let aux_carry: bool = check_aux_carry(old_val, new_val);
+        let aux_carry: bool = aux_carry(old_val, !val, !self.registers.status_carry());
 
         self.registers.set_accumulator(new_val);
         self.set_condition(new_val, Some(carry), Some(aux_carry));
@@ -1727,11 +2273,12 @@ impl Intel8080 {
     }
 
     // Logical and Register or Memory With Accumulator
-    fn ana(&mut self, src: Operand8, memory: &impl MemoryAccess) -> u64 {
-        let src = Operand8::from(src);
-
-        let val: u8 = self.registers.accumulator() & self.get_src(src, memory);
-        self.set_condition(val as u8, Some(false), Some(false));
+    fn ana(&mut self, src: Operand8, memory: &mut impl Bus) -> u64 {
+        let operand = self.get_src(src, memory);
+        let accumulator = self.registers.accumulator();
+        let val: u8 = accumulator & operand;
+        // AC is the OR of bit 3 of the operands
+        self.set_condition(val, Some(false), Some((accumulator | operand) & 0x08 != 0));
         self.registers.set_accumulator(val);
 
         match src {
@@ -1741,9 +2288,9 @@ impl Intel8080 {
     }
 
     // Logical Exclusive-Or Register or Memory with Accumulator
-    fn xra(&mut self, src: Operand8, memory: &impl MemoryAccess) -> u64 {
+    fn xra(&mut self, src: Operand8, memory: &mut impl Bus) -> u64 {
         let val: u8 = self.registers.accumulator() ^ self.get_src(src, memory);
-        self.set_condition(val as u8, Some(false), Some(false));
+        self.set_condition(val, Some(false), Some(false));
         self.registers.set_accumulator(val);
         
         match src {
@@ -1753,9 +2300,9 @@ impl Intel8080 {
     }
 
     // Logical or Register or Memory with Accumulator
-    fn ora(&mut self, src: Operand8, memory: &impl MemoryAccess) -> u64 {
+    fn ora(&mut self, src: Operand8, memory: &mut impl Bus) -> u64 {
         let val: u8 = self.registers.accumulator() | self.get_src(src, memory);
-        self.set_condition(val as u8, Some(false), Some(false));
+        self.set_condition(val, Some(false), Some(false));
         self.registers.set_accumulator(val);
         
         match src {
@@ -1765,11 +2312,12 @@ impl Intel8080 {
     }
 
     // Compare Register or Memory with Accumulator
-    fn cmp(&mut self, src: Operand8, memory: &impl MemoryAccess) -> u64 {
+    fn cmp(&mut self, src: Operand8, memory: &mut impl Bus) -> u64 {
         let old_val = self.registers.accumulator();
-        let (new_val, carry) = old_val.overflowing_sub(self.get_src(src, memory));
+        let val = self.get_src(src, memory);
+        let (new_val, carry) = old_val.overflowing_sub(val);
 
-        let aux_carry: bool = check_aux_carry(old_val, new_val);
+        let aux_carry: bool = aux_carry(old_val, !val, true);
 
         self.set_condition(new_val, Some(carry), Some(aux_carry));
         
@@ -1848,12 +2396,12 @@ impl Intel8080 {
     }
 
     // Push Data Onto Stack
-    fn push(&mut self, src: Operand16, memory: &mut impl MemoryAccess) -> u64 {
+    fn push(&mut self, src: Operand16, memory: &mut impl Bus) -> u64 {
         let first_register = match src {
             Operand16::RegPairB => { self.registers.b() },
             Operand16::RegPairD => { self.registers.d() },
             Operand16::RegPairH => { self.registers.h() },
-            Operand16::PSW => { self.registers.status() },
+            Operand16::PSW => { self.registers.accumulator() },
             _ => { panic!("Invalid src passed to PUSH: {:?}!", src) }
         };
         
@@ -1861,20 +2409,18 @@ impl Intel8080 {
             Operand16::RegPairB => { self.registers.c() },
             Operand16::RegPairD => { self.registers.e() },
             Operand16::RegPairH => { self.registers.l() },
-            Operand16::PSW => { self.registers.accumulator() },
+            Operand16::PSW => { self.registers.status() },
             _ => { unreachable!() }
         };
 
-        self.registers.set_sp(self.registers.sp().wrapping_sub(2));
-        memory.write_bytes(self.registers.sp(), &[second_register, first_register]);
+        self.push_stack(memory, u16::from_le_bytes([second_register, first_register]));
 
         11
     }
 
     // Pop Data Off Stack
-    fn pop(&mut self, dst: Operand16, memory: &impl MemoryAccess) -> u64 {
-        let bytes = memory.read_bytes::<2>(self.registers.sp());
-        self.registers.set_sp(self.registers.sp().wrapping_add(2));
+    fn pop(&mut self, dst: Operand16, memory: &mut impl Bus) -> u64 {
+        let bytes = self.pop_stack(memory).to_le_bytes();
 
         match dst {
             Operand16::RegPairB => {
@@ -1890,8 +2436,8 @@ impl Intel8080 {
                 self.registers.set_l(bytes[0]);
             },
             Operand16::PSW => {
-                self.registers.set_status_byte(bytes[1]);
-                self.registers.set_accumulator(bytes[0]);
+                self.registers.set_accumulator(bytes[1]);
+                self.registers.set_status_byte(bytes[0]);
             },
             _ => { panic!("Invalid dst passed to POP: {:?}!", dst) }
         };
@@ -1899,13 +2445,16 @@ impl Intel8080 {
     }
 
     // Double Add
-    fn dad(&mut self, src: Operand16) -> u64 {
+    fn dad(&mut self, src: Operand16, memory: &mut impl Bus) -> u64 {
         let val = self.get_src_16(src);
         
-        let carry = (val & 0x80) | (self.registers.pair_h() & 0x80) != 0;
+        let (result, carry) = self.registers.pair_h().overflowing_add(val);
         self.registers.set_status_carry(carry);
         
-        self.registers.set_pair_h(val + self.registers.pair_h());
+        self.registers.set_pair_h(result);
+
+        self.record_cycle(memory, MachineCycleKind::BusIdle, 0, 0, 3, 0);
+        self.record_cycle(memory, MachineCycleKind::BusIdle, 0, 0, 3, 0);
         10
     }
 
@@ -1932,14 +2481,19 @@ impl Intel8080 {
     }
 
     // Exchange Stack
-    fn xthl(&mut self, memory: &mut impl MemoryAccess) -> u64 {
-        let temp = self.registers.h();
-        self.registers.set_h(memory.read_byte(self.registers.sp().wrapping_add(1)));
-        memory.write_byte(self.registers.sp().wrapping_add(1), temp);
-        
-        let temp = self.registers.l();
-        self.registers.set_l(memory.read_byte(self.registers.sp()));
-        memory.write_byte(self.registers.sp(), temp);
+    fn xthl(&mut self, memory: &mut impl Bus) -> u64 {
+        let sp = self.registers.sp();
+        let l = self.read_memory(memory, sp, MachineCycleKind::StackRead);
+        let h = self.read_memory(memory, sp.wrapping_add(1), MachineCycleKind::StackRead);
+
+        self.write_memory(memory, sp.wrapping_add(1), self.registers.h(), MachineCycleKind::StackWrite);
+        // the final write takes 5 states
+        memory.write_byte(sp, self.registers.l());
+        let wait_states = memory.wait_states(sp, MachineCycleKind::StackWrite);
+        self.record_cycle(memory, MachineCycleKind::StackWrite, sp, self.registers.l(), 5, wait_states);
+
+        self.registers.set_h(h);
+        self.registers.set_l(l);
         18
     }
 
@@ -1950,39 +2504,43 @@ impl Intel8080 {
     }
 
     // Load Register Pair Immediate
-    fn lxi(&mut self, dst: Operand16, memory: &impl MemoryAccess) -> u64 {
+    fn lxi(&mut self, dst: Operand16, memory: &mut impl Bus) -> u64 {
         let val = self.load_imm16(memory);
         self.write_dst_16(dst, val);
         10
     }
 
     // Store Accumulator Direct
-    fn sta(&mut self, memory: &mut impl MemoryAccess) -> u64 {
+    fn sta(&mut self, memory: &mut impl Bus) -> u64 {
         let val = self.load_imm16(memory);
-        memory.write_byte(val, self.registers.accumulator());
+        self.write_memory(memory, val, self.registers.accumulator(), MachineCycleKind::MemoryWrite);
         13
     }
 
     // Load Accumulator Direct
-    fn lda(&mut self, memory: &mut impl MemoryAccess) -> u64 {
+    fn lda(&mut self, memory: &mut impl Bus) -> u64 {
         let val = self.load_imm16(memory);
-        self.registers.set_accumulator(memory.read_byte(val));
+        let val = self.read_memory(memory, val, MachineCycleKind::MemoryRead);
+        self.registers.set_accumulator(val);
         13
     }
 
     // Store H and L Direct
-    fn shld(&mut self, memory: &mut impl MemoryAccess) -> u64 {
+    fn shld(&mut self, memory: &mut impl Bus) -> u64 {
         let val = self.load_imm16(memory);
-        memory.write_bytes(val, &[self.registers.l(), self.registers.h()]);
+        self.write_memory(memory, val, self.registers.l(), MachineCycleKind::MemoryWrite);
+        self.write_memory(memory, val.wrapping_add(1), self.registers.h(), MachineCycleKind::MemoryWrite);
         16
     }
 
     // Load H and L Direct
-    fn lhld(&mut self, memory: &mut impl MemoryAccess) -> u64 {
+    fn lhld(&mut self, memory: &mut impl Bus) -> u64 {
         let val = self.load_imm16(memory);
-        let val = u16::from_le_bytes(memory.read_bytes::<2>(val));
-        self.registers.set_pair_h(val);
-        15
+        let l = self.read_memory(memory, val, MachineCycleKind::MemoryRead);
+        let h = self.read_memory(memory, val.wrapping_add(1), MachineCycleKind::MemoryRead);
+        self.registers.set_l(l);
+        self.registers.set_h(h);
+        16
     }
 
     // Load Program Counter
@@ -2006,7 +2564,7 @@ impl Intel8080 {
     }
 
     // Jump
-    fn jmp(&mut self, condition: ConditionCode, memory: &impl MemoryAccess) -> u64 {
+    fn jmp(&mut self, condition: ConditionCode, memory: &mut impl Bus) -> u64 {
         if self.check_condition(condition) {
             debug!("JMP condition met");
             let addr = self.load_imm16(memory);
@@ -2015,25 +2573,22 @@ impl Intel8080 {
         }
         else {
             debug!("JMP condition not met");
-            self.registers.set_pc(self.registers.pc().wrapping_add(2));
-            3
+            self.load_imm16(memory);
+            10
         }
     }
 
-    fn push_pc(&mut self, memory: &mut impl MemoryAccess) {
-        self.registers.set_sp(self.registers.sp().wrapping_sub(2));
-        memory.write_bytes(self.registers.sp(), &self.registers.pc().to_le_bytes());
+    fn push_pc(&mut self, memory: &mut impl Bus) {
+        self.push_stack(memory, self.registers.pc());
     }
 
-    fn pop_pc(&mut self, memory: &mut impl MemoryAccess) {
-        let pc = memory.read_bytes::<2>(self.registers.sp());
-        let pc = u16::from_le_bytes(pc);
-        self.registers.set_sp(self.registers.sp().wrapping_add(2));
+    fn pop_pc(&mut self, memory: &mut impl Bus) {
+        let pc = self.pop_stack(memory);
         self.registers.set_pc(pc);
     }
 
     // Call
-    fn call(&mut self, condition: ConditionCode, memory: &mut impl MemoryAccess) -> u64 {
+    fn call(&mut self, condition: ConditionCode, memory: &mut impl Bus) -> u64 {
         if self.check_condition(condition) {
             debug!("CALL condition met");
             let addr = self.load_imm16(memory);
@@ -2043,13 +2598,13 @@ impl Intel8080 {
         }
         else {
             debug!("CALL condition not met");
-            self.registers.set_pc(self.registers.pc().wrapping_add(2));
+            self.load_imm16(memory);
             11
         }
     }
     
     // Return
-    fn ret(&mut self, condition: ConditionCode, memory: &mut impl MemoryAccess) -> u64 {
+    fn ret(&mut self, condition: ConditionCode, memory: &mut impl Bus) -> u64 {
         if self.check_condition(condition) {
             debug!("RET condition met");
             self.pop_pc(memory);
@@ -2062,7 +2617,7 @@ impl Intel8080 {
     }
 
     // Reset
-    fn rst(&mut self, exp: u8, memory: &mut impl MemoryAccess) -> u64 {
+    fn rst(&mut self, exp: u8, memory: &mut impl Bus) -> u64 {
         self.push_pc(memory);
         self.registers.set_pc((exp as u16) << 3);
         11
@@ -2071,6 +2626,7 @@ impl Intel8080 {
     // Enable Interrupts
     fn ei(&mut self) -> u64 {
         self.inte = true;
+        self.ei_delay = true;
         4
     }
 
@@ -2081,35 +2637,122 @@ impl Intel8080 {
     }
 
     // Input
-    fn input(&mut self, memory: &impl MemoryAccess) -> u64 {
+    fn input(&mut self, memory: &mut impl Bus, io: &mut impl IoBus) -> u64 {
         self.load_imm(memory);
         self.io_port = self.registers.z();
-        self.awaiting_input = true;
+        let val = io.port_in(self.io_port);
+        let wait_states = io.wait_states(self.io_port, MachineCycleKind::InputRead);
+        self.record_cycle(memory, MachineCycleKind::InputRead, u16::from_le_bytes([self.io_port; 2]), val, 3, wait_states);
+        self.registers.set_accumulator(val);
         10
     }
 
     // Output
-    fn output(&mut self, memory: &impl MemoryAccess) -> u64 {
+    fn output(&mut self, memory: &mut impl Bus, io: &mut impl IoBus) -> u64 {
         self.load_imm(memory);
         self.io_port = self.registers.z();
-        self.output_ready = true;
+        io.port_out(self.io_port, self.registers.accumulator());
+        let wait_states = io.wait_states(self.io_port, MachineCycleKind::OutputWrite);
+        self.record_cycle(memory, MachineCycleKind::OutputWrite, u16::from_le_bytes([self.io_port; 2]),
+            self.registers.accumulator(), 3, wait_states);
         10
     }
 
     // Halt
-    fn hlt(&mut self) -> u64 {
+    fn hlt(&mut self, memory: &mut impl Bus) -> u64 {
         self.stopped = true;
+        self.record_cycle(memory, MachineCycleKind::HaltAcknowledge, self.registers.pc(), 0, 3, 0);
         7
     }
 }
 
+// Memory as the CPU drives it, with the monitor of step_cycles watching
+trait Bus: MemoryAccess {
+    fn machine_cycle(&mut self, cycle: &MachineCycle) -> u64;
+    fn hold(&mut self, cycle: &MachineCycle) -> u64;
+}
+
+struct Monitored<'a, M: MemoryAccess, B: BusMonitor> {
+    memory: &'a mut M,
+    monitor: &'a mut B
+}
+
+impl<M: MemoryAccess, B: BusMonitor> MemoryAccess for Monitored<'_, M, B> {
+    fn read_byte(&self, addr: u16) -> u8 {
+        self.memory.read_byte(addr)
+    }
+
+    fn write_byte(&mut self, addr: u16, val: u8) {
+        self.memory.write_byte(addr, val);
+    }
+
+    fn read_bytes<const C: usize>(&self, addr: u16) -> [u8; C] {
+        self.memory.read_bytes(addr)
+    }
+
+    fn write_bytes(&mut self, addr: u16, val: &[u8]) {
+        self.memory.write_bytes(addr, val);
+    }
+
+    fn wait_states(&self, addr: u16, kind: MachineCycleKind) -> u64 {
+        self.memory.wait_states(addr, kind)
+    }
+}
+
+impl<M: MemoryAccess, B: BusMonitor> Bus for Monitored<'_, M, B> {
+    fn machine_cycle(&mut self, cycle: &MachineCycle) -> u64 {
+        self.monitor.machine_cycle(cycle)
+    }
+
+    fn hold(&mut self, cycle: &MachineCycle) -> u64 {
+        self.monitor.hold(cycle)
+    }
+}
+
 // UTILITY FUNCTIONS
-fn check_aux_carry(old_val: u8, new_val: u8) -> bool {
-    if (new_val & 0x0F) < (old_val & 0x0F) { true } else { false }
+
+// T-states of the opcode fetch (M1), which includes any internal register work
+fn fetch_states(instruction: Instruction) -> u64 {
+    match instruction {
+        Instruction::MOV_B_B | Instruction::MOV_B_C | Instruction::MOV_B_D | Instruction::MOV_B_E |
+        Instruction::MOV_B_H | Instruction::MOV_B_L | Instruction::MOV_B_A |
+        Instruction::MOV_C_B | Instruction::MOV_C_C | Instruction::MOV_C_D | Instruction::MOV_C_E |
+        Instruction::MOV_C_H | Instruction::MOV_C_L | Instruction::MOV_C_A |
+        Instruction::MOV_D_B | Instruction::MOV_D_C | Instruction::MOV_D_D | Instruction::MOV_D_E |
+        Instruction::MOV_D_H | Instruction::MOV_D_L | Instruction::MOV_D_A |
+        Instruction::MOV_E_B | Instruction::MOV_E_C | Instruction::MOV_E_D | Instruction::MOV_E_E |
+        Instruction::MOV_E_H | Instruction::MOV_E_L | Instruction::MOV_E_A |
+        Instruction::MOV_H_B | Instruction::MOV_H_C | Instruction::MOV_H_D | Instruction::MOV_H_E |
+        Instruction::MOV_H_H | Instruction::MOV_H_L | Instruction::MOV_H_A |
+        Instruction::MOV_L_B | Instruction::MOV_L_C | Instruction::MOV_L_D | Instruction::MOV_L_E |
+        Instruction::MOV_L_H | Instruction::MOV_L_L | Instruction::MOV_L_A |
+        Instruction::MOV_A_B | Instruction::MOV_A_C | Instruction::MOV_A_D | Instruction::MOV_A_E |
+        Instruction::MOV_A_H | Instruction::MOV_A_L | Instruction::MOV_A_A |
+        Instruction::INR_B | Instruction::INR_C | Instruction::INR_D | Instruction::INR_E |
+        Instruction::INR_H | Instruction::INR_L | Instruction::INR_A |
+        Instruction::DCR_B | Instruction::DCR_C | Instruction::DCR_D | Instruction::DCR_E |
+        Instruction::DCR_H | Instruction::DCR_L | Instruction::DCR_A |
+        Instruction::INX_B | Instruction::INX_D | Instruction::INX_H | Instruction::INX_SP |
+        Instruction::DCX_B | Instruction::DCX_D | Instruction::DCX_H | Instruction::DCX_SP |
+        Instruction::PUSH_B | Instruction::PUSH_D | Instruction::PUSH_H | Instruction::PUSH_PSW |
+        Instruction::RST_1 | Instruction::RST_2 | Instruction::RST_3 | Instruction::RST_4 |
+        Instruction::RST_5 | Instruction::RST_6 | Instruction::RST_7 | Instruction::RST_8 |
+        Instruction::CALL | Instruction::CC | Instruction::CNC | Instruction::CZ |
+        Instruction::CNZ | Instruction::CM | Instruction::CP | Instruction::CPE | Instruction::CPO |
+        Instruction::RC | Instruction::RNC | Instruction::RZ | Instruction::RNZ |
+        Instruction::RM | Instruction::RP | Instruction::RPE | Instruction::RPO |
+        Instruction::SPHL | Instruction::PCHL => 5,
+        _ => 4
+    }
+}
+// Carry out of bit 3 when adding `val` and `carry` to `acc`. Subtraction adds
+// the complement with the borrow inverted, as the ALU does
+fn aux_carry(acc: u8, val: u8, carry: bool) -> bool {
+    (acc & 0x0F) + (val & 0x0F) + carry as u8 > 0x0F
 }
 
 fn parity_even(val: u8) -> bool {
-    return val.count_ones() % 2 == 0;
+    return val.count_ones().is_multiple_of(2);
 }
 
 // END UTILITY FUNCTIONS
@@ -2118,6 +2761,8 @@ fn parity_even(val: u8) -> bool {
 mod tests {
     use super::*;
     use crate::memory::Memory as Memory;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
 
     #[test]
     fn test_cma() {
@@ -2128,7 +2773,7 @@ mod tests {
         cpu.registers.set_pc(0);
         cpu.registers.set_accumulator(0b01010001);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.accumulator(), 0b10101110);
     }
@@ -2144,11 +2789,46 @@ mod tests {
         cpu.registers.set_status_carry(false);
         cpu.registers.set_status_aux_carry(false);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
         
         assert!(cpu.registers.status_carry());
         assert!(cpu.registers.status_aux_carry());
         assert_eq!(cpu.registers.accumulator(), 0x01);
+
+        // a carry from the addition before stays set
+        cpu.registers.set_pc(0);
+        cpu.registers.set_accumulator(0x15);
+        cpu.registers.set_status_carry(true);
+        cpu.registers.set_status_aux_carry(false);
+
+        cpu.step(&mut memory, &mut ());
+
+        assert!(cpu.registers.status_carry());
+        assert_eq!(cpu.registers.accumulator(), 0x75);
+
+        // the high correction is decided before the low one is added
+        cpu.registers.set_pc(0);
+        cpu.registers.set_accumulator(0xFA);
+        cpu.registers.set_status_carry(false);
+        cpu.registers.set_status_aux_carry(false);
+
+        cpu.step(&mut memory, &mut ());
+
+        assert!(cpu.registers.status_carry());
+        assert!(cpu.registers.status_aux_carry());
+        assert_eq!(cpu.registers.accumulator(), 0x60);
+
+        cpu.registers.set_pc(0);
+        cpu.registers.set_accumulator(0x9A);
+        cpu.registers.set_status_carry(false);
+        cpu.registers.set_status_aux_carry(false);
+
+        cpu.step(&mut memory, &mut ());
+
+        assert!(cpu.registers.status_carry());
+        assert!(cpu.registers.status_aux_carry());
+        assert!(cpu.registers.status_zero());
+        assert_eq!(cpu.registers.accumulator(), 0x00);
     }
 
     #[test]
@@ -2163,19 +2843,34 @@ mod tests {
         cpu.registers.set_pair_h(160);
         cpu.registers.set_accumulator(0x42);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
         assert_eq!(0x42, memory.read_byte(160));
         assert_eq!(memory.read_byte(160), cpu.registers.accumulator());
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
         assert_eq!(0x42, cpu.registers.b());
         assert_eq!(memory.read_byte(160), cpu.registers.b());
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
         assert_eq!(0x42, cpu.registers.c());
         assert_eq!(cpu.registers.b(), cpu.registers.c());
     }
 
+    #[test]
+    fn test_mov_h_e() {
+        let mut memory: Memory<1> = Memory::new();
+        memory.write_byte(0, Instruction::MOV_H_E as u8);
+
+        let mut cpu = Intel8080::new();
+        cpu.registers.set_pc(0);
+        cpu.registers.set_e(0x42);
+
+        cpu.step(&mut memory, &mut ());
+
+        assert_eq!(cpu.registers.h(), 0x42);
+        assert_eq!(cpu.registers.e(), 0x42);
+    }
+
     #[test]
     fn test_inr() {
         let mut memory: Memory<1> = Memory::new();
@@ -2185,9 +2880,18 @@ mod tests {
         cpu.registers.set_pc(0);
         cpu.registers.set_c(0x99);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.c(), 0x9A);
+        assert!(!cpu.registers.status_aux_carry());
+
+        cpu.registers.set_pc(0);
+        cpu.registers.set_c(0x8F);
+
+        cpu.step(&mut memory, &mut ());
+
+        assert_eq!(cpu.registers.c(), 0x90);
+        assert!(cpu.registers.status_aux_carry());
     }
     
     #[test]
@@ -2202,7 +2906,7 @@ mod tests {
         cpu.registers.set_l(0x50);
         
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(memory.read_byte(0x50), 0x3F);
     }
@@ -2219,10 +2923,10 @@ mod tests {
         cpu.registers.set_pair_b(56);
         cpu.registers.set_pair_d(57);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
         assert_eq!(cpu.registers.accumulator(), memory.read_byte(56));
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
         assert_eq!(cpu.registers.accumulator(), memory.read_byte(57));
     }
 
@@ -2240,11 +2944,11 @@ mod tests {
         cpu.registers.set_pair_d(176);
         cpu.registers.set_pair_b(150);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
         assert_eq!(cpu.registers.accumulator(), memory.read_byte(176));
         assert_eq!(cpu.registers.accumulator(), 0x35);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
         assert_eq!(cpu.registers.accumulator(), memory.read_byte(150));
         assert_eq!(cpu.registers.accumulator(), 0x19);
     }
@@ -2258,7 +2962,7 @@ mod tests {
         cpu.registers.set_pc(0);
         cpu.registers.set_accumulator(0x3E);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(0, cpu.registers.accumulator());
         assert!(!cpu.registers.status_carry());
@@ -2279,7 +2983,7 @@ mod tests {
         cpu.registers.set_l(0x02);
         cpu.registers.set_status_carry(true);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.accumulator(), 0x1);
         assert!(!cpu.registers.status_carry());
@@ -2299,10 +3003,11 @@ mod tests {
         cpu.registers.set_accumulator(0xFC);
         cpu.registers.set_c(0x0F);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.accumulator(), 0x0C);
         assert!(!cpu.registers.status_carry());
+        assert!(cpu.registers.status_aux_carry());
         assert!(!cpu.registers.status_zero());
         assert!(cpu.registers.status_parity());
         assert!(!cpu.registers.status_sign());
@@ -2318,7 +3023,7 @@ mod tests {
         cpu.registers.set_pc(0);
         cpu.registers.set_accumulator(0xFF);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.accumulator(), 0);
         assert!(!cpu.registers.status_carry());
@@ -2330,7 +3035,7 @@ mod tests {
         cpu.registers.set_accumulator(0xFF);
         cpu.registers.set_b(0x55);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.accumulator(), 0xAA);
         assert!(!cpu.registers.status_carry());
@@ -2350,7 +3055,7 @@ mod tests {
         cpu.registers.set_accumulator(0x33);
         cpu.registers.set_c(0x0F);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.accumulator(), 0x3F);
         assert!(!cpu.registers.status_carry());
@@ -2371,7 +3076,7 @@ mod tests {
         cpu.registers.set_accumulator(0x0A);
         cpu.registers.set_e(0x05);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.accumulator(), 0x0A);
         assert!(!cpu.registers.status_carry());
@@ -2380,21 +3085,23 @@ mod tests {
         cpu.registers.set_accumulator(0x02);
         cpu.registers.set_h(0x05);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.accumulator(), 0x02);
         assert!(cpu.registers.status_carry());
         assert!(!cpu.registers.status_zero());
+        assert!(!cpu.registers.status_aux_carry());
 
 
         cpu.registers.set_accumulator(0x05);
         cpu.registers.set_b(0x05);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.accumulator(), 0x05);
         assert!(!cpu.registers.status_carry());
         assert!(cpu.registers.status_zero());
+        assert!(cpu.registers.status_aux_carry());
     }
 
     #[test]
@@ -2406,7 +3113,7 @@ mod tests {
         cpu.registers.set_pc(0);
         cpu.registers.set_accumulator(0b11110010);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.accumulator(), 0b11100101);
         assert!(cpu.registers.status_carry());
@@ -2421,7 +3128,7 @@ mod tests {
         cpu.registers.set_pc(0);
         cpu.registers.set_accumulator(0b11110010);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.accumulator(), 0b01111001);
         assert!(!cpu.registers.status_carry());
@@ -2437,7 +3144,7 @@ mod tests {
         cpu.registers.set_accumulator(0b10110101);
         cpu.registers.set_status_carry(false);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.accumulator(), 0b01101010);
         assert!(cpu.registers.status_carry());
@@ -2453,7 +3160,7 @@ mod tests {
         cpu.registers.set_accumulator(0b01101010);
         cpu.registers.set_status_carry(true);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.accumulator(), 0b10110101);
         assert!(!cpu.registers.status_carry());
@@ -2471,17 +3178,17 @@ mod tests {
 
         let mut cpu = Intel8080::new();
         
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.pc(), 0x02);
         assert_eq!(cpu.registers.h(), 0x01);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
         
         assert_eq!(cpu.registers.pc(), 0x04);
         assert_eq!(cpu.registers.l(), 0xF4);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.pc(), 0x06);
         assert_eq!(memory.read_byte(0x01F4), 0xFF);
@@ -2497,7 +3204,7 @@ mod tests {
         cpu.registers.set_pc(0);
         cpu.registers.set_accumulator(0x14);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert!(cpu.registers.status_parity());
         assert!(!cpu.registers.status_zero());
@@ -2519,7 +3226,7 @@ mod tests {
         cpu.registers.set_accumulator(0x14);
         cpu.registers.set_status_carry(true);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert!(!cpu.registers.status_parity());
         assert!(!cpu.registers.status_zero());
@@ -2540,7 +3247,7 @@ mod tests {
         cpu.registers.set_pc(0);
         cpu.registers.set_accumulator(0x00);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert!(cpu.registers.status_parity());
         assert!(!cpu.registers.status_zero());
@@ -2565,7 +3272,7 @@ mod tests {
         cpu.registers.set_accumulator(0x00);
         cpu.registers.set_status_carry(false);
         
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
         
         assert_eq!(cpu.registers.accumulator(), 0xFF);
         assert_eq!(cpu.registers.pc(), 0x2);
@@ -2577,7 +3284,7 @@ mod tests {
         assert!(!cpu.registers.status_aux_carry());
 
         cpu.registers.set_accumulator(0x00);
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
         
         assert_eq!(cpu.registers.accumulator(), 0xFE);
         assert_eq!(cpu.registers.pc(), 0x4);
@@ -2599,13 +3306,13 @@ mod tests {
         cpu.registers.set_pc(0);
         cpu.registers.set_accumulator(0x3A);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert!(cpu.registers.status_parity());
         assert!(!cpu.registers.status_zero());
         assert!(!cpu.registers.status_sign());
         assert!(!cpu.registers.status_carry());
-        assert!(!cpu.registers.status_aux_carry());
+        assert!(cpu.registers.status_aux_carry());
 
         assert_eq!(cpu.registers.accumulator(), 0x0A);
         assert_eq!(cpu.registers.pc(), 0x02);
@@ -2621,7 +3328,7 @@ mod tests {
         cpu.registers.set_pc(0);
         cpu.registers.set_accumulator(0x3B);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert!(!cpu.registers.status_parity());
         assert!(!cpu.registers.status_zero());
@@ -2643,7 +3350,7 @@ mod tests {
         cpu.registers.set_pc(0);
         cpu.registers.set_accumulator(0xB5);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert!(!cpu.registers.status_parity());
         assert!(!cpu.registers.status_zero());
@@ -2665,13 +3372,14 @@ mod tests {
         cpu.registers.set_pc(0);
         cpu.registers.set_accumulator(0x4A);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert!(cpu.registers.status_parity());
         assert!(!cpu.registers.status_zero());
         assert!(!cpu.registers.status_sign());
         assert!(!cpu.registers.status_carry());
-        assert!(!cpu.registers.status_aux_carry());
+        // 0x4A + !0x40 + 1 carries out of bit 3
+        assert!(cpu.registers.status_aux_carry());
 
         assert_eq!(cpu.registers.accumulator(), 0x4A);
         assert_eq!(cpu.registers.pc(), 0x02);
@@ -2689,7 +3397,7 @@ mod tests {
         cpu.registers.set_h(0x00);
         cpu.registers.set_l(0xFF);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.d(), 0x00);
         assert_eq!(cpu.registers.e(), 0xFF);
@@ -2714,7 +3422,7 @@ mod tests {
         cpu.registers.set_l(0x3C);
 
         let initial_status = cpu.registers.status();
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.h(), 0x0D);
         assert_eq!(cpu.registers.l(), 0xF0);
@@ -2736,7 +3444,7 @@ mod tests {
         cpu.registers.set_h(0x50);
         cpu.registers.set_l(0x6C);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.sp(), 0x506C)
     }
@@ -2756,12 +3464,12 @@ mod tests {
         cpu.registers.set_pc(0);
         cpu.registers.set_accumulator(0x34);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(memory.read_byte(0x0124), 0x34);
         assert_eq!(cpu.registers.pc(), 0x3);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.accumulator(), 0x72);
         assert_eq!(cpu.registers.pc(), 0x6);
@@ -2784,13 +3492,13 @@ mod tests {
         cpu.registers.set_h(0xAE);
         cpu.registers.set_l(0x29);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(memory.read_byte(0x010A), 0x29);
         assert_eq!(memory.read_byte(0x010B), 0xAE);
         assert_eq!(cpu.registers.pc(), 0x3);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
         assert_eq!(cpu.registers.h(), 0x03);
         assert_eq!(cpu.registers.l(), 0xFF);
         assert_eq!(cpu.registers.pc(), 0x6);
@@ -2808,9 +3516,20 @@ mod tests {
         cpu.registers.set_h(0xA1);
         cpu.registers.set_l(0x7B);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.pair_h(), 0xD51A);
+        assert!(!cpu.registers.status_carry());
+
+        // carry comes from bit 15 and the sum wraps
+        cpu.registers.set_pc(0);
+        cpu.registers.set_pair_b(0x8001);
+        cpu.registers.set_pair_h(0x8000);
+
+        cpu.step(&mut memory, &mut ());
+
+        assert_eq!(cpu.registers.pair_h(), 0x0001);
+        assert!(cpu.registers.status_carry());
     }
 
     #[test]
@@ -2822,7 +3541,7 @@ mod tests {
         cpu.registers.set_h(0x98);
         cpu.registers.set_l(0x00);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.h(), 0x97);
         assert_eq!(cpu.registers.l(), 0xFF);
@@ -2837,7 +3556,7 @@ mod tests {
         cpu.registers.set_d(0x38);
         cpu.registers.set_e(0xFF);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.d(), 0x39);
         assert_eq!(cpu.registers.e(), 0x00);
@@ -2854,19 +3573,46 @@ mod tests {
         cpu.registers.set_e(0x9D);
         cpu.registers.set_sp(86);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.sp(), 84);
         assert_eq!(memory.read_byte(85), 0xBF);
         assert_eq!(memory.read_byte(84), 0x9D);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.sp(), 86);
         assert_eq!(cpu.registers.l(), 0x9D);
         assert_eq!(cpu.registers.h(), 0xBF);
     }
 
+    #[test]
+    fn test_push_pop_psw() {
+        let mut memory: Memory<100> = Memory::new();
+        memory.write_byte(0, Instruction::PUSH_PSW as u8);
+        memory.write_byte(1, Instruction::POP_PSW as u8);
+
+        let mut cpu = Intel8080::new();
+        cpu.registers.set_accumulator(0x12);
+        cpu.registers.set_status_byte(0x01);
+        cpu.registers.set_sp(86);
+
+        // A goes above the flags
+        cpu.step(&mut memory, &mut ());
+
+        assert_eq!(cpu.registers.sp(), 84);
+        assert_eq!(memory.read_byte(85), 0x12);
+        assert_eq!(memory.read_byte(84), 0x03);
+
+        memory.write_byte(85, 0x34);
+        memory.write_byte(84, 0xD7);
+        cpu.step(&mut memory, &mut ());
+
+        assert_eq!(cpu.registers.sp(), 86);
+        assert_eq!(cpu.registers.accumulator(), 0x34);
+        assert_eq!(cpu.registers.status(), 0xD7);
+    }
+
     #[test]
     fn test_pchl() {
         let mut memory: Memory<1> = Memory::new();
@@ -2877,7 +3623,7 @@ mod tests {
         cpu.registers.set_h(0x41);
         cpu.registers.set_l(0x3E);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.pc(), 0x413E);
     }
@@ -2893,14 +3639,14 @@ mod tests {
         let mut cpu = Intel8080::new();
         cpu.registers.set_pc(0x10);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.pc(), 0x0102);
 
         
         cpu.registers.set_status_carry(false);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.pc(), 0x0105);
     }
@@ -2923,7 +3669,7 @@ mod tests {
         cpu.registers.set_pc(0);
         cpu.registers.set_sp(10);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.pc(), 0x05);
         assert_eq!(cpu.registers.sp(), 0x08);
@@ -2945,7 +3691,7 @@ mod tests {
         cpu.registers.set_pc(0x00);
         cpu.registers.set_sp(0x08);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory, &mut ());
 
         assert_eq!(cpu.registers.pc(), 0x06);
         assert_eq!(cpu.registers.sp(), 0x0A);
@@ -2960,7 +3706,7 @@ mod tests {
         cpu.registers.set_pc(0x00);
         cpu.registers.set_sp(0x10);
 
-        let cycles = cpu.step(&mut memory);
+        let cycles = cpu.step(&mut memory, &mut ());
         
         assert_eq!(cycles, 11);
         assert_eq!(cpu.registers.sp(), 0x0E);
@@ -2969,6 +3715,70 @@ mod tests {
         assert_eq!(memory.read_byte(0x0F), 0x00);
     }
 
+    struct TestIo {
+        input: u8,
+        last_in: Option<u8>,
+        last_out: Option<(u8, u8)>,
+        inta: VecDeque<u8>,
+        wait_states: u64
+    }
+
+    impl TestIo {
+        fn new(input: u8) -> Self {
+            Self { input, last_in: None, last_out: None, inta: VecDeque::new(), wait_states: 0 }
+        }
+
+        fn with_interrupt(bytes: &[u8]) -> Self {
+            Self { inta: bytes.iter().copied().collect(), ..Self::new(0) }
+        }
+    }
+
+    impl IoBus for TestIo {
+        fn port_in(&mut self, port: u8) -> u8 {
+            self.last_in = Some(port);
+            self.input
+        }
+
+        fn port_out(&mut self, port: u8, val: u8) {
+            self.last_out = Some((port, val));
+        }
+
+        fn interrupt_ack(&mut self) -> u8 {
+            self.inta.pop_front().expect("unexpected INTA cycle")
+        }
+
+        fn wait_states(&self, _port: u8, _kind: MachineCycleKind) -> u64 {
+            self.wait_states
+        }
+    }
+
+    // Memory with a slow ROM below 0x40 that inserts two wait states per access
+    struct SlowRom {
+        memory: Memory<0x100>
+    }
+
+    impl MemoryAccess for SlowRom {
+        fn read_byte(&self, addr: u16) -> u8 {
+            self.memory.read_byte(addr)
+        }
+
+        fn write_byte(&mut self, addr: u16, val: u8) {
+            self.memory.write_byte(addr, val)
+        }
+
+        fn read_bytes<const C: usize>(&self, addr: u16) -> [u8; C] {
+            self.memory.read_bytes::<C>(addr)
+        }
+
+        fn write_bytes(&mut self, addr: u16, val: &[u8]) {
+            self.memory.write_bytes(addr, val)
+        }
+
+        fn wait_states(&self, addr: u16, _kind: MachineCycleKind) -> u64 {
+            if addr < 0x40 { 2 } else { 0 }
+        }
+    }
+
     #[test]
     fn test_input() {
         let mut memory: Memory<3> = Memory::new();
@@ -2976,18 +3786,16 @@ mod tests {
         memory.write_byte(1, 0x52);
         memory.write_byte(2, Instruction::NOP as u8);
 
+        let mut io = TestIo::new(0xA7);
         let mut cpu = Intel8080::new();
 
-        cpu.step(&mut memory);
+        let cycles = cpu.step(&mut memory, &mut io);
         
-        assert!(cpu.awaiting_input());
+        assert_eq!(cycles, 10);
+        assert_eq!(io.last_in, Some(0x52));
         assert_eq!(cpu.active_io_port(), 0x52);
-
-        cpu.write_input(0xFF);
-
-        cpu.step(&mut memory);
-        assert!(!cpu.awaiting_input());
-        assert_eq!(cpu.registers.accumulator(), 0xFF);
+        assert_eq!(cpu.registers.accumulator(), 0xA7);
+        assert_eq!(cpu.registers.pc(), 0x02);
     }
 
     #[test]
@@ -2997,18 +3805,18 @@ mod tests {
         memory.write_byte(1, 0x3C);
         memory.write_byte(2, Instruction::NOP as u8);
 
+        let mut io = TestIo::new(0x00);
         let mut cpu = Intel8080::new();
         cpu.registers.set_accumulator(0x5A);
 
-        cpu.step(&mut memory);
+        let cycles = cpu.step(&mut memory, &mut io);
         
-        assert!(cpu.output_ready());
+        assert_eq!(cycles, 10);
+        assert_eq!(io.last_out, Some((0x3C, 0x5A)));
         assert_eq!(cpu.active_io_port(), 0x3C);
-        assert_eq!(cpu.read_output(), 0x5A);
-
-        cpu.step(&mut memory);
-        assert!(!cpu.output_ready());
 
+        cpu.step(&mut memory, &mut io);
+        assert_eq!(io.last_out, Some((0x3C, 0x5A)));
     }
 
     #[test]
@@ -3018,44 +3826,575 @@ mod tests {
 
         let mut cpu = Intel8080::new();
 
-        let cycles = cpu.step(&mut memory);
+        let cycles = cpu.step(&mut memory, &mut ());
 
         assert_eq!(cycles, 7);
         assert!(cpu.stopped);
-        assert_eq!(cpu.step(&mut memory), 0);
+        // time passes while halted, for hosts clocking peripherals off it
+        assert_eq!(cpu.step(&mut memory, &mut ()), HALT_STATES);
+        assert_eq!(cpu.step(&mut memory, &mut ()), HALT_STATES);
+        assert_eq!(cpu.cycles(), 7 + 2 * HALT_STATES);
         assert_eq!(cpu.registers.pc(), 0x01);
+
+        let cycles = cpu.step_cycles(&mut memory, &mut (), &mut |_: &MachineCycle| 0);
+        assert_eq!(cycles, HALT_STATES);
+        assert_eq!(cpu.machine_cycles()[0].kind, MachineCycleKind::BusIdle);
     }
 
     #[test]
     fn test_interrupts() {
-        let mut memory: Memory<4> = Memory::new();
+        let mut memory: Memory<5> = Memory::new();
         memory.write_byte(0, Instruction::EI as u8);
-        memory.write_byte(1, Instruction::MOV_A_L as u8);
-        memory.write_byte(2, Instruction::DI as u8);
-        memory.write_byte(3, Instruction::XRA_A as u8);
+        memory.write_byte(1, Instruction::NOP as u8);
+        memory.write_byte(2, Instruction::MOV_A_L as u8);
+        memory.write_byte(3, Instruction::DI as u8);
+        memory.write_byte(4, Instruction::XRA_A as u8);
         
+        let mut io = TestIo::with_interrupt(&[Instruction::MOV_A_H as u8]);
         let mut cpu = Intel8080::new();
         cpu.registers.set_accumulator(0x75);
         cpu.registers.set_l(0x85);
         cpu.registers.set_h(0x55);
 
-        cpu.step(&mut memory); // EI
+        cpu.step(&mut memory, &mut io); // EI
+        cpu.step(&mut memory, &mut io); // NOP
 
-        cpu.interrupt(Instruction::MOV_A_H);
+        cpu.set_int(true);
 
-        cpu.step(&mut memory); // interrupt
-        assert_eq!(cpu.registers.pc(), 0x01);
+        cpu.step(&mut memory, &mut io); // interrupt
+        cpu.set_int(false);
+        assert_eq!(cpu.registers.pc(), 0x02);
         assert_eq!(cpu.registers.accumulator(), cpu.registers.h());
         assert_ne!(cpu.registers.accumulator(), cpu.registers.l());
+        assert!(!cpu.interrupts_enabled());
 
-        cpu.step(&mut memory); // MOV_A_L
-        cpu.step(&mut memory); // DI
+        cpu.step(&mut memory, &mut io); // MOV_A_L
+        cpu.step(&mut memory, &mut io); // DI
         
-        cpu.interrupt(Instruction::MOV_H_A);
+        cpu.set_int(true);
 
-        cpu.step(&mut memory); // XRA_A
-        assert_eq!(cpu.registers.pc(), 0x04);
+        cpu.step(&mut memory, &mut io); // XRA_A
+        assert_eq!(cpu.registers.pc(), 0x05);
         assert_ne!(cpu.registers.h(), cpu.registers.accumulator());
         assert_eq!(cpu.registers.accumulator(), 0x00);
     }
+
+    #[test]
+    fn test_ei_delay() {
+        // EI; RET must return before the pending interrupt is taken
+        let mut memory: Memory<0x20> = Memory::new();
+        memory.write_byte(0, Instruction::EI as u8);
+        memory.write_byte(1, Instruction::RET as u8);
+        memory.write_byte(0x10, 0x08);
+        memory.write_byte(0x11, 0x00);
+
+        let mut io = TestIo::with_interrupt(&[Instruction::RST_2 as u8]);
+        let mut cpu = Intel8080::new();
+        cpu.registers.set_sp(0x10);
+        cpu.set_int(true);
+
+        cpu.step(&mut memory, &mut io); // EI
+        assert!(cpu.interrupts_enabled());
+
+        cpu.step(&mut memory, &mut io); // RET
+        assert_eq!(cpu.registers.pc(), 0x08);
+        assert_eq!(cpu.registers.sp(), 0x12);
+
+        cpu.step(&mut memory, &mut io); // RST 1
+        assert_eq!(cpu.registers.pc(), 0x08);
+        assert_eq!(memory.read_byte(0x10), 0x08);
+        assert!(io.inta.is_empty());
+    }
+
+    #[test]
+    fn test_interrupt_pending_while_disabled() {
+        let mut memory: Memory<4> = Memory::new();
+        memory.write_byte(0, Instruction::NOP as u8);
+        memory.write_byte(1, Instruction::EI as u8);
+        memory.write_byte(2, Instruction::INR_B as u8);
+        memory.write_byte(3, Instruction::INR_B as u8);
+
+        let mut io = TestIo::with_interrupt(&[Instruction::INR_C as u8]);
+        let mut cpu = Intel8080::new();
+        cpu.set_int(true);
+
+        cpu.step(&mut memory, &mut io); // NOP, interrupts disabled
+        assert_eq!(cpu.registers.c(), 0x00);
+
+        cpu.step(&mut memory, &mut io); // EI
+        cpu.step(&mut memory, &mut io); // INR B
+        assert_eq!(cpu.registers.b(), 0x01);
+
+        cpu.step(&mut memory, &mut io); // interrupt
+        assert_eq!(cpu.registers.c(), 0x01);
+        assert_eq!(cpu.registers.pc(), 0x03);
+    }
+
+    #[test]
+    fn test_interrupt_clears_inte() {
+        let mut memory: Memory<3> = Memory::new();
+        memory.write_byte(0, Instruction::EI as u8);
+        memory.write_byte(1, Instruction::NOP as u8);
+        memory.write_byte(2, Instruction::NOP as u8);
+
+        let mut io = TestIo::with_interrupt(&[Instruction::INR_C as u8]);
+        let mut cpu = Intel8080::new();
+
+        cpu.step(&mut memory, &mut io); // EI
+        cpu.step(&mut memory, &mut io); // NOP
+        cpu.set_int(true);
+
+        cpu.step(&mut memory, &mut io); // interrupt
+        assert!(!cpu.interrupts_enabled());
+
+        // the line is still asserted, but interrupts are now disabled
+        cpu.step(&mut memory, &mut io); // NOP
+        assert_eq!(cpu.registers.c(), 0x01);
+        assert_eq!(cpu.registers.pc(), 0x03);
+    }
+
+    #[test]
+    fn test_interrupt_deasserted() {
+        let mut memory: Memory<2> = Memory::new();
+        memory.write_byte(0, Instruction::NOP as u8);
+        memory.write_byte(1, Instruction::NOP as u8);
+
+        let mut io = TestIo::with_interrupt(&[]);
+        let mut cpu = Intel8080::new();
+        cpu.inte = true;
+
+        cpu.set_int(true);
+        cpu.set_int(false);
+        cpu.step(&mut memory, &mut io);
+
+        assert_eq!(cpu.registers.pc(), 0x01);
+        assert!(cpu.interrupts_enabled());
+    }
+
+    #[test]
+    fn test_interrupt_wakes_hlt() {
+        let mut memory: Memory<0x20> = Memory::new();
+        memory.write_byte(0, Instruction::EI as u8);
+        memory.write_byte(1, Instruction::HLT as u8);
+
+        let mut io = TestIo::with_interrupt(&[Instruction::RST_2 as u8]);
+        let mut cpu = Intel8080::new();
+        cpu.registers.set_sp(0x20);
+
+        cpu.step(&mut memory, &mut io); // EI
+        cpu.step(&mut memory, &mut io); // HLT
+        assert!(cpu.halted());
+        assert_eq!(cpu.step(&mut memory, &mut io), HALT_STATES);
+
+        cpu.set_int(true);
+        let cycles = cpu.step(&mut memory, &mut io);
+
+        assert_eq!(cycles, 11);
+        assert!(!cpu.halted());
+        assert_eq!(cpu.registers.pc(), 0x08);
+        // the return address is the instruction after HLT
+        assert_eq!(memory.read_byte(0x1E), 0x02);
+    }
+
+    #[test]
+    fn test_hlt_with_interrupts_disabled() {
+        let mut memory: Memory<1> = Memory::new();
+        memory.write_byte(0, Instruction::HLT as u8);
+
+        let mut io = TestIo::with_interrupt(&[]);
+        let mut cpu = Intel8080::new();
+
+        cpu.step(&mut memory, &mut io);
+        cpu.set_int(true);
+
+        assert_eq!(cpu.step(&mut memory, &mut io), HALT_STATES);
+        assert!(cpu.halted());
+    }
+
+    #[test]
+    fn test_interrupt_rst() {
+        let mut memory: Memory<0x20> = Memory::new();
+        memory.write_byte(0x04, Instruction::NOP as u8);
+
+        let mut io = TestIo::with_interrupt(&[Instruction::RST_3 as u8]);
+        let mut cpu = Intel8080::new();
+        cpu.registers.set_pc(0x04);
+        cpu.registers.set_sp(0x20);
+        cpu.inte = true;
+
+        cpu.set_int(true);
+        let cycles = cpu.step(&mut memory, &mut io);
+
+        assert_eq!(cycles, 11);
+        assert_eq!(cpu.registers.pc(), 0x10);
+        assert_eq!(cpu.registers.sp(), 0x1E);
+        assert_eq!(memory.read_byte(0x1E), 0x04);
+        assert_eq!(memory.read_byte(0x1F), 0x00);
+    }
+
+    #[test]
+    fn test_interrupt_call() {
+        let mut memory: Memory<0x20> = Memory::new();
+        // operand bytes must come from the device, not from program memory
+        memory.write_byte(0x06, Instruction::NOP as u8);
+        memory.write_byte(0x07, 0xAA);
+        memory.write_byte(0x08, 0xBB);
+
+        let mut io = TestIo::with_interrupt(&[Instruction::CALL as u8, 0x34, 0x12]);
+        let mut cpu = Intel8080::new();
+        cpu.registers.set_pc(0x06);
+        cpu.registers.set_sp(0x20);
+        cpu.inte = true;
+
+        cpu.set_int(true);
+        let cycles = cpu.step(&mut memory, &mut io);
+
+        assert_eq!(cycles, 17);
+        assert!(io.inta.is_empty());
+        assert_eq!(cpu.registers.pc(), 0x1234);
+        assert_eq!(cpu.registers.sp(), 0x1E);
+        assert_eq!(memory.read_byte(0x1E), 0x06);
+        assert_eq!(memory.read_byte(0x1F), 0x00);
+    }
+
+    // 8080 instruction timings, with conditional calls and returns not taken
+    const CYCLES: [u64; 256] = [
+        4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4,
+        4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4,
+        4, 10, 16,  5,  5,  5,  7,  4,  4, 10, 16,  5,  5,  5,  7,  4,
+        4, 10, 13,  5, 10, 10, 10,  4,  4, 10, 13,  5,  5,  5,  7,  4,
+        5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5,
+        5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5,
+        5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5,
+        7,  7,  7,  7,  7,  7,  7,  7,  5,  5,  5,  5,  5,  5,  7,  5,
+        4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,
+        4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,
+        4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,
+        4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,
+        5, 10, 10, 10, 11, 11,  7, 11,  5, 10, 10, 10, 11, 17,  7, 11,
+        5, 10, 10, 10, 11, 11,  7, 11,  5, 10, 10, 10, 11, 17,  7, 11,
+        5, 10, 10, 18, 11, 11,  7, 11,  5,  5, 10,  4, 11, 17,  7, 11,
+        5, 10, 10,  4, 11, 11,  7, 11,  5,  5, 10,  4, 11, 17,  7, 11,
+    ];
+
+    #[test]
+    fn test_machine_cycles_all_opcodes() {
+        for status in [0x00, 0xFF] {
+            for opcode in 0..=255u8 {
+                let mut memory: Memory<0x10000> = Memory::new();
+                memory.write_bytes(0x100, &[opcode, 0x34, 0x12]);
+
+                let mut cpu = Intel8080::new();
+                cpu.registers.set_pc(0x100);
+                cpu.registers.set_sp(0x8000);
+                cpu.registers.set_pair_h(0x4000);
+                cpu.registers.set_status_byte(status);
+                let taken = cpu.check_condition(ConditionCode::from((opcode >> 3) & 0x07));
+
+                let cycles = cpu.step_cycles(&mut memory, &mut (), &mut |_: &MachineCycle| 0);
+
+                let mut expected = CYCLES[opcode as usize];
+                if taken && matches!(opcode & 0xC7, 0xC0 | 0xC4) {
+                    expected += 6;
+                }
+                assert_eq!(cycles, expected, "opcode {opcode:02X}");
+
+                let machine_cycles = cpu.machine_cycles();
+                let states: u64 = machine_cycles.iter().map(|cycle| cycle.states).sum();
+                assert_eq!(states, cycles, "opcode {opcode:02X}");
+                assert_eq!(machine_cycles[0].kind, MachineCycleKind::InstructionFetch);
+                assert_eq!(machine_cycles[0].status, 0xA2);
+                assert_eq!(machine_cycles[0].address, 0x100);
+                assert_eq!(machine_cycles[0].data, opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_machine_cycles_call() {
+        let mut memory: Memory<0x100> = Memory::new();
+        memory.write_bytes(0x10, &[Instruction::CALL as u8, 0x40, 0x00]);
+
+        let mut cpu = Intel8080::new();
+        cpu.registers.set_pc(0x10);
+        cpu.registers.set_sp(0x80);
+
+        let mut seen = Vec::new();
+        let cycles = cpu.step_cycles(&mut memory, &mut (), &mut |cycle: &MachineCycle| {
+            seen.push((cycle.kind, cycle.address, cycle.data, cycle.status, cycle.start));
+            0
+        });
+
+        assert_eq!(cycles, 17);
+        assert_eq!(seen, vec![
+            (MachineCycleKind::InstructionFetch, 0x10, 0xCD, 0xA2, 0),
+            (MachineCycleKind::MemoryRead, 0x11, 0x40, 0x82, 5),
+            (MachineCycleKind::MemoryRead, 0x12, 0x00, 0x82, 8),
+            (MachineCycleKind::StackWrite, 0x7F, 0x00, 0x04, 11),
+            (MachineCycleKind::StackWrite, 0x7E, 0x13, 0x04, 14),
+        ]);
+    }
+
+    #[test]
+    fn test_machine_cycles_io_and_halt() {
+        let mut memory: Memory<4> = Memory::new();
+        memory.write_bytes(0, &[Instruction::OUT as u8, 0x21, Instruction::HLT as u8]);
+
+        let mut cpu = Intel8080::new();
+        cpu.registers.set_accumulator(0x99);
+
+        cpu.step_cycles(&mut memory, &mut (), &mut |_: &MachineCycle| 0);
+        let out = cpu.machine_cycles()[2];
+        assert_eq!(out.kind, MachineCycleKind::OutputWrite);
+        assert_eq!(out.address, 0x2121);
+        assert_eq!(out.data, 0x99);
+        assert_eq!(out.status, 0x10);
+
+        cpu.step_cycles(&mut memory, &mut (), &mut |_: &MachineCycle| 0);
+        let halt = cpu.machine_cycles()[1];
+        assert_eq!(halt.kind, MachineCycleKind::HaltAcknowledge);
+        assert_eq!(halt.status, 0x8A);
+    }
+
+    #[test]
+    fn test_machine_cycles_interrupt() {
+        let mut memory: Memory<0x20> = Memory::new();
+        let mut io = TestIo::with_interrupt(&[Instruction::CALL as u8, 0x00, 0x01]);
+        let mut cpu = Intel8080::new();
+        cpu.registers.set_pc(0x05);
+        cpu.registers.set_sp(0x20);
+        cpu.inte = true;
+        cpu.stopped = true;
+        cpu.set_int(true);
+
+        let cycles = cpu.step_cycles(&mut memory, &mut io, &mut |_: &MachineCycle| 0);
+
+        assert_eq!(cycles, 17);
+        let kinds: Vec<MachineCycleKind> = cpu.machine_cycles().iter().map(|cycle| cycle.kind).collect();
+        assert_eq!(kinds, vec![
+            MachineCycleKind::InterruptAcknowledgeWhileHalt,
+            MachineCycleKind::InterruptOperand,
+            MachineCycleKind::InterruptOperand,
+            MachineCycleKind::StackWrite,
+            MachineCycleKind::StackWrite,
+        ]);
+        assert_eq!(cpu.machine_cycles()[0].status, 0x2B);
+    }
+
+    #[test]
+    fn test_bus_monitor_wait_states() {
+        let mut memory: Memory<0x100> = Memory::new();
+        memory.write_bytes(0, &[Instruction::LDA as u8, 0x80, 0x00, Instruction::NOP as u8]);
+        memory.write_byte(0x80, 0x5A);
+
+        let mut cpu = Intel8080::new();
+
+        // slow device at 0x80: one wait state per access
+        let mut monitor = |cycle: &MachineCycle| if cycle.address >= 0x80 { 1 } else { 0 };
+        let cycles = cpu.step_cycles(&mut memory, &mut (), &mut monitor);
+
+        assert_eq!(cycles, 14);
+        assert_eq!(cpu.registers.accumulator(), 0x5A);
+        let last = cpu.machine_cycles()[3];
+        assert_eq!(last.start, 10);
+        assert_eq!(last.wait_states, 1);
+
+        assert_eq!(cpu.step(&mut memory, &mut ()), 4);
+    }
+
+    #[test]
+    fn test_bus_monitor_sees_cycles_as_they_run() {
+        struct LoggedIo<'a>(&'a RefCell<Vec<String>>);
+
+        impl IoBus for LoggedIo<'_> {
+            fn port_in(&mut self, _port: u8) -> u8 {
+                0
+            }
+
+            fn port_out(&mut self, port: u8, val: u8) {
+                self.0.borrow_mut().push(format!("out {port:02X},{val:02X}"));
+            }
+        }
+
+        let mut memory: Memory<0x10> = Memory::new();
+        memory.write_bytes(0, &[Instruction::OUT as u8, 0x10]);
+
+        let log = RefCell::new(Vec::new());
+        let mut cpu = Intel8080::new();
+        let mut monitor = |cycle: &MachineCycle| {
+            log.borrow_mut().push(format!("{:?} at {}", cycle.kind, cycle.start));
+            1
+        };
+        let cycles = cpu.step_cycles(&mut memory, &mut LoggedIo(&log), &mut monitor);
+
+        assert_eq!(cycles, 13);
+        assert_eq!(log.into_inner(), vec!["InstructionFetch at 0", "MemoryRead at 5", "out 10,00", "OutputWrite at 9"]);
+    }
+
+    #[test]
+    fn test_memory_wait_states() {
+        let mut memory = SlowRom { memory: Memory::new() };
+        memory.write_bytes(0, &[Instruction::LDA as u8, 0x80, 0x00, Instruction::STA as u8, 0x10, 0x00]);
+        memory.write_byte(0x80, 0x33);
+
+        let mut cpu = Intel8080::new();
+
+        // fetch and both address bytes come from the ROM
+        assert_eq!(cpu.step(&mut memory, &mut ()), 13 + 6);
+        assert_eq!(cpu.registers.accumulator(), 0x33);
+
+        let cycles = cpu.step_cycles(&mut memory, &mut (), &mut |_: &MachineCycle| 0);
+        assert_eq!(cycles, 13 + 8);
+        let wait_states: Vec<u64> = cpu.machine_cycles().iter().map(|cycle| cycle.wait_states).collect();
+        assert_eq!(wait_states, vec![2, 2, 2, 2]);
+        assert_eq!(cpu.machine_cycles()[3].start, 4 + 2 + 3 + 2 + 3 + 2);
+    }
+
+    #[test]
+    fn test_io_wait_states() {
+        let mut memory: Memory<2> = Memory::new();
+        memory.write_bytes(0, &[Instruction::IN as u8, 0x10]);
+
+        let mut io = TestIo::new(0x42);
+        io.wait_states = 3;
+        let mut cpu = Intel8080::new();
+
+        assert_eq!(cpu.step(&mut memory, &mut io), 13);
+        assert_eq!(cpu.registers.accumulator(), 0x42);
+    }
+
+    #[test]
+    fn test_hold() {
+        let mut memory: Memory<0x20> = Memory::new();
+        memory.write_bytes(0, &[Instruction::LDA as u8, 0x10, 0x00]);
+
+        let mut cpu = Intel8080::new();
+        cpu.set_hold(true);
+
+        assert_eq!(cpu.step(&mut memory, &mut ()), 0);
+        assert!(cpu.hlda());
+        assert_eq!(cpu.registers.pc(), 0x00);
+
+        // the DMA device owns the bus for 8 cycles
+        memory.write_byte(0x10, 0x77);
+        cpu.dma_cycles(8);
+        assert_eq!(cpu.step(&mut memory, &mut ()), 8);
+        assert_eq!(cpu.stolen_cycles(), 8);
+
+        cpu.set_hold(false);
+        assert_eq!(cpu.step(&mut memory, &mut ()), 13);
+        assert!(!cpu.hlda());
+        assert_eq!(cpu.registers.accumulator(), 0x77);
+
+        // nothing is stolen outside of hold
+        cpu.dma_cycles(4);
+        assert_eq!(cpu.stolen_cycles(), 8);
+    }
+
+    #[test]
+    fn test_hold_between_machine_cycles() {
+        // a DMA request raised during the first address read of LDA
+        struct Dma {
+            requested: bool
+        }
+
+        impl BusMonitor for Dma {
+            fn machine_cycle(&mut self, _cycle: &MachineCycle) -> u64 {
+                0
+            }
+
+            fn hold(&mut self, cycle: &MachineCycle) -> u64 {
+                if cycle.kind == MachineCycleKind::MemoryRead && !self.requested {
+                    self.requested = true;
+                    return 8;
+                }
+                0
+            }
+        }
+
+        let mut memory: Memory<0x20> = Memory::new();
+        memory.write_bytes(0, &[Instruction::LDA as u8, 0x10, 0x00]);
+
+        let mut cpu = Intel8080::new();
+        let cycles = cpu.step_cycles(&mut memory, &mut (), &mut Dma { requested: false });
+
+        assert_eq!(cycles, 13 + 8);
+        assert_eq!(cpu.stolen_cycles(), 8);
+        assert_eq!(cpu.cycles(), 13 + 8);
+        assert!(!cpu.hlda());
+        let kinds: Vec<(MachineCycleKind, u64)> = cpu.machine_cycles().iter().map(|cycle| (cycle.kind, cycle.start)).collect();
+        assert_eq!(kinds, vec![
+            (MachineCycleKind::InstructionFetch, 0),
+            (MachineCycleKind::MemoryRead, 4),
+            (MachineCycleKind::Hold, 7),
+            (MachineCycleKind::MemoryRead, 15),
+            (MachineCycleKind::MemoryRead, 18)
+        ]);
+    }
+
+    #[test]
+    fn test_hold_while_halted() {
+        let mut memory: Memory<1> = Memory::new();
+        memory.write_byte(0, Instruction::HLT as u8);
+
+        let mut cpu = Intel8080::new();
+        cpu.step(&mut memory, &mut ());
+
+        cpu.set_hold(true);
+        cpu.step(&mut memory, &mut ());
+        assert!(cpu.hlda());
+
+        cpu.dma_cycles(3);
+        let cycles = cpu.step_cycles(&mut memory, &mut (), &mut |_: &MachineCycle| 0);
+        assert_eq!(cycles, 3);
+        assert_eq!(cpu.machine_cycles()[0].kind, MachineCycleKind::Hold);
+
+        cpu.set_hold(false);
+        assert_eq!(cpu.step(&mut memory, &mut ()), HALT_STATES);
+        assert!(cpu.halted());
+    }
+
+    #[test]
+    fn test_registers_mut() {
+        let mut memory: Memory<0x200> = Memory::new();
+        memory.write_byte(0x100, Instruction::PUSH_B as u8);
+
+        let mut cpu = Intel8080::new();
+        let registers = cpu.registers_mut();
+        registers.set_pc(0x100);
+        registers.set_sp(0x200);
+        registers.set_pair_b(0xBEEF);
+        registers.set_flags(Flags::ZERO | Flags::CARRY);
+
+        cpu.step(&mut memory, &mut ());
+
+        assert_eq!(cpu.registers().pc(), 0x101);
+        assert_eq!(cpu.registers().sp(), 0x1FE);
+        assert_eq!(memory.read_byte(0x1FE), 0xEF);
+        assert_eq!(memory.read_byte(0x1FF), 0xBE);
+        assert!(cpu.registers().status_zero());
+        assert!(cpu.registers().status_carry());
+        assert!(!cpu.registers().status_sign());
+    }
+
+    #[test]
+    fn test_flags() {
+        let mut registers = Registers::new();
+        assert_eq!(registers.flags(), Flags::empty());
+        assert_eq!(registers.status(), 0b0000_0010);
+
+        registers.set_status_byte(0xFF);
+        assert_eq!(registers.flags(), Flags::all());
+        assert_eq!(registers.status(), 0b1101_0111);
+        assert_eq!(registers.flags().to_string(), "SZ-A-P-C");
+
+        let mut flags = registers.flags();
+        flags.remove(Flags::SIGN | Flags::PARITY);
+        flags.set(Flags::AUX_CARRY, false);
+        registers.set_flags(flags);
+        assert_eq!(registers.flags().to_string(), "-Z-----C");
+        assert!(registers.flags().contains(Flags::ZERO | Flags::CARRY));
+        assert_eq!(!registers.flags(), Flags::SIGN | Flags::AUX_CARRY | Flags::PARITY);
+        assert_eq!(registers.status(), 0b0100_0011);
+    }
 }
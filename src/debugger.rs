@@ -0,0 +1,666 @@
+use std::collections::BTreeMap;
+
+use crate::bus::{MachineCycle, MachineCycleKind};
+use crate::cpu::{Flags, Instruction, Intel8080, Registers};
+use crate::io::IoBus;
+use crate::memory::MemoryAccess;
+
+// Breakpoints, watchpoints and stepping around Intel8080::step, shared by
+// the frontends. Every instruction runs through step_cycles so watchpoints
+// see the bus accesses it made; execution stops after the instruction that
+// made the access, as with hardware watchpoints.
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Register {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+    PSW
+}
+
+impl Register {
+    pub fn read(&self, registers: &Registers) -> u16 {
+        match self {
+            Register::A => registers.accumulator() as u16,
+            Register::B => registers.b() as u16,
+            Register::C => registers.c() as u16,
+            Register::D => registers.d() as u16,
+            Register::E => registers.e() as u16,
+            Register::H => registers.h() as u16,
+            Register::L => registers.l() as u16,
+            Register::BC => registers.pair_b(),
+            Register::DE => registers.pair_d(),
+            Register::HL => registers.pair_h(),
+            Register::SP => registers.sp(),
+            Register::PC => registers.pc(),
+            Register::PSW => registers.psw()
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual
+}
+
+impl Comparison {
+    pub fn compare(&self, left: u16, right: u16) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Condition {
+    // register compared with a value, as `register comparison value`
+    Register(Register, Comparison, u16),
+    // every flag in the set is set (true) or clear (false)
+    Flags(Flags, bool)
+}
+
+impl Condition {
+    pub fn holds(&self, registers: &Registers) -> bool {
+        match *self {
+            Condition::Register(register, comparison, value) => {
+                comparison.compare(register.read(registers), value)
+            },
+            Condition::Flags(flags, set) => {
+                let current = registers.flags();
+                match set {
+                    true => current.contains(flags),
+                    false => (current.bits() & flags.bits()) == 0
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Breakpoint {
+    pub address: u16,
+    pub enabled: bool,
+    // all must hold for the breakpoint to be hit
+    pub conditions: Vec<Condition>,
+    // hits passed over before the breakpoint stops execution
+    pub ignore: u64,
+    // times PC reached the address with the conditions holding
+    pub hits: u64
+}
+
+impl Breakpoint {
+    pub fn new(address: u16) -> Self {
+        Self {
+            address,
+            enabled: true,
+            conditions: Vec::new(),
+            ignore: 0,
+            hits: 0
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access
+}
+
+impl WatchKind {
+    fn matches(&self, kind: AccessKind) -> bool {
+        match self {
+            WatchKind::Read => kind == AccessKind::Read,
+            WatchKind::Write => kind == AccessKind::Write,
+            WatchKind::Access => true
+        }
+    }
+}
+
+// Memory watched from `address` for `len` bytes, wrapping at the top of memory
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Watchpoint {
+    pub address: u16,
+    pub len: u16,
+    pub kind: WatchKind
+}
+
+impl Watchpoint {
+    pub fn new(address: u16, len: u16, kind: WatchKind) -> Self {
+        Self {
+            address,
+            len,
+            kind
+        }
+    }
+
+    fn contains(&self, address: u16) -> bool {
+        address.wrapping_sub(self.address) < self.len
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AccessKind {
+    Read,
+    Write
+}
+
+// A bus access that triggered a watchpoint
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Access {
+    pub kind: AccessKind,
+    // kind of the watchpoint it matched
+    pub watch: WatchKind,
+    // memory address, or port for I/O
+    pub address: u16,
+    pub value: u8,
+    // the instruction that made the access
+    pub pc: u16
+}
+
+// A subroutine or interrupt handler entered through push_pc, found by
+// watching the stack writes of CALL, RST and acknowledged interrupts
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Frame {
+    // PC of the call, or where the interrupt was taken
+    pub call_site: u16,
+    pub return_address: u16,
+    // first instruction of the subroutine or handler
+    pub entry: u16,
+    // SP after the return address was pushed
+    pub sp: u16,
+    pub interrupt: bool
+}
+
+// Frames entered under Intel8080::step_cycles, innermost last. A frame is
+// left once SP rises above its return address, so RET, POPs of the return
+// address and stack resets all end it
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub(crate) struct FrameTracker {
+    frames: Vec<Frame>
+}
+
+impl FrameTracker {
+    pub(crate) fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub(crate) fn push(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    // Follow the machine cycles of the last step. Returns the frame it
+    // entered, if any
+    pub(crate) fn update(&mut self, cpu: &Intel8080) -> Option<Frame> {
+        // frames below SP have returned
+        let registers = cpu.registers();
+        let sp = registers.sp();
+        self.frames.retain(|frame| sp.wrapping_sub(frame.sp) as i16 <= 0);
+
+        let fetch = fetch_cycle(cpu.machine_cycles())?;
+        let pushed: Vec<u8> = cpu.machine_cycles().iter()
+            .filter(|cycle| cycle.kind == MachineCycleKind::StackWrite)
+            .map(|cycle| cycle.data)
+            .collect();
+        if !Instruction::from(fetch.data).is_call() || pushed.len() != 2 {
+            return None;
+        }
+
+        let frame = Frame {
+            call_site: fetch.address,
+            return_address: u16::from_le_bytes([pushed[1], pushed[0]]),
+            entry: registers.pc(),
+            sp,
+            interrupt: fetch.kind != MachineCycleKind::InstructionFetch
+        };
+        self.frames.push(frame);
+        Some(frame)
+    }
+}
+
+// The cycle an instruction or accepted interrupt was fetched in, None if the
+// step was spent halted or with the bus held
+pub(crate) fn fetch_cycle(cycles: &[MachineCycle]) -> Option<&MachineCycle> {
+    cycles.iter().find(|cycle| matches!(cycle.kind,
+        MachineCycleKind::InstructionFetch |
+        MachineCycleKind::InterruptAcknowledge |
+        MachineCycleKind::InterruptAcknowledgeWhileHalt))
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StopReason {
+    // a single step finished
+    Step,
+    Breakpoint(u16),
+    Watchpoint(Access),
+    PortWatchpoint(Access),
+    // the frame step_out was asked to leave returned
+    Returned,
+    // run_until reached its address
+    Reached(u16),
+    // halted, nothing runs until an interrupt
+    Halted,
+    // the step limit ran out
+    Limit
+}
+
+pub struct Debugger<M: MemoryAccess> {
+    cpu: Intel8080,
+    memory: M,
+    breakpoints: BTreeMap<u16, Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    port_watchpoints: BTreeMap<u8, WatchKind>,
+    frames: FrameTracker,
+    limit: Option<u64>
+}
+
+impl<M: MemoryAccess> Debugger<M> {
+    pub fn new(cpu: Intel8080, memory: M) -> Self {
+        Self {
+            cpu,
+            memory,
+            breakpoints: BTreeMap::new(),
+            watchpoints: Vec::new(),
+            port_watchpoints: BTreeMap::new(),
+            frames: FrameTracker::default(),
+            limit: None
+        }
+    }
+
+    pub fn cpu(&self) -> &Intel8080 {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Intel8080 {
+        &mut self.cpu
+    }
+
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
+    pub fn into_inner(self) -> (Intel8080, M) {
+        (self.cpu, self.memory)
+    }
+
+    // Replaces any breakpoint already at the address
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.insert(breakpoint.address, breakpoint);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> Option<Breakpoint> {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoint(&self, address: u16) -> Option<&Breakpoint> {
+        self.breakpoints.get(&address)
+    }
+
+    pub fn breakpoint_mut(&mut self, address: u16) -> Option<&mut Breakpoint> {
+        self.breakpoints.get_mut(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.values()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    // Removes one watchpoint equal to `watchpoint`, returning whether there was one
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        match self.watchpoints.iter().position(|other| *other == watchpoint) {
+            Some(index) => {
+                self.watchpoints.remove(index);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    // Replaces any watchpoint already on the port
+    pub fn add_port_watchpoint(&mut self, port: u8, kind: WatchKind) {
+        self.port_watchpoints.insert(port, kind);
+    }
+
+    pub fn remove_port_watchpoint(&mut self, port: u8) -> bool {
+        self.port_watchpoints.remove(&port).is_some()
+    }
+
+    // Frames entered while running under the debugger, innermost last
+    pub fn call_stack(&self) -> &[Frame] {
+        self.frames.frames()
+    }
+
+    // Instructions a single run, step over or step out may execute before
+    // stopping with StopReason::Limit. None runs without a limit
+    pub fn set_limit(&mut self, limit: Option<u64>) {
+        self.limit = limit;
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    // Execute one instruction, or accept a pending interrupt. Breakpoints are
+    // not checked
+    pub fn step_into(&mut self, io: &mut impl IoBus) -> StopReason {
+        self.execute(io).unwrap_or(StopReason::Step)
+    }
+
+    // Like step_into, but a CALL or RST that is taken, or an interrupt that
+    // is accepted, runs until it returns
+    pub fn step_over(&mut self, io: &mut impl IoBus) -> StopReason {
+        let depth = self.frames.frames().len();
+        if let Some(reason) = self.execute(io) {
+            return reason;
+        }
+        if self.frames.frames().len() <= depth {
+            return StopReason::Step;
+        }
+
+        self.run_while(io, |debugger| match debugger.frames.frames().len() <= depth {
+            true => Some(StopReason::Step),
+            false => None
+        })
+    }
+
+    // Run until the innermost frame returns. Without a frame entered under
+    // the debugger, the word on top of the stack is taken as the return
+    // address of the current subroutine
+    pub fn step_out(&mut self, io: &mut impl IoBus) -> StopReason {
+        if self.frames.frames().is_empty() {
+            let registers = self.cpu.registers();
+            let sp = registers.sp();
+            self.frames.push(Frame {
+                call_site: registers.pc(),
+                return_address: u16::from_le_bytes([self.memory.read_byte(sp), self.memory.read_byte(sp.wrapping_add(1))]),
+                entry: registers.pc(),
+                sp,
+                interrupt: false
+            });
+        }
+
+        let depth = self.frames.frames().len() - 1;
+        self.run_while(io, |debugger| match debugger.frames.frames().len() <= depth {
+            true => Some(StopReason::Returned),
+            false => None
+        })
+    }
+
+    // Run until a breakpoint, watchpoint, halt or the step limit
+    pub fn run(&mut self, io: &mut impl IoBus) -> StopReason {
+        self.run_while(io, |_| None)
+    }
+
+    // Run until PC reaches `address`. At least one instruction is executed,
+    // so this stops at the next arrival when PC is already there
+    pub fn run_until(&mut self, address: u16, io: &mut impl IoBus) -> StopReason {
+        self.run_while(io, |debugger| match debugger.cpu.registers().pc() == address {
+            true => Some(StopReason::Reached(address)),
+            false => None
+        })
+    }
+
+    // Execute instructions until one stops execution or `done` returns a
+    // reason. Breakpoints are checked from the second instruction on, so a
+    // run resumes from the breakpoint it stopped at
+    fn run_while(&mut self, io: &mut impl IoBus, mut done: impl FnMut(&Self) -> Option<StopReason>) -> StopReason {
+        let mut steps = 0;
+        loop {
+            if let Some(reason) = self.execute(io) {
+                return reason;
+            }
+            if let Some(reason) = done(self) {
+                return reason;
+            }
+            if let Some(address) = self.check_breakpoint() {
+                return StopReason::Breakpoint(address);
+            }
+            // checked after the breakpoint, which a resumed run would skip
+            steps += 1;
+            if self.limit.is_some_and(|limit| steps >= limit) {
+                return StopReason::Limit;
+            }
+        }
+    }
+
+    // Counts a hit on the breakpoint at PC, returning its address if it stops
+    fn check_breakpoint(&mut self) -> Option<u16> {
+        let registers = self.cpu.registers();
+        let breakpoint = self.breakpoints.get_mut(&registers.pc())?;
+        if !breakpoint.enabled || !breakpoint.conditions.iter().all(|condition| condition.holds(registers)) {
+            return None;
+        }
+
+        breakpoint.hits += 1;
+        match breakpoint.hits > breakpoint.ignore {
+            true => Some(breakpoint.address),
+            false => None
+        }
+    }
+
+    fn execute(&mut self, io: &mut impl IoBus) -> Option<StopReason> {
+        let pc = self.cpu.registers().pc();
+        self.cpu.step_cycles(&mut self.memory, io, &mut |_: &MachineCycle| 0);
+
+        let mut reason = None;
+        for cycle in self.cpu.machine_cycles() {
+            let (kind, port) = match cycle.kind {
+                MachineCycleKind::MemoryRead | MachineCycleKind::StackRead => (AccessKind::Read, false),
+                MachineCycleKind::MemoryWrite | MachineCycleKind::StackWrite => (AccessKind::Write, false),
+                MachineCycleKind::InputRead => (AccessKind::Read, true),
+                MachineCycleKind::OutputWrite => (AccessKind::Write, true),
+                _ => continue
+            };
+            if reason.is_some() {
+                continue;
+            }
+
+            let address = match port {
+                true => cycle.address & 0xff,
+                false => cycle.address
+            };
+            let watch = match port {
+                true => self.port_watchpoints.get(&(cycle.address as u8)).copied().filter(|watch| watch.matches(kind)),
+                false => self.watchpoints.iter()
+                    .find(|watch| watch.contains(cycle.address) && watch.kind.matches(kind))
+                    .map(|watch| watch.kind)
+            };
+            if let Some(watch) = watch {
+                let access = Access { kind, watch, address, value: cycle.data, pc };
+                reason = Some(match port {
+                    true => StopReason::PortWatchpoint(access),
+                    false => StopReason::Watchpoint(access)
+                });
+            }
+        }
+
+        self.frames.update(&self.cpu);
+
+        if reason.is_none() && self.cpu.halted() {
+            reason = Some(StopReason::Halted);
+        }
+        reason
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{assemble, Program};
+    use crate::memory::Memory;
+
+    fn debugger() -> (Debugger<Memory<0x100>>, Program) {
+        let program = assemble("
+        ORG     0
+        LXI     SP,80H
+        MVI     B,3
+LOOP:   CALL    BUMP
+NEXT:   DCR     B
+        JNZ     LOOP
+        OUT     10H
+DONE:   HLT
+BUMP:   LDA     VALUE
+        INR     A
+        STA     VALUE
+        RET
+VALUE:  DB      0
+").unwrap();
+
+        let mut memory = Memory::new();
+        program.load_into(&mut memory);
+        (Debugger::new(Intel8080::new(), memory), program)
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let (mut debugger, program) = debugger();
+        let bump = program.symbol("BUMP").unwrap();
+        let next = program.symbol("NEXT").unwrap();
+
+        let mut breakpoint = Breakpoint::new(bump);
+        breakpoint.ignore = 1;
+        debugger.add_breakpoint(breakpoint);
+        assert_eq!(debugger.run(&mut ()), StopReason::Breakpoint(bump));
+        assert_eq!(debugger.cpu().registers().b(), 2);
+        assert_eq!(debugger.breakpoint(bump).unwrap().hits, 2);
+
+        // a run resumes past the breakpoint it stopped at
+        assert_eq!(debugger.run(&mut ()), StopReason::Breakpoint(bump));
+        assert_eq!(debugger.cpu().registers().b(), 1);
+        debugger.breakpoint_mut(bump).unwrap().enabled = false;
+
+        let mut breakpoint = Breakpoint::new(next + 1);
+        breakpoint.conditions.push(Condition::Flags(Flags::ZERO, true));
+        debugger.add_breakpoint(breakpoint);
+        assert_eq!(debugger.run(&mut ()), StopReason::Breakpoint(next + 1));
+        assert_eq!(debugger.cpu().registers().b(), 0);
+        assert_eq!(debugger.breakpoint(next + 1).unwrap().hits, 1);
+
+        let (mut debugger, _) = self::debugger();
+        let mut breakpoint = Breakpoint::new(next);
+        breakpoint.conditions.push(Condition::Register(Register::A, Comparison::GreaterOrEqual, 2));
+        debugger.add_breakpoint(breakpoint);
+        assert_eq!(debugger.run(&mut ()), StopReason::Breakpoint(next));
+        assert_eq!(debugger.cpu().registers().b(), 2);
+
+        assert!(debugger.remove_breakpoint(next).is_some());
+        assert_eq!(debugger.run(&mut ()), StopReason::Halted);
+        assert_eq!(debugger.cpu().registers().pc(), program.symbol("DONE").unwrap() + 1);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let (mut debugger, program) = debugger();
+        let bump = program.symbol("BUMP").unwrap();
+        let value = program.symbol("VALUE").unwrap();
+
+        debugger.add_watchpoint(Watchpoint::new(value, 1, WatchKind::Write));
+        assert_eq!(debugger.run(&mut ()),
+            StopReason::Watchpoint(Access { kind: AccessKind::Write, watch: WatchKind::Write, address: value, value: 1, pc: bump + 4 }));
+        assert!(debugger.remove_watchpoint(Watchpoint::new(value, 1, WatchKind::Write)));
+
+        debugger.add_watchpoint(Watchpoint::new(value - 1, 2, WatchKind::Access));
+        assert_eq!(debugger.run(&mut ()),
+            StopReason::Watchpoint(Access { kind: AccessKind::Read, watch: WatchKind::Access, address: value, value: 1, pc: bump }));
+
+        // return addresses are stack writes
+        let (mut debugger, _) = self::debugger();
+        debugger.add_watchpoint(Watchpoint::new(0x7e, 2, WatchKind::Write));
+        assert_eq!(debugger.run(&mut ()),
+            StopReason::Watchpoint(Access { kind: AccessKind::Write, watch: WatchKind::Write, address: 0x7f, value: 0, pc: 5 }));
+
+        debugger.watchpoints.clear();
+        debugger.add_port_watchpoint(0x10, WatchKind::Write);
+        assert_eq!(debugger.run(&mut ()),
+            StopReason::PortWatchpoint(Access { kind: AccessKind::Write, watch: WatchKind::Write, address: 0x10, value: 3, pc: 0x0c }));
+        assert!(debugger.remove_port_watchpoint(0x10));
+    }
+
+    #[test]
+    fn test_stepping() {
+        let (mut debugger, program) = debugger();
+        let bump = program.symbol("BUMP").unwrap();
+        let next = program.symbol("NEXT").unwrap();
+
+        assert_eq!(debugger.step_into(&mut ()), StopReason::Step);
+        assert_eq!(debugger.step_into(&mut ()), StopReason::Step);
+        assert_eq!(debugger.step_into(&mut ()), StopReason::Step);
+        assert_eq!(debugger.cpu().registers().pc(), bump);
+        assert_eq!(debugger.call_stack(),
+            &[Frame { call_site: next - 3, return_address: next, entry: bump, sp: 0x7e, interrupt: false }]);
+
+        assert_eq!(debugger.step_out(&mut ()), StopReason::Returned);
+        assert_eq!(debugger.cpu().registers().pc(), next);
+        assert!(debugger.call_stack().is_empty());
+
+        // the CALL at LOOP runs through BUMP
+        debugger.step_over(&mut ());
+        debugger.step_over(&mut ());
+        assert_eq!(debugger.step_over(&mut ()), StopReason::Step);
+        assert_eq!(debugger.cpu().registers().pc(), next);
+        assert_eq!(debugger.memory().read_byte(program.symbol("VALUE").unwrap()), 2);
+
+        // unless a breakpoint is hit inside it
+        debugger.step_over(&mut ());
+        debugger.step_over(&mut ());
+        debugger.add_breakpoint(Breakpoint::new(bump + 3));
+        assert_eq!(debugger.step_over(&mut ()), StopReason::Breakpoint(bump + 3));
+
+        let done = program.symbol("DONE").unwrap();
+        assert_eq!(debugger.run_until(done, &mut ()), StopReason::Reached(done));
+        assert_eq!(debugger.step_into(&mut ()), StopReason::Halted);
+    }
+
+    #[test]
+    fn test_step_out_without_frame() {
+        let (mut debugger, program) = debugger();
+        let bump = program.symbol("BUMP").unwrap();
+
+        // entered BUMP without the debugger seeing the CALL
+        debugger.cpu_mut().registers_mut().set_sp(0x7e);
+        debugger.memory_mut().write_bytes(0x7e, &[0x34, 0x12]);
+        debugger.cpu_mut().registers_mut().set_pc(bump);
+
+        assert_eq!(debugger.step_out(&mut ()), StopReason::Returned);
+        assert_eq!(debugger.cpu().registers().pc(), 0x1234);
+        assert_eq!(debugger.cpu().registers().sp(), 0x80);
+    }
+
+    #[test]
+    fn test_limit() {
+        let (mut debugger, _) = debugger();
+        debugger.set_limit(Some(4));
+        assert_eq!(debugger.run(&mut ()), StopReason::Limit);
+        assert_eq!(debugger.cpu().cycles(), 10 + 7 + 17 + 13);
+    }
+}
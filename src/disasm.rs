@@ -0,0 +1,630 @@
+use std::fmt;
+
+use crate::cpu::Instruction;
+use crate::memory::MemoryAccess;
+
+// Disassembler producing Intel mnemonic syntax, e.g. `LXI H,1A2Bh`, or the
+// Z80 mnemonics for the same instruction, e.g. `LD HL,1A2Bh`.
+// Opcodes are decoded through Instruction, so undocumented opcodes come out
+// as the instruction the CPU executes for them (0xCB is JMP, 0x08 is NOP).
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Syntax {
+    // Intel 8080 mnemonics: MOV A,M
+    Intel,
+    // Zilog Z80 mnemonics: LD A,(HL)
+    Zilog
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Register {
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    M,
+    A
+}
+
+impl Register {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x7 {
+            0 => Register::B,
+            1 => Register::C,
+            2 => Register::D,
+            3 => Register::E,
+            4 => Register::H,
+            5 => Register::L,
+            6 => Register::M,
+            _ => Register::A
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Register::B => "B",
+            Register::C => "C",
+            Register::D => "D",
+            Register::E => "E",
+            Register::H => "H",
+            Register::L => "L",
+            Register::M => "M",
+            Register::A => "A"
+        }
+    }
+
+    pub fn zilog_name(&self) -> &'static str {
+        match self {
+            Register::M => "(HL)",
+            _ => self.name()
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RegisterPair {
+    B,
+    D,
+    H,
+    SP,
+    PSW
+}
+
+impl RegisterPair {
+    // Pair encoded in bits 4-5; `psw` selects PSW over SP for PUSH and POP
+    fn from_bits(bits: u8, psw: bool) -> Self {
+        match (bits >> 4) & 0x3 {
+            0 => RegisterPair::B,
+            1 => RegisterPair::D,
+            2 => RegisterPair::H,
+            _ if psw => RegisterPair::PSW,
+            _ => RegisterPair::SP
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegisterPair::B => "B",
+            RegisterPair::D => "D",
+            RegisterPair::H => "H",
+            RegisterPair::SP => "SP",
+            RegisterPair::PSW => "PSW"
+        }
+    }
+
+    pub fn zilog_name(&self) -> &'static str {
+        match self {
+            RegisterPair::B => "BC",
+            RegisterPair::D => "DE",
+            RegisterPair::H => "HL",
+            RegisterPair::SP => "SP",
+            RegisterPair::PSW => "AF"
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Condition {
+    NotZero,
+    Zero,
+    NoCarry,
+    Carry,
+    ParityOdd,
+    ParityEven,
+    Plus,
+    Minus
+}
+
+impl Condition {
+    fn from_bits(bits: u8) -> Self {
+        match (bits >> 3) & 0x7 {
+            0 => Condition::NotZero,
+            1 => Condition::Zero,
+            2 => Condition::NoCarry,
+            3 => Condition::Carry,
+            4 => Condition::ParityOdd,
+            5 => Condition::ParityEven,
+            6 => Condition::Plus,
+            _ => Condition::Minus
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Condition::NotZero => "NZ",
+            Condition::Zero => "Z",
+            Condition::NoCarry => "NC",
+            Condition::Carry => "C",
+            Condition::ParityOdd => "PO",
+            Condition::ParityEven => "PE",
+            Condition::Plus => "P",
+            Condition::Minus => "M"
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Operand {
+    Register(Register),
+    RegisterPair(RegisterPair),
+    // folded into the mnemonic in Intel syntax (JNZ, CC, RPE)
+    Condition(Condition),
+    // 8-bit immediate data
+    Byte(u8),
+    // 16-bit immediate data
+    Word(u16),
+    // memory address or jump target
+    Address(u16),
+    Port(u8),
+    // RST number 0-7
+    Restart(u8)
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Disassembly {
+    pub address: u16,
+    pub instruction: Instruction,
+    // Intel mnemonic, including the condition for conditional instructions.
+    // Used to derive the text in either syntax
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+    pub length: u16,
+    bytes: [u8; 3],
+    pub syntax: Syntax,
+    pub text: String
+}
+
+impl Disassembly {
+    // Raw bytes of the instruction, opcode first
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.length as usize]
+    }
+
+    // Text of the instruction in `syntax`, independent of the syntax it was
+    // disassembled with
+    pub fn format(&self, syntax: Syntax) -> String {
+        match syntax {
+            Syntax::Intel => format_intel(self.mnemonic, &self.operands),
+            Syntax::Zilog => format_zilog(self.mnemonic, &self.operands)
+        }
+    }
+
+    // Listing line with address and raw bytes: `0100  21 2B 1A  LXI H,1A2Bh`
+    pub fn listing_line(&self) -> String {
+        let bytes: Vec<String> = self.bytes().iter().map(|byte| format!("{byte:02X}")).collect();
+        format!("{:04X}  {:<8}  {}", self.address, bytes.join(" "), self.text)
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+const ALU_MNEMONICS: [&str; 8] = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"];
+const ALU_IMMEDIATE_MNEMONICS: [&str; 8] = ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"];
+const ROTATE_MNEMONICS: [&str; 8] = ["RLC", "RRC", "RAL", "RAR", "DAA", "CMA", "STC", "CMC"];
+
+const JUMP_MNEMONICS: [&str; 8] = ["JNZ", "JZ", "JNC", "JC", "JPO", "JPE", "JP", "JM"];
+const CALL_MNEMONICS: [&str; 8] = ["CNZ", "CZ", "CNC", "CC", "CPO", "CPE", "CP", "CM"];
+const RETURN_MNEMONICS: [&str; 8] = ["RNZ", "RZ", "RNC", "RC", "RPO", "RPE", "RP", "RM"];
+
+// Mnemonic and operands of an opcode, `operand` holding the little endian
+// bytes that follow it
+pub(crate) fn decode(instruction: Instruction, operand: [u8; 2]) -> (&'static str, Vec<Operand>) {
+    let opcode = instruction as u8;
+    let byte = operand[0];
+    let word = u16::from_le_bytes(operand);
+    let index = ((opcode >> 3) & 0x7) as usize;
+
+    let register = |bits: u8| Operand::Register(Register::from_bits(bits));
+    let pair = Operand::RegisterPair(RegisterPair::from_bits(opcode, false));
+    let stack_pair = Operand::RegisterPair(RegisterPair::from_bits(opcode, true));
+    let condition = Operand::Condition(Condition::from_bits(opcode));
+
+    match opcode {
+        0x00 => ("NOP", vec![]),
+        0x76 => ("HLT", vec![]),
+        0x40..=0x7f => ("MOV", vec![register(opcode >> 3), register(opcode)]),
+        0x80..=0xbf => (ALU_MNEMONICS[index], vec![register(opcode)]),
+        0x02 | 0x12 => ("STAX", vec![pair]),
+        0x0a | 0x1a => ("LDAX", vec![pair]),
+        0x22 => ("SHLD", vec![Operand::Address(word)]),
+        0x2a => ("LHLD", vec![Operand::Address(word)]),
+        0x32 => ("STA", vec![Operand::Address(word)]),
+        0x3a => ("LDA", vec![Operand::Address(word)]),
+        0xc3 => ("JMP", vec![Operand::Address(word)]),
+        0xcd => ("CALL", vec![Operand::Address(word)]),
+        0xc9 => ("RET", vec![]),
+        0xe9 => ("PCHL", vec![]),
+        0xf9 => ("SPHL", vec![]),
+        0xe3 => ("XTHL", vec![]),
+        0xeb => ("XCHG", vec![]),
+        0xf3 => ("DI", vec![]),
+        0xfb => ("EI", vec![]),
+        0xdb => ("IN", vec![Operand::Port(byte)]),
+        0xd3 => ("OUT", vec![Operand::Port(byte)]),
+        _ => match (opcode >> 6, opcode & 0xf, opcode & 0x7) {
+            (0, 0x1, _) => ("LXI", vec![pair, Operand::Word(word)]),
+            (0, 0x3, _) => ("INX", vec![pair]),
+            (0, 0x9, _) => ("DAD", vec![pair]),
+            (0, 0xb, _) => ("DCX", vec![pair]),
+            (0, _, 0x4) => ("INR", vec![register(opcode >> 3)]),
+            (0, _, 0x5) => ("DCR", vec![register(opcode >> 3)]),
+            (0, _, 0x6) => ("MVI", vec![register(opcode >> 3), Operand::Byte(byte)]),
+            (0, _, 0x7) => (ROTATE_MNEMONICS[index], vec![]),
+            (3, _, 0x0) => (RETURN_MNEMONICS[index], vec![condition]),
+            (3, 0x1, _) => ("POP", vec![stack_pair]),
+            (3, _, 0x2) => (JUMP_MNEMONICS[index], vec![condition, Operand::Address(word)]),
+            (3, _, 0x4) => (CALL_MNEMONICS[index], vec![condition, Operand::Address(word)]),
+            (3, 0x5, _) => ("PUSH", vec![stack_pair]),
+            (3, _, 0x6) => (ALU_IMMEDIATE_MNEMONICS[index], vec![Operand::Byte(byte)]),
+            (3, _, 0x7) => ("RST", vec![Operand::Restart(index as u8)]),
+            _ => unreachable!("opcode {opcode:#04x} is not canonical")
+        }
+    }
+}
+
+// Intel style hex number: `h` suffix, leading 0 when the first digit is a letter
+fn hex(val: u16, digits: usize) -> String {
+    let digits = format!("{val:0digits$X}");
+    if digits.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("0{digits}h")
+    }
+    else {
+        format!("{digits}h")
+    }
+}
+
+fn join(mnemonic: &str, operands: &[String]) -> String {
+    if operands.is_empty() {
+        mnemonic.to_string()
+    }
+    else {
+        format!("{} {}", mnemonic, operands.join(","))
+    }
+}
+
+fn format_operand(operand: &Operand) -> String {
+    match operand {
+        Operand::Register(register) => register.name().to_string(),
+        Operand::RegisterPair(pair) => pair.name().to_string(),
+        Operand::Condition(condition) => condition.name().to_string(),
+        Operand::Byte(val) | Operand::Port(val) => hex(*val as u16, 2),
+        Operand::Word(val) | Operand::Address(val) => hex(*val, 4),
+        Operand::Restart(n) => n.to_string()
+    }
+}
+
+fn format_intel(mnemonic: &str, operands: &[Operand]) -> String {
+    let operands: Vec<String> = operands.iter()
+        .filter(|operand| !matches!(operand, Operand::Condition(_)))
+        .map(format_operand)
+        .collect();
+
+    join(mnemonic, &operands)
+}
+
+fn format_zilog_operand(operand: &Operand) -> String {
+    match operand {
+        Operand::Register(register) => register.zilog_name().to_string(),
+        Operand::RegisterPair(pair) => pair.zilog_name().to_string(),
+        Operand::Restart(n) => hex(*n as u16 * 8, 2),
+        _ => format_operand(operand)
+    }
+}
+
+fn format_zilog(mnemonic: &str, operands: &[Operand]) -> String {
+    let operands: Vec<String> = operands.iter().map(format_zilog_operand).collect();
+    let op = |i: usize| operands[i].clone();
+    let indirect = |i: usize| format!("({})", operands[i]);
+
+    match mnemonic {
+        "MOV" | "MVI" | "LXI" => join("LD", &operands),
+        "STAX" | "STA" => join("LD", &[indirect(0), "A".to_string()]),
+        "LDAX" | "LDA" => join("LD", &["A".to_string(), indirect(0)]),
+        "SHLD" => join("LD", &[indirect(0), "HL".to_string()]),
+        "LHLD" => join("LD", &["HL".to_string(), indirect(0)]),
+        "SPHL" => "LD SP,HL".to_string(),
+        "INR" | "INX" => join("INC", &operands),
+        "DCR" | "DCX" => join("DEC", &operands),
+        "DAD" => join("ADD", &["HL".to_string(), op(0)]),
+        "ADD" | "ADI" => join("ADD", &["A".to_string(), op(0)]),
+        "ADC" | "ACI" => join("ADC", &["A".to_string(), op(0)]),
+        "SBB" | "SBI" => join("SBC", &["A".to_string(), op(0)]),
+        "SUB" | "SUI" => join("SUB", &operands),
+        "ANA" | "ANI" => join("AND", &operands),
+        "XRA" | "XRI" => join("XOR", &operands),
+        "ORA" | "ORI" => join("OR", &operands),
+        "CMP" | "CPI" => join("CP", &operands),
+        "RLC" => "RLCA".to_string(),
+        "RRC" => "RRCA".to_string(),
+        "RAL" => "RLA".to_string(),
+        "RAR" => "RRA".to_string(),
+        "CMA" => "CPL".to_string(),
+        "STC" => "SCF".to_string(),
+        "CMC" => "CCF".to_string(),
+        "HLT" => "HALT".to_string(),
+        "JMP" => join("JP", &operands),
+        "PCHL" => "JP (HL)".to_string(),
+        "XTHL" => "EX (SP),HL".to_string(),
+        "XCHG" => "EX DE,HL".to_string(),
+        "IN" => join("IN", &["A".to_string(), indirect(0)]),
+        "OUT" => join("OUT", &[indirect(0), "A".to_string()]),
+        "NOP" | "DAA" | "EI" | "DI" | "PUSH" | "POP" | "CALL" | "RET" | "RST" => join(mnemonic, &operands),
+        // conditional jump, call and return; the condition is the first operand
+        _ => match mnemonic.as_bytes()[0] {
+            b'J' => join("JP", &operands),
+            b'C' => join("CALL", &operands),
+            _ => join("RET", &operands)
+        }
+    }
+}
+
+// Decode the instruction at `addr`. Operand bytes wrap around at 0xFFFF
+pub fn disassemble(memory: &impl MemoryAccess, addr: u16, syntax: Syntax) -> Disassembly {
+    let opcode = memory.read_byte(addr);
+    let length = Instruction::from(opcode).length();
+
+    let mut bytes = [opcode, 0, 0];
+    for i in 1..length {
+        bytes[i as usize] = memory.read_byte(addr.wrapping_add(i));
+    }
+
+    disassemble_bytes(bytes, addr, syntax)
+}
+
+// Decode an instruction already fetched, `addr` is where it came from. Bytes
+// past the instruction's length are ignored
+pub fn disassemble_bytes(bytes: [u8; 3], addr: u16, syntax: Syntax) -> Disassembly {
+    let instruction = Instruction::from(bytes[0]);
+    let length = instruction.length();
+    let mut bytes = bytes;
+    bytes[length as usize..].fill(0);
+
+    let (mnemonic, operands) = decode(instruction, [bytes[1], bytes[2]]);
+    let text = match syntax {
+        Syntax::Intel => format_intel(mnemonic, &operands),
+        Syntax::Zilog => format_zilog(mnemonic, &operands)
+    };
+
+    Disassembly {
+        address: addr,
+        instruction,
+        mnemonic,
+        operands,
+        length,
+        bytes,
+        syntax,
+        text
+    }
+}
+
+// Decode every instruction starting in `start..end`. The last instruction
+// may extend past `end`
+pub fn disassemble_range(memory: &impl MemoryAccess, start: u16, end: u16, syntax: Syntax) -> Vec<Disassembly> {
+    let mut listing = Vec::new();
+    let mut addr = start as u32;
+
+    while addr < end as u32 {
+        let disassembly = disassemble(memory, addr as u16, syntax);
+        addr += disassembly.length as u32;
+        listing.push(disassembly);
+    }
+
+    listing
+}
+
+// Listing of `start..end`, one instruction per line
+pub fn listing(memory: &impl MemoryAccess, start: u16, end: u16, syntax: Syntax) -> String {
+    disassemble_range(memory, start, end, syntax).iter()
+        .map(|disassembly| disassembly.listing_line() + "\n")
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    fn text(bytes: &[u8]) -> String {
+        let mut memory: Memory<0x10> = Memory::new();
+        memory.write_bytes(0, bytes);
+        disassemble(&memory, 0, Syntax::Intel).text
+    }
+
+    fn zilog(bytes: &[u8]) -> String {
+        let mut memory: Memory<0x10> = Memory::new();
+        memory.write_bytes(0, bytes);
+        disassemble(&memory, 0, Syntax::Zilog).text
+    }
+
+    #[test]
+    fn test_intel_syntax() {
+        assert_eq!(text(&[0x21, 0x2b, 0x1a]), "LXI H,1A2Bh");
+        assert_eq!(text(&[0x36, 0xff]), "MVI M,0FFh");
+        assert_eq!(text(&[0x7e]), "MOV A,M");
+        assert_eq!(text(&[0x76]), "HLT");
+        assert_eq!(text(&[0x31, 0x00, 0xf0]), "LXI SP,0F000h");
+        assert_eq!(text(&[0xf5]), "PUSH PSW");
+        assert_eq!(text(&[0xc1]), "POP B");
+        assert_eq!(text(&[0x39]), "DAD SP");
+        assert_eq!(text(&[0x1a]), "LDAX D");
+        assert_eq!(text(&[0x32, 0x34, 0x12]), "STA 1234h");
+        assert_eq!(text(&[0xc2, 0x00, 0x01]), "JNZ 0100h");
+        assert_eq!(text(&[0xec, 0xcd, 0xab]), "CPE 0ABCDh");
+        assert_eq!(text(&[0xf8]), "RM");
+        assert_eq!(text(&[0xfe, 0x0d]), "CPI 0Dh");
+        assert_eq!(text(&[0x9e]), "SBB M");
+        assert_eq!(text(&[0xdb, 0x10]), "IN 10h");
+        assert_eq!(text(&[0xff]), "RST 7");
+        assert_eq!(text(&[0xc7]), "RST 0");
+        assert_eq!(text(&[0x27]), "DAA");
+
+        // undocumented opcodes decode as the instruction they execute
+        assert_eq!(text(&[0x08]), "NOP");
+        assert_eq!(text(&[0xcb, 0x00, 0x20]), "JMP 2000h");
+        assert_eq!(text(&[0xdd, 0x34, 0x12]), "CALL 1234h");
+    }
+
+    #[test]
+    fn test_zilog_syntax() {
+        assert_eq!(zilog(&[0x21, 0x2b, 0x1a]), "LD HL,1A2Bh");
+        assert_eq!(zilog(&[0x36, 0xff]), "LD (HL),0FFh");
+        assert_eq!(zilog(&[0x7e]), "LD A,(HL)");
+        assert_eq!(zilog(&[0x76]), "HALT");
+        assert_eq!(zilog(&[0xf5]), "PUSH AF");
+        assert_eq!(zilog(&[0x39]), "ADD HL,SP");
+        assert_eq!(zilog(&[0x1a]), "LD A,(DE)");
+        assert_eq!(zilog(&[0x02]), "LD (BC),A");
+        assert_eq!(zilog(&[0x32, 0x34, 0x12]), "LD (1234h),A");
+        assert_eq!(zilog(&[0x2a, 0x34, 0x12]), "LD HL,(1234h)");
+        assert_eq!(zilog(&[0xc2, 0x00, 0x01]), "JP NZ,0100h");
+        assert_eq!(zilog(&[0xf2, 0x00, 0x01]), "JP P,0100h");
+        assert_eq!(zilog(&[0xec, 0xcd, 0xab]), "CALL PE,0ABCDh");
+        assert_eq!(zilog(&[0xf8]), "RET M");
+        assert_eq!(zilog(&[0xfe, 0x0d]), "CP 0Dh");
+        assert_eq!(zilog(&[0x9e]), "SBC A,(HL)");
+        assert_eq!(zilog(&[0x90]), "SUB B");
+        assert_eq!(zilog(&[0x34]), "INC (HL)");
+        assert_eq!(zilog(&[0x0b]), "DEC BC");
+        assert_eq!(zilog(&[0xdb, 0x10]), "IN A,(10h)");
+        assert_eq!(zilog(&[0xd3, 0xfe]), "OUT (0FEh),A");
+        assert_eq!(zilog(&[0xff]), "RST 38h");
+        assert_eq!(zilog(&[0xe9]), "JP (HL)");
+        assert_eq!(zilog(&[0xe3]), "EX (SP),HL");
+        assert_eq!(zilog(&[0xeb]), "EX DE,HL");
+        assert_eq!(zilog(&[0x2f]), "CPL");
+    }
+
+    #[test]
+    fn test_dialects_agree() {
+        // Zilog mnemonic for each Intel mnemonic
+        fn zilog_mnemonic(intel: &'static str) -> &'static str {
+            match intel {
+                "MOV" | "MVI" | "LXI" | "STAX" | "LDAX" | "SHLD" | "LHLD" | "STA" | "LDA" | "SPHL" => "LD",
+                "INR" | "INX" => "INC",
+                "DCR" | "DCX" => "DEC",
+                "DAD" | "ADD" | "ADI" => "ADD",
+                "ADC" | "ACI" => "ADC",
+                "SBB" | "SBI" => "SBC",
+                "SUB" | "SUI" => "SUB",
+                "ANA" | "ANI" => "AND",
+                "XRA" | "XRI" => "XOR",
+                "ORA" | "ORI" => "OR",
+                "CMP" | "CPI" => "CP",
+                "RLC" => "RLCA",
+                "RRC" => "RRCA",
+                "RAL" => "RLA",
+                "RAR" => "RRA",
+                "CMA" => "CPL",
+                "STC" => "SCF",
+                "CMC" => "CCF",
+                "HLT" => "HALT",
+                "JMP" | "PCHL" => "JP",
+                "XTHL" | "XCHG" => "EX",
+                "CALL" => "CALL",
+                "RET" => "RET",
+                _ if intel.starts_with('J') => "JP",
+                _ if intel.starts_with('C') => "CALL",
+                _ if intel.starts_with('R') && intel.len() <= 3 && intel != "RST" => "RET",
+                _ => intel
+            }
+        }
+
+        for opcode in 0..=0xffu8 {
+            let mut memory: Memory<0x10> = Memory::new();
+            memory.write_bytes(0, &[opcode, 0xcd, 0xab]);
+            let intel = disassemble(&memory, 0, Syntax::Intel);
+            let zilog = disassemble(&memory, 0, Syntax::Zilog);
+
+            assert_eq!(intel.instruction, zilog.instruction);
+            assert_eq!(intel.length, zilog.length);
+            assert_eq!(intel.bytes(), zilog.bytes());
+            assert_eq!(intel.operands, zilog.operands);
+            assert_eq!(intel.format(Syntax::Zilog), zilog.text);
+            assert_eq!(zilog.format(Syntax::Intel), intel.text);
+
+            let zilog_text = zilog.text.split(' ').next().unwrap();
+            assert_eq!(zilog_text, zilog_mnemonic(intel.mnemonic), "opcode {opcode:#04x}");
+
+            // immediate data, addresses and ports come out the same in both
+            for operand in &intel.operands {
+                let number = match operand {
+                    Operand::Byte(_) | Operand::Word(_) | Operand::Address(_) | Operand::Port(_) => {
+                        format_operand(operand)
+                    },
+                    _ => continue
+                };
+                assert!(intel.text.ends_with(&number) || intel.text.contains(&format!("{number},")));
+                assert!(zilog.text.contains(&number), "opcode {opcode:#04x}: {}", zilog.text);
+            }
+
+            // condition codes read the same, only Intel folds them into the mnemonic
+            if let Some(Operand::Condition(condition)) = intel.operands.first() {
+                assert!(intel.mnemonic.ends_with(condition.name()));
+                assert!(zilog.text.contains(&format!(" {},", condition.name()))
+                    || zilog.text.ends_with(&format!(" {}", condition.name())));
+            }
+        }
+    }
+
+    #[test]
+    fn test_operands() {
+        let mut memory: Memory<0x10> = Memory::new();
+        memory.write_bytes(0, &[0xda, 0x34, 0x12]);
+        let disassembly = disassemble(&memory, 0, Syntax::Intel);
+
+        assert_eq!(disassembly.instruction, Instruction::JC);
+        assert_eq!(disassembly.mnemonic, "JC");
+        assert_eq!(disassembly.length, 3);
+        assert_eq!(disassembly.bytes(), &[0xda, 0x34, 0x12]);
+        assert_eq!(disassembly.operands, vec![Operand::Condition(Condition::Carry), Operand::Address(0x1234)]);
+    }
+
+    #[test]
+    fn test_all_opcodes() {
+        for opcode in 0..=0xffu8 {
+            let mut memory: Memory<0x10> = Memory::new();
+            memory.write_bytes(0, &[opcode, 0x34, 0x12]);
+            let disassembly = disassemble(&memory, 0, Syntax::Intel);
+            let instruction = Instruction::from(opcode);
+
+            assert_eq!(disassembly.length, instruction.length());
+            // the Debug name starts with the mnemonic for everything but RST
+            let name = format!("{:?}", instruction);
+            if disassembly.mnemonic != "RST" {
+                assert_eq!(name.split('_').next().unwrap(), disassembly.mnemonic, "opcode {opcode:#04x}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_listing() {
+        let mut memory: Memory<0x10> = Memory::new();
+        memory.write_bytes(0, &[0x21, 0x2b, 0x1a, 0x36, 0xff, 0x76]);
+
+        assert_eq!(listing(&memory, 0, 6, Syntax::Intel),
+            "0000  21 2B 1A  LXI H,1A2Bh\n\
+             0003  36 FF     MVI M,0FFh\n\
+             0005  76        HLT\n");
+
+        // an instruction straddling the end is listed in full
+        let range = disassemble_range(&memory, 0, 2, Syntax::Intel);
+        assert_eq!(range.len(), 1);
+        assert_eq!(range[0].length, 3);
+    }
+
+    #[test]
+    fn test_wraps_at_end_of_memory() {
+        let mut memory: Memory<0x10000> = Memory::new();
+        memory.write_byte(0xffff, 0xc3);
+        memory.write_bytes(0, &[0x34, 0x12]);
+
+        assert_eq!(disassemble(&memory, 0xffff, Syntax::Intel).text, "JMP 1234h");
+        assert_eq!(disassemble_range(&memory, 0xfffe, 0xffff, Syntax::Intel).len(), 1);
+    }
+}
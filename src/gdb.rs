@@ -0,0 +1,645 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use log::{debug, warn};
+
+use crate::debugger::{AccessKind, Breakpoint, Debugger, StopReason, WatchKind, Watchpoint};
+use crate::io::IoBus;
+use crate::memory::MemoryAccess;
+
+// GDB remote serial protocol stub. A client attaches over TCP or a Unix
+// socket and drives a Debugger: registers, memory, breakpoints, watchpoints,
+// single steps and continues. GDB has no 8080 architecture, so the target
+// description only names the registers; `g` sends them in its order, each
+// little endian.
+
+// Instructions run between checks for an interrupt (^C) from the client
+const RUN_SLICE: u64 = 10_000;
+
+const PACKET_SIZE: usize = 0x1000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.emu8080.i8080.cpu">
+    <reg name="af" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="uint16"/>
+    <reg name="hl" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTER_COUNT: usize = 6;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum GdbError {
+    Io(String)
+}
+
+impl fmt::Display for GdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GdbError::Io(message) => write!(f, "I/O error: {message}")
+        }
+    }
+}
+
+impl std::error::Error for GdbError {}
+
+impl From<io::Error> for GdbError {
+    fn from(error: io::Error) -> Self {
+        GdbError::Io(error.to_string())
+    }
+}
+
+// Why a session ended
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GdbExit {
+    Detached,
+    Killed,
+    Disconnected
+}
+
+// A stream the stub can poll for ^C while the target runs
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+// Wait for one client on `addr` and serve it until it detaches or disconnects
+pub fn serve_tcp<M: MemoryAccess>(addr: impl ToSocketAddrs,
+    debugger: &mut Debugger<M>,
+    io: &mut impl IoBus) -> Result<GdbExit, GdbError> {
+
+    let listener = TcpListener::bind(addr)?;
+    let (stream, peer) = listener.accept()?;
+    debug!("GDB client connected from {peer}");
+    stream.set_nodelay(true)?;
+    serve(stream, debugger, io)
+}
+
+#[cfg(unix)]
+pub fn serve_unix<M: MemoryAccess>(path: impl AsRef<std::path::Path>,
+    debugger: &mut Debugger<M>,
+    io: &mut impl IoBus) -> Result<GdbExit, GdbError> {
+
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    let (stream, _) = listener.accept()?;
+    serve(stream, debugger, io)
+}
+
+// Serve a client already connected on `connection`
+pub fn serve<M: MemoryAccess>(connection: impl Connection,
+    debugger: &mut Debugger<M>,
+    io: &mut impl IoBus) -> Result<GdbExit, GdbError> {
+
+    let mut session = Session {
+        connection,
+        debugger,
+        io,
+        input: VecDeque::new(),
+        ack: true
+    };
+    session.run()
+}
+
+enum Reply {
+    Packet(String),
+    Exit(GdbExit)
+}
+
+// What the client did while the target ran
+enum Poll {
+    Running,
+    Interrupted,
+    Disconnected
+}
+
+struct Session<'a, C: Connection, M: MemoryAccess, I: IoBus> {
+    connection: C,
+    debugger: &'a mut Debugger<M>,
+    io: &'a mut I,
+    // bytes received but not yet handled
+    input: VecDeque<u8>,
+    // acknowledge packets with '+', until the client asks for no-ack mode
+    ack: bool
+}
+
+impl<C: Connection, M: MemoryAccess, I: IoBus> Session<'_, C, M, I> {
+    fn run(&mut self) -> Result<GdbExit, GdbError> {
+        while let Some(packet) = self.read_packet()? {
+            debug!("GDB <- {packet}");
+            let reply = self.handle(&packet);
+            match reply {
+                Reply::Packet(reply) => {
+                    self.write_packet(&reply)?;
+                    if packet == "QStartNoAckMode" {
+                        self.ack = false;
+                    }
+                },
+                Reply::Exit(exit) => {
+                    if exit == GdbExit::Detached {
+                        self.write_packet("OK")?;
+                    }
+                    return Ok(exit);
+                }
+            }
+        }
+        Ok(GdbExit::Disconnected)
+    }
+
+    fn handle(&mut self, packet: &str) -> Reply {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => Some(stop_reply(StopReason::Step)),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "Z" => self.set_point(args, true),
+            "z" => self.set_point(args, false),
+            "s" | "c" => {
+                return self.resume(args, command == "c").unwrap_or_else(|| Reply::Packet("E01".to_string()));
+            },
+            "H" => Some("OK".to_string()),
+            "D" => return Reply::Exit(GdbExit::Detached),
+            "k" => return Reply::Exit(GdbExit::Killed),
+            "q" | "Q" => self.query(packet),
+            _ => Some(String::new())
+        };
+        // malformed packets are answered with an error rather than ignored
+        Reply::Packet(reply.unwrap_or_else(|| "E01".to_string()))
+    }
+
+    fn query(&self, packet: &str) -> Option<String> {
+        if packet.starts_with("qSupported") {
+            return Some(format!("PacketSize={PACKET_SIZE:x};qXfer:features:read+;QStartNoAckMode+"));
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, length) = args.split_once(',')?;
+            let offset = usize::from_str_radix(offset, 16).ok()?.min(TARGET_XML.len());
+            let length = usize::from_str_radix(length, 16).ok()?;
+            let chunk = &TARGET_XML[offset..TARGET_XML.len().min(offset + length)];
+            let more = offset + chunk.len() < TARGET_XML.len();
+            return Some(format!("{}{chunk}", if more { "m" } else { "l" }));
+        }
+
+        Some(match packet {
+            "QStartNoAckMode" => "OK",
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => ""
+        }.to_string())
+    }
+
+    fn registers(&self) -> [u16; REGISTER_COUNT] {
+        let registers = self.debugger.cpu().registers();
+        [registers.psw(), registers.pair_b(), registers.pair_d(), registers.pair_h(), registers.sp(), registers.pc()]
+    }
+
+    fn set_register(&mut self, index: usize, val: u16) -> Option<()> {
+        let registers = self.debugger.cpu_mut().registers_mut();
+        match index {
+            0 => registers.set_psw(val),
+            1 => registers.set_pair_b(val),
+            2 => registers.set_pair_d(val),
+            3 => registers.set_pair_h(val),
+            4 => registers.set_sp(val),
+            5 => registers.set_pc(val),
+            _ => return None
+        }
+        Some(())
+    }
+
+    fn read_registers(&self) -> Option<String> {
+        Some(self.registers().iter().map(|val| encode(&val.to_le_bytes())).collect())
+    }
+
+    fn write_registers(&mut self, args: &str) -> Option<String> {
+        let bytes = decode(args)?;
+        if bytes.len() != REGISTER_COUNT * 2 {
+            return None;
+        }
+        for (index, val) in bytes.chunks(2).enumerate() {
+            self.set_register(index, u16::from_le_bytes([val[0], val[1]]))?;
+        }
+        Some("OK".to_string())
+    }
+
+    fn read_register(&self, args: &str) -> Option<String> {
+        let index = usize::from_str_radix(args, 16).ok()?;
+        let val = self.registers().get(index).copied()?;
+        Some(encode(&val.to_le_bytes()))
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (index, val) = args.split_once('=')?;
+        let index = usize::from_str_radix(index, 16).ok()?;
+        let val = decode(val)?;
+        if val.len() != 2 {
+            return None;
+        }
+        self.set_register(index, u16::from_le_bytes([val[0], val[1]]))?;
+        Some("OK".to_string())
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = parse_range(args)?;
+        let memory = self.debugger.memory();
+        let bytes: Vec<u8> = (0..len).map(|i| memory.read_byte(addr.wrapping_add(i))).collect();
+        Some(encode(&bytes))
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (addr, len) = parse_range(range)?;
+        let bytes = decode(data)?;
+        if bytes.len() != len as usize {
+            return None;
+        }
+
+        let memory = self.debugger.memory_mut();
+        for (i, byte) in bytes.iter().enumerate() {
+            memory.write_byte(addr.wrapping_add(i as u16), *byte);
+        }
+        Some("OK".to_string())
+    }
+
+    // Z/z: type 0 and 1 are breakpoints, 2, 3 and 4 write, read and access
+    // watchpoints
+    fn set_point(&mut self, args: &str, insert: bool) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let addr = u16::from_str_radix(fields.next()?, 16).ok()?;
+        let len = u16::from_str_radix(fields.next()?.split(';').next()?, 16).ok()?;
+
+        let watch = match kind {
+            "0" | "1" => {
+                match insert {
+                    true => self.debugger.add_breakpoint(Breakpoint::new(addr)),
+                    false => { self.debugger.remove_breakpoint(addr); }
+                }
+                return Some("OK".to_string());
+            },
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Some(String::new())
+        };
+
+        let watchpoint = Watchpoint::new(addr, len, watch);
+        match insert {
+            true => self.debugger.add_watchpoint(watchpoint),
+            false => { self.debugger.remove_watchpoint(watchpoint); }
+        }
+        Some("OK".to_string())
+    }
+
+    // s and c, optionally from a new address
+    fn resume(&mut self, args: &str, run: bool) -> Option<Reply> {
+        if !args.is_empty() {
+            let pc = u16::from_str_radix(args, 16).ok()?;
+            self.debugger.cpu_mut().registers_mut().set_pc(pc);
+        }
+
+        if !run {
+            return Some(Reply::Packet(stop_reply(self.debugger.step_into(self.io))));
+        }
+
+        let limit = self.debugger.limit();
+        self.debugger.set_limit(Some(RUN_SLICE));
+        let reply = loop {
+            let reason = self.debugger.run(self.io);
+            if reason != StopReason::Limit {
+                break Reply::Packet(stop_reply(reason));
+            }
+            match self.poll() {
+                Ok(Poll::Running) => continue,
+                Ok(Poll::Interrupted) => break Reply::Packet(format!("S{SIGINT:02x}")),
+                Ok(Poll::Disconnected) => break Reply::Exit(GdbExit::Disconnected),
+                Err(error) => {
+                    warn!("GDB connection failed while running: {error}");
+                    break Reply::Packet(format!("S{SIGINT:02x}"));
+                }
+            }
+        };
+        self.debugger.set_limit(limit);
+        Some(reply)
+    }
+
+    // Whether the client sent ^C or went away. Other bytes are kept for
+    // read_packet
+    fn poll(&mut self) -> io::Result<Poll> {
+        self.connection.set_nonblocking(true)?;
+        let mut buffer = [0; 256];
+        let result = loop {
+            match self.connection.read(&mut buffer) {
+                Ok(0) => break Ok(true),
+                Ok(len) => self.input.extend(&buffer[..len]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break Ok(false),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => break Err(error)
+            }
+        };
+        self.connection.set_nonblocking(false)?;
+        if result? {
+            return Ok(Poll::Disconnected);
+        }
+
+        match self.input.iter().position(|byte| *byte == 0x03) {
+            Some(index) => {
+                self.input.remove(index);
+                Ok(Poll::Interrupted)
+            },
+            None => Ok(Poll::Running)
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>, GdbError> {
+        if self.input.is_empty() {
+            let mut buffer = [0; 256];
+            let len = loop {
+                match self.connection.read(&mut buffer) {
+                    Ok(len) => break len,
+                    Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(error) => return Err(error.into())
+                }
+            };
+            self.input.extend(&buffer[..len]);
+        }
+        Ok(self.input.pop_front())
+    }
+
+    // Next packet with a valid checksum, None once the client disconnects.
+    // Acks and a stray ^C between packets are dropped
+    fn read_packet(&mut self) -> Result<Option<String>, GdbError> {
+        loop {
+            match self.read_byte()? {
+                Some(b'$') => {},
+                Some(_) => continue,
+                None => return Ok(None)
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None)
+                }
+            }
+            let (Some(high), Some(low)) = (self.read_byte()?, self.read_byte()?) else {
+                return Ok(None);
+            };
+
+            let valid = decode(&String::from_utf8_lossy(&[high, low]))
+                .is_some_and(|checksum| checksum[0] == checksum_of(&data));
+            if self.ack {
+                self.connection.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            warn!("GDB packet with bad checksum dropped");
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> Result<(), GdbError> {
+        debug!("GDB -> {data}");
+        let packet = format!("${data}#{:02x}", checksum_of(data.as_bytes()));
+        loop {
+            self.connection.write_all(packet.as_bytes())?;
+            self.connection.flush()?;
+            if !self.ack {
+                return Ok(());
+            }
+
+            // resend until acknowledged
+            loop {
+                match self.read_byte()? {
+                    Some(b'+') => return Ok(()),
+                    Some(b'-') => break,
+                    Some(_) => continue,
+                    None => return Err(GdbError::Io("connection closed".to_string()))
+                }
+            }
+        }
+    }
+}
+
+fn stop_reply(reason: StopReason) -> String {
+    match reason {
+        StopReason::Watchpoint(access) => {
+            let kind = match (access.watch, access.kind) {
+                (WatchKind::Access, _) => "awatch",
+                (_, AccessKind::Read) => "rwatch",
+                (_, AccessKind::Write) => "watch"
+            };
+            format!("T{SIGTRAP:02x}{kind}:{:04x};", access.address)
+        },
+        _ => format!("S{SIGTRAP:02x}")
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// "addr,len" as used by m and M
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let (addr, len) = text.split_once(',')?;
+    let addr = u16::from_str_radix(addr, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    if len > PACKET_SIZE / 2 {
+        return None;
+    }
+    Some((addr, len as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use crate::asm::assemble;
+    use crate::cpu::Intel8080;
+    use crate::memory::Memory;
+
+    struct Client {
+        stream: TcpStream,
+        ack: bool
+    }
+
+    impl Client {
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn send(&mut self, packet: &str) {
+            write!(self.stream, "${packet}#{:02x}", checksum_of(packet.as_bytes())).unwrap();
+            if self.ack {
+                assert_eq!(self.read_byte(), b'+');
+            }
+        }
+
+        fn receive(&mut self) -> String {
+            assert_eq!(self.read_byte(), b'$');
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    byte => data.push(byte)
+                }
+            }
+            let checksum = [self.read_byte(), self.read_byte()];
+            assert_eq!(decode(std::str::from_utf8(&checksum).unwrap()).unwrap()[0], checksum_of(&data));
+            if self.ack {
+                self.stream.write_all(b"+").unwrap();
+            }
+            String::from_utf8(data).unwrap()
+        }
+
+        fn request(&mut self, packet: &str) -> String {
+            self.send(packet);
+            self.receive()
+        }
+    }
+
+    #[test]
+    fn test_session() {
+        let program = assemble("
+        ORG     0
+        LXI     SP,80H
+        MVI     A,42H
+        CALL    STORE
+        HLT
+STORE:  STA     VALUE
+        RET
+SPIN:   JMP     SPIN
+VALUE:  DB      0
+").unwrap();
+        let store = program.symbol("STORE").unwrap();
+        let value = program.symbol("VALUE").unwrap();
+        let spin = program.symbol("SPIN").unwrap();
+
+        let mut memory: Memory<0x100> = Memory::new();
+        program.load_into(&mut memory);
+        let mut debugger = Debugger::new(Intel8080::new(), memory);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut client = Client { stream, ack: true };
+
+            assert!(client.request("qSupported:xmlRegisters=i386").contains("qXfer:features:read+"));
+            let xml = client.request("qXfer:features:read:target.xml:0,10");
+            assert_eq!(xml, format!("m{}", &TARGET_XML[..0x10]));
+            let xml = client.request(&format!("qXfer:features:read:target.xml:10,{:x}", TARGET_XML.len()));
+            assert_eq!(xml, format!("l{}", &TARGET_XML[0x10..]));
+            assert_eq!(client.request("?"), "S05");
+
+            assert_eq!(client.request("m0,3"), "318000");
+            assert_eq!(client.request("M70,2:abcd"), "OK");
+            assert_eq!(client.request("m70,2"), "abcd");
+            assert_eq!(client.request("m70,zz"), "E01");
+
+            assert_eq!(client.request(&format!("Z0,{store:x},1")), "OK");
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("g"), format!("02420000000000007e00{store:02x}00"));
+            assert_eq!(client.request(&format!("z0,{store:x},1")), "OK");
+
+            assert_eq!(client.request(&format!("Z2,{value:x},1")), "OK");
+            assert_eq!(client.request("c"), format!("T05watch:{value:04x};"));
+            assert_eq!(client.request(&format!("m{value:x},1")), "42");
+            assert_eq!(client.request("s"), "S05");
+            assert_eq!(client.request("p5"), "0800");
+            assert_eq!(client.request("P2=3412"), "OK");
+            assert_eq!(client.request("p2"), "3412");
+
+            // an access watchpoint is reported as one whatever the access
+            assert_eq!(client.request(&format!("z2,{value:x},1")), "OK");
+            assert_eq!(client.request(&format!("Z4,{value:x},1")), "OK");
+            assert_eq!(client.request(&format!("c{store:x}")), format!("T05awatch:{value:04x};"));
+
+            // ^C stops a run that would not end
+            assert_eq!(client.request("QStartNoAckMode"), "OK");
+            client.ack = false;
+            client.send(&format!("c{spin:x}"));
+            thread::sleep(Duration::from_millis(50));
+            client.stream.write_all(&[0x03]).unwrap();
+            assert_eq!(client.receive(), "S02");
+
+            assert_eq!(client.request("D"), "OK");
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        assert_eq!(serve(stream, &mut debugger, &mut ()), Ok(GdbExit::Detached));
+        client.join().unwrap();
+
+        let registers = debugger.cpu().registers();
+        assert_eq!(registers.pair_d(), 0x1234);
+        assert!((spin..spin + 3).contains(&registers.pc()));
+    }
+
+    #[test]
+    fn test_disconnect_while_running() {
+        let program = assemble("SPIN:   JMP     SPIN").unwrap();
+        let mut memory: Memory<0x100> = Memory::new();
+        program.load_into(&mut memory);
+        let mut debugger = Debugger::new(Intel8080::new(), memory);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            let mut client = Client { stream, ack: true };
+            client.send("c");
+            thread::sleep(Duration::from_millis(50));
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        assert_eq!(serve(stream, &mut debugger, &mut ()), Ok(GdbExit::Disconnected));
+        client.join().unwrap();
+        assert_eq!(debugger.limit(), None);
+    }
+}
@@ -0,0 +1,329 @@
+use std::fmt;
+
+use crate::memory::MemoryAccess;
+
+// Intel HEX records, `:LLAAAATT<data>CC`:
+//
+//   00  data
+//   01  end of file; a non-zero address is taken as the start address, as
+//       CP/M's ASM writes it
+//   02  extended segment address, shifted left by 4 and added to addresses
+//   03  start segment address, CS:IP
+//   04  extended linear address, the upper 16 bits of addresses
+//   05  start linear address
+//
+// Addresses past 0xFFFF are an error since the 8080 cannot reach them.
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+// Data bytes per record written by write_hex
+pub const HEX_RECORD_LEN: usize = 16;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum HexErrorKind {
+    MissingStartCode,
+    InvalidHexDigit(char),
+    // odd number of hex digits, or fewer than a record needs
+    Malformed,
+    LengthMismatch { declared: usize, actual: usize },
+    BadChecksum { expected: u8, found: u8 },
+    UnknownRecordType(u8),
+    // a record whose type needs a different number of data bytes
+    BadRecordLength { record_type: u8, len: usize },
+    AddressOutOfRange(u32),
+    DataAfterEndOfFile,
+    MissingEndOfFile
+}
+
+impl fmt::Display for HexErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HexErrorKind::MissingStartCode => write!(f, "record does not start with ':'"),
+            HexErrorKind::InvalidHexDigit(c) => write!(f, "invalid hex digit {c:?}"),
+            HexErrorKind::Malformed => write!(f, "record is too short or has an odd number of digits"),
+            HexErrorKind::LengthMismatch { declared, actual } => {
+                write!(f, "record declares {declared} data bytes but has {actual}")
+            },
+            HexErrorKind::BadChecksum { expected, found } => {
+                write!(f, "checksum is {found:02X}, expected {expected:02X}")
+            },
+            HexErrorKind::UnknownRecordType(record_type) => write!(f, "unknown record type {record_type:02X}"),
+            HexErrorKind::BadRecordLength { record_type, len } => {
+                write!(f, "record type {record_type:02X} cannot have {len} data bytes")
+            },
+            HexErrorKind::AddressOutOfRange(addr) => write!(f, "address {addr:X} is outside of the 64K address space"),
+            HexErrorKind::DataAfterEndOfFile => write!(f, "record after the end of file record"),
+            HexErrorKind::MissingEndOfFile => write!(f, "no end of file record")
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HexError {
+    // 1-based line of the record
+    pub line: usize,
+    pub kind: HexErrorKind
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl std::error::Error for HexError {}
+
+struct Record {
+    record_type: u8,
+    address: u16,
+    data: Vec<u8>
+}
+
+fn parse_record(line: &str) -> Result<Record, HexErrorKind> {
+    let digits = line.strip_prefix(':').ok_or(HexErrorKind::MissingStartCode)?;
+    if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(HexErrorKind::InvalidHexDigit(c));
+    }
+    if digits.len() % 2 != 0 || digits.len() < 10 {
+        return Err(HexErrorKind::Malformed);
+    }
+
+    let bytes: Vec<u8> = (0..digits.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+        .collect();
+
+    let declared = bytes[0] as usize;
+    let actual = bytes.len() - 5;
+    if declared != actual {
+        return Err(HexErrorKind::LengthMismatch { declared, actual });
+    }
+
+    let found = bytes[bytes.len() - 1];
+    let sum = bytes[..bytes.len() - 1].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    let expected = sum.wrapping_neg();
+    if found != expected {
+        return Err(HexErrorKind::BadChecksum { expected, found });
+    }
+
+    Ok(Record {
+        record_type: bytes[3],
+        address: u16::from_be_bytes([bytes[1], bytes[2]]),
+        data: bytes[4..bytes.len() - 1].to_vec()
+    })
+}
+
+// Load every data record into `memory` and return the start address, if the
+// file has one
+pub fn load_hex(text: &str, memory: &mut impl MemoryAccess) -> Result<Option<u16>, HexError> {
+    let mut base: u32 = 0;
+    let mut entry = None;
+    let mut ended = false;
+    let mut last_line = 0;
+
+    for (index, line) in text.lines().enumerate() {
+        // CP/M pads files with ^Z
+        let line = line.trim().trim_end_matches('\x1a');
+        if line.is_empty() {
+            continue;
+        }
+
+        last_line = index + 1;
+        let error = |kind| HexError { line: index + 1, kind };
+        if ended {
+            return Err(error(HexErrorKind::DataAfterEndOfFile));
+        }
+
+        let record = parse_record(line).map_err(error)?;
+        let expected_len = match record.record_type {
+            DATA => None,
+            END_OF_FILE => Some(0),
+            EXTENDED_SEGMENT_ADDRESS | EXTENDED_LINEAR_ADDRESS => Some(2),
+            START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => Some(4),
+            record_type => return Err(error(HexErrorKind::UnknownRecordType(record_type)))
+        };
+        if expected_len.is_some_and(|len| len != record.data.len()) {
+            return Err(error(HexErrorKind::BadRecordLength {
+                record_type: record.record_type,
+                len: record.data.len()
+            }));
+        }
+
+        let data = &record.data;
+        match record.record_type {
+            DATA => {
+                for (offset, byte) in data.iter().enumerate() {
+                    let addr = base + record.address as u32 + offset as u32;
+                    if addr > 0xffff {
+                        return Err(error(HexErrorKind::AddressOutOfRange(addr)));
+                    }
+                    memory.write_byte(addr as u16, *byte);
+                }
+            },
+            END_OF_FILE => {
+                if entry.is_none() && record.address != 0 {
+                    entry = Some(record.address);
+                }
+                ended = true;
+            },
+            EXTENDED_SEGMENT_ADDRESS => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            EXTENDED_LINEAR_ADDRESS => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            START_SEGMENT_ADDRESS => {
+                let cs = u16::from_be_bytes([data[0], data[1]]) as u32;
+                let ip = u16::from_be_bytes([data[2], data[3]]) as u32;
+                let addr = (cs << 4) + ip;
+                if addr > 0xffff {
+                    return Err(error(HexErrorKind::AddressOutOfRange(addr)));
+                }
+                entry = Some(addr as u16);
+            },
+            _ => {
+                let addr = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                if addr > 0xffff {
+                    return Err(error(HexErrorKind::AddressOutOfRange(addr)));
+                }
+                entry = Some(addr as u16);
+            }
+        }
+    }
+
+    if !ended {
+        return Err(HexError { line: last_line + 1, kind: HexErrorKind::MissingEndOfFile });
+    }
+
+    Ok(entry)
+}
+
+fn write_record(out: &mut String, record_type: u8, address: u16, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend_from_slice(data);
+    let checksum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg();
+    bytes.push(checksum);
+
+    out.push(':');
+    for byte in bytes {
+        out.push_str(&format!("{byte:02X}"));
+    }
+    out.push('\n');
+}
+
+// Dump `len` bytes from `start` as data records, followed by a start segment
+// address record for `entry` and the end of file record
+pub fn write_hex(memory: &impl MemoryAccess, start: u16, len: usize, entry: Option<u16>) -> String {
+    let len = len.min(0x10000 - start as usize);
+    let mut out = String::new();
+
+    for offset in (0..len).step_by(HEX_RECORD_LEN) {
+        let address = start.wrapping_add(offset as u16);
+        let data: Vec<u8> = (0..HEX_RECORD_LEN.min(len - offset))
+            .map(|i| memory.read_byte(address.wrapping_add(i as u16)))
+            .collect();
+        write_record(&mut out, DATA, address, &data);
+    }
+
+    if let Some(entry) = entry {
+        let [high, low] = entry.to_be_bytes();
+        write_record(&mut out, START_SEGMENT_ADDRESS, 0, &[0, 0, high, low]);
+    }
+    write_record(&mut out, END_OF_FILE, 0, &[]);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    fn load(text: &str) -> Result<(Memory<0x10000>, Option<u16>), HexError> {
+        let mut memory = Memory::new();
+        let entry = load_hex(text, &mut memory)?;
+        Ok((memory, entry))
+    }
+
+    fn error(text: &str) -> HexError {
+        load(text).err().unwrap()
+    }
+
+    #[test]
+    fn test_load() {
+        let text = "\
+:10010000214601360121470136007EFE09D2190140
+:0300300002337A1E
+
+:00000001FF
+";
+        let (memory, entry) = load(text).unwrap();
+
+        assert_eq!(memory.get_bytes(0x100, 0x110), &[
+            0x21, 0x46, 0x01, 0x36, 0x01, 0x21, 0x47, 0x01,
+            0x36, 0x00, 0x7e, 0xfe, 0x09, 0xd2, 0x19, 0x01
+        ]);
+        assert_eq!(memory.get_bytes(0x30, 0x33), &[0x02, 0x33, 0x7a]);
+        assert_eq!(entry, None);
+    }
+
+    #[test]
+    fn test_entry_point() {
+        assert_eq!(load(":0400000300100020C9\n:00000001FF").unwrap().1, Some(0x120));
+        assert_eq!(load(":0400000500000100F6\n:00000001FF").unwrap().1, Some(0x100));
+        // CP/M ASM puts the END address into the end of file record
+        assert_eq!(load(":00010001FE\n").unwrap().1, Some(0x100));
+    }
+
+    #[test]
+    fn test_extended_address() {
+        // segment 0x0100 puts offset 0 at 0x1000
+        let (memory, _) = load(":020000020100FB\n:01000000AA55\n:00000001FF").unwrap();
+        assert_eq!(memory.read_byte(0x1000), 0xaa);
+
+        let (memory, _) = load(":020000040000FA\n:01000000AA55\n:00000001FF").unwrap();
+        assert_eq!(memory.read_byte(0), 0xaa);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error("0300300002337A1E"), HexError { line: 1, kind: HexErrorKind::MissingStartCode });
+        assert_eq!(error(":00000001FF\n:0300300002337A1F").line, 2);
+        assert_eq!(error(":0300300002337A1F").kind, HexErrorKind::BadChecksum { expected: 0x1e, found: 0x1f });
+        assert_eq!(error(":0300300002337AG1").kind, HexErrorKind::InvalidHexDigit('G'));
+        assert_eq!(error(":0400300002337A1E").kind, HexErrorKind::LengthMismatch { declared: 4, actual: 3 });
+        assert_eq!(error(":0000000").kind, HexErrorKind::Malformed);
+        assert_eq!(error(":00000006FA").kind, HexErrorKind::UnknownRecordType(6));
+        assert_eq!(error(":0100000400FB").kind, HexErrorKind::BadRecordLength { record_type: 4, len: 1 });
+        assert_eq!(error(":020000040001F9\n:01000000AA55").kind, HexErrorKind::AddressOutOfRange(0x10000));
+        assert_eq!(error(":02FFFF000102FD").kind, HexErrorKind::AddressOutOfRange(0x10000));
+        assert_eq!(error(":00000001FF\n:00000001FF").kind, HexErrorKind::DataAfterEndOfFile);
+        assert_eq!(error(":0300300002337A1E\n"), HexError { line: 2, kind: HexErrorKind::MissingEndOfFile });
+
+        assert_eq!(error(":0300300002337A1F").to_string(), "line 1: checksum is 1F, expected 1E");
+    }
+
+    #[test]
+    fn test_write() {
+        let mut memory: Memory<0x10000> = Memory::new();
+        memory.write_bytes(0x30, &[0x02, 0x33, 0x7a]);
+        assert_eq!(write_hex(&memory, 0x30, 3, None), ":0300300002337A1E\n:00000001FF\n");
+
+        for (i, byte) in (0x100..0x125).zip(1..) {
+            memory.write_byte(i, byte);
+        }
+        let text = write_hex(&memory, 0x100, 0x25, Some(0x100));
+        assert_eq!(text.lines().count(), 5);
+
+        let (loaded, entry) = load(&text).unwrap();
+        assert_eq!(loaded.get_bytes(0x100, 0x125), memory.get_bytes(0x100, 0x125));
+        assert_eq!(entry, Some(0x100));
+
+        // the whole address space
+        let text = write_hex(&memory, 0, 0x10000, None);
+        let (loaded, _) = load(&text).unwrap();
+        assert_eq!(loaded.as_slice(), memory.as_slice());
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::VecDeque;
+
+use crate::bus::MachineCycleKind;
+use crate::cpu::Intel8080;
+use crate::io::IoBus;
+use crate::memory::MemoryAccess;
+use crate::snapshot::CpuState;
+
+// Execution history for stepping backwards. Each step taken through
+// History::step records the parts of the CPU state it changed and the bytes
+// it overwrote, so undoing it restores the CPU and memory exactly. Changes
+// made between steps, by the host or by DMA, are not recorded.
+
+// Part of the CPU state a step changed, holding its value from before
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Change {
+    Pc(u16),
+    Sp(u16),
+    Bc(u16),
+    De(u16),
+    Hl(u16),
+    Psw(u16),
+    Wz(u16),
+    IoPort(u8),
+    // INTE, EI delay, halted, INT, HOLD and HLDA
+    Control([bool; 6]),
+    DmaCycles(u64),
+    StolenCycles(u64),
+    Cycles(u64)
+}
+
+fn control(state: &CpuState) -> [bool; 6] {
+    [state.inte, state.ei_delay, state.halted, state.int_line, state.hold_line, state.hlda]
+}
+
+fn diff(before: &CpuState, after: &CpuState) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let words = [
+        (before.pc, after.pc, Change::Pc as fn(u16) -> Change),
+        (before.sp, after.sp, Change::Sp),
+        (before.bc, after.bc, Change::Bc),
+        (before.de, after.de, Change::De),
+        (before.hl, after.hl, Change::Hl),
+        (before.psw, after.psw, Change::Psw),
+        (before.wz, after.wz, Change::Wz)
+    ];
+    for (before, after, change) in words {
+        if before != after {
+            changes.push(change(before));
+        }
+    }
+
+    if before.io_port != after.io_port {
+        changes.push(Change::IoPort(before.io_port));
+    }
+    if control(before) != control(after) {
+        changes.push(Change::Control(control(before)));
+    }
+    if before.dma_cycles != after.dma_cycles {
+        changes.push(Change::DmaCycles(before.dma_cycles));
+    }
+    if before.stolen_cycles != after.stolen_cycles {
+        changes.push(Change::StolenCycles(before.stolen_cycles));
+    }
+    if before.cycles != after.cycles {
+        changes.push(Change::Cycles(before.cycles));
+    }
+    changes
+}
+
+fn undo(state: &mut CpuState, change: Change) {
+    match change {
+        Change::Pc(val) => state.pc = val,
+        Change::Sp(val) => state.sp = val,
+        Change::Bc(val) => state.bc = val,
+        Change::De(val) => state.de = val,
+        Change::Hl(val) => state.hl = val,
+        Change::Psw(val) => state.psw = val,
+        Change::Wz(val) => state.wz = val,
+        Change::IoPort(val) => state.io_port = val,
+        Change::Control([inte, ei_delay, halted, int_line, hold_line, hlda]) => {
+            state.inte = inte;
+            state.ei_delay = ei_delay;
+            state.halted = halted;
+            state.int_line = int_line;
+            state.hold_line = hold_line;
+            state.hlda = hlda;
+        },
+        Change::DmaCycles(val) => state.dma_cycles = val,
+        Change::StolenCycles(val) => state.stolen_cycles = val,
+        Change::Cycles(val) => state.cycles = val
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Step {
+    changes: Vec<Change>,
+    // address and previous contents of every byte written, in write order
+    overwritten: Vec<(u16, u8)>
+}
+
+// Passes accesses through to `memory`, keeping what each write replaced
+struct Recorder<'a, M: MemoryAccess> {
+    memory: &'a mut M,
+    overwritten: Vec<(u16, u8)>
+}
+
+impl<M: MemoryAccess> MemoryAccess for Recorder<'_, M> {
+    fn read_byte(&self, addr: u16) -> u8 {
+        self.memory.read_byte(addr)
+    }
+
+    fn write_byte(&mut self, addr: u16, val: u8) {
+        self.overwritten.push((addr, self.memory.read_byte(addr)));
+        self.memory.write_byte(addr, val);
+    }
+
+    fn read_bytes<const C: usize>(&self, addr: u16) -> [u8; C] {
+        self.memory.read_bytes(addr)
+    }
+
+    fn write_bytes(&mut self, addr: u16, val: &[u8]) {
+        for offset in 0..val.len() {
+            let addr = addr.wrapping_add(offset as u16);
+            self.overwritten.push((addr, self.memory.read_byte(addr)));
+        }
+        self.memory.write_bytes(addr, val);
+    }
+
+    fn wait_states(&self, addr: u16, kind: MachineCycleKind) -> u64 {
+        self.memory.wait_states(addr, kind)
+    }
+}
+
+// The last `capacity` steps, oldest dropped first
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct History {
+    steps: VecDeque<Step>,
+    capacity: usize
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            steps: VecDeque::with_capacity(capacity),
+            capacity
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Steps that can be undone
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.steps.clear();
+    }
+
+    // Intel8080::step, recorded
+    pub fn step<M: MemoryAccess>(&mut self, cpu: &mut Intel8080, memory: &mut M, io: &mut impl IoBus) -> u64 {
+        let before = cpu.save_state();
+
+        let mut recorder = Recorder { memory, overwritten: Vec::new() };
+        let step_cycles = cpu.step(&mut recorder, io);
+
+        if self.capacity > 0 {
+            if self.steps.len() == self.capacity {
+                self.steps.pop_front();
+            }
+            self.steps.push_back(Step {
+                changes: diff(&before, &cpu.save_state()),
+                overwritten: recorder.overwritten
+            });
+        }
+        step_cycles
+    }
+
+    // Undo the last recorded step. Returns false if there is none
+    pub fn step_back(&mut self, cpu: &mut Intel8080, memory: &mut impl MemoryAccess) -> bool {
+        let Some(step) = self.steps.pop_back() else {
+            return false;
+        };
+
+        for (addr, val) in step.overwritten.iter().rev() {
+            memory.write_byte(*addr, *val);
+        }
+        let mut state = cpu.save_state();
+        for change in step.changes {
+            undo(&mut state, change);
+        }
+        cpu.restore_state(&state);
+        true
+    }
+
+    // Step back until PC is `addr`, at least once. Returns false, having
+    // undone every recorded step, if PC was not there within the history
+    pub fn run_back_to(&mut self, addr: u16, cpu: &mut Intel8080, memory: &mut impl MemoryAccess) -> bool {
+        while self.step_back(cpu, memory) {
+            if cpu.registers().pc() == addr {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{assemble, Program};
+    use crate::memory::Memory;
+    use crate::snapshot::Snapshot;
+
+    fn program() -> (Memory<0x100>, Program) {
+        let program = assemble("
+        ORG     0
+        LXI     SP,80H
+        LXI     H,TABLE
+        MVI     B,4
+        EI
+FILL:   MOV     M,B
+        INX     H
+        PUSH    B
+        CALL    DOUBLE
+        POP     B
+        DCR     B
+        JNZ     FILL
+        DI
+        HLT
+DOUBLE: LDA     TOTAL
+        ADD     A
+        STA     TOTAL
+        RET
+TOTAL:  DB      1
+TABLE:  DS      4
+").unwrap();
+        let mut memory = Memory::new();
+        program.load_into(&mut memory);
+        (memory, program)
+    }
+
+    #[test]
+    fn test_step_back() {
+        let (mut memory, _) = program();
+        let mut cpu = Intel8080::new();
+        let mut history = History::new(1000);
+
+        let mut snapshots = vec![Snapshot::capture(&cpu, &memory)];
+        let mut cycles = vec![0];
+        while !cpu.halted() {
+            history.step(&mut cpu, &mut memory, &mut ());
+            snapshots.push(Snapshot::capture(&cpu, &memory));
+            cycles.push(cpu.cycles());
+        }
+        assert_eq!(history.len(), snapshots.len() - 1);
+
+        while let Some(snapshot) = snapshots.pop() {
+            assert_eq!(Snapshot::capture(&cpu, &memory), snapshot);
+            assert_eq!(cpu.cycles(), cycles.pop().unwrap());
+            history.step_back(&mut cpu, &mut memory);
+        }
+        assert!(history.is_empty());
+        assert!(!history.step_back(&mut cpu, &mut memory));
+    }
+
+    #[test]
+    fn test_run_back_to() {
+        let (mut memory, program) = program();
+        let total = program.symbol("TOTAL").unwrap();
+        let table = program.symbol("TABLE").unwrap();
+        let mut cpu = Intel8080::new();
+        let mut history = History::new(20);
+
+        while !cpu.halted() {
+            history.step(&mut cpu, &mut memory, &mut ());
+        }
+        assert_eq!(history.len(), 20);
+        assert_eq!(memory.read_byte(total), 0x10);
+
+        // back into the last call of DOUBLE, before TOTAL was stored
+        assert!(history.run_back_to(program.symbol("DOUBLE").unwrap() + 4, &mut cpu, &mut memory));
+        assert_eq!(cpu.registers().accumulator(), 0x10);
+        assert_eq!(memory.read_byte(total), 0x08);
+        assert_eq!(cpu.registers().sp(), 0x7c);
+        assert_eq!(memory.read_byte(0x7c), program.symbol("FILL").unwrap() as u8 + 6);
+
+        // the 20 steps reach back to the third call
+        assert!(!history.run_back_to(0x00, &mut cpu, &mut memory));
+        assert!(history.is_empty());
+        assert_eq!(cpu.registers().pc(), program.symbol("DOUBLE").unwrap());
+        assert_eq!(memory.read_byte(total), 0x04);
+        assert_eq!(memory.get_bytes(table, table + 4), &[4, 3, 2, 0]);
+    }
+}
@@ -0,0 +1,561 @@
+use log::debug;
+
+use crate::cpu::Instruction;
+
+// Intel 8259A Programmable Interrupt Controller in 8080 (MCS-80/85) mode.
+//
+// The host maps `read`/`write` onto the two ports selected by A0, feeds the
+// IR lines with `set_irq`, drives the CPU INT pin from `int()` and answers
+// IoBus::interrupt_ack with `inta()`, which supplies the CALL opcode followed
+// by the vector address over the three INTA cycles.
+//
+// Cascading is programmed (ICW3 is stored) but only a single controller is
+// emulated, so a master never hands its INTA cycles to a slave.
+
+const ICW1_IC4: u8   = 0b0000_0001;
+const ICW1_SNGL: u8  = 0b0000_0010;
+const ICW1_ADI: u8   = 0b0000_0100;
+const ICW1_LTIM: u8  = 0b0000_1000;
+const ICW1_INIT: u8  = 0b0001_0000;
+
+const ICW4_UPM: u8   = 0b0000_0001;
+const ICW4_AEOI: u8  = 0b0000_0010;
+
+const OCW3_SEL: u8   = 0b0000_1000;
+const OCW3_RIS: u8   = 0b0000_0001;
+const OCW3_RR: u8    = 0b0000_0010;
+const OCW3_POLL: u8  = 0b0000_0100;
+const OCW3_SMM: u8   = 0b0010_0000;
+const OCW3_ESMM: u8  = 0b0100_0000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum InitState {
+    Ready,
+    Icw2,
+    Icw3,
+    Icw4
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum AckState {
+    Idle,
+    // CALL sent, next byte is the low vector address for this level
+    VectorLow(u8),
+    // low byte sent, next byte is ICW2 for this level
+    VectorHigh(u8)
+}
+
+#[derive(Debug)]
+pub struct I8259 {
+    irr: u8,
+    isr: u8,
+    imr: u8,
+    lines: u8,
+    icw1: u8,
+    icw2: u8,
+    icw3: u8,
+    icw4: u8,
+    init: InitState,
+    ack: AckState,
+    // level with the lowest priority; IR0 is highest after initialization
+    lowest_priority: u8,
+    rotate_on_aeoi: bool,
+    special_mask: bool,
+    read_isr: bool,
+    poll: bool
+}
+
+impl Default for I8259 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl I8259 {
+    pub fn new() -> Self {
+        Self {
+            irr: 0,
+            isr: 0,
+            // stay quiet until the controller has been programmed
+            imr: 0xFF,
+            lines: 0,
+            icw1: 0,
+            icw2: 0,
+            icw3: 0,
+            icw4: 0,
+            init: InitState::Ready,
+            ack: AckState::Idle,
+            lowest_priority: 7,
+            rotate_on_aeoi: false,
+            special_mask: false,
+            read_isr: false,
+            poll: false
+        }
+    }
+
+    pub fn irr(&self) -> u8 {
+        self.irr
+    }
+
+    pub fn isr(&self) -> u8 {
+        self.isr
+    }
+
+    pub fn imr(&self) -> u8 {
+        self.imr
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        self.icw1 & ICW1_LTIM != 0
+    }
+
+    pub fn auto_eoi(&self) -> bool {
+        self.icw4 & ICW4_AEOI != 0
+    }
+
+    // Write to the port selected by A0
+    pub fn write(&mut self, a0: bool, val: u8) {
+        if !a0 && val & ICW1_INIT != 0 {
+            self.write_icw1(val);
+            return;
+        }
+
+        match (self.init, a0) {
+            (InitState::Icw2, true) => {
+                self.icw2 = val;
+                self.init = if self.icw1 & ICW1_SNGL == 0 {
+                    InitState::Icw3
+                }
+                else if self.icw1 & ICW1_IC4 != 0 {
+                    InitState::Icw4
+                }
+                else {
+                    InitState::Ready
+                };
+            },
+            (InitState::Icw3, true) => {
+                self.icw3 = val;
+                self.init = if self.icw1 & ICW1_IC4 != 0 { InitState::Icw4 } else { InitState::Ready };
+            },
+            (InitState::Icw4, true) => {
+                if val & ICW4_UPM != 0 {
+                    debug!("8259: 8086 mode selected, INTA will still produce 8080 CALL vectors");
+                }
+                self.icw4 = val;
+                self.init = InitState::Ready;
+            },
+            (_, true) => {
+                // OCW1
+                self.imr = val;
+            },
+            (_, false) => {
+                if val & OCW3_SEL != 0 {
+                    self.write_ocw3(val);
+                }
+                else {
+                    self.write_ocw2(val);
+                }
+            }
+        }
+    }
+
+    // Read from the port selected by A0
+    pub fn read(&mut self, a0: bool) -> u8 {
+        if a0 {
+            return self.imr;
+        }
+
+        if self.poll {
+            self.poll = false;
+            return match self.resolve() {
+                Some(level) => {
+                    self.accept(level);
+                    0x80 | level
+                },
+                None => 0
+            }
+        }
+
+        match self.read_isr {
+            true => self.isr,
+            false => self.irr
+        }
+    }
+
+    // Drive interrupt request line `line` (0-7) high or low
+    pub fn set_irq(&mut self, line: u8, level: bool) {
+        let bit = 1 << (line & 0x07);
+        let was_high = self.lines & bit != 0;
+
+        if level {
+            self.lines |= bit;
+            if self.level_triggered() || !was_high {
+                self.irr |= bit;
+            }
+        }
+        else {
+            self.lines &= !bit;
+            self.irr &= !bit;
+        }
+    }
+
+    // State of the INT output
+    pub fn int(&self) -> bool {
+        self.ack != AckState::Idle || self.resolve().is_some()
+    }
+
+    // Next byte of the interrupt acknowledge sequence: CALL, vector low, vector high
+    pub fn inta(&mut self) -> u8 {
+        match self.ack {
+            AckState::Idle => {
+                // a request that went away before INTA is answered as IR7
+                // without setting the in-service bit
+                let level = match self.resolve() {
+                    Some(level) => {
+                        self.accept(level);
+                        level
+                    },
+                    None => 7
+                };
+                self.ack = AckState::VectorLow(level);
+                Instruction::CALL as u8
+            },
+            AckState::VectorLow(level) => {
+                self.ack = AckState::VectorHigh(level);
+                self.vector_low(level)
+            },
+            AckState::VectorHigh(level) => {
+                self.ack = AckState::Idle;
+                if self.auto_eoi() {
+                    self.end_of_interrupt(level, self.rotate_on_aeoi);
+                }
+                self.icw2
+            }
+        }
+    }
+
+    // Call address of the handler for `level`
+    pub fn vector(&self, level: u8) -> u16 {
+        u16::from_le_bytes([self.vector_low(level & 0x07), self.icw2])
+    }
+
+    fn vector_low(&self, level: u8) -> u8 {
+        if self.icw1 & ICW1_ADI != 0 {
+            // interval of 4: A7-A5 from ICW1
+            (self.icw1 & 0xE0) | (level << 2)
+        }
+        else {
+            // interval of 8: A7-A6 from ICW1
+            (self.icw1 & 0xC0) | (level << 3)
+        }
+    }
+
+    fn write_icw1(&mut self, val: u8) {
+        debug!("8259: ICW1 {val:02X}");
+        self.icw1 = val;
+        self.icw4 = 0;
+        self.init = InitState::Icw2;
+        self.ack = AckState::Idle;
+        self.irr = if self.level_triggered() { self.lines } else { 0 };
+        self.isr = 0;
+        self.imr = 0;
+        self.lowest_priority = 7;
+        self.rotate_on_aeoi = false;
+        self.special_mask = false;
+        self.read_isr = false;
+        self.poll = false;
+    }
+
+    fn write_ocw2(&mut self, val: u8) {
+        let level = val & 0x07;
+        match val >> 5 {
+            // non-specific EOI
+            0b001 => {
+                if let Some(level) = self.highest_in_service() {
+                    self.end_of_interrupt(level, false);
+                }
+            },
+            // specific EOI
+            0b011 => { self.end_of_interrupt(level, false) },
+            // rotate on non-specific EOI
+            0b101 => {
+                if let Some(level) = self.highest_in_service() {
+                    self.end_of_interrupt(level, true);
+                }
+            },
+            // rotate in automatic EOI mode (set / clear)
+            0b100 => { self.rotate_on_aeoi = true },
+            0b000 => { self.rotate_on_aeoi = false },
+            // rotate on specific EOI
+            0b111 => { self.end_of_interrupt(level, true) },
+            // set priority
+            0b110 => { self.lowest_priority = level },
+            _ => {}
+        }
+    }
+
+    fn write_ocw3(&mut self, val: u8) {
+        if val & OCW3_ESMM != 0 {
+            self.special_mask = val & OCW3_SMM != 0;
+        }
+        if val & OCW3_RR != 0 {
+            self.read_isr = val & OCW3_RIS != 0;
+        }
+        self.poll = val & OCW3_POLL != 0;
+    }
+
+    fn end_of_interrupt(&mut self, level: u8, rotate: bool) {
+        self.isr &= !(1 << level);
+        if rotate {
+            self.lowest_priority = level;
+        }
+    }
+
+    // Levels 0-7 from highest to lowest current priority
+    fn priority_order(&self) -> impl Iterator<Item = u8> {
+        let start = (self.lowest_priority + 1) & 0x07;
+        (0..8).map(move |i| (start + i) & 0x07)
+    }
+
+    fn highest_in_service(&self) -> Option<u8> {
+        self.priority_order().find(|level| self.isr & (1 << level) != 0)
+    }
+
+    // Highest priority request that may interrupt what is currently in service
+    fn resolve(&self) -> Option<u8> {
+        if self.init != InitState::Ready {
+            return None;
+        }
+
+        let requests = self.irr & !self.imr;
+        let in_service = match self.special_mask {
+            true => self.isr & !self.imr,
+            false => self.isr
+        };
+
+        for level in self.priority_order() {
+            let bit = 1 << level;
+            if in_service & bit != 0 && !self.special_mask {
+                return None;
+            }
+            if requests & bit != 0 && in_service & bit == 0 {
+                return Some(level);
+            }
+        }
+
+        None
+    }
+
+    fn accept(&mut self, level: u8) {
+        let bit = 1 << level;
+        self.isr |= bit;
+        if !self.level_triggered() {
+            self.irr &= !bit;
+        }
+        debug!("8259: IR{level} in service");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Intel8080;
+    use crate::io::IoBus;
+    use crate::memory::{Memory, MemoryAccess};
+
+    // ICW1 with interval 4 and vectors in 0x1040.., single, ICW4 needed
+    fn init(pic: &mut I8259, icw1_extra: u8, icw4: u8) {
+        pic.write(false, 0x40 | ICW1_INIT | ICW1_SNGL | ICW1_ADI | ICW1_IC4 | icw1_extra);
+        pic.write(true, 0x10);
+        pic.write(true, icw4);
+    }
+
+    fn ack(pic: &mut I8259) -> [u8; 3] {
+        [pic.inta(), pic.inta(), pic.inta()]
+    }
+
+    #[test]
+    fn test_init_sequence() {
+        let mut pic = I8259::new();
+        pic.set_irq(0, true);
+        assert!(!pic.int());
+
+        init(&mut pic, 0, 0);
+        assert_eq!(pic.imr(), 0x00);
+
+        pic.set_irq(3, true);
+        assert!(pic.int());
+        assert_eq!(pic.irr(), 0x08);
+    }
+
+    #[test]
+    fn test_call_vectors() {
+        let mut pic = I8259::new();
+        init(&mut pic, 0, 0);
+        pic.set_irq(5, true);
+
+        assert_eq!(ack(&mut pic), [Instruction::CALL as u8, 0x54, 0x10]);
+        assert_eq!(pic.isr(), 0x20);
+        assert_eq!(pic.irr(), 0x00);
+
+        // interval of 8 uses A7-A6 only
+        let mut pic = I8259::new();
+        pic.write(false, 0xC0 | ICW1_INIT | ICW1_SNGL);
+        pic.write(true, 0x20);
+        assert_eq!(pic.vector(5), 0x20E8);
+    }
+
+    #[test]
+    fn test_fixed_priority_and_eoi() {
+        let mut pic = I8259::new();
+        init(&mut pic, 0, 0);
+        pic.set_irq(4, true);
+        pic.set_irq(2, true);
+
+        assert_eq!(ack(&mut pic)[1], 0x48);
+        // IR4 is lower priority than IR2 in service
+        assert!(!pic.int());
+
+        pic.write(false, 0x20); // non-specific EOI
+        assert_eq!(pic.isr(), 0x00);
+        assert!(pic.int());
+        assert_eq!(ack(&mut pic)[1], 0x50);
+
+        pic.write(false, 0x64); // specific EOI for IR4
+        assert_eq!(pic.isr(), 0x00);
+    }
+
+    #[test]
+    fn test_nested_higher_priority() {
+        let mut pic = I8259::new();
+        init(&mut pic, 0, 0);
+        pic.set_irq(6, true);
+        ack(&mut pic);
+
+        pic.set_irq(1, true);
+        assert!(pic.int());
+        ack(&mut pic);
+        assert_eq!(pic.isr(), 0x42);
+    }
+
+    #[test]
+    fn test_mask() {
+        let mut pic = I8259::new();
+        init(&mut pic, 0, 0);
+        pic.write(true, 0x01);
+        pic.set_irq(0, true);
+        assert!(!pic.int());
+        assert_eq!(pic.read(true), 0x01);
+
+        pic.write(true, 0x00);
+        assert!(pic.int());
+    }
+
+    #[test]
+    fn test_rotating_priority() {
+        let mut pic = I8259::new();
+        init(&mut pic, 0, 0);
+        pic.set_irq(0, true);
+        ack(&mut pic);
+
+        // rotate on non-specific EOI: IR0 becomes lowest priority
+        pic.write(false, 0xA0);
+        pic.set_irq(0, false);
+        pic.set_irq(0, true);
+        pic.set_irq(7, true);
+        assert_eq!(ack(&mut pic)[1], 0x5C);
+
+        // set priority so IR6 is lowest, making IR7 the highest
+        pic.write(false, 0x20);
+        pic.write(false, 0xC6);
+        pic.set_irq(7, false);
+        pic.set_irq(7, true);
+        assert_eq!(ack(&mut pic)[1], 0x5C);
+    }
+
+    #[test]
+    fn test_edge_and_level_triggering() {
+        let mut pic = I8259::new();
+        init(&mut pic, 0, ICW4_AEOI);
+        pic.set_irq(2, true);
+        ack(&mut pic);
+        assert_eq!(pic.isr(), 0x00);
+        // still high, but edge mode needs a new rising edge
+        assert!(!pic.int());
+        pic.set_irq(2, false);
+        pic.set_irq(2, true);
+        assert!(pic.int());
+
+        let mut pic = I8259::new();
+        init(&mut pic, ICW1_LTIM, ICW4_AEOI);
+        pic.set_irq(2, true);
+        ack(&mut pic);
+        assert!(pic.int());
+        pic.set_irq(2, false);
+        assert!(!pic.int());
+    }
+
+    #[test]
+    fn test_read_registers_and_poll() {
+        let mut pic = I8259::new();
+        init(&mut pic, 0, 0);
+        pic.set_irq(3, true);
+        assert_eq!(pic.read(false), 0x08);
+
+        pic.write(false, OCW3_SEL | OCW3_POLL);
+        assert_eq!(pic.read(false), 0x83);
+
+        pic.write(false, OCW3_SEL | OCW3_RR | OCW3_RIS);
+        assert_eq!(pic.read(false), 0x08);
+    }
+
+    struct PicBus {
+        pic: I8259
+    }
+
+    impl IoBus for PicBus {
+        fn port_in(&mut self, port: u8) -> u8 {
+            self.pic.read(port & 1 != 0)
+        }
+
+        fn port_out(&mut self, port: u8, val: u8) {
+            self.pic.write(port & 1 != 0, val)
+        }
+
+        fn interrupt_ack(&mut self) -> u8 {
+            self.pic.inta()
+        }
+    }
+
+    #[test]
+    fn test_cpu_interrupt_path() {
+        let program = [
+            Instruction::LXI_SP as u8, 0x00, 0x02,
+            Instruction::MVI_A as u8, 0x56, // ICW1
+            Instruction::OUT as u8, 0x20,
+            Instruction::MVI_A as u8, 0x01, // ICW2
+            Instruction::OUT as u8, 0x21,
+            Instruction::EI as u8,
+            Instruction::NOP as u8,
+            Instruction::NOP as u8,
+        ];
+        let mut memory: Memory<0x200> = Memory::new();
+        memory.write_bytes(0, &program);
+
+        let mut bus = PicBus { pic: I8259::new() };
+        let mut cpu = Intel8080::new();
+        // setup, EI and the NOP it shadows
+        for _ in 0..7 {
+            cpu.step(&mut memory, &mut bus);
+        }
+
+        bus.pic.set_irq(1, true);
+        cpu.set_int(bus.pic.int());
+        let cycles = cpu.step(&mut memory, &mut bus);
+
+        assert_eq!(cycles, 17);
+        assert_eq!(cpu.registers().pc(), 0x0144);
+        assert_eq!(memory.read_bytes::<2>(0x1FE), [0x0D, 0x00]);
+        assert_eq!(bus.pic.isr(), 0x02);
+    }
+}
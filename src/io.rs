@@ -0,0 +1,28 @@
+use crate::bus::MachineCycleKind;
+
+pub trait IoBus {
+    // Called by IN; the returned byte is loaded into the accumulator
+    fn port_in(&mut self, port: u8) -> u8;
+    // Called by OUT with the contents of the accumulator
+    fn port_out(&mut self, port: u8, val: u8);
+
+    // Called once per INTA cycle after an interrupt is accepted: first for the
+    // opcode, then for each operand byte. Defaults to a floating bus (RST 7)
+    fn interrupt_ack(&mut self) -> u8 {
+        0xFF
+    }
+
+    // Wait states the device at `port` inserts into an IN or OUT cycle
+    fn wait_states(&self, _port: u8, _kind: MachineCycleKind) -> u64 {
+        0
+    }
+}
+
+// No devices attached: reads float high and writes are discarded
+impl IoBus for () {
+    fn port_in(&mut self, _port: u8) -> u8 {
+        0xFF
+    }
+
+    fn port_out(&mut self, _port: u8, _val: u8) {}
+}
@@ -1,10 +1,56 @@
+pub mod asm;
+pub mod bdos;
+pub mod bios;
+pub mod bus;
+pub mod console;
+mod cpm;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod hex;
+pub mod history;
+pub mod i8259;
+pub mod io;
+pub mod link;
+pub mod loader;
 pub mod memory;
+pub mod monitor;
+pub mod profiler;
+pub mod rel;
+pub mod snapshot;
+pub mod srec;
+#[cfg(test)]
+mod testing;
+pub mod trace;
 
+pub use asm::{assemble, Assembler, AsmError, Program};
+pub use bdos::{run_com, Bdos, BdosError};
+pub use bios::{Bios, BiosError, Disk, DiskDefinition, DiskParameterBlock};
+pub use bus::{BusMonitor, MachineCycle, MachineCycleKind};
+pub use console::{BufferConsole, Console, StdConsole};
+pub use cpu::Flags;
 pub use cpu::Instruction;
 pub use cpu::Intel8080;
+pub use cpu::Registers;
 pub use cpu::CYCLE_TIME_SECS;
 pub use cpu::CYCLE_TIME_NANO_SECS;
+pub use cpu::HALT_STATES;
+pub use debugger::{Breakpoint, Condition, Debugger, StopReason, WatchKind, Watchpoint};
+pub use disasm::{disassemble, disassemble_bytes, disassemble_range, Disassembly, Syntax};
+pub use gdb::{GdbError, GdbExit};
+pub use hex::{load_hex, write_hex, HexError};
+pub use history::History;
+pub use i8259::I8259;
+pub use io::IoBus;
+pub use link::{LinkedImage, Linker};
+pub use loader::{ImageFormat, LoadEntry, LoadError, LoadMap};
 pub use memory::MemoryAccess;
 pub use memory::Memory;
+pub use monitor::Monitor;
+pub use profiler::Profiler;
+pub use rel::{read_rel, write_rel, RelItem};
+pub use snapshot::{CpuState, Snapshot, SnapshotError};
+pub use srec::{load_srec, SRecordError};
+pub use trace::{TraceFormat, TraceRecord, TraceWriter, Tracer};
 
@@ -0,0 +1,485 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::memory::MemoryAccess;
+use crate::rel::{read_rel, Address, AddressType, RelError, RelItem};
+
+// Linker for Microsoft REL modules, laid out the way L80 does by default:
+// program segments one after another from the program origin, data segments
+// after the last program segment (or from the data origin), then COMMON
+// blocks.
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum LinkError {
+    UndefinedSymbols(Vec<String>),
+    DuplicateSymbol(String),
+    // segments of the named module do not fit below 0x10000
+    AddressOverflow(String),
+    // chain of references to the named symbol does not end
+    BadChain(String)
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::UndefinedSymbols(names) => write!(f, "undefined symbols: {}", names.join(", ")),
+            LinkError::DuplicateSymbol(name) => write!(f, "symbol {name} is defined more than once"),
+            LinkError::AddressOverflow(module) => write!(f, "module {module} does not fit in memory"),
+            LinkError::BadChain(name) => write!(f, "reference chain of {name} does not end")
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct LinkedImage {
+    // address of the first byte of `bytes`
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    // start address from the first module that has one
+    pub entry: Option<u16>,
+    // every PUBLIC symbol
+    pub symbols: BTreeMap<String, u16>
+}
+
+impl LinkedImage {
+    pub fn load_into(&self, memory: &mut impl MemoryAccess) {
+        for (offset, byte) in self.bytes.iter().enumerate() {
+            memory.write_byte(self.origin.wrapping_add(offset as u16), *byte);
+        }
+    }
+}
+
+struct Module {
+    name: String,
+    items: Vec<RelItem>,
+    program_base: u16,
+    data_base: u16
+}
+
+pub struct Linker {
+    modules: Vec<Vec<RelItem>>,
+    program_origin: u16,
+    data_origin: Option<u16>
+}
+
+impl Default for Linker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Linker {
+    // Program segments start at 0x100, the CP/M TPA
+    pub fn new() -> Self {
+        Self {
+            modules: Vec::new(),
+            program_origin: 0x100,
+            data_origin: None
+        }
+    }
+
+    pub fn set_program_origin(&mut self, origin: u16) {
+        self.program_origin = origin;
+    }
+
+    pub fn set_data_origin(&mut self, origin: u16) {
+        self.data_origin = Some(origin);
+    }
+
+    // Add every module in `items`, each ending at its end of module item
+    pub fn add_items(&mut self, items: &[RelItem]) {
+        let mut module = Vec::new();
+        for item in items {
+            match item {
+                RelItem::EndFile => break,
+                RelItem::EndModule(_) => {
+                    module.push(item.clone());
+                    self.modules.push(std::mem::take(&mut module));
+                },
+                _ => module.push(item.clone())
+            }
+        }
+
+        if !module.is_empty() {
+            self.modules.push(module);
+        }
+    }
+
+    pub fn add_rel(&mut self, bytes: &[u8]) -> Result<(), RelError> {
+        let items = read_rel(bytes)?;
+        self.add_items(&items);
+        Ok(())
+    }
+
+    pub fn link(&self) -> Result<LinkedImage, LinkError> {
+        let (modules, commons) = self.layout()?;
+        let mut linking = Linking {
+            memory: vec![0; 0x10000],
+            low: usize::MAX,
+            high: 0,
+            symbols: BTreeMap::new(),
+            undefined: Vec::new(),
+            entry: None
+        };
+
+        for module in &modules {
+            linking.define_publics(module, &commons)?;
+        }
+        for module in &modules {
+            linking.load(module, &commons)?;
+        }
+
+        if !linking.undefined.is_empty() {
+            return Err(LinkError::UndefinedSymbols(linking.undefined));
+        }
+
+        let bytes = match linking.low <= linking.high {
+            true => linking.memory[linking.low..=linking.high].to_vec(),
+            false => Vec::new()
+        };
+
+        Ok(LinkedImage {
+            origin: if bytes.is_empty() { self.program_origin } else { linking.low as u16 },
+            bytes,
+            entry: linking.entry,
+            symbols: linking.symbols
+        })
+    }
+
+    // Base address of every module's segments and of the COMMON blocks
+    fn layout(&self) -> Result<(Vec<Module>, HashMap<String, u16>), LinkError> {
+        let mut modules = Vec::new();
+        let mut sizes = Vec::new();
+        let mut common_sizes: Vec<(String, u32)> = Vec::new();
+
+        for (index, items) in self.modules.iter().enumerate() {
+            let mut name = format!("#{}", index + 1);
+            let mut program_size = 0;
+            let mut data_size = 0;
+            let mut common = String::new();
+            let mut location = Address::program(0);
+            let mut advance = |location: &mut Address, common: &str, len: u32| {
+                let end = location.value as u32 + len;
+                match location.kind {
+                    AddressType::Program => program_size = program_size.max(end),
+                    AddressType::Data => data_size = data_size.max(end),
+                    AddressType::Common => grow_common(&mut common_sizes, common, end),
+                    AddressType::Absolute => {}
+                }
+                location.value = location.value.wrapping_add(len as u16);
+            };
+
+            for item in items {
+                match item {
+                    RelItem::ProgramName(program) => name = program.clone(),
+                    RelItem::Byte(_) => advance(&mut location, &common, 1),
+                    RelItem::Word(..) => advance(&mut location, &common, 2),
+                    RelItem::SetLocation(address) => location = *address,
+                    RelItem::SelectCommon(block) => common = block.clone(),
+                    RelItem::ProgramSize(size) => advance(&mut Address::program(0), &common, size.value as u32),
+                    RelItem::DataSize(size) => advance(&mut Address::data(0), &common, size.value as u32),
+                    RelItem::CommonSize(size, block) => {
+                        advance(&mut Address { kind: AddressType::Common, value: 0 }, block, size.value as u32);
+                    },
+                    _ => {}
+                }
+            }
+
+            sizes.push((program_size, data_size));
+            modules.push(Module { name, items: items.clone(), program_base: 0, data_base: 0 });
+        }
+
+        let mut next = self.program_origin as u32;
+        for (module, (program_size, _)) in modules.iter_mut().zip(&sizes) {
+            module.program_base = next as u16;
+            next += program_size;
+            if next > 0x10000 {
+                return Err(LinkError::AddressOverflow(module.name.clone()));
+            }
+        }
+
+        let mut next = self.data_origin.map_or(next, |origin| origin as u32);
+        for (module, (_, data_size)) in modules.iter_mut().zip(&sizes) {
+            module.data_base = next as u16;
+            next += data_size;
+            if next > 0x10000 {
+                return Err(LinkError::AddressOverflow(module.name.clone()));
+            }
+        }
+
+        let mut commons = HashMap::new();
+        for (block, size) in common_sizes {
+            commons.insert(block.clone(), next as u16);
+            next += size;
+            if next > 0x10000 {
+                return Err(LinkError::AddressOverflow(format!("/{block}/")));
+            }
+        }
+
+        Ok((modules, commons))
+    }
+}
+
+fn grow_common(sizes: &mut Vec<(String, u32)>, block: &str, size: u32) {
+    match sizes.iter_mut().find(|(name, _)| name == block) {
+        Some((_, current)) => *current = (*current).max(size),
+        None => sizes.push((block.to_string(), size))
+    }
+}
+
+fn relocate(module: &Module, commons: &HashMap<String, u16>, common: &str, kind: AddressType, value: u16) -> u16 {
+    let base = match kind {
+        AddressType::Absolute => 0,
+        AddressType::Program => module.program_base,
+        AddressType::Data => module.data_base,
+        AddressType::Common => commons.get(common).copied().unwrap_or(0)
+    };
+
+    base.wrapping_add(value)
+}
+
+struct Linking {
+    memory: Vec<u8>,
+    // range of addresses written
+    low: usize,
+    high: usize,
+    symbols: BTreeMap<String, u16>,
+    undefined: Vec<String>,
+    entry: Option<u16>
+}
+
+impl Linking {
+    fn define_publics(&mut self, module: &Module, commons: &HashMap<String, u16>) -> Result<(), LinkError> {
+        let mut common = String::new();
+        for item in &module.items {
+            match item {
+                RelItem::SelectCommon(block) => common = block.clone(),
+                RelItem::EntryPoint(address, name) => {
+                    let value = relocate(module, commons, &common, address.kind, address.value);
+                    if self.symbols.insert(name.clone(), value).is_some() {
+                        return Err(LinkError::DuplicateSymbol(name.clone()));
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_byte(&mut self, addr: u16, val: u8) {
+        let addr = addr as usize;
+        self.memory[addr] = val;
+        self.low = self.low.min(addr);
+        self.high = self.high.max(addr);
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.memory[addr as usize], self.memory[addr.wrapping_add(1) as usize]])
+    }
+
+    fn write_word(&mut self, addr: u16, val: u16) {
+        let [low, high] = val.to_le_bytes();
+        self.write_byte(addr, low);
+        self.write_byte(addr.wrapping_add(1), high);
+    }
+
+    // Replace each word of the chain starting at `addr` by `val`
+    fn resolve_chain(&mut self, mut addr: u16, val: u16, name: &str) -> Result<(), LinkError> {
+        for _ in 0..0x10000 {
+            let next = self.read_word(addr);
+            self.write_word(addr, val);
+            if next == 0 {
+                return Ok(());
+            }
+            addr = next;
+        }
+
+        Err(LinkError::BadChain(name.to_string()))
+    }
+
+    fn load(&mut self, module: &Module, commons: &HashMap<String, u16>) -> Result<(), LinkError> {
+        let mut common = String::new();
+        let mut location = Address::program(0);
+        // offset for the next word loaded, and where that word went
+        let mut offset = None;
+        let mut offsets = Vec::new();
+
+        for item in &module.items {
+            let addr = relocate(module, commons, &common, location.kind, location.value);
+            match item {
+                RelItem::Byte(byte) => {
+                    if let Some(offset) = offset.take() {
+                        offsets.push((addr, offset));
+                    }
+                    self.write_byte(addr, *byte);
+                    location.value = location.value.wrapping_add(1);
+                },
+                RelItem::Word(kind, value) => {
+                    if let Some(offset) = offset.take() {
+                        offsets.push((addr, offset));
+                    }
+                    let value = relocate(module, commons, &common, *kind, *value);
+                    self.write_word(addr, value);
+                    location.value = location.value.wrapping_add(2);
+                },
+                RelItem::SetLocation(address) => location = *address,
+                RelItem::SelectCommon(block) => common = block.clone(),
+                RelItem::ExternalPlusOffset(address) => offset = Some(address.value),
+                RelItem::ExternalMinusOffset(address) => offset = Some(address.value.wrapping_neg()),
+                RelItem::ChainExternal(head, name) => {
+                    if head.kind == AddressType::Absolute && head.value == 0 {
+                        continue;
+                    }
+                    let head = relocate(module, commons, &common, head.kind, head.value);
+                    match self.symbols.get(name) {
+                        Some(value) => self.resolve_chain(head, *value, name)?,
+                        None => {
+                            if !self.undefined.contains(name) {
+                                self.undefined.push(name.clone());
+                            }
+                        }
+                    }
+                },
+                RelItem::ChainAddress(head) => {
+                    let head = relocate(module, commons, &common, head.kind, head.value);
+                    self.resolve_chain(head, addr, &module.name)?;
+                },
+                RelItem::EndModule(start) if self.entry.is_none() && *start != Address::absolute(0) => {
+                    self.entry = Some(relocate(module, commons, &common, start.kind, start.value));
+                },
+                _ => {}
+            }
+        }
+
+        for (addr, offset) in offsets {
+            let value = self.read_word(addr).wrapping_add(offset);
+            self.write_word(addr, value);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Instruction;
+    use crate::memory::Memory;
+    use crate::rel::write_rel;
+
+    // CALLs and an LXI with an offset chained through PRINT, a data segment
+    // and a COMMON block
+    fn main_module() -> Vec<RelItem> {
+        vec![
+            RelItem::ProgramName("MAIN".to_string()),
+            RelItem::CommonSize(Address::absolute(4), "BUF".to_string()),
+            RelItem::DataSize(Address::absolute(3)),
+            RelItem::ProgramSize(Address::program(16)),
+            RelItem::SelectCommon("BUF".to_string()),
+            RelItem::SetLocation(Address::program(0)),
+            // LXI H,MSG
+            RelItem::Byte(Instruction::LXI_H as u8),
+            RelItem::Word(AddressType::Data, 0),
+            // CALL PRINT, end of the chain
+            RelItem::Byte(Instruction::CALL as u8),
+            RelItem::Byte(0),
+            RelItem::Byte(0),
+            // CALL PRINT
+            RelItem::Byte(Instruction::CALL as u8),
+            RelItem::Word(AddressType::Program, 4),
+            // LXI D,BUF+1
+            RelItem::Byte(Instruction::LXI_D as u8),
+            RelItem::Word(AddressType::Common, 1),
+            // LXI B,PRINT+2
+            RelItem::Byte(Instruction::LXI_B as u8),
+            RelItem::ExternalPlusOffset(Address::absolute(2)),
+            RelItem::Word(AddressType::Program, 7),
+            RelItem::Byte(Instruction::HLT as u8),
+            RelItem::SetLocation(Address::data(0)),
+            RelItem::Byte(b'H'),
+            RelItem::Byte(b'I'),
+            RelItem::Byte(0),
+            RelItem::ChainExternal(Address::program(13), "PRINT".to_string()),
+            RelItem::EntryPoint(Address::program(0), "START".to_string()),
+            RelItem::EndModule(Address::program(0)),
+            RelItem::EndFile
+        ]
+    }
+
+    fn print_module() -> Vec<RelItem> {
+        vec![
+            RelItem::ProgramName("PRINT".to_string()),
+            RelItem::ProgramSize(Address::program(2)),
+            RelItem::EntryPoint(Address::program(1), "PRINT".to_string()),
+            RelItem::Byte(Instruction::NOP as u8),
+            RelItem::Byte(Instruction::RET as u8),
+            RelItem::EndModule(Address::absolute(0)),
+            RelItem::EndFile
+        ]
+    }
+
+    #[test]
+    fn test_link() {
+        let mut linker = Linker::new();
+        linker.add_rel(&write_rel(&main_module())).unwrap();
+        linker.add_rel(&write_rel(&print_module())).unwrap();
+        let image = linker.link().unwrap();
+
+        assert_eq!(image.origin, 0x100);
+        assert_eq!(image.entry, Some(0x100));
+        assert_eq!(image.symbols.get("START"), Some(&0x100));
+        assert_eq!(image.symbols.get("PRINT"), Some(&0x111));
+        assert_eq!(image.bytes, vec![
+            0x21, 0x12, 0x01,
+            0xcd, 0x11, 0x01,
+            0xcd, 0x11, 0x01,
+            // BUF follows the data segment
+            0x11, 0x16, 0x01,
+            0x01, 0x13, 0x01,
+            0x76,
+            0x00, 0xc9,
+            b'H', b'I', 0x00
+        ]);
+
+        let mut memory: Memory<0x200> = Memory::new();
+        image.load_into(&mut memory);
+        assert_eq!(memory.read_byte(0x111), 0xc9);
+    }
+
+    #[test]
+    fn test_origins() {
+        let mut linker = Linker::new();
+        linker.set_program_origin(0);
+        linker.set_data_origin(0x8000);
+        // two modules in one file
+        let mut items = main_module();
+        items.pop();
+        items.extend(print_module());
+        linker.add_items(&items);
+        let image = linker.link().unwrap();
+
+        assert_eq!(image.origin, 0);
+        assert_eq!(&image.bytes[..6], &[0x21, 0x00, 0x80, 0xcd, 0x11, 0x00]);
+        assert_eq!(&image.bytes[0x8000..], b"HI\0");
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut linker = Linker::new();
+        linker.add_items(&main_module());
+        assert_eq!(linker.link(), Err(LinkError::UndefinedSymbols(vec!["PRINT".to_string()])));
+
+        linker.add_items(&print_module());
+        linker.add_items(&print_module());
+        assert_eq!(linker.link(), Err(LinkError::DuplicateSymbol("PRINT".to_string())));
+
+        let mut linker = Linker::new();
+        linker.set_program_origin(0xfff0);
+        linker.add_items(&main_module());
+        linker.add_items(&print_module());
+        assert_eq!(linker.link(), Err(LinkError::AddressOverflow("PRINT".to_string())));
+    }
+}
@@ -0,0 +1,320 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::hex::{load_hex, HexError};
+use crate::memory::{Memory, MemoryAccess};
+use crate::srec::{load_srec, SRecordError};
+
+// Loads a set of image files into memory as described by a load map. Every
+// file is read and placed before anything is written, so a map that overlaps
+// itself or runs past the end of memory leaves memory untouched.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ImageFormat {
+    IntelHex,
+    SRecord,
+    Binary
+}
+
+impl ImageFormat {
+    // Guess the format from the file extension, falling back to raw binary
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("hex" | "ihx" | "ihex") => ImageFormat::IntelHex,
+            Some("s19" | "s28" | "s37" | "srec" | "mot") => ImageFormat::SRecord,
+            _ => ImageFormat::Binary
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LoadEntry {
+    pub path: PathBuf,
+    pub format: ImageFormat,
+    // address the first selected byte is loaded at
+    pub base: u16,
+    // file offset of the first byte to load, or for HEX and S-records the
+    // first record address
+    pub offset: usize,
+    // number of bytes from `offset` to load, the rest of the file if None
+    pub length: Option<usize>
+}
+
+impl LoadEntry {
+    pub fn new(path: impl Into<PathBuf>, format: ImageFormat, base: u16) -> Self {
+        Self {
+            path: path.into(),
+            format,
+            base,
+            offset: 0,
+            length: None
+        }
+    }
+
+    // Map a file address into memory, None if it is outside the window
+    // selected by `offset` and `length`
+    fn translate(&self, addr: usize) -> Option<usize> {
+        let index = addr.checked_sub(self.offset)?;
+        if self.length.is_some_and(|length| index >= length) {
+            return None;
+        }
+
+        Some(self.base as usize + index)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum LoadError {
+    Io { path: PathBuf, message: String },
+    Hex { path: PathBuf, error: HexError },
+    SRecord { path: PathBuf, error: SRecordError },
+    // byte would land at or past the end of memory
+    OutOfRange { path: PathBuf, address: usize },
+    // byte would land on one already loaded from `other`
+    Overlap { path: PathBuf, other: PathBuf, address: u16 }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io { path, message } => write!(f, "{}: {message}", path.display()),
+            LoadError::Hex { path, error } => write!(f, "{}: {error}", path.display()),
+            LoadError::SRecord { path, error } => write!(f, "{}: {error}", path.display()),
+            LoadError::OutOfRange { path, address } => {
+                write!(f, "{}: address {address:X} is outside of memory", path.display())
+            },
+            LoadError::Overlap { path, other, address } => {
+                write!(f, "{}: address {address:04X} is already loaded from {}", path.display(), other.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+// Contents of one file keyed by file address
+struct FileImage {
+    bytes: Vec<(usize, u8)>,
+    entry: Option<u16>
+}
+
+// Collects the writes of the HEX and S-record loaders
+#[derive(Default)]
+struct Capture {
+    bytes: Vec<(u16, u8)>
+}
+
+impl MemoryAccess for Capture {
+    fn read_byte(&self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn write_byte(&mut self, addr: u16, val: u8) {
+        self.bytes.push((addr, val));
+    }
+
+    fn read_bytes<const C: usize>(&self, _addr: u16) -> [u8; C] {
+        [0; C]
+    }
+
+    fn write_bytes(&mut self, addr: u16, val: &[u8]) {
+        for (offset, byte) in val.iter().enumerate() {
+            self.write_byte(addr.wrapping_add(offset as u16), *byte);
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct LoadMap {
+    entries: Vec<LoadEntry>
+}
+
+impl LoadMap {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new()
+        }
+    }
+
+    pub fn add(&mut self, entry: LoadEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn entries(&self) -> &[LoadEntry] {
+        &self.entries
+    }
+
+    // Load every entry into the first `size` bytes of `memory` and return the
+    // start address of the first HEX or S-record file that has one
+    pub fn load(&self, memory: &mut impl MemoryAccess, size: usize) -> Result<Option<u16>, LoadError> {
+        let size = size.min(0x10000);
+        let mut owners: Vec<Option<usize>> = vec![None; size];
+        let mut bytes = Vec::new();
+        let mut entry = None;
+
+        for (index, load) in self.entries.iter().enumerate() {
+            let image = Self::read(load)?;
+
+            for (addr, byte) in image.bytes {
+                let Some(address) = load.translate(addr) else {
+                    continue;
+                };
+                if address >= size {
+                    return Err(LoadError::OutOfRange { path: load.path.clone(), address });
+                }
+                if let Some(owner) = owners[address] {
+                    return Err(LoadError::Overlap {
+                        path: load.path.clone(),
+                        other: self.entries[owner].path.clone(),
+                        address: address as u16
+                    });
+                }
+
+                owners[address] = Some(index);
+                bytes.push((address as u16, byte));
+            }
+
+            if entry.is_none() {
+                entry = image.entry
+                    .and_then(|addr| load.translate(addr as usize))
+                    .filter(|addr| *addr <= 0xffff)
+                    .map(|addr| addr as u16);
+            }
+        }
+
+        for (address, byte) in bytes {
+            memory.write_byte(address, byte);
+        }
+
+        Ok(entry)
+    }
+
+    pub fn load_memory<const N: usize>(&self, memory: &mut Memory<N>) -> Result<Option<u16>, LoadError> {
+        self.load(memory, N)
+    }
+
+    fn read(load: &LoadEntry) -> Result<FileImage, LoadError> {
+        let contents = fs::read(&load.path).map_err(|err| LoadError::Io {
+            path: load.path.clone(),
+            message: err.to_string()
+        })?;
+
+        match load.format {
+            ImageFormat::Binary => Ok(FileImage { bytes: contents.into_iter().enumerate().collect(), entry: None }),
+            ImageFormat::IntelHex => {
+                let mut capture = Capture::default();
+                let entry = load_hex(&String::from_utf8_lossy(&contents), &mut capture)
+                    .map_err(|error| LoadError::Hex { path: load.path.clone(), error })?;
+                Ok(FileImage {
+                    bytes: capture.bytes.into_iter().map(|(addr, byte)| (addr as usize, byte)).collect(),
+                    entry
+                })
+            },
+            ImageFormat::SRecord => {
+                let mut capture = Capture::default();
+                let entry = load_srec(&String::from_utf8_lossy(&contents), &mut capture)
+                    .map_err(|error| LoadError::SRecord { path: load.path.clone(), error })?;
+                Ok(FileImage {
+                    bytes: capture.bytes.into_iter().map(|(addr, byte)| (addr as usize, byte)).collect(),
+                    entry
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(ImageFormat::from_path(Path::new("MON.HEX")), ImageFormat::IntelHex);
+        assert_eq!(ImageFormat::from_path(Path::new("rom.s19")), ImageFormat::SRecord);
+        assert_eq!(ImageFormat::from_path(Path::new("basic.bin")), ImageFormat::Binary);
+        assert_eq!(ImageFormat::from_path(Path::new("ROM")), ImageFormat::Binary);
+    }
+
+    #[test]
+    fn test_load_map() {
+        let dir = temp_dir("loader", "map");
+        fs::write(dir.join("rom.bin"), (0..=255).collect::<Vec<u8>>()).unwrap();
+        fs::write(dir.join("mon.hex"), ":0300300002337A1E\n:00010001FE\n").unwrap();
+        fs::write(dir.join("patch.s19"), "S1050040AABB55\n").unwrap();
+
+        let mut map = LoadMap::new();
+        // second half of the ROM dump, 16 bytes of it
+        let mut rom = LoadEntry::new(dir.join("rom.bin"), ImageFormat::Binary, 0x800);
+        rom.offset = 0x80;
+        rom.length = Some(0x10);
+        map.add(rom);
+        map.add(LoadEntry::new(dir.join("mon.hex"), ImageFormat::IntelHex, 0x1000));
+        map.add(LoadEntry::new(dir.join("patch.s19"), ImageFormat::SRecord, 0));
+
+        let mut memory: Memory<0x2000> = Memory::new();
+        let entry = map.load_memory(&mut memory).unwrap();
+
+        assert_eq!(memory.get_bytes(0x800, 0x811), &[
+            0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+            0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x8d, 0x8e, 0x8f, 0x00
+        ]);
+        assert_eq!(memory.get_bytes(0x1030, 0x1033), &[0x02, 0x33, 0x7a]);
+        assert_eq!(memory.get_bytes(0x40, 0x42), &[0xaa, 0xbb]);
+        // the HEX file's start address moves with it
+        assert_eq!(entry, Some(0x1100));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let dir = temp_dir("loader", "errors");
+        fs::write(dir.join("a.bin"), [1; 0x100]).unwrap();
+        fs::write(dir.join("b.bin"), [2; 0x100]).unwrap();
+        fs::write(dir.join("bad.hex"), ":0300300002337A1F\n").unwrap();
+
+        let mut memory: Memory<0x1000> = Memory::new();
+
+        let mut map = LoadMap::new();
+        map.add(LoadEntry::new(dir.join("a.bin"), ImageFormat::Binary, 0));
+        map.add(LoadEntry::new(dir.join("b.bin"), ImageFormat::Binary, 0xff));
+        assert_eq!(map.load_memory(&mut memory), Err(LoadError::Overlap {
+            path: dir.join("b.bin"),
+            other: dir.join("a.bin"),
+            address: 0xff
+        }));
+        // nothing is written when the map does not load
+        assert_eq!(memory.read_byte(0), 0);
+
+        let mut map = LoadMap::new();
+        map.add(LoadEntry::new(dir.join("a.bin"), ImageFormat::Binary, 0xf80));
+        assert_eq!(map.load_memory(&mut memory), Err(LoadError::OutOfRange {
+            path: dir.join("a.bin"),
+            address: 0x1000
+        }));
+        let mut full: Memory<0x10000> = Memory::new();
+        map.entries[0].base = 0xff80;
+        assert_eq!(map.load_memory(&mut full), Err(LoadError::OutOfRange {
+            path: dir.join("a.bin"),
+            address: 0x10000
+        }));
+
+        let mut map = LoadMap::new();
+        map.add(LoadEntry::new(dir.join("bad.hex"), ImageFormat::IntelHex, 0));
+        let error = map.load_memory(&mut memory).unwrap_err();
+        assert!(matches!(error, LoadError::Hex { .. }));
+        assert!(error.to_string().ends_with("bad.hex: line 1: checksum is 1F, expected 1E"));
+
+        let mut map = LoadMap::new();
+        map.add(LoadEntry::new(dir.join("missing.bin"), ImageFormat::Binary, 0));
+        assert!(matches!(map.load_memory(&mut memory), Err(LoadError::Io { .. })));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,30 @@
+use std::env;
+use std::io::{self, BufRead, Write};
+
+use emu8080::{Monitor, StdConsole};
+
+// emu8080 [file [args]]: the DDT-style monitor on stdin and stdout. A file
+// given on the command line is read as with I and R. Running CP/M programs
+// share the terminal with the monitor.
+fn main() {
+    let mut monitor = Monitor::new(".", StdConsole::new());
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some((file, tail)) = args.split_first() {
+        print!("{}", monitor.command(&format!("I{file} {}", tail.join(" "))));
+        print!("{}", monitor.command("R"));
+    }
+
+    let stdin = io::stdin();
+    let mut line = String::new();
+    while !monitor.quit() {
+        print!("{}", monitor.prompt());
+        let _ = io::stdout().flush();
+
+        line.clear();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => print!("{}", monitor.command(&line))
+        }
+    }
+}
@@ -1,9 +1,19 @@
+#![allow(clippy::needless_return)]
+
+use crate::bus::MachineCycleKind;
+
 pub trait MemoryAccess {
     fn read_byte(&self, addr: u16) -> u8;
     fn write_byte(&mut self, addr: u16, val: u8);
 
     fn read_bytes<const C: usize> (&self, addr: u16) -> [u8; C];
     fn write_bytes(&mut self, addr: u16, val: &[u8]);
+
+    // Wait states the device at `addr` inserts into a machine cycle by holding
+    // READY low. They are added to the cycles reported by Intel8080::step
+    fn wait_states(&self, _addr: u16, _kind: MachineCycleKind) -> u64 {
+        0
+    }
 }
 
 pub struct Memory<const N: usize> {
@@ -13,7 +23,7 @@ pub struct Memory<const N: usize> {
 impl<const N: usize> Memory<N> {
     pub fn new() -> Self {
         return Memory {
-            arr: [0 as u8; N]
+            arr: [0; N]
         }
     }
 
@@ -25,6 +35,20 @@ impl<const N: usize> Memory<N> {
     pub fn get_bytes(&self, start: u16, end: u16) -> &[u8] {
         &self.arr[start as usize..end as usize]
     }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.arr
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.arr
+    }
+}
+
+impl<const N: usize> Default for Memory<N> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<const N: usize> MemoryAccess for Memory<N> {
@@ -0,0 +1,601 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::asm::Assembler;
+use crate::bdos::{Bdos, Exit, TPA};
+use crate::console::Console;
+use crate::cpu::{Flags, Intel8080};
+use crate::disasm::{disassemble, Syntax};
+use crate::hex::load_hex;
+use crate::loader::ImageFormat;
+use crate::memory::{Memory, MemoryAccess};
+use crate::srec::load_srec;
+
+// Command monitor in the style of CP/M's DDT, driven a line at a time by the
+// emu8080 binary. Numbers are hex. A .COM file is loaded at the TPA with the
+// BDOS set up, and BDOS calls are answered while it runs.
+//
+//   Ifile args   name the file R reads and set the command tail
+//   R[bias]      read the file, HEX and S-records at their addresses plus
+//                bias, .COM at 0100, anything else at bias
+//   D[s][,f]     dump memory
+//   L[s][,f]     list instructions
+//   A[s]         assemble a line at a time, until an empty line or '.'
+//   S[s]         set memory a byte at a time, until '.'
+//   X[r]         show the registers, or change register or flag r
+//   G[s][,b...]  go from s until one of the temporary breakpoints or a halt
+//   T[n]         trace n instructions, showing the registers before each
+//   U[n]         run n instructions, showing the registers after the last
+//   Q            quit
+
+// Lines shown by D and L without an end address
+const DUMP_LINES: u16 = 12;
+const LIST_LINES: u16 = 12;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Mode {
+    Command,
+    Assemble(u16),
+    Set(u16),
+    Register(char)
+}
+
+// Register names X accepts, flags first
+const REGISTERS: &str = "CZMEIABDHSP";
+
+pub struct Monitor<C: Console> {
+    cpu: Intel8080,
+    memory: Box<Memory<0x10000>>,
+    bdos: Bdos<C>,
+    // a .COM program is loaded and its BDOS calls are trapped
+    cpm: bool,
+    file: Option<PathBuf>,
+    args: String,
+    mode: Mode,
+    // where D and L continue from
+    dump: u16,
+    list: u16,
+    quit: bool
+}
+
+impl<C: Console> Monitor<C> {
+    // `directory` is where the BDOS of a loaded .COM program finds its files
+    pub fn new(directory: impl Into<PathBuf>, console: C) -> Self {
+        let mut cpu = Intel8080::new();
+        cpu.registers_mut().set_pc(TPA);
+        Self {
+            cpu,
+            memory: Box::new(Memory::new()),
+            bdos: Bdos::new(directory, console),
+            cpm: false,
+            file: None,
+            args: String::new(),
+            mode: Mode::Command,
+            dump: TPA,
+            list: TPA,
+            quit: false
+        }
+    }
+
+    pub fn cpu(&self) -> &Intel8080 {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Intel8080 {
+        &mut self.cpu
+    }
+
+    pub fn memory(&self) -> &Memory<0x10000> {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory<0x10000> {
+        &mut self.memory
+    }
+
+    pub fn console(&self) -> &C {
+        self.bdos.console()
+    }
+
+    // Whether Q was given
+    pub fn quit(&self) -> bool {
+        self.quit
+    }
+
+    pub fn prompt(&self) -> String {
+        match self.mode {
+            Mode::Command => "-".to_string(),
+            Mode::Assemble(addr) => format!("{addr:04X} "),
+            Mode::Set(addr) => format!("{addr:04X} {:02X} ", self.memory.read_byte(addr)),
+            Mode::Register(name) => format!("{name}={} ", self.register_text(name))
+        }
+    }
+
+    // Handle a line of input, returning what to show for it
+    pub fn command(&mut self, line: &str) -> String {
+        let line = line.trim();
+        match self.mode {
+            Mode::Command => {},
+            Mode::Assemble(addr) => return self.assemble_line(addr, line),
+            Mode::Set(addr) => return self.set_line(addr, line),
+            Mode::Register(name) => return self.register_line(name, line)
+        }
+
+        let Some(command) = line.chars().next() else {
+            return String::new();
+        };
+        let args = line[command.len_utf8()..].trim();
+        let output = match command.to_ascii_uppercase() {
+            'I' => self.name_file(args),
+            'R' => self.read(args),
+            'D' => self.dump(args),
+            'L' => self.list(args),
+            'A' => self.enter_mode(args, Mode::Assemble),
+            'S' => self.enter_mode(args, Mode::Set),
+            'X' => self.examine(args),
+            'G' => self.go(args),
+            'T' => self.trace(args, true),
+            'U' => self.trace(args, false),
+            'Q' => {
+                self.quit = true;
+                Some(String::new())
+            },
+            _ => None
+        };
+        output.unwrap_or_else(|| "?\n".to_string())
+    }
+
+    fn name_file(&mut self, args: &str) -> Option<String> {
+        let (file, tail) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        if file.is_empty() {
+            return None;
+        }
+        self.file = Some(PathBuf::from(file));
+        self.args = tail.trim().to_string();
+        Some(String::new())
+    }
+
+    fn read(&mut self, args: &str) -> Option<String> {
+        let bias = parse_or(args, 0)?;
+        let Some(path) = self.file.clone() else {
+            return Some("no file, name one with I\n".to_string());
+        };
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(err) => return Some(format!("{}: {err}\n", path.display()))
+        };
+
+        let com = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("com"));
+        let loaded = if com {
+            if bias != 0 {
+                return None;
+            }
+            self.bdos.load_com(&mut self.cpu, &mut *self.memory, &contents, &self.args)
+                .map(|_| (TPA.wrapping_add(contents.len() as u16), None))
+                .map_err(|err| err.to_string())
+        }
+        else {
+            let mut image = Biased { memory: &mut self.memory, bias, next: bias };
+            let entry = match ImageFormat::from_path(&path) {
+                ImageFormat::IntelHex => load_hex(&String::from_utf8_lossy(&contents), &mut image)
+                    .map_err(|err| err.to_string()),
+                ImageFormat::SRecord => load_srec(&String::from_utf8_lossy(&contents), &mut image)
+                    .map_err(|err| err.to_string()),
+                ImageFormat::Binary => {
+                    image.write_bytes(0, &contents);
+                    Ok(None)
+                }
+            };
+            // the start address moves with the data
+            entry.map(|entry| (image.next, entry.map(|entry| entry.wrapping_add(bias))))
+        };
+
+        let (next, entry) = match loaded {
+            Ok(loaded) => loaded,
+            Err(err) => return Some(format!("{}: {err}\n", path.display()))
+        };
+        if let Some(entry) = entry {
+            self.cpu.registers_mut().set_pc(entry);
+        }
+        self.cpm = com;
+        let pc = self.cpu.registers().pc();
+        self.dump = pc;
+        self.list = pc;
+        Some(format!("NEXT  PC\n{next:04X} {pc:04X}\n"))
+    }
+
+    fn dump(&mut self, args: &str) -> Option<String> {
+        let (start, end) = self.range(args, self.dump)?;
+        let end = end.unwrap_or(start.wrapping_add(DUMP_LINES * 16 - 1));
+
+        let mut output = String::new();
+        let mut addr = start;
+        loop {
+            let len = (end.wrapping_sub(addr) as usize + 1).min(16);
+            let bytes: Vec<u8> = (0..len as u16).map(|i| self.memory.read_byte(addr.wrapping_add(i))).collect();
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+            let text: String = bytes.iter()
+                .map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' })
+                .collect();
+            output += &format!("{addr:04X} {:<47}  {text}\n", hex.join(" "));
+
+            addr = addr.wrapping_add(len as u16);
+            if len < 16 || addr == end.wrapping_add(1) {
+                break;
+            }
+        }
+        self.dump = addr;
+        Some(output)
+    }
+
+    fn list(&mut self, args: &str) -> Option<String> {
+        let (start, end) = self.range(args, self.list)?;
+
+        let mut output = String::new();
+        let mut addr = start;
+        for line in 0.. {
+            let done = match end {
+                Some(end) => addr.wrapping_sub(start) > end.wrapping_sub(start),
+                None => line == LIST_LINES
+            };
+            if done {
+                break;
+            }
+            let disassembly = disassemble(&*self.memory, addr, Syntax::Intel);
+            output += &disassembly.listing_line();
+            output.push('\n');
+            addr = addr.wrapping_add(disassembly.length);
+        }
+        self.list = addr;
+        Some(output)
+    }
+
+    fn enter_mode(&mut self, args: &str, mode: fn(u16) -> Mode) -> Option<String> {
+        let addr = parse_or(args, self.cpu.registers().pc())?;
+        self.mode = mode(addr);
+        Some(String::new())
+    }
+
+    fn assemble_line(&mut self, addr: u16, line: &str) -> String {
+        if line.is_empty() || line == "." {
+            self.mode = Mode::Command;
+            self.list = addr;
+            return String::new();
+        }
+
+        let mut assembler = Assembler::new();
+        assembler.set_radix(16);
+        let program = match assembler.assemble(&format!("\tORG 0{addr:X}H\n\t{line}\n")) {
+            Ok(program) => program,
+            Err(_) => return "?\n".to_string()
+        };
+        program.load_into(&mut *self.memory);
+        let len: usize = program.segments.iter().map(|segment| segment.bytes.len()).sum();
+        self.mode = Mode::Assemble(addr.wrapping_add(len as u16));
+        String::new()
+    }
+
+    fn set_line(&mut self, addr: u16, line: &str) -> String {
+        if line == "." {
+            self.mode = Mode::Command;
+            return String::new();
+        }
+        if !line.is_empty() {
+            match parse_hex(line) {
+                Some(val) if val <= 0xff => self.memory.write_byte(addr, val as u8),
+                _ => return "?\n".to_string()
+            }
+        }
+        self.mode = Mode::Set(addr.wrapping_add(1));
+        String::new()
+    }
+
+    fn examine(&mut self, args: &str) -> Option<String> {
+        if args.is_empty() {
+            return Some(self.registers_line() + "\n");
+        }
+
+        let name = args.to_ascii_uppercase();
+        let mut chars = name.chars();
+        match (chars.next(), chars.next()) {
+            (Some(name), None) if REGISTERS.contains(name) => {
+                self.mode = Mode::Register(name);
+                Some(String::new())
+            },
+            _ => None
+        }
+    }
+
+    fn register_line(&mut self, name: char, line: &str) -> String {
+        self.mode = Mode::Command;
+        if line.is_empty() {
+            return String::new();
+        }
+
+        let Some(val) = parse_hex(line) else {
+            return "?\n".to_string();
+        };
+        let registers = self.cpu.registers_mut();
+        let flag = match name {
+            'C' => Flags::CARRY,
+            'Z' => Flags::ZERO,
+            'M' => Flags::SIGN,
+            'E' => Flags::PARITY,
+            'I' => Flags::AUX_CARRY,
+            _ => Flags::empty()
+        };
+        match name {
+            _ if !flag.is_empty() && val <= 1 => {
+                let mut flags = registers.flags();
+                flags.set(flag, val == 1);
+                registers.set_flags(flags);
+            },
+            'A' if val <= 0xff => registers.set_accumulator(val as u8),
+            'B' => registers.set_pair_b(val),
+            'D' => registers.set_pair_d(val),
+            'H' => registers.set_pair_h(val),
+            'S' => registers.set_sp(val),
+            'P' => registers.set_pc(val),
+            _ => return "?\n".to_string()
+        }
+        String::new()
+    }
+
+    fn register_text(&self, name: char) -> String {
+        let registers = self.cpu.registers();
+        let flags = registers.flags();
+        match name {
+            'C' => (flags.contains(Flags::CARRY) as u8).to_string(),
+            'Z' => (flags.contains(Flags::ZERO) as u8).to_string(),
+            'M' => (flags.contains(Flags::SIGN) as u8).to_string(),
+            'E' => (flags.contains(Flags::PARITY) as u8).to_string(),
+            'I' => (flags.contains(Flags::AUX_CARRY) as u8).to_string(),
+            'A' => format!("{:02X}", registers.accumulator()),
+            'B' => format!("{:04X}", registers.pair_b()),
+            'D' => format!("{:04X}", registers.pair_d()),
+            'H' => format!("{:04X}", registers.pair_h()),
+            'S' => format!("{:04X}", registers.sp()),
+            _ => format!("{:04X}", registers.pc())
+        }
+    }
+
+    // `C0Z1M0E1I0 A=00 B=0000 D=0000 H=0000 S=0100 P=0100 MVI A,42h`
+    fn registers_line(&self) -> String {
+        let mut line = String::new();
+        for name in REGISTERS.chars() {
+            line += &match name {
+                'C' | 'Z' | 'M' | 'E' | 'I' => format!("{name}{}", self.register_text(name)),
+                _ => format!(" {name}={}", self.register_text(name))
+            };
+        }
+
+        let pc = self.cpu.registers().pc();
+        line + " " + &disassemble(&*self.memory, pc, Syntax::Intel).text
+    }
+
+    fn go(&mut self, args: &str) -> Option<String> {
+        let mut fields = args.split(',').map(str::trim);
+        let start = fields.next().unwrap_or("");
+        let mut breakpoints = BTreeSet::new();
+        for field in fields {
+            breakpoints.insert(parse_hex(field)?);
+        }
+        // a new start address also leaves a halt
+        if !start.is_empty() {
+            let mut state = self.cpu.save_state();
+            state.pc = parse_hex(start)?;
+            state.halted = false;
+            self.cpu.restore_state(&state);
+        }
+
+        Some(self.execute(&breakpoints, None, false))
+    }
+
+    fn trace(&mut self, args: &str, show: bool) -> Option<String> {
+        let count = parse_or(args, 1)?;
+        let output = self.execute(&BTreeSet::new(), Some(count as u64), show);
+        match show {
+            true => Some(output),
+            false => Some(self.registers_line() + "\n" + &output)
+        }
+    }
+
+    // Run until a breakpoint, a halt, the end of a CP/M program or `count`
+    // instructions, ending the output with the stop address as `*addr`.
+    // With `trace` the registers are shown before each instruction
+    fn execute(&mut self, breakpoints: &BTreeSet<u16>, count: Option<u64>, trace: bool) -> String {
+        let mut output = String::new();
+        let mut steps = 0;
+        let stop = loop {
+            if self.cpm {
+                if let Some(exit) = self.bdos.trap(&mut self.cpu, &mut *self.memory) {
+                    self.cpm = false;
+                    break match exit {
+                        Exit::WarmBoot => " warm boot",
+                        Exit::SystemReset => " system reset",
+                        Exit::Halted => " halted"
+                    };
+                }
+            }
+            if count.is_some_and(|count| steps == count) {
+                break "";
+            }
+            if trace {
+                output += &(self.registers_line() + "\n");
+            }
+
+            self.cpu.step(&mut *self.memory, &mut ());
+            steps += 1;
+            if self.cpu.halted() {
+                break " halted";
+            }
+            if breakpoints.contains(&self.cpu.registers().pc()) {
+                break "";
+            }
+        };
+
+        let pc = self.cpu.registers().pc();
+        self.list = pc;
+        output + &format!("*{pc:04X}{stop}\n")
+    }
+
+    // "s,f", either optional; s defaults to `start`
+    fn range(&self, args: &str, start: u16) -> Option<(u16, Option<u16>)> {
+        let (first, last) = args.split_once(',').unwrap_or((args, ""));
+        let first = parse_or(first.trim(), start)?;
+        let last = match last.trim() {
+            "" => None,
+            last => Some(parse_hex(last)?)
+        };
+        Some((first, last))
+    }
+}
+
+// Memory seen through a load bias, noting the address past the highest byte
+// written
+struct Biased<'a> {
+    memory: &'a mut Memory<0x10000>,
+    bias: u16,
+    next: u16
+}
+
+impl MemoryAccess for Biased<'_> {
+    fn read_byte(&self, addr: u16) -> u8 {
+        self.memory.read_byte(addr.wrapping_add(self.bias))
+    }
+
+    fn write_byte(&mut self, addr: u16, val: u8) {
+        let addr = addr.wrapping_add(self.bias);
+        self.memory.write_byte(addr, val);
+        self.next = self.next.max(addr.wrapping_add(1));
+    }
+
+    fn read_bytes<const C: usize>(&self, addr: u16) -> [u8; C] {
+        std::array::from_fn(|i| self.read_byte(addr.wrapping_add(i as u16)))
+    }
+
+    fn write_bytes(&mut self, addr: u16, val: &[u8]) {
+        for (offset, byte) in val.iter().enumerate() {
+            self.write_byte(addr.wrapping_add(offset as u16), *byte);
+        }
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    let text = text.trim();
+    let digits = text.strip_suffix(['H', 'h']).unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn parse_or(text: &str, default: u16) -> Option<u16> {
+    match text.trim() {
+        "" => Some(default),
+        text => parse_hex(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+    use crate::console::BufferConsole;
+    use crate::hex::write_hex;
+
+    fn monitor() -> Monitor<BufferConsole> {
+        Monitor::new(".", BufferConsole::default())
+    }
+
+    fn run(monitor: &mut Monitor<BufferConsole>, lines: &[&str]) -> String {
+        lines.iter().map(|line| monitor.command(line)).collect()
+    }
+
+    #[test]
+    fn test_assemble_and_list() {
+        let mut monitor = monitor();
+        assert_eq!(monitor.command("a100"), "");
+        assert_eq!(monitor.prompt(), "0100 ");
+        assert_eq!(run(&mut monitor, &["MVI A,42H", "LXI H,1234H", "BOGUS", "CALL 110H", "HLT"]), "?\n");
+        assert_eq!(monitor.prompt(), "0109 ");
+        assert_eq!(monitor.command("."), "");
+        assert_eq!(run(&mut monitor, &["A110", "INR A", "RET", ""]), "");
+        assert_eq!(monitor.prompt(), "-");
+
+        assert_eq!(monitor.command("L100,105"),
+            "0100  3E 42     MVI A,42h\n0102  21 34 12  LXI H,1234h\n0105  CD 10 01  CALL 0110h\n");
+        assert_eq!(monitor.command("L").lines().next(), Some("0108  76        HLT"));
+        assert_eq!(monitor.command("D100,111"),
+            "0100 3E 42 21 34 12 CD 10 01 76 00 00 00 00 00 00 00  >B!4....v.......\n\
+             0110 3C C9                                            <.\n");
+
+        assert_eq!(run(&mut monitor, &["S101", "", "43", "."]), "");
+        assert_eq!(monitor.memory().get_bytes(0x100, 0x103), &[0x3e, 0x42, 0x43]);
+        assert_eq!(monitor.command("S1FFFF"), "?\n");
+        assert_eq!(monitor.command("Y"), "?\n");
+
+        // bare numbers are hex, as everywhere else in the monitor
+        run(&mut monitor, &["A200", "JMP 100", "MVI A,10", "LXI B,1D", "ANI 0FB", ""]);
+        assert_eq!(monitor.memory().get_bytes(0x200, 0x20a), &[0xc3, 0x00, 0x01, 0x3e, 0x10, 0x01, 0x1d, 0x00, 0xe6, 0xfb]);
+    }
+
+    #[test]
+    fn test_registers_and_execution() {
+        let mut monitor = monitor();
+        run(&mut monitor, &["A100", "MVI A,42H", "CALL 108H", "INR A", "HLT", "", "A108", "DCR A", "RET", ""]);
+
+        assert_eq!(monitor.command("X"), "C0Z0M0E0I0 A=00 B=0000 D=0000 H=0000 S=0000 P=0100 MVI A,42h\n");
+        assert_eq!(run(&mut monitor, &["XS", "80", "XC", "1", "XB"]), "");
+        assert_eq!(monitor.prompt(), "B=0000 ");
+        assert_eq!(monitor.command("ZZ"), "?\n");
+        assert_eq!(monitor.command("XQ"), "?\n");
+        assert_eq!(monitor.command("X"), "C1Z0M0E0I0 A=00 B=0000 D=0000 H=0000 S=0080 P=0100 MVI A,42h\n");
+
+        assert_eq!(monitor.command("T2"),
+            "C1Z0M0E0I0 A=00 B=0000 D=0000 H=0000 S=0080 P=0100 MVI A,42h\n\
+             C1Z0M0E0I0 A=42 B=0000 D=0000 H=0000 S=0080 P=0102 CALL 0108h\n\
+             *0108\n");
+        assert_eq!(monitor.command("U"), "C1Z0M0E1I1 A=41 B=0000 D=0000 H=0000 S=007E P=0109 RET\n*0109\n");
+        assert_eq!(monitor.command("G,106"), "*0106\n");
+        assert_eq!(monitor.cpu().registers().accumulator(), 0x42);
+        assert_eq!(monitor.command("G"), "*0107 halted\n");
+        assert_eq!(monitor.command("G100,105,109"), "*0109\n");
+        assert_eq!(monitor.command("G100,x"), "?\n");
+    }
+
+    #[test]
+    fn test_read() {
+        let dir = temp_dir("monitor", "read");
+
+        // prints HI through the BDOS and returns to CP/M
+        let com = dir.join("HI.COM");
+        fs::write(&com, [0x0e, 0x02, 0x1e, b'H', 0xcd, 0x05, 0x00, 0x1e, b'I', 0xcd, 0x05, 0x00, 0xc9]).unwrap();
+        let mut monitor = monitor();
+        assert_eq!(monitor.command("R"), "no file, name one with I\n");
+        assert_eq!(monitor.command(&format!("I{} A.TXT", com.display())), "");
+        assert_eq!(monitor.command("R"), "NEXT  PC\n010D 0100\n");
+        assert_eq!(monitor.memory().get_bytes(0x5d, 0x60), b"A  ");
+        assert_eq!(monitor.command("R100"), "?\n");
+        assert_eq!(monitor.command("G"), "*0000 warm boot\n");
+        assert_eq!(monitor.console().output_string(), "HI");
+
+        let mut memory: Memory<0x100> = Memory::new();
+        memory.write_bytes(0x40, &[1, 2, 3]);
+        let hex = dir.join("image.hex");
+        fs::write(&hex, write_hex(&memory, 0x40, 3, Some(0x40))).unwrap();
+        let mut monitor = self::monitor();
+        monitor.command(&format!("I{}", hex.display()));
+        assert_eq!(monitor.command("R1000"), "NEXT  PC\n1043 1040\n");
+        assert_eq!(monitor.memory().get_bytes(0x1040, 0x1043), &[1, 2, 3]);
+
+        let bin = dir.join("rom.bin");
+        fs::write(&bin, [4, 5]).unwrap();
+        monitor.command(&format!("I{}", bin.display()));
+        assert_eq!(monitor.command("R"), "NEXT  PC\n0002 1040\n");
+        assert_eq!(monitor.memory().get_bytes(0, 2), &[4, 5]);
+
+        monitor.command(&format!("I{}", dir.join("missing.bin").display()));
+        assert!(monitor.command("R").contains("missing.bin"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::bus::{MachineCycle, MachineCycleKind};
+use crate::cpu::Intel8080;
+use crate::debugger::{fetch_cycle, FrameTracker};
+use crate::io::IoBus;
+use crate::memory::MemoryAccess;
+
+// Profiles guest code stepped through Profiler::step. Cycles are counted per
+// instruction address and per subroutine, where a subroutine is entered by a
+// CALL, RST or interrupt that pushes PC and left once SP rises above the
+// return address, so RET, POPs of the return address and stack resets all
+// end it. Code outside any call is counted against the address profiling
+// started at. Accepting an interrupt is counted against its handler, and
+// against no instruction address.
+
+// Instructions executed at an address or in a routine, and their cycles
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Counts {
+    pub instructions: u64,
+    pub cycles: u64
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct RoutineProfile {
+    pub calls: u64,
+    // instructions and cycles in the routine itself
+    pub own: Counts,
+    // cycles in the routine and everything it called
+    pub total_cycles: u64
+}
+
+// Calls from one routine to another and the cycles spent under them
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct CallArc {
+    pub calls: u64,
+    pub cycles: u64
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Profiler {
+    addresses: BTreeMap<u16, Counts>,
+    routines: BTreeMap<u16, RoutineProfile>,
+    // keyed by (caller, callee)
+    arcs: BTreeMap<(u16, u16), CallArc>,
+    // cycles per call stack, root first
+    stacks: BTreeMap<Vec<u16>, u64>,
+    // interrupts accepted and the cycles taken to accept them
+    interrupts: Counts,
+    names: BTreeMap<u16, String>,
+    root: Option<u16>,
+    frames: FrameTracker
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Name a routine in the reports, which otherwise show its address
+    pub fn name(&mut self, addr: u16, name: impl Into<String>) {
+        self.names.insert(addr, name.into());
+    }
+
+    pub fn address(&self, addr: u16) -> Option<&Counts> {
+        self.addresses.get(&addr)
+    }
+
+    pub fn addresses(&self) -> impl Iterator<Item = (u16, &Counts)> {
+        self.addresses.iter().map(|(addr, counts)| (*addr, counts))
+    }
+
+    pub fn routine(&self, addr: u16) -> Option<&RoutineProfile> {
+        self.routines.get(&addr)
+    }
+
+    pub fn arc(&self, caller: u16, callee: u16) -> Option<&CallArc> {
+        self.arcs.get(&(caller, callee))
+    }
+
+    pub fn interrupts(&self) -> &Counts {
+        &self.interrupts
+    }
+
+    // Cycles counted so far
+    pub fn cycles(&self) -> u64 {
+        self.addresses.values().map(|counts| counts.cycles).sum::<u64>() + self.interrupts.cycles
+    }
+
+    // Intel8080::step, profiled
+    pub fn step(&mut self, cpu: &mut Intel8080, memory: &mut impl MemoryAccess, io: &mut impl IoBus) -> u64 {
+        let pc = cpu.registers().pc();
+        let cycles = cpu.step_cycles(memory, io, &mut |_: &MachineCycle| 0);
+
+        // halted, or the bus is held
+        let Some(fetch) = fetch_cycle(cpu.machine_cycles()) else {
+            return cycles;
+        };
+        let interrupt = fetch.kind != MachineCycleKind::InstructionFetch;
+        self.root.get_or_insert(pc);
+
+        if !interrupt {
+            self.count(cycles);
+            let counts = self.addresses.entry(pc).or_default();
+            counts.instructions += 1;
+            counts.cycles += cycles;
+        }
+
+        if self.frames.update(cpu).is_some() {
+            let stack = self.stack();
+            let (caller, routine) = (stack[stack.len() - 2], stack[stack.len() - 1]);
+            self.routines.entry(routine).or_default().calls += 1;
+            self.arcs.entry((caller, routine)).or_default().calls += 1;
+        }
+        if interrupt {
+            self.count(cycles);
+            self.interrupts.instructions += 1;
+            self.interrupts.cycles += cycles;
+        }
+        cycles
+    }
+
+    // Routines entered, from the root to the innermost
+    fn stack(&self) -> Vec<u16> {
+        let root = self.root.unwrap_or(0);
+        std::iter::once(root).chain(self.frames.frames().iter().map(|frame| frame.entry)).collect()
+    }
+
+    // Charge an instruction to the routines on the stack
+    fn count(&mut self, cycles: u64) {
+        let stack = self.stack();
+
+        let own = &mut self.routines.entry(stack[stack.len() - 1]).or_default().own;
+        own.instructions += 1;
+        own.cycles += cycles;
+
+        // a recursive routine or arc is only charged once per instruction
+        let routines: BTreeSet<u16> = stack.iter().copied().collect();
+        for routine in routines {
+            self.routines.entry(routine).or_default().total_cycles += cycles;
+        }
+        let arcs: BTreeSet<(u16, u16)> = stack.windows(2).map(|pair| (pair[0], pair[1])).collect();
+        for arc in arcs {
+            self.arcs.entry(arc).or_default().cycles += cycles;
+        }
+
+        *self.stacks.entry(stack).or_default() += cycles;
+    }
+
+    fn routine_name(&self, addr: u16) -> String {
+        match self.names.get(&addr) {
+            Some(name) => name.clone(),
+            None => format!("{addr:04X}")
+        }
+    }
+
+    // Routines by their own cycles, most first
+    pub fn flat_report(&self) -> String {
+        let total = self.cycles().max(1) as f64;
+        let mut routines: Vec<(&u16, &RoutineProfile)> = self.routines.iter().collect();
+        routines.sort_by(|a, b| b.1.own.cycles.cmp(&a.1.own.cycles).then(a.0.cmp(b.0)));
+
+        let mut report = format!("{:>7} {:>12} {:>12} {:>12} {:>8}  routine\n",
+            "%own", "own cycles", "total cycles", "instructions", "calls");
+        for (addr, routine) in routines {
+            report += &format!("{:>7.2} {:>12} {:>12} {:>12} {:>8}  {}\n",
+                routine.own.cycles as f64 * 100.0 / total, routine.own.cycles, routine.total_cycles,
+                routine.own.instructions, routine.calls, self.routine_name(*addr));
+        }
+        report
+    }
+
+    // Each routine by total cycles, with the routines that called it above
+    // and the routines it called below, and the cycles spent under each call
+    pub fn call_graph_report(&self) -> String {
+        let mut routines: Vec<(&u16, &RoutineProfile)> = self.routines.iter().collect();
+        routines.sort_by(|a, b| b.1.total_cycles.cmp(&a.1.total_cycles).then(a.0.cmp(b.0)));
+
+        let mut report = format!("{:>12} {:>12} {:>12}  routine\n\n", "calls", "own cycles", "total cycles");
+        for (addr, routine) in routines {
+            for ((caller, _), arc) in self.arcs.iter().filter(|((_, callee), _)| callee == addr) {
+                report += &format!("{:>12} {:>12} {:>12}      from {}\n", arc.calls, "", arc.cycles, self.routine_name(*caller));
+            }
+            report += &format!("{:>12} {:>12} {:>12}  {}\n",
+                routine.calls, routine.own.cycles, routine.total_cycles, self.routine_name(*addr));
+            for ((_, callee), arc) in self.arcs.iter().filter(|((caller, _), _)| caller == addr) {
+                report += &format!("{:>12} {:>12} {:>12}      to {}\n", arc.calls, "", arc.cycles, self.routine_name(*callee));
+            }
+            report.push('\n');
+        }
+        report
+    }
+
+    // A line per call stack in the collapsed format of flamegraph.pl and
+    // inferno: routines from the root separated by ';', then the cycles
+    pub fn collapsed_stacks(&self) -> String {
+        let mut report = String::new();
+        for (stack, cycles) in &self.stacks {
+            let names: Vec<String> = stack.iter().map(|addr| self.routine_name(*addr)).collect();
+            report += &format!("{} {cycles}\n", names.join(";"));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::memory::Memory;
+
+    #[test]
+    fn test_profile() {
+        let program = assemble("
+        ORG     0
+START:  LXI     SP,80H
+        MVI     B,2
+LOOP:   CALL    OUTER
+        DCR     B
+        JNZ     LOOP
+        CZ      INNER
+        HLT
+OUTER:  CALL    INNER
+        CNZ     INNER
+        RET
+INNER:  INR     A
+        RET
+").unwrap();
+        let mut memory: Memory<0x100> = Memory::new();
+        program.load_into(&mut memory);
+        let symbol = |name| program.symbol(name).unwrap();
+
+        let mut cpu = Intel8080::new();
+        let mut profiler = Profiler::new();
+        profiler.name(0, "START");
+        profiler.name(symbol("OUTER"), "OUTER");
+        profiler.name(symbol("INNER"), "INNER");
+        let mut cycles = 0;
+        while !cpu.halted() {
+            cycles += profiler.step(&mut cpu, &mut memory, &mut ());
+        }
+        // steps while halted are not counted
+        profiler.step(&mut cpu, &mut memory, &mut ());
+
+        assert_eq!(profiler.cycles(), cycles);
+        assert_eq!(profiler.address(symbol("LOOP")), Some(&Counts { instructions: 2, cycles: 34 }));
+        assert_eq!(profiler.address(symbol("INNER")), Some(&Counts { instructions: 5, cycles: 25 }));
+
+        // INR A and RET, five times over
+        let inner = profiler.routine(symbol("INNER")).unwrap();
+        assert_eq!(inner, &RoutineProfile { calls: 5, own: Counts { instructions: 10, cycles: 75 }, total_cycles: 75 });
+        // CALL, CNZ and RET, twice
+        let outer = profiler.routine(symbol("OUTER")).unwrap();
+        assert_eq!(outer.calls, 2);
+        assert_eq!(outer.own, Counts { instructions: 6, cycles: 2 * (17 + 17 + 10) });
+        assert_eq!(outer.total_cycles, outer.own.cycles + 4 * 15);
+        let start = profiler.routine(0).unwrap();
+        assert_eq!(start.total_cycles, cycles);
+        assert_eq!(profiler.arc(symbol("OUTER"), symbol("INNER")), Some(&CallArc { calls: 4, cycles: 60 }));
+        assert_eq!(profiler.arc(0, symbol("INNER")), Some(&CallArc { calls: 1, cycles: 15 }));
+
+        assert_eq!(profiler.collapsed_stacks(),
+            format!("START {}\nSTART;OUTER {}\nSTART;OUTER;INNER 60\nSTART;INNER 15\n",
+                start.own.cycles, outer.own.cycles));
+
+        let flat = profiler.flat_report();
+        let lines: Vec<&str> = flat.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].ends_with("START"));
+        assert!(lines[3].ends_with(&format!("{:>12} {:>8}  INNER", 10, 5)));
+
+        let graph = profiler.call_graph_report();
+        assert!(graph.contains(&format!("{:>12} {:>12} {:>12}      from OUTER\n", 4, "", 60)));
+        assert!(graph.contains(&format!("{:>12} {:>12} {:>12}      to INNER\n", 4, "", 60)));
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let program = assemble("
+        ORG     0
+        LXI     SP,80H
+        EI
+SPIN:   JMP     SPIN
+        ORG     38H
+        RET
+").unwrap();
+        let mut memory: Memory<0x100> = Memory::new();
+        program.load_into(&mut memory);
+        let spin = program.symbol("SPIN").unwrap();
+
+        let mut cpu = Intel8080::new();
+        let mut profiler = Profiler::new();
+        let mut cycles = 0;
+        for _ in 0..3 {
+            cycles += profiler.step(&mut cpu, &mut memory, &mut ());
+        }
+        // a floating bus answers the acknowledge with RST 7
+        cpu.set_int(true);
+        cycles += profiler.step(&mut cpu, &mut memory, &mut ());
+        cpu.set_int(false);
+        for _ in 0..2 {
+            cycles += profiler.step(&mut cpu, &mut memory, &mut ());
+        }
+        assert_eq!(cpu.registers().pc(), spin);
+
+        assert_eq!(profiler.cycles(), cycles);
+        assert_eq!(profiler.interrupts(), &Counts { instructions: 1, cycles: 11 });
+        assert_eq!(profiler.address(spin), Some(&Counts { instructions: 2, cycles: 20 }));
+        assert_eq!(profiler.address(0x38), Some(&Counts { instructions: 1, cycles: 10 }));
+        assert_eq!(profiler.routine(0x38),
+            Some(&RoutineProfile { calls: 1, own: Counts { instructions: 2, cycles: 21 }, total_cycles: 21 }));
+        assert_eq!(profiler.arc(0, 0x38), Some(&CallArc { calls: 1, cycles: 21 }));
+    }
+}
@@ -0,0 +1,431 @@
+use std::fmt;
+
+// Microsoft relocatable object format, as written by M80 and read by L80.
+//
+// A .REL file is a bit stream, most significant bit first:
+//
+//   0 + 8 bits               absolute byte
+//   1 01 + 16 bits           program relative word, low byte first
+//   1 10 + 16 bits           data relative word
+//   1 11 + 16 bits           common relative word
+//   1 00 + 4 bit control     link item, followed by an A field (2 bit
+//                            address type + 16 bit value), a B field (3 bit
+//                            length + up to 7 8-bit characters) or both, A
+//                            field first
+//
+// End of module (control 14) and end of file (15) are padded to a byte
+// boundary.
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum AddressType {
+    #[default]
+    Absolute,
+    Program,
+    Data,
+    Common
+}
+
+impl AddressType {
+    fn from_bits(bits: u16) -> Self {
+        match bits & 0x3 {
+            0 => AddressType::Absolute,
+            1 => AddressType::Program,
+            2 => AddressType::Data,
+            _ => AddressType::Common
+        }
+    }
+
+    fn bits(&self) -> u16 {
+        match self {
+            AddressType::Absolute => 0,
+            AddressType::Program => 1,
+            AddressType::Data => 2,
+            AddressType::Common => 3
+        }
+    }
+}
+
+// Value of an A field, relative to the segment given by `kind`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Address {
+    pub kind: AddressType,
+    pub value: u16
+}
+
+impl Address {
+    pub fn absolute(value: u16) -> Self {
+        Self { kind: AddressType::Absolute, value }
+    }
+
+    pub fn program(value: u16) -> Self {
+        Self { kind: AddressType::Program, value }
+    }
+
+    pub fn data(value: u16) -> Self {
+        Self { kind: AddressType::Data, value }
+    }
+}
+
+// Names longer than this are truncated when written
+pub const MAX_NAME_LEN: usize = 7;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum RelItem {
+    // loaded at the location counter as is
+    Byte(u8),
+    // loaded at the location counter after adding the base of its segment
+    Word(AddressType, u16),
+    // 0: name of a PUBLIC, for library searches
+    EntrySymbol(String),
+    // 1: following common relative items refer to this COMMON block
+    SelectCommon(String),
+    // 2
+    ProgramName(String),
+    // 3
+    LibrarySearch(String),
+    // 4: B field reserved for extensions
+    Extension(String),
+    // 5: size of a COMMON block
+    CommonSize(Address, String),
+    // 6: the words of a chain through the loaded code, starting at the
+    // address and ending at absolute 0, are replaced by the symbol's value
+    ChainExternal(Address, String),
+    // 7: PUBLIC symbol and its value
+    EntryPoint(Address, String),
+    // 8: subtract from the next word once externals are resolved
+    ExternalMinusOffset(Address),
+    // 9: add to the next word once externals are resolved
+    ExternalPlusOffset(Address),
+    // 10
+    DataSize(Address),
+    // 11
+    SetLocation(Address),
+    // 12: the words of a chain are replaced by the location counter
+    ChainAddress(Address),
+    // 13
+    ProgramSize(Address),
+    // 14: the address is the start address of the program, absolute 0 if none
+    EndModule(Address),
+    // 15
+    EndFile
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum RelError {
+    // the stream ended before the end of file item
+    Truncated,
+    // a name with characters outside 7-bit ASCII
+    InvalidName
+}
+
+impl fmt::Display for RelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelError::Truncated => write!(f, "REL file ends before its end of file item"),
+            RelError::InvalidName => write!(f, "REL file contains a symbol that is not ASCII")
+        }
+    }
+}
+
+impl std::error::Error for RelError {}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    // index of the next bit
+    pos: usize
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: usize) -> Result<u16, RelError> {
+        let mut val = 0;
+        for _ in 0..count {
+            let byte = self.bytes.get(self.pos / 8).ok_or(RelError::Truncated)?;
+            let bit = (byte >> (7 - self.pos % 8)) & 1;
+            val = (val << 1) | bit as u16;
+            self.pos += 1;
+        }
+
+        Ok(val)
+    }
+
+    fn word(&mut self) -> Result<u16, RelError> {
+        let low = self.bits(8)?;
+        let high = self.bits(8)?;
+        Ok((high << 8) | low)
+    }
+
+    fn align(&mut self) {
+        self.pos = self.pos.next_multiple_of(8);
+    }
+
+    fn a_field(&mut self) -> Result<Address, RelError> {
+        let kind = AddressType::from_bits(self.bits(2)?);
+        let value = self.word()?;
+        Ok(Address { kind, value })
+    }
+
+    fn b_field(&mut self) -> Result<String, RelError> {
+        let len = self.bits(3)?;
+        let mut name = String::new();
+        for _ in 0..len {
+            let c = self.bits(8)? as u8;
+            if !c.is_ascii() {
+                return Err(RelError::InvalidName);
+            }
+            name.push(c as char);
+        }
+
+        Ok(name)
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    // bits used in the last byte, 0 when it is full
+    used: usize
+}
+
+impl BitWriter {
+    fn bits(&mut self, count: usize, val: u16) {
+        for i in (0..count).rev() {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            let bit = ((val >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << (7 - self.used);
+            self.used = (self.used + 1) % 8;
+        }
+    }
+
+    fn word(&mut self, val: u16) {
+        self.bits(8, val & 0xff);
+        self.bits(8, val >> 8);
+    }
+
+    fn align(&mut self) {
+        self.used = 0;
+    }
+
+    fn a_field(&mut self, address: &Address) {
+        self.bits(2, address.kind.bits());
+        self.word(address.value);
+    }
+
+    fn b_field(&mut self, name: &str) {
+        let name: Vec<u8> = name.bytes().take(MAX_NAME_LEN).collect();
+        self.bits(3, name.len() as u16);
+        for c in name {
+            self.bits(8, (c & 0x7f) as u16);
+        }
+    }
+
+    fn link_item(&mut self, control: u16) {
+        self.bits(3, 0b100);
+        self.bits(4, control);
+    }
+}
+
+// Parse a REL file up to its end of file item. Anything after it, like CP/M
+// end of file padding, is ignored
+pub fn read_rel(bytes: &[u8]) -> Result<Vec<RelItem>, RelError> {
+    let mut reader = BitReader { bytes, pos: 0 };
+    let mut items = Vec::new();
+
+    loop {
+        if reader.bits(1)? == 0 {
+            items.push(RelItem::Byte(reader.bits(8)? as u8));
+            continue;
+        }
+
+        let item = match reader.bits(2)? {
+            0 => match reader.bits(4)? {
+                0 => RelItem::EntrySymbol(reader.b_field()?),
+                1 => RelItem::SelectCommon(reader.b_field()?),
+                2 => RelItem::ProgramName(reader.b_field()?),
+                3 => RelItem::LibrarySearch(reader.b_field()?),
+                4 => RelItem::Extension(reader.b_field()?),
+                5 => RelItem::CommonSize(reader.a_field()?, reader.b_field()?),
+                6 => RelItem::ChainExternal(reader.a_field()?, reader.b_field()?),
+                7 => RelItem::EntryPoint(reader.a_field()?, reader.b_field()?),
+                8 => RelItem::ExternalMinusOffset(reader.a_field()?),
+                9 => RelItem::ExternalPlusOffset(reader.a_field()?),
+                10 => RelItem::DataSize(reader.a_field()?),
+                11 => RelItem::SetLocation(reader.a_field()?),
+                12 => RelItem::ChainAddress(reader.a_field()?),
+                13 => RelItem::ProgramSize(reader.a_field()?),
+                14 => {
+                    let start = reader.a_field()?;
+                    reader.align();
+                    RelItem::EndModule(start)
+                },
+                _ => {
+                    items.push(RelItem::EndFile);
+                    return Ok(items);
+                }
+            },
+            kind => RelItem::Word(AddressType::from_bits(kind), reader.word()?)
+        };
+
+        items.push(item);
+    }
+}
+
+// Encode items as a REL file. An end of file item is appended if the items
+// do not end with one
+pub fn write_rel(items: &[RelItem]) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+
+    for item in items {
+        match item {
+            RelItem::Byte(byte) => {
+                writer.bits(1, 0);
+                writer.bits(8, *byte as u16);
+            },
+            RelItem::Word(kind, val) => {
+                writer.bits(1, 1);
+                writer.bits(2, kind.bits());
+                writer.word(*val);
+            },
+            RelItem::EntrySymbol(name) => {
+                writer.link_item(0);
+                writer.b_field(name);
+            },
+            RelItem::SelectCommon(name) => {
+                writer.link_item(1);
+                writer.b_field(name);
+            },
+            RelItem::ProgramName(name) => {
+                writer.link_item(2);
+                writer.b_field(name);
+            },
+            RelItem::LibrarySearch(name) => {
+                writer.link_item(3);
+                writer.b_field(name);
+            },
+            RelItem::Extension(name) => {
+                writer.link_item(4);
+                writer.b_field(name);
+            },
+            RelItem::CommonSize(address, name) => {
+                writer.link_item(5);
+                writer.a_field(address);
+                writer.b_field(name);
+            },
+            RelItem::ChainExternal(address, name) => {
+                writer.link_item(6);
+                writer.a_field(address);
+                writer.b_field(name);
+            },
+            RelItem::EntryPoint(address, name) => {
+                writer.link_item(7);
+                writer.a_field(address);
+                writer.b_field(name);
+            },
+            RelItem::ExternalMinusOffset(address) => {
+                writer.link_item(8);
+                writer.a_field(address);
+            },
+            RelItem::ExternalPlusOffset(address) => {
+                writer.link_item(9);
+                writer.a_field(address);
+            },
+            RelItem::DataSize(address) => {
+                writer.link_item(10);
+                writer.a_field(address);
+            },
+            RelItem::SetLocation(address) => {
+                writer.link_item(11);
+                writer.a_field(address);
+            },
+            RelItem::ChainAddress(address) => {
+                writer.link_item(12);
+                writer.a_field(address);
+            },
+            RelItem::ProgramSize(address) => {
+                writer.link_item(13);
+                writer.a_field(address);
+            },
+            RelItem::EndModule(address) => {
+                writer.link_item(14);
+                writer.a_field(address);
+                writer.align();
+            },
+            RelItem::EndFile => {
+                writer.link_item(15);
+                writer.align();
+                return writer.bytes;
+            }
+        }
+    }
+
+    writer.link_item(15);
+    writer.bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bit_layout() {
+        let items = vec![
+            RelItem::ProgramName("A".to_string()),
+            RelItem::Byte(0x3e),
+            RelItem::Word(AddressType::Program, 0x1234),
+            RelItem::EndModule(Address::absolute(0)),
+            RelItem::EndFile
+        ];
+
+        // 1 00 0010 001 01000001 | 0 00111110 | 1 01 00110100 00010010 |
+        // 1 00 1110 00 00000000 00000000 pad | 1 00 1111 pad
+        let bytes = vec![
+            0b1000_0100, 0b0101_0000, 0b0100_0111, 0b1101_0100, 0b1101_0000,
+            0b0100_1010, 0b0111_0000, 0b0000_0000, 0b0000_0000, 0b1001_1110
+        ];
+
+        assert_eq!(write_rel(&items), bytes);
+        assert_eq!(read_rel(&bytes).unwrap(), items);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let items = vec![
+            RelItem::ProgramName("MAIN".to_string()),
+            RelItem::LibrarySearch("FORLIB".to_string()),
+            RelItem::EntrySymbol("START".to_string()),
+            RelItem::SelectCommon("BUF".to_string()),
+            RelItem::CommonSize(Address::absolute(0x80), "BUF".to_string()),
+            RelItem::DataSize(Address::absolute(4)),
+            RelItem::ProgramSize(Address::program(3)),
+            RelItem::SetLocation(Address::data(0)),
+            RelItem::Byte(0xff),
+            RelItem::Word(AddressType::Common, 0x10),
+            RelItem::ExternalPlusOffset(Address::absolute(2)),
+            RelItem::ExternalMinusOffset(Address::absolute(1)),
+            RelItem::ChainAddress(Address::program(0)),
+            RelItem::ChainExternal(Address::program(1), "PRINT".to_string()),
+            RelItem::EntryPoint(Address::program(0), "START".to_string()),
+            RelItem::Extension("X".to_string()),
+            RelItem::EndModule(Address::program(0)),
+            RelItem::ProgramName("LIB".to_string()),
+            RelItem::EndModule(Address::absolute(0)),
+            RelItem::EndFile
+        ];
+
+        let mut bytes = write_rel(&items);
+        // CP/M pads files with ^Z
+        bytes.extend_from_slice(&[0x1a; 4]);
+        assert_eq!(read_rel(&bytes).unwrap(), items);
+    }
+
+    #[test]
+    fn test_errors() {
+        let items = vec![RelItem::ProgramName("TOOLONGNAME".to_string())];
+        let bytes = write_rel(&items);
+
+        assert_eq!(read_rel(&bytes).unwrap(), vec![RelItem::ProgramName("TOOLONG".to_string()), RelItem::EndFile]);
+        assert_eq!(read_rel(&bytes[..4]), Err(RelError::Truncated));
+        assert_eq!(read_rel(&[]), Err(RelError::Truncated));
+    }
+}
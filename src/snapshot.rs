@@ -0,0 +1,286 @@
+use std::fmt;
+
+use crate::cpu::Intel8080;
+use crate::memory::Memory;
+
+// Save states for Intel8080 plus the contents of a Memory<N>.
+//
+// Binary format, version 2. All multi-byte values are little endian.
+//
+//   offset  size  field
+//        0     4  magic "8080"
+//        4     2  format version (2)
+//        6     2  PC
+//        8     2  SP
+//       10     2  BC
+//       12     2  DE
+//       14     2  HL
+//       16     2  PSW (A in the high byte, status in the low byte)
+//       18     2  WZ
+//       20     1  I/O port latch
+//       21     1  state bits: 0 INTE, 1 EI delay, 2 halted, 3 INT pin,
+//                 4 HOLD pin, 5 HLDA
+//       22     8  DMA cycles not yet returned by step
+//       30     8  total stolen cycles
+//       38     8  total cycles, see Intel8080::cycles
+//       46     4  memory length N
+//       50     N  memory contents
+//
+// Version 1 is still read. It has no total cycles, which start at 0, and the
+// memory length follows the stolen cycles at offset 38.
+
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"8080";
+pub const SNAPSHOT_VERSION: u16 = 2;
+
+const HEADER_LEN: usize = 50;
+const HEADER_LEN_V1: usize = 42;
+
+const STATE_INTE: u8     = 0b0000_0001;
+const STATE_EI_DELAY: u8 = 0b0000_0010;
+const STATE_HALTED: u8   = 0b0000_0100;
+const STATE_INT: u8      = 0b0000_1000;
+const STATE_HOLD: u8     = 0b0001_0000;
+const STATE_HLDA: u8     = 0b0010_0000;
+
+// CPU state between two instructions, see Intel8080::save_state
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuState {
+    pub pc: u16,
+    pub sp: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub psw: u16,
+    pub wz: u16,
+    pub io_port: u8,
+    pub inte: bool,
+    pub ei_delay: bool,
+    pub halted: bool,
+    pub int_line: bool,
+    pub hold_line: bool,
+    pub hlda: bool,
+    pub dma_cycles: u64,
+    pub stolen_cycles: u64,
+    pub cycles: u64
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot {
+    pub cpu: CpuState,
+    pub memory: Vec<u8>
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    MemorySizeMismatch { expected: usize, found: usize }
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not an 8080 snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {version}")
+            },
+            SnapshotError::Truncated => write!(f, "snapshot data is truncated"),
+            SnapshotError::MemorySizeMismatch { expected, found } => {
+                write!(f, "snapshot holds {found} bytes of memory, expected {expected}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl Snapshot {
+    pub fn capture<const N: usize>(cpu: &Intel8080, memory: &Memory<N>) -> Self {
+        Self {
+            cpu: cpu.save_state(),
+            memory: memory.as_slice().to_vec()
+        }
+    }
+
+    pub fn restore<const N: usize>(&self, cpu: &mut Intel8080, memory: &mut Memory<N>) -> Result<(), SnapshotError> {
+        if self.memory.len() != N {
+            return Err(SnapshotError::MemorySizeMismatch { expected: N, found: self.memory.len() });
+        }
+
+        cpu.restore_state(&self.cpu);
+        memory.as_mut_slice().copy_from_slice(&self.memory);
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let cpu = &self.cpu;
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.memory.len());
+
+        bytes.extend_from_slice(&SNAPSHOT_MAGIC);
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        for pair in [cpu.pc, cpu.sp, cpu.bc, cpu.de, cpu.hl, cpu.psw, cpu.wz] {
+            bytes.extend_from_slice(&pair.to_le_bytes());
+        }
+        bytes.push(cpu.io_port);
+
+        let mut state = 0;
+        for (bit, set) in [
+            (STATE_INTE, cpu.inte),
+            (STATE_EI_DELAY, cpu.ei_delay),
+            (STATE_HALTED, cpu.halted),
+            (STATE_INT, cpu.int_line),
+            (STATE_HOLD, cpu.hold_line),
+            (STATE_HLDA, cpu.hlda)] {
+            if set {
+                state |= bit;
+            }
+        }
+        bytes.push(state);
+
+        bytes.extend_from_slice(&cpu.dma_cycles.to_le_bytes());
+        bytes.extend_from_slice(&cpu.stolen_cycles.to_le_bytes());
+        bytes.extend_from_slice(&cpu.cycles.to_le_bytes());
+        bytes.extend_from_slice(&(self.memory.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.memory);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        if bytes.len() < 6 {
+            return Err(SnapshotError::Truncated);
+        }
+        if bytes[0..4] != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        let header_len = match version {
+            1 => HEADER_LEN_V1,
+            SNAPSHOT_VERSION => HEADER_LEN,
+            _ => return Err(SnapshotError::UnsupportedVersion(version))
+        };
+        if bytes.len() < header_len {
+            return Err(SnapshotError::Truncated);
+        }
+
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let state = bytes[21];
+
+        let cpu = CpuState {
+            pc: u16_at(6),
+            sp: u16_at(8),
+            bc: u16_at(10),
+            de: u16_at(12),
+            hl: u16_at(14),
+            psw: u16_at(16),
+            wz: u16_at(18),
+            io_port: bytes[20],
+            inte: state & STATE_INTE != 0,
+            ei_delay: state & STATE_EI_DELAY != 0,
+            halted: state & STATE_HALTED != 0,
+            int_line: state & STATE_INT != 0,
+            hold_line: state & STATE_HOLD != 0,
+            hlda: state & STATE_HLDA != 0,
+            dma_cycles: u64_at(22),
+            stolen_cycles: u64_at(30),
+            cycles: if version == 1 { 0 } else { u64_at(38) }
+        };
+
+        let len = u32::from_le_bytes(bytes[header_len - 4..header_len].try_into().unwrap()) as usize;
+        let memory = bytes.get(header_len..header_len + len).ok_or(SnapshotError::Truncated)?;
+
+        Ok(Self { cpu, memory: memory.to_vec() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Instruction;
+    use crate::memory::MemoryAccess;
+
+    fn counter_program() -> Memory<0x100> {
+        let mut memory: Memory<0x100> = Memory::new();
+        memory.write_bytes(0, &[
+            Instruction::LXI_SP as u8, 0x00, 0x01,
+            Instruction::LXI_H as u8, 0x80, 0x00,
+            Instruction::INR_M as u8,
+            Instruction::PUSH_H as u8,
+            Instruction::POP_B as u8,
+            Instruction::INX_H as u8,
+            Instruction::JMP as u8, 0x06, 0x00,
+        ]);
+        memory
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut memory = counter_program();
+        let mut cpu = Intel8080::new();
+        for _ in 0..10 {
+            cpu.step(&mut memory, &mut ());
+        }
+        cpu.set_int(true);
+
+        let bytes = Snapshot::capture(&cpu, &memory).to_bytes();
+        assert_eq!(bytes.len(), HEADER_LEN + 0x100);
+
+        let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+        let mut restored_memory: Memory<0x100> = Memory::new();
+        let mut restored = Intel8080::new();
+        snapshot.restore(&mut restored, &mut restored_memory).unwrap();
+
+        assert_eq!(restored.save_state(), cpu.save_state());
+        assert_eq!(restored.cycles(), cpu.cycles());
+        assert!(restored.int());
+
+        for _ in 0..20 {
+            assert_eq!(restored.step(&mut restored_memory, &mut ()), cpu.step(&mut memory, &mut ()));
+        }
+        assert_eq!(restored.save_state(), cpu.save_state());
+        assert_eq!(restored_memory.as_slice(), memory.as_slice());
+    }
+
+    #[test]
+    fn test_version_1() {
+        let mut memory = counter_program();
+        let mut cpu = Intel8080::new();
+        for _ in 0..10 {
+            cpu.step(&mut memory, &mut ());
+        }
+        let snapshot = Snapshot::capture(&cpu, &memory);
+
+        // the same state without the total cycles
+        let mut bytes = snapshot.to_bytes();
+        bytes[4] = 1;
+        bytes.drain(38..46);
+
+        let old = Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(old.cpu, CpuState { cycles: 0, ..snapshot.cpu.clone() });
+        assert_eq!(old.memory, snapshot.memory);
+        assert_eq!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]), Err(SnapshotError::Truncated));
+    }
+
+    #[test]
+    fn test_errors() {
+        let memory = counter_program();
+        let bytes = Snapshot::capture(&Intel8080::new(), &memory).to_bytes();
+
+        assert_eq!(Snapshot::from_bytes(b"Z80!\x01\x00"), Err(SnapshotError::BadMagic));
+
+        let mut future = bytes.clone();
+        future[4] = 3;
+        assert_eq!(Snapshot::from_bytes(&future), Err(SnapshotError::UnsupportedVersion(3)));
+
+        assert_eq!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]), Err(SnapshotError::Truncated));
+
+        let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+        let mut small: Memory<0x10> = Memory::new();
+        assert_eq!(snapshot.restore(&mut Intel8080::new(), &mut small),
+            Err(SnapshotError::MemorySizeMismatch { expected: 0x10, found: 0x100 }));
+    }
+}
@@ -0,0 +1,190 @@
+use std::fmt;
+
+use crate::memory::MemoryAccess;
+
+// Motorola S-records, `S<type><count><address><data><checksum>`, where the
+// count covers the address, data and checksum bytes:
+//
+//   S0      header, ignored
+//   S1/2/3  data with a 16, 24 or 32-bit address
+//   S5/6    count of the data records so far, 16 or 24-bit
+//   S7/8/9  start address, 32, 24 or 16-bit
+//
+// Addresses past 0xFFFF are an error since the 8080 cannot reach them.
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SRecordErrorKind {
+    MissingStartCode,
+    InvalidHexDigit(char),
+    // odd number of hex digits, or fewer than a record needs
+    Malformed,
+    LengthMismatch { declared: usize, actual: usize },
+    BadChecksum { expected: u8, found: u8 },
+    UnknownRecordType(char),
+    AddressOutOfRange(u32),
+    RecordCountMismatch { declared: u32, actual: u32 }
+}
+
+impl fmt::Display for SRecordErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SRecordErrorKind::MissingStartCode => write!(f, "record does not start with 'S'"),
+            SRecordErrorKind::InvalidHexDigit(c) => write!(f, "invalid hex digit {c:?}"),
+            SRecordErrorKind::Malformed => write!(f, "record is too short or has an odd number of digits"),
+            SRecordErrorKind::LengthMismatch { declared, actual } => {
+                write!(f, "record declares {declared} bytes but has {actual}")
+            },
+            SRecordErrorKind::BadChecksum { expected, found } => {
+                write!(f, "checksum is {found:02X}, expected {expected:02X}")
+            },
+            SRecordErrorKind::UnknownRecordType(c) => write!(f, "unknown record type S{c}"),
+            SRecordErrorKind::AddressOutOfRange(addr) => {
+                write!(f, "address {addr:X} is outside of the 64K address space")
+            },
+            SRecordErrorKind::RecordCountMismatch { declared, actual } => {
+                write!(f, "count record says {declared} data records but there were {actual}")
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SRecordError {
+    // 1-based line of the record
+    pub line: usize,
+    pub kind: SRecordErrorKind
+}
+
+impl fmt::Display for SRecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl std::error::Error for SRecordError {}
+
+// Load every data record into `memory` and return the start address, if the
+// file has one
+pub fn load_srec(text: &str, memory: &mut impl MemoryAccess) -> Result<Option<u16>, SRecordError> {
+    let mut entry = None;
+    let mut data_records = 0;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim().trim_end_matches('\x1a');
+        if line.is_empty() {
+            continue;
+        }
+
+        let error = |kind| SRecordError { line: index + 1, kind };
+        let digits = line.strip_prefix(['S', 's']).ok_or(error(SRecordErrorKind::MissingStartCode))?;
+        let record_type = digits.chars().next().ok_or(error(SRecordErrorKind::Malformed))?;
+        let address_len = match record_type {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => return Err(error(SRecordErrorKind::UnknownRecordType(record_type)))
+        };
+
+        let digits = &digits[1..];
+        if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+            return Err(error(SRecordErrorKind::InvalidHexDigit(c)));
+        }
+        if digits.len() % 2 != 0 || digits.len() < 2 * (address_len + 2) {
+            return Err(error(SRecordErrorKind::Malformed));
+        }
+
+        let bytes: Vec<u8> = (0..digits.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+            .collect();
+
+        let declared = bytes[0] as usize;
+        let actual = bytes.len() - 1;
+        if declared != actual {
+            return Err(error(SRecordErrorKind::LengthMismatch { declared, actual }));
+        }
+
+        let found = bytes[bytes.len() - 1];
+        let expected = !bytes[..bytes.len() - 1].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if found != expected {
+            return Err(error(SRecordErrorKind::BadChecksum { expected, found }));
+        }
+
+        let address = bytes[1..1 + address_len].iter().fold(0u32, |addr, byte| addr << 8 | *byte as u32);
+        let data = &bytes[1 + address_len..bytes.len() - 1];
+        match record_type {
+            '1' | '2' | '3' => {
+                for (offset, byte) in data.iter().enumerate() {
+                    let addr = address + offset as u32;
+                    if addr > 0xffff {
+                        return Err(error(SRecordErrorKind::AddressOutOfRange(addr)));
+                    }
+                    memory.write_byte(addr as u16, *byte);
+                }
+                data_records += 1;
+            },
+            '5' | '6' if address != data_records => {
+                return Err(error(SRecordErrorKind::RecordCountMismatch {
+                    declared: address,
+                    actual: data_records
+                }));
+            },
+            '7' | '8' | '9' => {
+                if address > 0xffff {
+                    return Err(error(SRecordErrorKind::AddressOutOfRange(address)));
+                }
+                entry = Some(address as u16);
+            },
+            _ => {}
+        }
+    }
+
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    fn load(text: &str) -> Result<(Memory<0x10000>, Option<u16>), SRecordError> {
+        let mut memory = Memory::new();
+        let entry = load_srec(text, &mut memory)?;
+        Ok((memory, entry))
+    }
+
+    #[test]
+    fn test_load() {
+        let text = "\
+S00F000068656C6C6F202020202000003C
+S11F00007C0802A6900100049421FFF07C6C1B787C8C23783C6000003863000026
+S5030001FB
+S9030000FC
+";
+        let (memory, entry) = load(text).unwrap();
+        assert_eq!(memory.get_bytes(0, 4), &[0x7c, 0x08, 0x02, 0xa6]);
+        assert_eq!(memory.read_byte(0x1b), 0x00);
+        assert_eq!(memory.read_byte(0x19), 0x63);
+        assert_eq!(entry, Some(0));
+
+        let (memory, entry) = load("S2060001003E0FAB\nS804000100FA").unwrap();
+        assert_eq!(memory.get_bytes(0x100, 0x102), &[0x3e, 0x0f]);
+        assert_eq!(entry, Some(0x100));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text| load(text).err().unwrap();
+
+        assert_eq!(error(":030000FC"), SRecordError { line: 1, kind: SRecordErrorKind::MissingStartCode });
+        assert_eq!(error("S9030000FC\nS9030000FD").kind, SRecordErrorKind::BadChecksum { expected: 0xfc, found: 0xfd });
+        assert_eq!(error("S9030000FC\nS9030000FD").line, 2);
+        assert_eq!(error("S4030000FC").kind, SRecordErrorKind::UnknownRecordType('4'));
+        assert_eq!(error("S9040000FC").kind, SRecordErrorKind::LengthMismatch { declared: 4, actual: 3 });
+        assert_eq!(error("S90300").kind, SRecordErrorKind::Malformed);
+        assert_eq!(error("S9030000FX").kind, SRecordErrorKind::InvalidHexDigit('X'));
+        assert_eq!(error("S20501000055A4").kind, SRecordErrorKind::AddressOutOfRange(0x10000));
+        assert_eq!(error("S5030002FA").kind, SRecordErrorKind::RecordCountMismatch { declared: 2, actual: 0 });
+
+        assert_eq!(error("S9030000FD").to_string(), "line 1: checksum is FD, expected FC");
+    }
+}
@@ -0,0 +1,29 @@
+use std::fmt::Debug;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::cpu::Intel8080;
+use crate::memory::{Memory, MemoryAccess};
+
+// Helpers shared by the unit tests
+
+// An empty directory of its own for the test `name` in `module`
+pub(crate) fn temp_dir(module: &str, name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("emu8080-{module}-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Enter `entry` as a CALL from 0x1234 would, answer it with `trap` and check
+// it returned there
+pub(crate) fn call<E: PartialEq + Debug>(cpu: &mut Intel8080, memory: &mut Memory<0x10000>, entry: u16,
+    trap: impl FnOnce(&mut Intel8080, &mut Memory<0x10000>) -> Option<E>) {
+    cpu.registers_mut().set_pc(entry);
+    cpu.registers_mut().set_sp(0x8000);
+    memory.write_bytes(0x8000, &[0x34, 0x12]);
+
+    assert_eq!(trap(cpu, memory), None);
+    assert_eq!(cpu.registers().pc(), 0x1234);
+    assert_eq!(cpu.registers().sp(), 0x8002);
+}
@@ -0,0 +1,202 @@
+use std::io::Write;
+
+use crate::cpu::{Flags, Instruction};
+use crate::disasm::{disassemble_bytes, Disassembly, Syntax};
+
+// Records of executed instructions, produced by Intel8080::step_traced.
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TraceRecord {
+    pub pc: u16,
+    pub instruction: Instruction,
+    // instruction bytes, the first `length` of them are used
+    pub bytes: [u8; 3],
+    pub length: u16,
+    // the instruction came from the bus as an interrupt, not from PC
+    pub interrupt: bool,
+    // four bytes at PC before execution, as the compact format prints them
+    pub memory: [u8; 4],
+    // registers before execution
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub inte: bool,
+    // cycles executed before this instruction
+    pub cycles: u64,
+    // cycles taken by this instruction, wait states included
+    pub instruction_cycles: u64
+}
+
+impl TraceRecord {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.length as usize]
+    }
+
+    pub fn accumulator(&self) -> u8 {
+        (self.af >> 8) as u8
+    }
+
+    pub fn flags(&self) -> Flags {
+        Flags::from_bits(self.af as u8)
+    }
+
+    pub fn disassemble(&self, syntax: Syntax) -> Disassembly {
+        disassemble_bytes(self.bytes, self.pc, syntax)
+    }
+
+    pub fn format(&self, format: TraceFormat) -> String {
+        match format {
+            TraceFormat::Human => {
+                let bytes: Vec<String> = self.bytes().iter().map(|byte| format!("{byte:02X}")).collect();
+                let text = self.disassemble(Syntax::Intel).text;
+                format!("{:04X}{} {:<8}  {:<14}  A={:02X} F={} BC={:04X} DE={:04X} HL={:04X} SP={:04X} {} CYC={}",
+                    self.pc, if self.interrupt { "*" } else { " " }, bytes.join(" "), text,
+                    self.accumulator(), self.flags(), self.bc, self.de, self.hl, self.sp,
+                    if self.inte { "EI" } else { "DI" }, self.cycles)
+            },
+            TraceFormat::Compact => {
+                let [m0, m1, m2, m3] = self.memory;
+                format!("PC: {:04X}, AF: {:04X}, BC: {:04X}, DE: {:04X}, HL: {:04X}, SP: {:04X}, CYC: {}\t({m0:02X} {m1:02X} {m2:02X} {m3:02X})",
+                    self.pc, self.af, self.bc, self.de, self.hl, self.sp, self.cycles)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TraceFormat {
+    // address, bytes, disassembly, registers, flags and cycles, for reading.
+    // An interrupt is marked with a '*' after the address
+    Human,
+    // one line per instruction in the format other 8080 emulators log, for
+    // diffing runs
+    Compact
+}
+
+pub trait Tracer {
+    fn trace(&mut self, record: &TraceRecord);
+}
+
+impl<F: FnMut(&TraceRecord)> Tracer for F {
+    fn trace(&mut self, record: &TraceRecord) {
+        self(record)
+    }
+}
+
+impl Tracer for Vec<TraceRecord> {
+    fn trace(&mut self, record: &TraceRecord) {
+        self.push(record.clone());
+    }
+}
+
+// Writes a line per record. Write errors end the output rather than the run
+pub struct TraceWriter<W: Write> {
+    writer: W,
+    format: TraceFormat,
+    failed: bool
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(writer: W, format: TraceFormat) -> Self {
+        Self {
+            writer,
+            format,
+            failed: false
+        }
+    }
+
+    // Whether a write has failed and later records were dropped
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Tracer for TraceWriter<W> {
+    fn trace(&mut self, record: &TraceRecord) {
+        if !self.failed {
+            self.failed = writeln!(self.writer, "{}", record.format(self.format)).is_err();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Intel8080;
+    use crate::io::IoBus;
+    use crate::memory::{Memory, MemoryAccess};
+
+    #[test]
+    fn test_step_traced() {
+        let mut memory: Memory<0x100> = Memory::new();
+        // LXI SP,0080h; MVI A,42h; CALL 0010h / 0010: RET
+        memory.write_bytes(0, &[0x31, 0x80, 0x00, 0x3e, 0x42, 0xcd, 0x10, 0x00]);
+        memory.write_byte(0x10, 0xc9);
+
+        let mut cpu = Intel8080::new();
+        let mut records = Vec::new();
+        for _ in 0..4 {
+            cpu.step_traced(&mut memory, &mut (), &mut records);
+        }
+
+        assert_eq!(records.iter().map(|record| record.pc).collect::<Vec<_>>(), [0x00, 0x03, 0x05, 0x10]);
+        assert_eq!(records[2].bytes(), &[0xcd, 0x10, 0x00]);
+        assert_eq!(records[2].instruction, Instruction::CALL);
+        assert_eq!(records[2].accumulator(), 0x42);
+        assert_eq!(records[2].sp, 0x80);
+        assert_eq!(records[3].sp, 0x7e);
+        assert_eq!(records.iter().map(|record| record.cycles).collect::<Vec<_>>(), [0, 10, 17, 34]);
+        assert_eq!(records[3].instruction_cycles, 10);
+        assert_eq!(cpu.cycles(), 44);
+
+        assert_eq!(records[2].format(TraceFormat::Human),
+            "0005  CD 10 00  CALL 0010h      A=42 F=-------- BC=0000 DE=0000 HL=0000 SP=0080 DI CYC=17");
+        assert_eq!(records[2].format(TraceFormat::Compact),
+            "PC: 0005, AF: 4202, BC: 0000, DE: 0000, HL: 0000, SP: 0080, CYC: 17\t(CD 10 00 00)");
+    }
+
+    struct Rst;
+
+    impl IoBus for Rst {
+        fn port_in(&mut self, _port: u8) -> u8 {
+            0
+        }
+
+        fn port_out(&mut self, _port: u8, _val: u8) {}
+
+        fn interrupt_ack(&mut self) -> u8 {
+            0xd7
+        }
+    }
+
+    #[test]
+    fn test_trace_interrupt() {
+        let mut memory: Memory<0x100> = Memory::new();
+        // EI; NOP; HLT
+        memory.write_bytes(0, &[0xfb, 0x00, 0x76]);
+        memory.write_byte(0x10, 0x76);
+        let mut cpu = Intel8080::new();
+        cpu.registers_mut().set_sp(0x80);
+
+        let mut writer = TraceWriter::new(Vec::new(), TraceFormat::Human);
+        // nothing executes once halted
+        for _ in 0..4 {
+            cpu.step_traced(&mut memory, &mut Rst, &mut writer);
+        }
+        cpu.set_int(true);
+        cpu.step_traced(&mut memory, &mut Rst, &mut writer);
+
+        let text = String::from_utf8(writer.into_inner()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[2].starts_with("0002  76        HLT"));
+        assert!(lines[3].starts_with("0003* D7        RST 2"));
+        assert!(lines[3].contains(" EI "));
+    }
+}
@@ -0,0 +1,76 @@
+// Runs the public domain 8080 exercisers as CP/M programs on the crate's BDOS
+// and checks what they print. The .COM files are not distributed with the
+// crate: put them in tests/roms, or point EMU8080_ROMS at a directory holding
+// them, and run `cargo test -- --ignored`. A missing program fails its test.
+//
+// 8080EXM runs for minutes even in release builds, so it is only built with
+// the `exm` feature.
+
+use std::fs;
+use std::path::PathBuf;
+
+use emu8080::{Bdos, BufferConsole, Intel8080, Memory};
+
+fn rom(name: &str) -> PathBuf {
+    let dir = std::env::var_os("EMU8080_ROMS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms"));
+
+    let path = dir.join(name);
+    assert!(path.is_file(), "{name}: {} not found", path.display());
+    path
+}
+
+// Output of the program
+fn run(name: &str, max_steps: Option<u64>) -> String {
+    let path = rom(name);
+    let program = fs::read(&path).unwrap();
+
+    let mut bdos = Bdos::new(path.parent().unwrap(), BufferConsole::default());
+    let mut cpu = Intel8080::new();
+    let mut memory: Memory<0x10000> = Memory::new();
+    bdos.load_com(&mut cpu, &mut memory, &program, "").unwrap();
+
+    let mut steps = 0;
+    while bdos.trap(&mut cpu, &mut memory).is_none() {
+        assert!(!cpu.halted(), "{name} halted:\n{}", bdos.console().output_string());
+        assert!(max_steps.is_none_or(|max| steps < max), "{name} did not finish:\n{}", bdos.console().output_string());
+
+        cpu.step(&mut memory, &mut ());
+        steps += 1;
+    }
+
+    let output = bdos.console().output_string();
+    println!("{output}");
+    output
+}
+
+#[test]
+#[ignore = "needs the exerciser ROMs"]
+fn test_8080pre() {
+    let output = run("8080PRE.COM", Some(10_000_000));
+    assert!(output.contains("8080 Preliminary tests complete"), "{output}");
+}
+
+#[test]
+#[ignore = "needs the exerciser ROMs"]
+fn test_tst8080() {
+    let output = run("TST8080.COM", Some(10_000_000));
+    assert!(output.contains("CPU IS OPERATIONAL"), "{output}");
+}
+
+#[test]
+#[ignore = "needs the exerciser ROMs"]
+fn test_cputest() {
+    let output = run("CPUTEST.COM", Some(1_000_000_000));
+    assert!(output.contains("CPU TESTS OK"), "{output}");
+}
+
+#[cfg(feature = "exm")]
+#[test]
+#[ignore = "needs the exerciser ROMs"]
+fn test_8080exm() {
+    let output = run("8080EXM.COM", None);
+    assert!(!output.contains("ERROR"), "{output}");
+    assert!(output.contains("Tests complete"), "{output}");
+}